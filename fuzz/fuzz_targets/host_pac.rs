@@ -0,0 +1,31 @@
+#![no_main]
+
+use boa_ast::scope::Scope;
+use boa_interner::Interner;
+use boa_parser::{Parser, Source};
+use libfuzzer_sys::fuzz_target;
+use qpac::{host, pac::Pac};
+
+// Every line is a host candidate, whatever passes normalization ends up in the PAC
+fuzz_target!(|data: &[u8]| {
+    let input = String::from_utf8_lossy(data);
+    let mut hosts: Vec<String> = input
+        .lines()
+        .filter_map(|line| host::normalize(line).ok())
+        .collect();
+    hosts.sort();
+    hosts.dedup();
+    assert_valid_js(&Pac::generate(hosts));
+
+    // Generation must stay safe even for input that skipped validation
+    let raw = input.lines().map(String::from).collect();
+    assert_valid_js(&Pac::generate(raw));
+});
+
+fn assert_valid_js(pac: &Pac) {
+    let mut interner = Interner::default();
+    let mut parser = Parser::new(Source::from_bytes(pac.file.as_bytes()));
+    if let Err(e) = parser.parse_script(&Scope::new_global(), &mut interner) {
+        panic!("generated PAC is not valid JS: {e}\n{}", pac.file);
+    }
+}
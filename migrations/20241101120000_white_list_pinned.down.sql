@@ -0,0 +1 @@
+ALTER TABLE white_list DROP COLUMN pinned;
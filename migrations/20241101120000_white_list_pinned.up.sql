@@ -0,0 +1 @@
+ALTER TABLE white_list ADD COLUMN pinned INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1 @@
+ALTER TABLE pac DROP COLUMN hosts;
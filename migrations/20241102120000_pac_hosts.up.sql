@@ -0,0 +1,2 @@
+-- JSON array of hosts the file was generated from
+ALTER TABLE pac ADD COLUMN hosts TEXT;
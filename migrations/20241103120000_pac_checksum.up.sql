@@ -0,0 +1,2 @@
+-- BLAKE3 of the file body, hex encoded
+ALTER TABLE pac ADD COLUMN checksum TEXT;
@@ -0,0 +1,6 @@
+CREATE TABLE host_tags (
+	host TEXT NOT NULL REFERENCES white_list(host) ON DELETE CASCADE,
+	tag TEXT NOT NULL
+);
+CREATE UNIQUE INDEX IF NOT EXISTS idx_host_tags_host_tag ON host_tags(host, tag);
+CREATE INDEX IF NOT EXISTS idx_host_tags_tag ON host_tags(tag);
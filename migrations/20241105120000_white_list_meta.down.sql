@@ -0,0 +1,5 @@
+ALTER TABLE white_list DROP COLUMN updated_at;
+ALTER TABLE white_list DROP COLUMN created_at;
+ALTER TABLE white_list DROP COLUMN include_subdomains;
+ALTER TABLE white_list DROP COLUMN expires_at;
+ALTER TABLE white_list DROP COLUMN note;
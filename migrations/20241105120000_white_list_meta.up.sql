@@ -0,0 +1,7 @@
+ALTER TABLE white_list ADD COLUMN note TEXT;
+-- Unix seconds
+ALTER TABLE white_list ADD COLUMN expires_at INTEGER;
+ALTER TABLE white_list ADD COLUMN include_subdomains INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE white_list ADD COLUMN created_at INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE white_list ADD COLUMN updated_at INTEGER NOT NULL DEFAULT 0;
+UPDATE white_list SET created_at = CAST(strftime('%s', 'now') AS INTEGER), updated_at = CAST(strftime('%s', 'now') AS INTEGER);
@@ -0,0 +1,7 @@
+CREATE TABLE snapshots (
+	name TEXT NOT NULL,
+	created_at INTEGER NOT NULL,
+	-- JSON array of host entries
+	hosts TEXT NOT NULL
+);
+CREATE UNIQUE INDEX IF NOT EXISTS idx_snapshots_name ON snapshots(name);
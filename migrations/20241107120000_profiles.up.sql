@@ -0,0 +1,6 @@
+CREATE TABLE profiles (
+	name TEXT NOT NULL,
+	-- PAC proxy chain, e.g. "PROXY 10.0.0.1:3128; DIRECT;"
+	proxy TEXT NOT NULL
+);
+CREATE UNIQUE INDEX IF NOT EXISTS idx_profiles_name ON profiles(name);
@@ -0,0 +1,6 @@
+DROP TRIGGER IF EXISTS white_list_insert_version;
+DROP TRIGGER IF EXISTS white_list_update_version;
+DROP TRIGGER IF EXISTS white_list_delete_version;
+DROP TRIGGER IF EXISTS host_tags_insert_version;
+DROP TRIGGER IF EXISTS host_tags_delete_version;
+DELETE FROM conf WHERE key = 'hosts_version';
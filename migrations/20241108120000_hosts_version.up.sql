@@ -0,0 +1,26 @@
+-- Bumped on every host change, instances sharing the database poll it
+INSERT OR IGNORE INTO conf(key, value) VALUES ('hosts_version', '0');
+CREATE TRIGGER white_list_insert_version AFTER INSERT ON white_list
+BEGIN
+	UPDATE conf SET value = CAST(CAST(value AS INTEGER) + 1 AS TEXT) WHERE key = 'hosts_version';
+END;
+
+CREATE TRIGGER white_list_update_version AFTER UPDATE ON white_list
+BEGIN
+	UPDATE conf SET value = CAST(CAST(value AS INTEGER) + 1 AS TEXT) WHERE key = 'hosts_version';
+END;
+
+CREATE TRIGGER white_list_delete_version AFTER DELETE ON white_list
+BEGIN
+	UPDATE conf SET value = CAST(CAST(value AS INTEGER) + 1 AS TEXT) WHERE key = 'hosts_version';
+END;
+
+CREATE TRIGGER host_tags_insert_version AFTER INSERT ON host_tags
+BEGIN
+	UPDATE conf SET value = CAST(CAST(value AS INTEGER) + 1 AS TEXT) WHERE key = 'hosts_version';
+END;
+
+CREATE TRIGGER host_tags_delete_version AFTER DELETE ON host_tags
+BEGIN
+	UPDATE conf SET value = CAST(CAST(value AS INTEGER) + 1 AS TEXT) WHERE key = 'hosts_version';
+END;
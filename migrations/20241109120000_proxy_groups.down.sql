@@ -0,0 +1,2 @@
+ALTER TABLE white_list DROP COLUMN proxy_group;
+DROP TABLE proxy_groups;
@@ -0,0 +1,8 @@
+CREATE TABLE proxy_groups (
+	name TEXT NOT NULL,
+	-- PAC proxy chain for hosts of the group
+	proxy TEXT NOT NULL
+);
+CREATE UNIQUE INDEX IF NOT EXISTS idx_proxy_groups_name ON proxy_groups(name);
+-- Hosts of unknown groups fall back to the default proxy
+ALTER TABLE white_list ADD COLUMN proxy_group TEXT;
@@ -0,0 +1,7 @@
+CREATE TABLE upstreams (
+	-- Upstreams are picked in this order on the client
+	position INTEGER NOT NULL PRIMARY KEY,
+	-- PAC proxy chain
+	proxy TEXT NOT NULL,
+	weight INTEGER NOT NULL
+);
@@ -0,0 +1,10 @@
+CREATE TABLE client_fetches (
+	-- Unix time of the UTC midnight starting the day
+	day INTEGER NOT NULL,
+	-- Client network, never a full address
+	client TEXT NOT NULL,
+	-- Empty for the default pac
+	profile TEXT NOT NULL DEFAULT '',
+	fetches INTEGER NOT NULL
+);
+CREATE UNIQUE INDEX IF NOT EXISTS idx_client_fetches ON client_fetches(day, client, profile);
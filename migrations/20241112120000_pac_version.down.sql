@@ -0,0 +1,2 @@
+DROP INDEX idx_pac_version;
+ALTER TABLE pac DROP COLUMN version;
@@ -0,0 +1,4 @@
+-- Short sequential label of a stored file, shown as v1, v2, ...
+ALTER TABLE pac ADD COLUMN version INTEGER;
+UPDATE pac SET version = (SELECT COUNT(*) FROM pac p WHERE p.rowid <= pac.rowid);
+CREATE UNIQUE INDEX IF NOT EXISTS idx_pac_version ON pac(version);
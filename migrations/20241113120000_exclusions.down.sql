@@ -0,0 +1,3 @@
+DROP TRIGGER IF EXISTS exclusions_delete_version;
+DROP TRIGGER IF EXISTS exclusions_insert_version;
+DROP TABLE exclusions;
@@ -0,0 +1,15 @@
+-- Hosts always sent DIRECT, checked before groups and the mode
+CREATE TABLE exclusions (
+	host TEXT NOT NULL PRIMARY KEY,
+	created_at INTEGER NOT NULL
+);
+
+CREATE TRIGGER exclusions_insert_version AFTER INSERT ON exclusions
+BEGIN
+	UPDATE conf SET value = CAST(CAST(value AS INTEGER) + 1 AS TEXT) WHERE key = 'hosts_version';
+END;
+
+CREATE TRIGGER exclusions_delete_version AFTER DELETE ON exclusions
+BEGIN
+	UPDATE conf SET value = CAST(CAST(value AS INTEGER) + 1 AS TEXT) WHERE key = 'hosts_version';
+END;
@@ -0,0 +1,3 @@
+ALTER TABLE pac DROP COLUMN qpac_version;
+ALTER TABLE pac DROP COLUMN host_count;
+ALTER TABLE pac DROP COLUMN generated_at;
@@ -0,0 +1,4 @@
+-- Written to the header of the file as well, NULL for files generated before
+ALTER TABLE pac ADD COLUMN generated_at INTEGER;
+ALTER TABLE pac ADD COLUMN host_count INTEGER;
+ALTER TABLE pac ADD COLUMN qpac_version TEXT;
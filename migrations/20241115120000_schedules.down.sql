@@ -0,0 +1,2 @@
+ALTER TABLE proxy_groups DROP COLUMN schedule;
+ALTER TABLE white_list DROP COLUMN schedule;
@@ -0,0 +1,3 @@
+-- Parsed as crate::schedule::Schedule, NULL routes at any time
+ALTER TABLE white_list ADD COLUMN schedule TEXT;
+ALTER TABLE proxy_groups ADD COLUMN schedule TEXT;
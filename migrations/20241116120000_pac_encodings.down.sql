@@ -0,0 +1 @@
+DROP TABLE pac_encodings;
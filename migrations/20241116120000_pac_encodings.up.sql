@@ -0,0 +1,7 @@
+-- Precompressed bodies of stored files, served as is
+CREATE TABLE pac_encodings (
+	hash TEXT NOT NULL REFERENCES pac(hash) ON DELETE CASCADE,
+	encoding TEXT NOT NULL,
+	body BLOB NOT NULL,
+	PRIMARY KEY (hash, encoding)
+);
@@ -0,0 +1,3 @@
+ALTER TABLE white_list ADD COLUMN include_subdomains INTEGER NOT NULL DEFAULT 0;
+UPDATE white_list SET include_subdomains = 1 WHERE kind = 'suffix';
+ALTER TABLE white_list DROP COLUMN kind;
@@ -0,0 +1,4 @@
+-- Parsed as crate::rules::EntryKind, replaces the include_subdomains flag
+ALTER TABLE white_list ADD COLUMN kind TEXT NOT NULL DEFAULT 'exact';
+UPDATE white_list SET kind = 'suffix' WHERE include_subdomains;
+ALTER TABLE white_list DROP COLUMN include_subdomains;
@@ -0,0 +1,8 @@
+CREATE TABLE networks (
+	name TEXT NOT NULL,
+	-- IPv4 network in CIDR notation myIpAddress() is checked against
+	network TEXT NOT NULL,
+	-- PAC proxy chain for proxied hosts while the client is in the network
+	proxy TEXT NOT NULL
+);
+CREATE UNIQUE INDEX IF NOT EXISTS idx_networks_name ON networks(name);
@@ -0,0 +1,5 @@
+CREATE TABLE ip_ranges (
+	-- IPv4 network in CIDR notation hosts are resolved into
+	network TEXT NOT NULL
+);
+CREATE UNIQUE INDEX IF NOT EXISTS idx_ip_ranges_network ON ip_ranges(network);
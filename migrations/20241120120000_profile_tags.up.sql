@@ -0,0 +1,2 @@
+-- Comma separated host tags the profile is limited to, empty lists every host
+ALTER TABLE profiles ADD COLUMN tags TEXT NOT NULL DEFAULT '';
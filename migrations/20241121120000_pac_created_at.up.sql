@@ -0,0 +1,3 @@
+-- Unix seconds of the first upload, files stored before only have generated_at
+ALTER TABLE pac ADD COLUMN created_at INTEGER;
+UPDATE pac SET created_at = generated_at;
@@ -0,0 +1,13 @@
+CREATE TABLE audit_log (
+	id INTEGER PRIMARY KEY AUTOINCREMENT,
+	-- Unix time
+	at INTEGER NOT NULL,
+	-- token, session, anonymous or server
+	actor TEXT NOT NULL,
+	-- Client address, NULL for the server's own operations
+	ip TEXT,
+	action TEXT NOT NULL,
+	-- JSON
+	payload TEXT NOT NULL DEFAULT 'null'
+);
+CREATE INDEX IF NOT EXISTS idx_audit_log_at ON audit_log(at);
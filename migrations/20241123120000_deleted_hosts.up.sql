@@ -0,0 +1,8 @@
+CREATE TABLE deleted_hosts (
+	host TEXT PRIMARY KEY NOT NULL,
+	-- JSON of the entry with its tags as it was removed
+	entry TEXT NOT NULL,
+	-- Unix time
+	deleted_at INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS idx_deleted_hosts_deleted_at ON deleted_hosts(deleted_at);
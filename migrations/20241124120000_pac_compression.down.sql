@@ -0,0 +1,3 @@
+-- Compressed files can't be decompressed here and stay unreadable
+ALTER TABLE pac DROP COLUMN size;
+ALTER TABLE pac DROP COLUMN file_encoding;
@@ -0,0 +1,5 @@
+-- 'zstd' when file holds the compressed bytes of the body, 'identity' for text
+ALTER TABLE pac ADD COLUMN file_encoding TEXT NOT NULL DEFAULT 'identity';
+-- Bytes of the uncompressed body
+ALTER TABLE pac ADD COLUMN size INTEGER;
+UPDATE pac SET size = length(CAST(file AS BLOB));
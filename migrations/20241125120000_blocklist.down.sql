@@ -0,0 +1,4 @@
+DROP TRIGGER IF EXISTS blocklist_delete_version;
+DROP TRIGGER IF EXISTS blocklist_update_version;
+DROP TRIGGER IF EXISTS blocklist_insert_version;
+DROP TABLE blocklist;
@@ -0,0 +1,22 @@
+-- Hosts sent DIRECT or blocked whatever else matches them, checked first
+CREATE TABLE blocklist (
+	host TEXT NOT NULL PRIMARY KEY,
+	kind TEXT NOT NULL DEFAULT 'exact',
+	action TEXT NOT NULL DEFAULT 'block',
+	created_at INTEGER NOT NULL
+);
+
+CREATE TRIGGER blocklist_insert_version AFTER INSERT ON blocklist
+BEGIN
+	UPDATE conf SET value = CAST(CAST(value AS INTEGER) + 1 AS TEXT) WHERE key = 'hosts_version';
+END;
+
+CREATE TRIGGER blocklist_update_version AFTER UPDATE ON blocklist
+BEGIN
+	UPDATE conf SET value = CAST(CAST(value AS INTEGER) + 1 AS TEXT) WHERE key = 'hosts_version';
+END;
+
+CREATE TRIGGER blocklist_delete_version AFTER DELETE ON blocklist
+BEGIN
+	UPDATE conf SET value = CAST(CAST(value AS INTEGER) + 1 AS TEXT) WHERE key = 'hosts_version';
+END;
@@ -0,0 +1,19 @@
+DROP TRIGGER IF EXISTS proxy_groups_insert_config_version;
+DROP TRIGGER IF EXISTS proxy_groups_update_config_version;
+DROP TRIGGER IF EXISTS proxy_groups_delete_config_version;
+DROP TRIGGER IF EXISTS upstreams_insert_config_version;
+DROP TRIGGER IF EXISTS upstreams_update_config_version;
+DROP TRIGGER IF EXISTS upstreams_delete_config_version;
+DROP TRIGGER IF EXISTS profiles_insert_config_version;
+DROP TRIGGER IF EXISTS profiles_update_config_version;
+DROP TRIGGER IF EXISTS profiles_delete_config_version;
+DROP TRIGGER IF EXISTS networks_insert_config_version;
+DROP TRIGGER IF EXISTS networks_update_config_version;
+DROP TRIGGER IF EXISTS networks_delete_config_version;
+DROP TRIGGER IF EXISTS ip_ranges_insert_config_version;
+DROP TRIGGER IF EXISTS ip_ranges_update_config_version;
+DROP TRIGGER IF EXISTS ip_ranges_delete_config_version;
+DROP TRIGGER IF EXISTS conf_insert_config_version;
+DROP TRIGGER IF EXISTS conf_update_config_version;
+DROP TRIGGER IF EXISTS conf_delete_config_version;
+DELETE FROM conf WHERE key = 'config_version';
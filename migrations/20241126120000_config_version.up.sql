@@ -0,0 +1,96 @@
+-- Bumped on every change of settings files are generated with, instances
+-- sharing the database poll it next to hosts_version
+INSERT OR IGNORE INTO conf(key, value) VALUES ('config_version', '0');
+
+CREATE TRIGGER proxy_groups_insert_config_version AFTER INSERT ON proxy_groups
+BEGIN
+	UPDATE conf SET value = CAST(CAST(value AS INTEGER) + 1 AS TEXT) WHERE key = 'config_version';
+END;
+
+CREATE TRIGGER proxy_groups_update_config_version AFTER UPDATE ON proxy_groups
+BEGIN
+	UPDATE conf SET value = CAST(CAST(value AS INTEGER) + 1 AS TEXT) WHERE key = 'config_version';
+END;
+
+CREATE TRIGGER proxy_groups_delete_config_version AFTER DELETE ON proxy_groups
+BEGIN
+	UPDATE conf SET value = CAST(CAST(value AS INTEGER) + 1 AS TEXT) WHERE key = 'config_version';
+END;
+
+CREATE TRIGGER upstreams_insert_config_version AFTER INSERT ON upstreams
+BEGIN
+	UPDATE conf SET value = CAST(CAST(value AS INTEGER) + 1 AS TEXT) WHERE key = 'config_version';
+END;
+
+CREATE TRIGGER upstreams_update_config_version AFTER UPDATE ON upstreams
+BEGIN
+	UPDATE conf SET value = CAST(CAST(value AS INTEGER) + 1 AS TEXT) WHERE key = 'config_version';
+END;
+
+CREATE TRIGGER upstreams_delete_config_version AFTER DELETE ON upstreams
+BEGIN
+	UPDATE conf SET value = CAST(CAST(value AS INTEGER) + 1 AS TEXT) WHERE key = 'config_version';
+END;
+
+CREATE TRIGGER profiles_insert_config_version AFTER INSERT ON profiles
+BEGIN
+	UPDATE conf SET value = CAST(CAST(value AS INTEGER) + 1 AS TEXT) WHERE key = 'config_version';
+END;
+
+CREATE TRIGGER profiles_update_config_version AFTER UPDATE ON profiles
+BEGIN
+	UPDATE conf SET value = CAST(CAST(value AS INTEGER) + 1 AS TEXT) WHERE key = 'config_version';
+END;
+
+CREATE TRIGGER profiles_delete_config_version AFTER DELETE ON profiles
+BEGIN
+	UPDATE conf SET value = CAST(CAST(value AS INTEGER) + 1 AS TEXT) WHERE key = 'config_version';
+END;
+
+CREATE TRIGGER networks_insert_config_version AFTER INSERT ON networks
+BEGIN
+	UPDATE conf SET value = CAST(CAST(value AS INTEGER) + 1 AS TEXT) WHERE key = 'config_version';
+END;
+
+CREATE TRIGGER networks_update_config_version AFTER UPDATE ON networks
+BEGIN
+	UPDATE conf SET value = CAST(CAST(value AS INTEGER) + 1 AS TEXT) WHERE key = 'config_version';
+END;
+
+CREATE TRIGGER networks_delete_config_version AFTER DELETE ON networks
+BEGIN
+	UPDATE conf SET value = CAST(CAST(value AS INTEGER) + 1 AS TEXT) WHERE key = 'config_version';
+END;
+
+CREATE TRIGGER ip_ranges_insert_config_version AFTER INSERT ON ip_ranges
+BEGIN
+	UPDATE conf SET value = CAST(CAST(value AS INTEGER) + 1 AS TEXT) WHERE key = 'config_version';
+END;
+
+CREATE TRIGGER ip_ranges_update_config_version AFTER UPDATE ON ip_ranges
+BEGIN
+	UPDATE conf SET value = CAST(CAST(value AS INTEGER) + 1 AS TEXT) WHERE key = 'config_version';
+END;
+
+CREATE TRIGGER ip_ranges_delete_config_version AFTER DELETE ON ip_ranges
+BEGIN
+	UPDATE conf SET value = CAST(CAST(value AS INTEGER) + 1 AS TEXT) WHERE key = 'config_version';
+END;
+
+CREATE TRIGGER conf_insert_config_version AFTER INSERT ON conf
+	WHEN NEW.key IN ('proxy', 'mode', 'bypass_private')
+BEGIN
+	UPDATE conf SET value = CAST(CAST(value AS INTEGER) + 1 AS TEXT) WHERE key = 'config_version';
+END;
+
+CREATE TRIGGER conf_update_config_version AFTER UPDATE ON conf
+	WHEN NEW.key IN ('proxy', 'mode', 'bypass_private')
+BEGIN
+	UPDATE conf SET value = CAST(CAST(value AS INTEGER) + 1 AS TEXT) WHERE key = 'config_version';
+END;
+
+CREATE TRIGGER conf_delete_config_version AFTER DELETE ON conf
+	WHEN OLD.key IN ('proxy', 'mode', 'bypass_private')
+BEGIN
+	UPDATE conf SET value = CAST(CAST(value AS INTEGER) + 1 AS TEXT) WHERE key = 'config_version';
+END;
@@ -0,0 +1,17 @@
+DROP TABLE IF EXISTS audit_log;
+DROP TABLE IF EXISTS client_fetches;
+DROP TABLE IF EXISTS blocklist;
+DROP TABLE IF EXISTS exclusions;
+DROP TABLE IF EXISTS ip_ranges;
+DROP TABLE IF EXISTS networks;
+DROP TABLE IF EXISTS upstreams;
+DROP TABLE IF EXISTS proxy_groups;
+DROP TABLE IF EXISTS profiles;
+DROP TABLE IF EXISTS snapshots;
+DROP TABLE IF EXISTS deleted_hosts;
+DROP TABLE IF EXISTS host_tags;
+DROP TABLE IF EXISTS white_list;
+DROP TABLE IF EXISTS pac_encodings;
+DROP TABLE IF EXISTS pac;
+DROP TABLE IF EXISTS conf;
+DROP FUNCTION IF EXISTS bump_version;
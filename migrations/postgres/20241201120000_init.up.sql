@@ -0,0 +1,160 @@
+-- Same tables as the sqlite migrations up to config_version, unix times are
+-- seconds in BIGINT columns. Names sort bytewise like in sqlite
+CREATE TABLE conf (
+	key TEXT COLLATE "C" NOT NULL PRIMARY KEY,
+	value TEXT NOT NULL
+);
+INSERT INTO conf(key, value) VALUES ('hosts_version', '0'), ('config_version', '0');
+
+CREATE TABLE pac (
+	hash TEXT NOT NULL PRIMARY KEY,
+	-- zstd compressed body
+	file BYTEA NOT NULL,
+	file_encoding TEXT NOT NULL DEFAULT 'zstd',
+	size BIGINT,
+	-- JSON array of the hosts the file was generated from
+	hosts TEXT,
+	checksum TEXT,
+	version BIGINT UNIQUE,
+	generated_at BIGINT,
+	host_count BIGINT,
+	qpac_version TEXT,
+	created_at BIGINT
+);
+
+CREATE TABLE pac_encodings (
+	hash TEXT NOT NULL REFERENCES pac(hash) ON DELETE CASCADE,
+	encoding TEXT NOT NULL,
+	body BYTEA NOT NULL,
+	PRIMARY KEY (hash, encoding)
+);
+
+CREATE TABLE white_list (
+	host TEXT COLLATE "C" NOT NULL PRIMARY KEY,
+	pinned BOOLEAN NOT NULL DEFAULT FALSE,
+	note TEXT,
+	expires_at BIGINT,
+	created_at BIGINT NOT NULL DEFAULT 0,
+	updated_at BIGINT NOT NULL DEFAULT 0,
+	proxy_group TEXT,
+	schedule TEXT,
+	kind TEXT NOT NULL DEFAULT 'exact'
+);
+
+CREATE TABLE host_tags (
+	host TEXT COLLATE "C" NOT NULL REFERENCES white_list(host) ON DELETE CASCADE,
+	tag TEXT COLLATE "C" NOT NULL,
+	PRIMARY KEY (host, tag)
+);
+CREATE INDEX idx_host_tags_tag ON host_tags(tag);
+
+CREATE TABLE deleted_hosts (
+	host TEXT COLLATE "C" NOT NULL PRIMARY KEY,
+	-- JSON of the entry with its tags as it was removed
+	entry TEXT NOT NULL,
+	deleted_at BIGINT NOT NULL
+);
+CREATE INDEX idx_deleted_hosts_deleted_at ON deleted_hosts(deleted_at);
+
+CREATE TABLE snapshots (
+	name TEXT COLLATE "C" NOT NULL PRIMARY KEY,
+	created_at BIGINT NOT NULL,
+	-- JSON array of host entries
+	hosts TEXT NOT NULL
+);
+
+CREATE TABLE profiles (
+	name TEXT COLLATE "C" NOT NULL PRIMARY KEY,
+	proxy TEXT NOT NULL,
+	-- Comma separated
+	tags TEXT NOT NULL DEFAULT ''
+);
+
+CREATE TABLE proxy_groups (
+	name TEXT COLLATE "C" NOT NULL PRIMARY KEY,
+	proxy TEXT NOT NULL,
+	schedule TEXT
+);
+
+CREATE TABLE upstreams (
+	position BIGINT NOT NULL PRIMARY KEY,
+	proxy TEXT NOT NULL,
+	weight BIGINT NOT NULL
+);
+
+CREATE TABLE networks (
+	name TEXT COLLATE "C" NOT NULL PRIMARY KEY,
+	network TEXT NOT NULL,
+	proxy TEXT NOT NULL
+);
+
+CREATE TABLE ip_ranges (
+	network TEXT COLLATE "C" NOT NULL PRIMARY KEY
+);
+
+CREATE TABLE exclusions (
+	host TEXT COLLATE "C" NOT NULL PRIMARY KEY,
+	created_at BIGINT NOT NULL
+);
+
+CREATE TABLE blocklist (
+	host TEXT COLLATE "C" NOT NULL PRIMARY KEY,
+	kind TEXT NOT NULL DEFAULT 'exact',
+	action TEXT NOT NULL DEFAULT 'block',
+	created_at BIGINT NOT NULL
+);
+
+CREATE TABLE client_fetches (
+	day BIGINT NOT NULL,
+	client TEXT COLLATE "C" NOT NULL,
+	-- Empty for the default pac
+	profile TEXT COLLATE "C" NOT NULL DEFAULT '',
+	fetches BIGINT NOT NULL,
+	PRIMARY KEY (day, client, profile)
+);
+
+CREATE TABLE audit_log (
+	id BIGSERIAL PRIMARY KEY,
+	at BIGINT NOT NULL,
+	actor TEXT NOT NULL,
+	ip TEXT,
+	action TEXT NOT NULL,
+	-- JSON
+	payload TEXT NOT NULL DEFAULT 'null'
+);
+CREATE INDEX idx_audit_log_at ON audit_log(at);
+
+-- Bumps the conf counter named by the trigger argument, once per changed row
+-- like the sqlite triggers
+CREATE FUNCTION bump_version() RETURNS trigger AS $$
+BEGIN
+	UPDATE conf SET value = (value::BIGINT + 1)::TEXT WHERE key = TG_ARGV[0];
+	RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER white_list_version AFTER INSERT OR UPDATE OR DELETE ON white_list
+	FOR EACH ROW EXECUTE FUNCTION bump_version('hosts_version');
+CREATE TRIGGER host_tags_version AFTER INSERT OR DELETE ON host_tags
+	FOR EACH ROW EXECUTE FUNCTION bump_version('hosts_version');
+CREATE TRIGGER exclusions_version AFTER INSERT OR DELETE ON exclusions
+	FOR EACH ROW EXECUTE FUNCTION bump_version('hosts_version');
+CREATE TRIGGER blocklist_version AFTER INSERT OR UPDATE OR DELETE ON blocklist
+	FOR EACH ROW EXECUTE FUNCTION bump_version('hosts_version');
+
+CREATE TRIGGER proxy_groups_config_version AFTER INSERT OR UPDATE OR DELETE ON proxy_groups
+	FOR EACH ROW EXECUTE FUNCTION bump_version('config_version');
+CREATE TRIGGER upstreams_config_version AFTER INSERT OR UPDATE OR DELETE ON upstreams
+	FOR EACH ROW EXECUTE FUNCTION bump_version('config_version');
+CREATE TRIGGER profiles_config_version AFTER INSERT OR UPDATE OR DELETE ON profiles
+	FOR EACH ROW EXECUTE FUNCTION bump_version('config_version');
+CREATE TRIGGER networks_config_version AFTER INSERT OR UPDATE OR DELETE ON networks
+	FOR EACH ROW EXECUTE FUNCTION bump_version('config_version');
+CREATE TRIGGER ip_ranges_config_version AFTER INSERT OR UPDATE OR DELETE ON ip_ranges
+	FOR EACH ROW EXECUTE FUNCTION bump_version('config_version');
+CREATE TRIGGER conf_config_version AFTER INSERT OR UPDATE ON conf
+	FOR EACH ROW WHEN (NEW.key IN ('proxy', 'mode', 'bypass_private'))
+	EXECUTE FUNCTION bump_version('config_version');
+CREATE TRIGGER conf_delete_config_version AFTER DELETE ON conf
+	FOR EACH ROW WHEN (OLD.key IN ('proxy', 'mode', 'bypass_private'))
+	EXECUTE FUNCTION bump_version('config_version');
@@ -0,0 +1,6 @@
+CREATE OR REPLACE FUNCTION bump_version() RETURNS trigger AS $$
+BEGIN
+	UPDATE conf SET value = (value::BIGINT + 1)::TEXT WHERE key = TG_ARGV[0];
+	RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
@@ -0,0 +1,9 @@
+-- Instances sharing the database LISTEN on qpac_changes, the payload is the
+-- bumped counter. Postgres sends them on commit, once per transaction
+CREATE OR REPLACE FUNCTION bump_version() RETURNS trigger AS $$
+BEGIN
+	UPDATE conf SET value = (value::BIGINT + 1)::TEXT WHERE key = TG_ARGV[0];
+	PERFORM pg_notify('qpac_changes', TG_ARGV[0]);
+	RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
@@ -1,6 +1,17 @@
-use crate::instrument::instrumentation::Instrumentation;
+use crate::{
+    http_client::HttpClientArgs,
+    instrument::instrumentation::Instrumentation,
+    pac::{JsTarget, MatchStrategy, PacMode},
+    rules::EntryKind,
+    storage::StorageKind,
+};
 use clap::{Parser, Subcommand};
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use sqlx::sqlite::SqliteJournalMode;
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    str::FromStr,
+};
 
 #[derive(Debug, Parser)]
 #[command(version, about, long_about = None)]
@@ -8,6 +19,15 @@ pub struct Args {
     #[clap(flatten)]
     pub instrumentation: Instrumentation,
 
+    #[clap(flatten)]
+    pub http_client: HttpClientArgs,
+
+    #[clap(flatten)]
+    pub sqlite: SqliteArgs,
+
+    #[clap(flatten)]
+    pub postgres: PostgresArgs,
+
     #[command(subcommand)]
     pub command: Command,
 }
@@ -15,28 +35,666 @@ pub struct Args {
 #[derive(Debug, Subcommand, Clone)]
 pub enum Command {
     /// Start http server
-    Serve {
-        /// Bind ip address
-        #[arg(short, long, env = "QPAC_BIND", default_value_t = SocketAddr::new(
-            IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 8080)
+    Serve(Box<ServeArgs>),
+
+    /// Apply pending database migrations
+    Migrate(MigrateArgs),
+
+    /// Copy the database into a directory, deleting the oldest copies past
+    /// `--keep`
+    Backup {
+        /// Sqlite connection string, e.g. sqlite://data/qpac.db
+        #[arg(short, long, env = "QPAC_DATABASE")]
+        database: String,
+
+        #[arg(long, env = "QPAC_BACKUP_DIR")]
+        dir: PathBuf,
+
+        #[arg(
+            long,
+            env = "QPAC_BACKUP_KEEP",
+            default_value_t = 7,
+            value_parser = clap::value_parser!(u32).range(1..)
         )]
-        bind: SocketAddr,
+        keep: u32,
+    },
+
+    /// Write hosts, snapshots and profiles as a versioned JSON document
+    ExportAll {
+        /// Sqlite connection string, e.g. sqlite://data/qpac.db
+        #[arg(short, long, env = "QPAC_DATABASE")]
+        database: String,
 
-        /// Argon2 PHC or string token for auth puproses
-        #[arg(short, long, env = "QPAC_TOKEN")]
-        token: Option<String>,
+        /// Output file, stdout when unset
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
 
-        /// Sqlite connection string
-        /// example:
-        ///     sqlite://data/qpac.db
-        ///     sqlite::memory:
+    /// Replace hosts, snapshots and profiles with a document written by `export-all`
+    ImportAll {
+        /// Sqlite connection string, e.g. sqlite://data/qpac.db
         #[arg(short, long, env = "QPAC_DATABASE")]
-        database: Option<String>,
+        database: String,
+
+        /// Input file, stdin when unset
+        input: Option<PathBuf>,
+    },
+
+    /// Copy hosts, settings and stored files from one storage into an empty
+    /// other, checking the counts afterwards
+    MigrateData {
+        /// Source storage, `sqlite://...`, `postgres://...`, `file:<path>` or
+        /// `memory`
+        #[arg(long)]
+        from: String,
+
+        /// Target storage, `sqlite://...`, `postgres://...` or `file:<path>`
+        #[arg(long)]
+        to: String,
     },
 
+    /// Interactively write a server env file, generate an admin token and
+    /// create the database
+    Init(InitArgs),
+
     /// Generate Argon2 PHC token
     Hash { token: String },
 
-    /// Test connection to server
-    Add,
+    /// Add hosts on a running server
+    Add(AddArgs),
+
+    /// Remove hosts on a running server
+    Remove(HostsArgs),
+
+    /// Print hosts of a running server
+    List(ClientArgs),
+
+    /// Print stored files of a running server, newest first: version, hash,
+    /// size in bytes and unix time of the upload
+    Versions(VersionsArgs),
+
+    /// Print hosts of a running server matching a `LIKE` pattern, e.g.
+    /// `%google%`
+    Search(SearchArgs),
+
+    /// Start a throwaway server with in-memory storage on a free local port
+    /// and check adding, serving and removing hosts end-to-end. Exits with 1
+    /// when a step fails
+    Selftest(SelftestArgs),
+}
+
+/// Generation settings taken from the command line rather than storage
+#[derive(Debug, clap::Args, Clone, Copy, Default)]
+pub struct GenerateArgs {
+    /// How the generated pac looks hosts up: `binary` searches a sorted
+    /// array, `object` an object literal, `trie` nested labels, `chunked`
+    /// several small sorted arrays. The latter ones suit large lists
+    #[arg(long, env = "QPAC_MATCH_STRATEGY", default_value = "binary")]
+    pub match_strategy: MatchStrategy,
+
+    /// Also define `FindProxyForURLEx` for clients that prefer it, accept
+    /// bracketed IPv6 literals and treat unique local, link-local and loopback
+    /// IPv6 addresses as private
+    #[arg(long, env = "QPAC_IPV6")]
+    pub ipv6: bool,
+
+    /// Emit the stored IP range rules. The pac then resolves every host no
+    /// name rule matched with `dnsResolve`, which blocks the browser until DNS
+    /// answers, so ranges are ignored without it
+    #[arg(long, env = "QPAC_RESOLVE_IP_RANGES")]
+    pub resolve_ip_ranges: bool,
+
+    /// Language level of the generated pac: `es5` runs on every interpreter,
+    /// `es2015` uses `Set`, `const`, classes and arrow functions
+    #[arg(long, env = "QPAC_JS_TARGET", default_value = "es5")]
+    pub js_target: JsTarget,
+}
+
+/// Applied to every sqlite database opened, by `serve` and the database
+/// subcommands alike
+#[derive(Debug, clap::Args, Clone)]
+pub struct SqliteArgs {
+    /// Key the database is encrypted with, needs a build with the `sqlcipher`
+    /// feature. Existing plaintext databases can't be opened with a key
+    #[arg(long, env = "QPAC_DB_KEY", global = true, hide_env_values = true)]
+    pub db_key: Option<String>,
+
+    /// File holding the database key, e.g. a docker or systemd secret
+    #[arg(
+        long,
+        env = "QPAC_DB_KEY_FILE",
+        global = true,
+        conflicts_with = "db_key"
+    )]
+    pub db_key_file: Option<PathBuf>,
+
+    /// Connections kept open to the database
+    #[arg(
+        long,
+        env = "QPAC_DB_POOL_SIZE",
+        global = true,
+        default_value_t = 10,
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub db_pool_size: u32,
+
+    /// Milliseconds a statement waits for a locked database before failing
+    #[arg(
+        long,
+        env = "QPAC_DB_BUSY_TIMEOUT",
+        global = true,
+        default_value_t = 3000
+    )]
+    pub db_busy_timeout: u64,
+
+    /// `wal`, `delete`, `truncate`, `persist`, `memory` or `off`. Only `wal`
+    /// lets reads run while a write is in progress
+    #[arg(
+        long,
+        env = "QPAC_DB_JOURNAL_MODE",
+        global = true,
+        default_value = "wal"
+    )]
+    pub db_journal_mode: SqliteJournalMode,
+
+    /// Page cache per connection, in pages or in KiB when negative
+    #[arg(
+        long,
+        env = "QPAC_DB_CACHE_SIZE",
+        global = true,
+        default_value_t = 10000,
+        allow_negative_numbers = true
+    )]
+    pub db_cache_size: i64,
+}
+
+impl Default for SqliteArgs {
+    fn default() -> Self {
+        Self {
+            db_key: None,
+            db_key_file: None,
+            db_pool_size: 10,
+            db_busy_timeout: 3000,
+            db_journal_mode: SqliteJournalMode::Wal,
+            db_cache_size: 10000,
+        }
+    }
+}
+
+impl SqliteArgs {
+    /// Key of `--db-key` or the trimmed contents of `--db-key-file`
+    pub fn key(&self) -> std::io::Result<Option<String>> {
+        match (&self.db_key, &self.db_key_file) {
+            (Some(key), _) => Ok(Some(key.clone())),
+            (None, Some(path)) => Ok(Some(std::fs::read_to_string(path)?.trim().to_string())),
+            (None, None) => Ok(None),
+        }
+    }
+}
+
+/// Applied to every postgres database opened, by `serve` and `migrate-data`
+#[derive(Debug, clap::Args, Clone)]
+pub struct PostgresArgs {
+    /// Connections kept open to the database
+    #[arg(
+        long,
+        env = "QPAC_PG_POOL_SIZE",
+        global = true,
+        default_value_t = 10,
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub pg_pool_size: u32,
+
+    /// Milliseconds a statement may run before it's cancelled, unlimited
+    /// when unset
+    #[arg(
+        long,
+        env = "QPAC_PG_STATEMENT_TIMEOUT",
+        global = true,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub pg_statement_timeout: Option<u64>,
+}
+
+impl Default for PostgresArgs {
+    fn default() -> Self {
+        Self {
+            pg_pool_size: 10,
+            pg_statement_timeout: None,
+        }
+    }
+}
+
+/// S3 compatible bucket generated files are mirrored to for a CDN
+#[derive(Debug, clap::Args, Clone, Default)]
+pub struct BucketArgs {
+    /// Bucket generated files are also written to, as `<hash>.pac` and
+    /// `latest.pac`. Credentials and region come from the `AWS_*` variables
+    #[arg(long, env = "QPAC_PAC_BUCKET")]
+    pub pac_bucket: Option<String>,
+
+    /// Endpoint of a non AWS service, e.g. `http://127.0.0.1:9000` for MinIO
+    #[arg(long, env = "QPAC_PAC_BUCKET_ENDPOINT", requires = "pac_bucket")]
+    pub pac_bucket_endpoint: Option<String>,
+
+    /// Prefix of the object keys, e.g. `pac/`
+    #[arg(long, env = "QPAC_PAC_BUCKET_PREFIX", default_value = "")]
+    pub pac_bucket_prefix: String,
+}
+
+/// `NAME` or `NAME=VALUE` of `--tenant` and `--tenant-token`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TenantArg {
+    pub name: String,
+    pub value: Option<String>,
+}
+
+impl FromStr for TenantArg {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, value) = match s.trim().split_once('=') {
+            Some((name, value)) => (name, Some(value.trim().to_string())),
+            None => (s.trim(), None),
+        };
+        if name.is_empty() {
+            return Err("expected NAME or NAME=VALUE".to_string());
+        }
+        Ok(Self {
+            name: name.trim().to_string(),
+            value,
+        })
+    }
+}
+
+#[derive(Debug, clap::Args, Clone)]
+pub struct ServeArgs {
+    /// Bind ip address
+    #[arg(short, long, env = "QPAC_BIND", default_value_t = SocketAddr::new(
+        IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 8080)
+    )]
+    pub bind: SocketAddr,
+
+    /// Use an already bound listening socket passed by a supervisor (s6, runit)
+    #[arg(long, env = "QPAC_BIND_FD", conflicts_with = "bind")]
+    pub bind_fd: Option<i32>,
+
+    /// Disable Nagle's algorithm on accepted connections
+    #[arg(long, env = "QPAC_TCP_NODELAY")]
+    pub tcp_nodelay: bool,
+
+    /// Idle seconds before TCP keep-alive probes are sent, off when unset
+    #[arg(long, env = "QPAC_TCP_KEEPALIVE")]
+    pub tcp_keepalive: Option<u64>,
+
+    /// Seconds between TCP keep-alive probes
+    #[arg(long, env = "QPAC_TCP_KEEPALIVE_INTERVAL", requires = "tcp_keepalive")]
+    pub tcp_keepalive_interval: Option<u64>,
+
+    /// Seconds a connection may sit idle between requests before it's closed,
+    /// 0 disables HTTP/1 keep-alive
+    #[arg(long, env = "QPAC_HTTP1_KEEPALIVE_TIMEOUT")]
+    pub http1_keepalive_timeout: Option<u64>,
+
+    /// Seconds a client gets to send the headers of a request once it started
+    #[arg(long, env = "QPAC_HTTP1_HEADER_READ_TIMEOUT")]
+    pub http1_header_read_timeout: Option<u64>,
+
+    /// Simultaneous connections allowed from one client IP, further ones are
+    /// closed right after accept
+    #[arg(long, env = "QPAC_MAX_CONNECTIONS_PER_IP", value_parser = clap::value_parser!(u32).range(1..))]
+    pub max_connections_per_ip: Option<u32>,
+
+    /// Upper bound in milliseconds for the `X-Request-Deadline-Ms` header
+    /// clients send, requests without the header run unbounded
+    #[arg(long, env = "QPAC_MAX_REQUEST_DEADLINE", default_value_t = 30_000)]
+    pub max_request_deadline: u64,
+
+    /// Proxy chain returned by the generated pac for listed hosts, tried in
+    /// order, e.g. `--proxy "SOCKS5 a:1080" --proxy "PROXY b:3128" --proxy
+    /// DIRECT` or `QPAC_PROXY="SOCKS5 a:1080; DIRECT"`. Stored in the database
+    /// and applied on startup
+    #[arg(long, env = "QPAC_PROXY", value_delimiter = ';')]
+    pub proxy: Vec<String>,
+
+    /// `whitelist` proxies only listed hosts, `blacklist` everything except
+    /// them. Stored in the database and applied on startup
+    #[arg(long, env = "QPAC_MODE")]
+    pub mode: Option<PacMode>,
+
+    /// Send plain host names and private network addresses (RFC 1918,
+    /// loopback, link-local) DIRECT. Stored in the database and applied on
+    /// startup
+    #[arg(long, env = "QPAC_BYPASS_PRIVATE")]
+    pub bypass_private: Option<bool>,
+
+    #[clap(flatten)]
+    pub generate: GenerateArgs,
+
+    /// Argon2 PHC or string token for auth puproses
+    #[arg(short, long, env = "QPAC_TOKEN")]
+    pub token: Option<String>,
+
+    /// Extra isolated host lists served under `/t/<name>/`, each with its own
+    /// pac files and settings, e.g. `--tenant team-a=sqlite://data/team-a.db`
+    /// or `QPAC_TENANTS="a=sqlite://a.db; b=sqlite://b.db"`. The value is a
+    /// postgres connection string with `--storage postgres` and a state file
+    /// with `--storage file`, memory storage takes just the name
+    #[arg(long = "tenant", env = "QPAC_TENANTS", value_delimiter = ';')]
+    pub tenants: Vec<TenantArg>,
+
+    /// Token, plain or Argon2 PHC, that only manages one tenant, e.g.
+    /// `--tenant-token team-a=<token>`. `--token` manages every tenant
+    #[arg(
+        long = "tenant-token",
+        env = "QPAC_TENANT_TOKENS",
+        value_delimiter = ';'
+    )]
+    pub tenant_tokens: Vec<TenantArg>,
+
+    /// Serve mutation endpoints without a token on non-loopback addresses,
+    /// startup is refused otherwise. Has no effect with a token
+    #[arg(long, env = "QPAC_ALLOW_UNAUTHENTICATED")]
+    pub allow_unauthenticated: bool,
+
+    /// Mark admin session cookies `Secure`, for servers behind HTTPS
+    #[arg(long, env = "QPAC_SECURE_COOKIES")]
+    pub secure_cookies: bool,
+
+    /// Where the server keeps its state: `sqlite` in `--database`,
+    /// `postgres` in `--postgres-url`, `file` in `--state-file`, `memory`
+    /// until shutdown. The bucket, maintenance and backup options only apply
+    /// to sqlite
+    #[arg(long, env = "QPAC_STORAGE", default_value = "sqlite")]
+    pub storage: StorageKind,
+
+    /// Sqlite connection string
+    /// example:
+    ///     sqlite://data/qpac.db
+    ///     sqlite::memory:
+    #[arg(short, long, env = "QPAC_DATABASE")]
+    pub database: Option<String>,
+
+    /// Postgres connection string of `--storage postgres`, e.g.
+    /// `postgres://qpac:secret@db:5432/qpac`
+    #[arg(long, env = "QPAC_POSTGRES_URL", hide_env_values = true)]
+    pub postgres_url: Option<String>,
+
+    /// Json file of `--storage file`, created when missing and rewritten on
+    /// every change
+    #[arg(long, env = "QPAC_STATE_FILE")]
+    pub state_file: Option<PathBuf>,
+
+    #[clap(flatten)]
+    pub bucket: BucketArgs,
+
+    /// Refuse to start with pending migrations instead of applying them,
+    /// run `qpac migrate` to apply
+    #[arg(long, env = "QPAC_NO_AUTO_MIGRATE")]
+    pub no_auto_migrate: bool,
+
+    /// Seconds between database maintenance runs (WAL checkpoint, optimize,
+    /// incremental vacuum), off when unset
+    #[arg(
+        long,
+        env = "QPAC_MAINTENANCE_INTERVAL",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub maintenance_interval: Option<u64>,
+
+    /// Directory the database is copied to every `--backup-interval`, off
+    /// when unset
+    #[arg(long, env = "QPAC_BACKUP_DIR")]
+    pub backup_dir: Option<PathBuf>,
+
+    /// Seconds between backups
+    #[arg(
+        long,
+        env = "QPAC_BACKUP_INTERVAL",
+        default_value_t = 24 * 60 * 60,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub backup_interval: u64,
+
+    /// Backups kept in `--backup-dir`, older ones are deleted
+    #[arg(
+        long,
+        env = "QPAC_BACKUP_KEEP",
+        default_value_t = 7,
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub backup_keep: u32,
+
+    /// Share the database with other instances, only the holder of a
+    /// regeneration lease with this many seconds of expiry regenerates files
+    #[arg(
+        long,
+        env = "QPAC_REGENERATION_LEASE",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub regeneration_lease: Option<u64>,
+
+    /// Seconds between checks for host and settings changes made by other
+    /// instances sharing the database, caches are refreshed when one is found.
+    /// Postgres pushes changes without it
+    #[arg(
+        long,
+        env = "QPAC_CHANGE_POLL_INTERVAL",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub change_poll_interval: Option<u64>,
+
+    /// Hosts file loaded on startup when the list is empty, one host per line,
+    /// `#` starts a comment
+    #[arg(long, env = "QPAC_SEED_FILE")]
+    pub seed_file: Option<PathBuf>,
+
+    /// Mirror the hosts and settings of another qpac, e.g.
+    /// `https://qpac.example.com` or a tenant of it. Local changes are
+    /// overwritten once the primary changes
+    #[arg(long, env = "QPAC_REPLICATE_FROM")]
+    pub replicate_from: Option<String>,
+
+    /// Admin token of the primary, it only serves its state to admins
+    #[arg(long, env = "QPAC_REPLICATE_TOKEN", requires = "replicate_from")]
+    pub replicate_token: Option<String>,
+
+    /// Seconds between polls of the primary
+    #[arg(
+        long,
+        env = "QPAC_REPLICATE_INTERVAL",
+        default_value_t = 30,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub replicate_interval: u64,
+
+    /// Expose prometheus metrics on `/metrics`
+    #[arg(long, env = "QPAC_METRICS")]
+    pub metrics: bool,
+
+    /// Warn when more than this many hosts change within the alert window
+    #[arg(long, env = "QPAC_CHANGE_ALERT_THRESHOLD")]
+    pub change_alert_threshold: Option<usize>,
+
+    /// POST a JSON alert to this URL when the change threshold is crossed
+    #[arg(
+        long,
+        env = "QPAC_CHANGE_ALERT_WEBHOOK",
+        requires = "change_alert_threshold"
+    )]
+    pub change_alert_webhook: Option<String>,
+
+    /// Alert window in seconds
+    #[arg(long, env = "QPAC_CHANGE_ALERT_WINDOW", default_value_t = 60)]
+    pub change_alert_window: u64,
+
+    /// Count pac fetches per day by client network (/24 or /48) and profile,
+    /// served on `/stats/clients`
+    #[arg(long, env = "QPAC_CLIENT_STATS")]
+    pub client_stats: bool,
+
+    /// Days client stats are kept for
+    #[arg(
+        long,
+        env = "QPAC_CLIENT_STATS_RETENTION",
+        default_value_t = 90,
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub client_stats_retention: u32,
+
+    /// Stored files kept besides the latest and staged ones, older files are
+    /// deleted hourly unless `--pac-retention-days` keeps them. Rolling back
+    /// to a deleted version is no longer possible
+    #[arg(
+        long,
+        env = "QPAC_PAC_RETENTION_KEEP",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub pac_retention_keep: Option<u32>,
+
+    /// Days stored files are kept for, older files are deleted hourly unless
+    /// `--pac-retention-keep` keeps them
+    #[arg(
+        long,
+        env = "QPAC_PAC_RETENTION_DAYS",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub pac_retention_days: Option<u32>,
+
+    /// Days removed hosts can be restored for before they are forgotten
+    #[arg(
+        long,
+        env = "QPAC_DELETED_RETENTION",
+        default_value_t = 30,
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub deleted_retention: u32,
+
+    /// Days audit log entries are kept for
+    #[arg(
+        long,
+        env = "QPAC_AUDIT_RETENTION",
+        default_value_t = 90,
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub audit_retention: u32,
+
+    /// Polling interval in seconds recommended to clients on `/poll-hint`
+    #[arg(long, env = "QPAC_POLL_INTERVAL", default_value_t = 300)]
+    pub poll_interval: u64,
+
+    /// Warn when a generated pac is larger than this many bytes, some clients
+    /// (WinHTTP) refuse files over 1 MiB
+    #[arg(long, env = "QPAC_MAX_PAC_SIZE")]
+    pub max_pac_size: Option<usize>,
+
+    /// Keep serving the current pac instead of publishing one over
+    /// `--max-pac-size`, profile pacs over it fail to serve
+    #[arg(long, env = "QPAC_REFUSE_OVERSIZED_PAC", requires = "max_pac_size")]
+    pub refuse_oversized_pac: bool,
+
+    /// Re-check stored files against their checksum when serving `/:hash`
+    #[arg(long, env = "QPAC_VERIFY_CONTENT")]
+    pub verify_content: bool,
+}
+
+#[derive(Debug, clap::Args, Clone)]
+pub struct InitArgs {
+    /// Env file with the server settings
+    #[arg(short, long, default_value = "qpac.env")]
+    pub output: PathBuf,
+
+    /// Replace an existing env file
+    #[arg(long)]
+    pub force: bool,
+
+    /// Hosts file to seed the database with, one host per line, `#` starts a
+    /// comment. Asked for when unset
+    #[arg(long)]
+    pub seed: Option<PathBuf>,
+
+    /// Accept every default instead of prompting
+    #[arg(short, long)]
+    pub yes: bool,
+}
+
+#[derive(Debug, clap::Args, Clone)]
+pub struct SelftestArgs {
+    /// Seconds each step may take, regeneration included
+    #[arg(long, default_value_t = 10)]
+    pub timeout: u64,
+
+    /// Show the output of the throwaway server
+    #[arg(long)]
+    pub server_output: bool,
+}
+
+#[derive(Debug, clap::Args, Clone)]
+pub struct MigrateArgs {
+    /// Sqlite or postgres connection string, e.g. sqlite://data/qpac.db or
+    /// postgres://db/qpac
+    #[arg(short, long, env = "QPAC_DATABASE")]
+    pub database: String,
+
+    /// Only compare the compiled-in migrations with the database, exits with
+    /// 1 when they differ
+    #[arg(long)]
+    pub check: bool,
+}
+
+/// Connection to a running server for client commands
+///
+/// Exit codes: 0 success, 1 unexpected error, 2 validation error, 3 auth
+/// failure, 4 not found, 5 server error, 6 connection error
+#[derive(Debug, clap::Args, Clone)]
+pub struct ClientArgs {
+    /// Server base url
+    #[arg(
+        short,
+        long,
+        env = "QPAC_SERVER",
+        default_value = "http://127.0.0.1:8080"
+    )]
+    pub server: String,
+
+    /// Plain auth token sent as a bearer token
+    #[arg(short, long, env = "QPAC_CLIENT_TOKEN")]
+    pub token: Option<String>,
+}
+
+#[derive(Debug, clap::Args, Clone)]
+pub struct HostsArgs {
+    #[clap(flatten)]
+    pub client: ClientArgs,
+
+    #[arg(required = true)]
+    pub hosts: Vec<String>,
+}
+
+#[derive(Debug, clap::Args, Clone)]
+pub struct VersionsArgs {
+    #[clap(flatten)]
+    pub client: ClientArgs,
+
+    #[arg(short, long, default_value_t = 50)]
+    pub limit: u32,
+}
+
+#[derive(Debug, clap::Args, Clone)]
+pub struct SearchArgs {
+    #[clap(flatten)]
+    pub client: ClientArgs,
+
+    /// `%` matches any run of characters, `_` a single one
+    pub pattern: String,
+}
+
+#[derive(Debug, clap::Args, Clone)]
+pub struct AddArgs {
+    #[clap(flatten)]
+    pub hosts: HostsArgs,
+
+    /// `exact` matches just the hosts, `suffix` their subdomains as well
+    #[arg(long, default_value = "exact")]
+    pub kind: EntryKind,
 }
@@ -0,0 +1,72 @@
+use crate::{
+    args::{AddArgs, ClientArgs, HostsArgs, SearchArgs, VersionsArgs},
+    client::{Client, ClientError, HostResult},
+    http_client::HttpClient,
+};
+
+pub async fn add(http: &HttpClient, args: AddArgs) -> Result<(), ClientError> {
+    let client = Client::from_args(http.clone(), &args.hosts.client)?;
+    report(
+        client
+            .add_hosts_of_kind(&args.hosts.hosts, args.kind)
+            .await?,
+    )
+}
+
+pub async fn remove(http: &HttpClient, args: HostsArgs) -> Result<(), ClientError> {
+    let client = Client::from_args(http.clone(), &args.client)?;
+    report(client.remove_hosts(&args.hosts).await?)
+}
+
+pub async fn list(http: &HttpClient, args: ClientArgs) -> Result<(), ClientError> {
+    let client = Client::from_args(http.clone(), &args)?;
+    for host in client.list().await? {
+        println!("{host}");
+    }
+    Ok(())
+}
+
+pub async fn search(http: &HttpClient, args: SearchArgs) -> Result<(), ClientError> {
+    let client = Client::from_args(http.clone(), &args.client)?;
+    for host in client.search(&args.pattern).await? {
+        println!("{host}");
+    }
+    Ok(())
+}
+
+/// One stored file per line, newest first, the latest and staged ones marked
+pub async fn versions(http: &HttpClient, args: VersionsArgs) -> Result<(), ClientError> {
+    let client = Client::from_args(http.clone(), &args.client)?;
+    for v in client.versions(args.limit).await? {
+        let created = v.created_at.map_or("-".to_string(), |t| t.to_string());
+        let mark = match (v.latest, v.staged) {
+            (true, _) => " latest",
+            (_, true) => " staged",
+            _ => "",
+        };
+        println!("{} {} {} {created}{mark}", v.version, v.hash, v.size);
+    }
+    Ok(())
+}
+
+/// Prints failed hosts, fails with not found only when every failure is one
+fn report(results: Vec<HostResult>) -> Result<(), ClientError> {
+    let failed: Vec<&HostResult> = results.iter().filter(|r| !r.success).collect();
+    for r in failed.iter() {
+        eprintln!("{}: {}", r.host, r.error.as_deref().unwrap_or("failed"));
+    }
+    if failed.is_empty() {
+        return Ok(());
+    }
+    let message = format!("{} of {} hosts failed", failed.len(), results.len());
+    let all_missing = failed.iter().all(|r| {
+        r.error
+            .as_deref()
+            .is_some_and(|e| e.starts_with("NotFound"))
+    });
+    if all_missing {
+        Err(ClientError::NotFound(message))
+    } else {
+        Err(ClientError::Validation(message))
+    }
+}
@@ -0,0 +1,354 @@
+use std::time::Duration;
+
+use futures::Stream;
+use reqwest::{RequestBuilder, StatusCode, Url};
+use serde::Deserialize;
+use serde_json::json;
+use thiserror::Error;
+
+use crate::{args::ClientArgs, http_client::HttpClient, rules::EntryKind, storage::InstanceState};
+
+/// Request failure, each kind maps to a documented exit code of the client
+/// subcommands
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum ClientError {
+    #[error("{0}")]
+    Validation(String),
+
+    #[error("Unauthorized: {0}")]
+    Auth(String),
+
+    #[error("Not found: {0}")]
+    NotFound(String),
+
+    #[error("Server error: {0}")]
+    Server(String),
+
+    #[error("Connection error: {0}")]
+    Connection(String),
+}
+
+impl ClientError {
+    /// 0 success, 1 unexpected, 2 validation, 3 auth, 4 not found, 5 server, 6 connection
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ClientError::Validation(_) => 2,
+            ClientError::Auth(_) => 3,
+            ClientError::NotFound(_) => 4,
+            ClientError::Server(_) => 5,
+            ClientError::Connection(_) => 6,
+        }
+    }
+
+    fn from_status(status: StatusCode, body: String) -> Self {
+        match status {
+            StatusCode::BAD_REQUEST | StatusCode::CONFLICT | StatusCode::UNPROCESSABLE_ENTITY => {
+                ClientError::Validation(body)
+            }
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => ClientError::Auth(body),
+            StatusCode::NOT_FOUND => ClientError::NotFound(body),
+            _ => ClientError::Server(format!("{status}: {body}")),
+        }
+    }
+}
+
+impl From<reqwest::Error> for ClientError {
+    fn from(value: reqwest::Error) -> Self {
+        if value.is_connect() || value.is_timeout() || value.is_request() {
+            ClientError::Connection(value.to_string())
+        } else {
+            ClientError::Server(value.to_string())
+        }
+    }
+}
+
+/// Outcome of one host of a batch request
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct HostResult {
+    pub host: String,
+    pub success: bool,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BatchResponse {
+    results: Vec<HostResult>,
+}
+
+/// Stored file as listed by [`Client::versions`]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct VersionInfo {
+    /// `v12` style label
+    pub version: String,
+    pub hash: String,
+    pub size: i64,
+    pub created_at: Option<i64>,
+    pub latest: bool,
+    pub staged: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct PollHint {
+    interval_secs: u64,
+    hash: Option<String>,
+}
+
+/// Change seen by [`Client::subscribe_events`]
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum Event {
+    /// Latest pac has a new hash, also sent for the hash current when subscribing
+    PacChanged { hash: String },
+}
+
+/// Typed access to a running qpac server
+#[derive(Debug, Clone)]
+pub struct Client {
+    http: HttpClient,
+    base: Url,
+    token: Option<String>,
+}
+
+impl Client {
+    /// `server` is the base url, `token` the plain admin token
+    pub fn new(http: HttpClient, server: &str, token: Option<String>) -> Result<Self, ClientError> {
+        let base = format!("{}/", server.trim_end_matches('/'));
+        let base = Url::parse(&base)
+            .map_err(|e| ClientError::Validation(format!("invalid server url: {e}")))?;
+        Ok(Self { http, base, token })
+    }
+
+    pub fn from_args(http: HttpClient, args: &ClientArgs) -> Result<Self, ClientError> {
+        Self::new(http, &args.server, args.token.clone())
+    }
+
+    /// Fails as a whole when the host is rejected
+    pub async fn add_host(&self, host: &str) -> Result<(), ClientError> {
+        self.post("add", json!({ "host": host })).await.map(|_| ())
+    }
+
+    /// Result of every host, the request itself only fails as a whole on
+    /// auth, connection or server errors
+    pub async fn add_hosts(&self, hosts: &[String]) -> Result<Vec<HostResult>, ClientError> {
+        self.add_hosts_of_kind(hosts, EntryKind::Exact).await
+    }
+
+    /// Same as [`Client::add_hosts`], suffix hosts match their subdomains too
+    pub async fn add_hosts_of_kind(
+        &self,
+        hosts: &[String],
+        kind: EntryKind,
+    ) -> Result<Vec<HostResult>, ClientError> {
+        self.post_batch("add", json!({ "hosts": hosts, "kind": kind }))
+            .await
+    }
+
+    pub async fn remove_host(&self, host: &str) -> Result<(), ClientError> {
+        self.post("remove", json!({ "host": host }))
+            .await
+            .map(|_| ())
+    }
+
+    /// Pinned hosts are reported as failed
+    pub async fn remove_hosts(&self, hosts: &[String]) -> Result<Vec<HostResult>, ClientError> {
+        self.post_batch("remove", json!({ "hosts": hosts })).await
+    }
+
+    pub async fn list(&self) -> Result<Vec<String>, ClientError> {
+        let url = self.endpoint("list")?;
+        let res = self.http.send(|c| self.prepare(c.get(url.clone()))).await?;
+        Ok(check_status(res).await?.json().await?)
+    }
+
+    /// Up to `limit` stored files, newest first
+    pub async fn versions(&self, limit: u32) -> Result<Vec<VersionInfo>, ClientError> {
+        let mut url = self.endpoint("versions")?;
+        url.query_pairs_mut()
+            .append_pair("limit", &limit.to_string());
+        let res = self.http.send(|c| self.prepare(c.get(url.clone()))).await?;
+        Ok(check_status(res).await?.json().await?)
+    }
+
+    /// Hosts matching a `LIKE` pattern such as `%google%`
+    pub async fn search(&self, pattern: &str) -> Result<Vec<String>, ClientError> {
+        let mut url = self.endpoint("search")?;
+        url.query_pairs_mut().append_pair("q", pattern);
+        let res = self.http.send(|c| self.prepare(c.get(url.clone()))).await?;
+        Ok(check_status(res).await?.json().await?)
+    }
+
+    /// Hash of the latest pac, `None` until one has been generated
+    pub async fn latest_hash(&self) -> Result<Option<String>, ClientError> {
+        Ok(self.poll_hint().await?.hash)
+    }
+
+    /// Polls the server for pac changes, at the interval it recommends when
+    /// `every` is unset. Errors are yielded and polling goes on
+    pub fn subscribe_events(
+        &self,
+        every: Option<Duration>,
+    ) -> impl Stream<Item = Result<Event, ClientError>> + '_ {
+        futures::stream::unfold(
+            (None::<String>, None::<Duration>),
+            move |(mut last, mut wait)| async move {
+                loop {
+                    if let Some(wait) = wait {
+                        tokio::time::sleep(wait).await;
+                    }
+                    let hint = match self.poll_hint().await {
+                        Ok(hint) => hint,
+                        Err(e) => {
+                            let wait = every.unwrap_or(DEFAULT_POLL_INTERVAL);
+                            return Some((Err(e), (last, Some(wait))));
+                        }
+                    };
+                    wait = Some(every.unwrap_or(Duration::from_secs(hint.interval_secs.max(1))));
+                    if let Some(hash) = hint.hash.filter(|h| last.as_ref() != Some(h)) {
+                        last = Some(hash.clone());
+                        return Some((Ok(Event::PacChanged { hash }), (last, wait)));
+                    }
+                }
+            },
+        )
+    }
+
+    async fn poll_hint(&self) -> Result<PollHint, ClientError> {
+        let url = self.endpoint("poll-hint")?;
+        let res = self.http.send(|c| self.prepare(c.get(url.clone()))).await?;
+        Ok(check_status(res).await?.json().await?)
+    }
+
+    /// Hosts and settings of the server, needs the admin token
+    pub async fn state(&self) -> Result<InstanceState, ClientError> {
+        let url = self.endpoint("state")?;
+        let res = self.http.send(|c| self.prepare(c.get(url.clone()))).await?;
+        Ok(check_status(res).await?.json().await?)
+    }
+
+    /// Replaces the hosts and settings of the server with `state`
+    pub async fn push_state(&self, state: &InstanceState) -> Result<(), ClientError> {
+        let url = self.endpoint("state")?;
+        let res = self
+            .http
+            .send(|c| self.prepare(c.put(url.clone())).json(state))
+            .await?;
+        check_status(res).await.map(|_| ())
+    }
+
+    async fn post(
+        &self,
+        path: &str,
+        body: serde_json::Value,
+    ) -> Result<reqwest::Response, ClientError> {
+        let url = self.endpoint(path)?;
+        let res = self
+            .http
+            .send(|c| self.prepare(c.post(url.clone())).json(&body))
+            .await?;
+        check_status(res).await
+    }
+
+    async fn post_batch(
+        &self,
+        path: &str,
+        body: serde_json::Value,
+    ) -> Result<Vec<HostResult>, ClientError> {
+        let res = self.post(path, body).await?;
+        let batch: BatchResponse = res.json().await?;
+        Ok(batch.results)
+    }
+
+    fn endpoint(&self, path: &str) -> Result<Url, ClientError> {
+        self.base
+            .join(path)
+            .map_err(|e| ClientError::Validation(format!("invalid path {path}: {e}")))
+    }
+
+    /// Adds the token and lets the server stop working on requests this
+    /// client stopped waiting for
+    fn prepare(&self, builder: RequestBuilder) -> RequestBuilder {
+        let builder = builder.header(
+            "X-Request-Deadline-Ms",
+            self.http.timeout().as_millis().to_string(),
+        );
+        match &self.token {
+            Some(token) => builder.bearer_auth(token),
+            None => builder,
+        }
+    }
+}
+
+/// Used when the server can't be asked for its recommended interval
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+async fn check_status(res: reqwest::Response) -> Result<reqwest::Response, ClientError> {
+    let status = res.status();
+    if status.is_success() {
+        return Ok(res);
+    }
+    let body = res.text().await.unwrap_or_default();
+    Err(ClientError::from_status(status, body))
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use axum::{routing::get, Json, Router};
+    use futures::StreamExt;
+
+    use super::*;
+    use crate::http_client::HttpClientArgs;
+
+    #[test]
+    fn maps_statuses_to_exit_codes() {
+        let code = |status| ClientError::from_status(status, String::new()).exit_code();
+        assert_eq!(code(StatusCode::UNPROCESSABLE_ENTITY), 2);
+        assert_eq!(code(StatusCode::BAD_REQUEST), 2);
+        assert_eq!(code(StatusCode::UNAUTHORIZED), 3);
+        assert_eq!(code(StatusCode::NOT_FOUND), 4);
+        assert_eq!(code(StatusCode::SERVICE_UNAVAILABLE), 5);
+        assert_eq!(ClientError::Connection(String::new()).exit_code(), 6);
+    }
+
+    #[tokio::test]
+    async fn subscribes_to_hash_changes() {
+        let polls = Arc::new(AtomicUsize::new(0));
+        let app = Router::new().route(
+            "/poll-hint",
+            get(move || async move {
+                let hash = match polls.fetch_add(1, Ordering::SeqCst) {
+                    0 => None,
+                    1 | 2 => Some("a"),
+                    _ => Some("b"),
+                };
+                Json(json!({ "interval_secs": 60, "hash": hash }))
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let http = HttpClient::new(&HttpClientArgs {
+            client_timeout: 5,
+            ..Default::default()
+        })
+        .unwrap();
+        let client = Client::new(http, &format!("http://{addr}"), None).unwrap();
+        let events: Vec<_> = client
+            .subscribe_events(Some(Duration::from_millis(1)))
+            .take(2)
+            .collect()
+            .await;
+        assert_eq!(
+            events,
+            vec![
+                Ok(Event::PacChanged { hash: "a".into() }),
+                Ok(Event::PacChanged { hash: "b".into() }),
+            ]
+        );
+    }
+}
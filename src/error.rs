@@ -1,10 +1,14 @@
 use axum::{
-    http::{Response, StatusCode},
+    http::{header, Response, StatusCode},
     response::IntoResponse,
 };
 use std::error::Error;
 use thiserror::Error;
 
+use crate::{
+    host::HostError, instrument::metrics::DB_BUSY_TIMEOUTS, web::revision::REVISION_HEADER,
+};
+
 pub type Result<T, E = Report> = color_eyre::Result<T, E>;
 pub struct Report(color_eyre::Report);
 
@@ -47,18 +51,67 @@ pub enum AppError {
     #[error("NotFound")]
     NotFound,
 
+    #[error("InvalidHost: {0}")]
+    InvalidHost(#[from] HostError),
+
+    #[error("Conflict: {0}")]
+    Conflict(String),
+
+    #[error("Validation: {field}: {message}")]
+    Validation { field: String, message: String },
+
+    #[error("Unavailable: {0}")]
+    Unavailable(String),
+
+    #[error("StaleRevision: list changed since revision {expected}, it is at {current}")]
+    StaleRevision { expected: i64, current: i64 },
+
+    #[error("Timeout: {0}")]
+    Timeout(String),
+
     #[error("Internal error: {0}")]
     Other(String),
 }
 
+/// Seconds a client should wait before retrying while storage is unavailable
+pub const RETRY_AFTER_SECS: u64 = 5;
+
 impl IntoResponse for AppError {
     fn into_response(self) -> axum::response::Response {
+        let status = self.status_code();
+        if status == StatusCode::SERVICE_UNAVAILABLE {
+            return (
+                status,
+                [(header::RETRY_AFTER, RETRY_AFTER_SECS.to_string())],
+                self.to_string(),
+            )
+                .into_response();
+        }
+        if let AppError::StaleRevision { current, .. } = self {
+            return (
+                status,
+                [(REVISION_HEADER, current.to_string())],
+                self.to_string(),
+            )
+                .into_response();
+        }
+        (status, self.to_string()).into_response()
+    }
+}
+
+impl AppError {
+    pub fn status_code(&self) -> StatusCode {
         match self {
-            AppError::PreconditionFailed(_) => {
-                (StatusCode::BAD_REQUEST, self.to_string()).into_response()
+            AppError::PreconditionFailed(_) => StatusCode::BAD_REQUEST,
+            AppError::NotFound => StatusCode::NOT_FOUND,
+            AppError::InvalidHost(_) | AppError::Validation { .. } => {
+                StatusCode::UNPROCESSABLE_ENTITY
             }
-            AppError::NotFound => (StatusCode::NOT_FOUND, self.to_string()).into_response(),
-            _ => (StatusCode::INTERNAL_SERVER_ERROR, self.to_string()).into_response(),
+            AppError::Conflict(_) => StatusCode::CONFLICT,
+            AppError::StaleRevision { .. } => StatusCode::PRECONDITION_FAILED,
+            // Both pass once storage catches up, clients get a Retry-After
+            AppError::Unavailable(_) | AppError::Timeout(_) => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::Other(_) => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
 }
@@ -77,9 +130,85 @@ impl From<Box<dyn Error + Send + Sync>> for AppError {
 
 impl From<sqlx::Error> for AppError {
     fn from(value: sqlx::Error) -> Self {
+        if is_busy(&value) {
+            metrics::counter!(DB_BUSY_TIMEOUTS).increment(1);
+        }
         match value {
             sqlx::Error::RowNotFound => Self::NotFound,
+            sqlx::Error::Database(e) if e.is_unique_violation() => Self::Conflict(e.to_string()),
+            sqlx::Error::PoolTimedOut => Self::Timeout("Database pool timed out".to_string()),
+            ref v if is_busy(v) => Self::Unavailable(v.to_string()),
+            v @ (sqlx::Error::Io(_) | sqlx::Error::PoolClosed | sqlx::Error::WorkerCrashed) => {
+                Self::Unavailable(v.to_string())
+            }
             v => Self::Other(v.to_string()),
         }
     }
 }
+
+impl From<object_store::Error> for AppError {
+    fn from(value: object_store::Error) -> Self {
+        match value {
+            object_store::Error::NotFound { .. } => Self::NotFound,
+            v => Self::Unavailable(v.to_string()),
+        }
+    }
+}
+
+/// SQLITE_BUSY/SQLITE_LOCKED (after `busy_timeout`) or an exhausted pool
+fn is_busy(err: &sqlx::Error) -> bool {
+    match err {
+        sqlx::Error::PoolTimedOut => true,
+        sqlx::Error::Database(e) => matches!(e.code().as_deref(), Some("5" | "6")),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn maps_sqlx_errors() {
+        assert_eq!(AppError::from(sqlx::Error::RowNotFound), AppError::NotFound);
+        assert_eq!(
+            AppError::from(sqlx::Error::PoolTimedOut).status_code(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert_eq!(
+            AppError::from(sqlx::Error::PoolClosed).status_code(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+    }
+
+    #[test]
+    fn unavailable_sets_retry_after() {
+        for err in [
+            AppError::Unavailable("down".to_string()),
+            AppError::Timeout("slow".to_string()),
+        ] {
+            let res = err.into_response();
+            assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+            assert_eq!(
+                res.headers().get(header::RETRY_AFTER).unwrap(),
+                &RETRY_AFTER_SECS.to_string()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn maps_unique_violation_to_conflict() -> Result<()> {
+        let pool = sqlx::SqlitePool::connect("sqlite::memory:").await?;
+        sqlx::query("CREATE TABLE t (v TEXT NOT NULL UNIQUE); INSERT INTO t VALUES ('a');")
+            .execute(&pool)
+            .await?;
+        let err = sqlx::query("INSERT INTO t VALUES ('a')")
+            .execute(&pool)
+            .await
+            .unwrap_err();
+        let err = AppError::from(err);
+        assert!(matches!(err, AppError::Conflict(_)), "{err:?}");
+        assert_eq!(err.status_code(), StatusCode::CONFLICT);
+        Ok(())
+    }
+}
@@ -0,0 +1,87 @@
+use std::time::Duration;
+
+use reqwest::{Client, Proxy, RequestBuilder, Response};
+use tracing::{debug, warn};
+
+use crate::error::Result;
+
+#[derive(clap::Args, Debug, Default, Clone)]
+pub struct HttpClientArgs {
+    /// Proxy for outbound requests, e.g. socks5h://127.0.0.1:1080
+    ///
+    /// HTTPS_PROXY, HTTP_PROXY and ALL_PROXY are honored when not set
+    #[arg(long, env = "QPAC_CLIENT_PROXY", global = true)]
+    pub client_proxy: Option<String>,
+
+    /// Outbound request timeout in seconds
+    #[arg(long, env = "QPAC_CLIENT_TIMEOUT", default_value_t = 10, global = true)]
+    pub client_timeout: u64,
+
+    /// Retries for failed outbound requests (connection errors, timeouts, 5xx)
+    #[arg(long, env = "QPAC_CLIENT_RETRIES", default_value_t = 2, global = true)]
+    pub client_retries: u32,
+}
+
+/// Shared outbound HTTP client, cheap to clone
+#[derive(Debug, Clone)]
+pub struct HttpClient {
+    client: Client,
+    retries: u32,
+    timeout: Duration,
+}
+
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(200);
+
+impl HttpClient {
+    pub fn new(args: &HttpClientArgs) -> Result<Self> {
+        let mut builder = Client::builder()
+            .user_agent(concat!(
+                env!("CARGO_PKG_NAME"),
+                "/",
+                env!("CARGO_PKG_VERSION")
+            ))
+            .timeout(Duration::from_secs(args.client_timeout));
+        if let Some(proxy) = &args.client_proxy {
+            builder = builder.proxy(Proxy::all(proxy)?);
+        }
+        Ok(Self {
+            client: builder.build()?,
+            retries: args.client_retries,
+            timeout: Duration::from_secs(args.client_timeout),
+        })
+    }
+
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// Per request timeout, sent to qpac servers as the request deadline
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Sends the request built by `request`, retrying with exponential backoff
+    pub async fn send(
+        &self,
+        request: impl Fn(&Client) -> RequestBuilder,
+    ) -> Result<Response, reqwest::Error> {
+        let mut attempt = 0;
+        loop {
+            let res = request(&self.client).send().await;
+            let retryable = match &res {
+                Ok(r) => r.status().is_server_error(),
+                Err(e) => e.is_connect() || e.is_timeout(),
+            };
+            if !retryable || attempt >= self.retries {
+                return res;
+            }
+            let delay = RETRY_BASE_DELAY * 2u32.pow(attempt);
+            match &res {
+                Ok(r) => debug!("Got {}, retrying in {delay:?}", r.status()),
+                Err(e) => warn!("Request failed: {e}, retrying in {delay:?}"),
+            }
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+}
@@ -0,0 +1,212 @@
+use std::{
+    io::{self, BufRead, Write},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::Path,
+};
+
+use crate::{
+    args::{InitArgs, SqliteArgs},
+    error::Result,
+    host,
+    pac::Pac,
+    rules::RuleSet,
+    storage::{sqlite_storage::SqliteStorage, HostEntry, ImportMode, Storage},
+    utils,
+};
+
+const DEFAULT_BIND: &str = "0.0.0.0:8080";
+const DEFAULT_DATABASE: &str = "sqlite://data/qpac.db";
+
+/// Settings asked for by `qpac init`
+#[derive(Debug, Clone, PartialEq)]
+struct Setup {
+    bind: SocketAddr,
+    database: String,
+    /// Argon2 PHC of the admin token
+    token_hash: String,
+}
+
+/// First run setup: writes the env file, creates the database and seeds it
+pub async fn run(args: InitArgs, sqlite: &SqliteArgs) -> Result<()> {
+    if args.output.exists() && !args.force {
+        return Err(color_eyre::eyre::eyre!(
+            "{} already exists, pass --force to replace it",
+            args.output.display()
+        )
+        .into());
+    }
+    let mut prompt = Prompt::new(args.yes);
+
+    let bind = loop {
+        match prompt.ask("Bind address", DEFAULT_BIND)?.parse() {
+            Ok(bind) => break bind,
+            Err(e) if !args.yes => eprintln!("Invalid address: {e}"),
+            Err(e) => return Err(color_eyre::eyre::eyre!("Invalid address: {e}").into()),
+        }
+    };
+    let database = prompt.ask("Database", DEFAULT_DATABASE)?;
+    let seed = match args.seed {
+        Some(path) => Some(path),
+        None => Some(prompt.ask("Hosts file to seed from, empty to skip", "")?)
+            .filter(|p| !p.is_empty())
+            .map(Into::into),
+    };
+
+    let token = utils::token::generate();
+    let setup = Setup {
+        bind,
+        database,
+        token_hash: utils::token::hash(token.as_bytes()),
+    };
+
+    create_database_dir(&setup.database)?;
+    let storage = SqliteStorage::new_with(&setup.database, sqlite).await?;
+    println!("Database {} is ready", setup.database);
+    if let Some(path) = seed {
+        let (hosts, errors) = parse_hosts(&std::fs::read_to_string(&path)?);
+        for error in errors.iter() {
+            eprintln!("Skipping {error}");
+        }
+        let diff = storage
+            .import_hosts(hosts, ImportMode::Merge, false)
+            .await?;
+        let entries = storage.host_entries().await?;
+        let pac = Pac::generate(RuleSet::new(entries.iter().map(HostEntry::rule)).into_patterns());
+        storage.upload_file(&pac).await?;
+        storage.set_latest(&pac.hash).await?;
+        println!("Seeded {} hosts from {}", diff.added.len(), path.display());
+    }
+
+    write_env_file(&args.output, &render_env(&setup))?;
+    println!("Wrote {}", args.output.display());
+    println!();
+    println!("Admin token, shown only once:");
+    println!("  {token}");
+    println!();
+    println!("Client profile:");
+    println!("  QPAC_SERVER=http://{}", client_addr(setup.bind));
+    println!("  QPAC_CLIENT_TOKEN={token}");
+    Ok(())
+}
+
+/// Reads answers from stdin, or takes every default when `yes` is set
+struct Prompt {
+    yes: bool,
+    stdin: io::StdinLock<'static>,
+}
+
+impl Prompt {
+    fn new(yes: bool) -> Self {
+        Self {
+            yes,
+            stdin: io::stdin().lock(),
+        }
+    }
+
+    fn ask(&mut self, question: &str, default: &str) -> io::Result<String> {
+        if self.yes {
+            return Ok(default.to_string());
+        }
+        match default {
+            "" => print!("{question}: "),
+            _ => print!("{question} [{default}]: "),
+        }
+        io::stdout().flush()?;
+        let mut answer = String::new();
+        self.stdin.read_line(&mut answer)?;
+        let answer = answer.trim();
+        Ok(match answer {
+            "" => default.to_string(),
+            _ => answer.to_string(),
+        })
+    }
+}
+
+/// Normalized hosts of a seed file and a message for every line skipped
+pub fn parse_hosts(text: &str) -> (Vec<String>, Vec<String>) {
+    let mut hosts = vec![];
+    let mut errors = vec![];
+    for (i, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        match host::normalize(line) {
+            Ok(host) => hosts.push(host),
+            Err(e) => errors.push(format!("line {}: {line}: {e}", i + 1)),
+        }
+    }
+    (hosts, errors)
+}
+
+/// Env file for systemd `EnvironmentFile` or `docker --env-file`, values are
+/// unquoted so the token hash has to be quoted when sourcing it from a shell
+fn render_env(setup: &Setup) -> String {
+    format!(
+        "# Written by `qpac init`\n\
+         QPAC_BIND={}\n\
+         QPAC_DATABASE={}\n\
+         QPAC_TOKEN={}\n",
+        setup.bind, setup.database, setup.token_hash
+    )
+}
+
+/// Sqlite creates the file but not the directories leading to it
+fn create_database_dir(url: &str) -> io::Result<()> {
+    let Some(path) = url.strip_prefix("sqlite://") else {
+        return Ok(());
+    };
+    let path = path.split('?').next().unwrap_or_default();
+    match Path::new(path).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => std::fs::create_dir_all(dir),
+        _ => Ok(()),
+    }
+}
+
+/// The token hash isn't the token, the file is still kept private
+fn write_env_file(path: &Path, contents: &str) -> io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(contents.as_bytes())
+}
+
+/// Address clients on this machine reach a server bound to `bind` with
+fn client_addr(bind: SocketAddr) -> SocketAddr {
+    match bind.ip() {
+        ip if ip.is_unspecified() => SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), bind.port()),
+        _ => bind,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_seed_hosts() {
+        let (hosts, errors) = parse_hosts("# work\nExample.com\n\n*.b.com # cdn\na..b\n");
+        assert_eq!(hosts, vec!["example.com", "*.b.com"]);
+        assert_eq!(errors, vec!["line 5: a..b: host contains an empty label"]);
+    }
+
+    #[test]
+    fn renders_env() {
+        let setup = Setup {
+            bind: DEFAULT_BIND.parse().unwrap(),
+            database: DEFAULT_DATABASE.to_string(),
+            token_hash: "$argon2id$v=19$m=65540,t=3,p=4$salt$hash".to_string(),
+        };
+        let env = render_env(&setup);
+        assert!(env.contains("QPAC_BIND=0.0.0.0:8080\n"));
+        assert!(env.contains("QPAC_TOKEN=$argon2id$v=19$m=65540,t=3,p=4$salt$hash\n"));
+        assert_eq!(
+            client_addr(setup.bind),
+            "127.0.0.1:8080".parse::<SocketAddr>().unwrap()
+        );
+    }
+}
@@ -0,0 +1,272 @@
+use std::{
+    collections::BTreeMap,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use metrics::{describe_counter, describe_gauge, describe_histogram, Unit};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+
+pub const DB_POOL_CONNECTIONS: &str = "qpac_db_pool_connections";
+pub const DB_POOL_ACQUIRE_SECONDS: &str = "qpac_db_pool_acquire_seconds";
+pub const DB_MAINTENANCE_SECONDS: &str = "qpac_db_maintenance_seconds";
+pub const DB_BUSY_TIMEOUTS: &str = "qpac_db_busy_timeouts_total";
+pub const CONTENT_VERIFICATION_FAILURES: &str = "qpac_content_verification_failures_total";
+pub const HOST_CHANGE_ALERTS: &str = "qpac_host_change_alerts_total";
+pub const PAC_SIZE_BYTES: &str = "qpac_pac_size_bytes";
+pub const STORAGE_ROWS: &str = "qpac_storage_rows";
+pub const STORAGE_BYTES: &str = "qpac_storage_bytes";
+pub const OVERSIZED_PACS: &str = "qpac_oversized_pacs_total";
+pub const BUCKET_MIRROR_FAILURES: &str = "qpac_bucket_mirror_failures_total";
+pub const CONNECTIONS_REJECTED: &str = "qpac_connections_rejected_total";
+pub const HTTP_REQUESTS: &str = "qpac_http_requests_total";
+/// Buckets carry the trace id of their latest request as an exemplar in the
+/// OpenMetrics format, see [`record_exemplar`]
+pub const HTTP_REQUEST_DURATION_SECONDS: &str = "qpac_http_request_duration_seconds";
+const HTTP_REQUEST_DURATION_BUCKETS: &[f64] = &[
+    0.0005, 0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0,
+];
+
+/// Content type of [`render_openmetrics`]
+pub const OPENMETRICS_CONTENT_TYPE: &str =
+    "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Latest traced request of a request duration bucket
+#[derive(Debug, Clone, PartialEq)]
+struct Exemplar {
+    trace_id: String,
+    seconds: f64,
+    timestamp: f64,
+}
+
+/// Exemplars by rendered labels of the series and upper bound of the bucket
+#[derive(Debug, Default)]
+struct Exemplars(BTreeMap<(String, u64), Exemplar>);
+
+impl Exemplars {
+    fn record(&mut self, labels: &[(&str, String)], seconds: f64, trace_id: &str) {
+        let bound = HTTP_REQUEST_DURATION_BUCKETS
+            .iter()
+            .copied()
+            .find(|b| seconds <= *b)
+            .unwrap_or(f64::INFINITY);
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs_f64())
+            .unwrap_or_default();
+        self.0.insert(
+            (series_labels(labels), bound.to_bits()),
+            Exemplar {
+                trace_id: trace_id.to_string(),
+                seconds,
+                timestamp,
+            },
+        );
+    }
+
+    /// Appends exemplars to the duration buckets of `text` rendered by the
+    /// exporter. OpenMetrics has no blank lines and ends with `# EOF`
+    fn annotate(&self, text: &str) -> String {
+        let bucket = format!("{HTTP_REQUEST_DURATION_SECONDS}_bucket{{");
+        let mut out = String::with_capacity(text.len());
+        for line in text.lines().filter(|l| !l.is_empty()) {
+            out.push_str(line);
+            let exemplar = line
+                .strip_prefix(&bucket)
+                .and_then(|rest| rest.split_once("} "))
+                .and_then(|(labels, _)| labels.rsplit_once("le=\""))
+                .and_then(|(labels, le)| {
+                    let bound: f64 = le.trim_end_matches('"').parse().ok()?;
+                    let key = (labels.trim_end_matches(',').to_string(), bound.to_bits());
+                    self.0.get(&key)
+                });
+            if let Some(e) = exemplar {
+                out.push_str(&format!(
+                    " # {{trace_id=\"{}\"}} {} {:.3}",
+                    e.trace_id, e.seconds, e.timestamp
+                ));
+            }
+            out.push('\n');
+        }
+        out.push_str("# EOF\n");
+        out
+    }
+}
+
+static EXEMPLARS: Mutex<Exemplars> = Mutex::new(Exemplars(BTreeMap::new()));
+
+/// Labels the way the exporter renders them
+fn series_labels(labels: &[(&str, String)]) -> String {
+    labels
+        .iter()
+        .map(|(name, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{name}=\"{value}\"")
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Keeps `trace_id` as the exemplar of the request duration bucket `seconds`
+/// falls into, the histogram itself is recorded separately
+pub fn record_exemplar(labels: &[(&str, String)], seconds: f64, trace_id: &str) {
+    EXEMPLARS
+        .lock()
+        .expect("Poisoned exemplars")
+        .record(labels, seconds, trace_id);
+}
+
+/// Metrics in the OpenMetrics format, which unlike the prometheus one carries
+/// exemplars
+pub fn render_openmetrics(handle: &PrometheusHandle) -> String {
+    EXEMPLARS
+        .lock()
+        .expect("Poisoned exemplars")
+        .annotate(&handle.render())
+}
+
+/// Installs global prometheus recorder, handle is used to render `/metrics`
+pub fn setup() -> color_eyre::Result<PrometheusHandle> {
+    let handle = PrometheusBuilder::new()
+        .set_buckets_for_metric(
+            Matcher::Full(DB_POOL_ACQUIRE_SECONDS.to_string()),
+            &[0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 3.0],
+        )?
+        .set_buckets_for_metric(
+            Matcher::Full(DB_MAINTENANCE_SECONDS.to_string()),
+            &[0.001, 0.01, 0.1, 0.5, 1.0, 5.0, 30.0, 120.0],
+        )?
+        .set_buckets_for_metric(
+            Matcher::Full(HTTP_REQUEST_DURATION_SECONDS.to_string()),
+            HTTP_REQUEST_DURATION_BUCKETS,
+        )?
+        .install_recorder()?;
+    describe();
+    Ok(handle)
+}
+
+fn describe() {
+    describe_gauge!(
+        DB_POOL_CONNECTIONS,
+        Unit::Count,
+        "Database pool connections by state (idle, in_use)"
+    );
+    describe_histogram!(
+        DB_POOL_ACQUIRE_SECONDS,
+        Unit::Seconds,
+        "Time spent waiting for a database connection"
+    );
+    describe_histogram!(
+        DB_MAINTENANCE_SECONDS,
+        Unit::Seconds,
+        "Duration of database maintenance tasks by task (checkpoint, optimize, vacuum)"
+    );
+    describe_counter!(
+        DB_BUSY_TIMEOUTS,
+        Unit::Count,
+        "Database operations failed because the database was busy or the pool timed out"
+    );
+    describe_counter!(
+        CONTENT_VERIFICATION_FAILURES,
+        Unit::Count,
+        "Stored files that didn't match their checksum"
+    );
+    describe_counter!(
+        HOST_CHANGE_ALERTS,
+        Unit::Count,
+        "Times host changes went over the configured rate threshold"
+    );
+    describe_gauge!(
+        PAC_SIZE_BYTES,
+        Unit::Bytes,
+        "Size of the last generated pac file"
+    );
+    describe_gauge!(
+        STORAGE_ROWS,
+        Unit::Count,
+        "Stored rows by table (hosts, deleted_hosts, files, snapshots, audit_log) and tenant, empty for the root list"
+    );
+    describe_gauge!(
+        STORAGE_BYTES,
+        Unit::Bytes,
+        "Storage size by part (database, wal, latest_pac) and tenant, empty for the root list"
+    );
+    describe_counter!(
+        OVERSIZED_PACS,
+        Unit::Count,
+        "Generated pac files over the configured size limit"
+    );
+    describe_counter!(
+        BUCKET_MIRROR_FAILURES,
+        Unit::Count,
+        "Failed writes to the pac bucket by operation (file, latest, delete), retried later"
+    );
+    describe_counter!(
+        CONNECTIONS_REJECTED,
+        Unit::Count,
+        "Connections closed because the client IP was over its connection limit"
+    );
+    describe_counter!(
+        HTTP_REQUESTS,
+        Unit::Count,
+        "HTTP requests by method, route template and status class"
+    );
+    describe_histogram!(
+        HTTP_REQUEST_DURATION_SECONDS,
+        Unit::Seconds,
+        "HTTP request latency by method, route template and status class"
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn annotates_buckets_with_exemplars() {
+        let recorder = PrometheusBuilder::new()
+            .set_buckets_for_metric(
+                Matcher::Full(HTTP_REQUEST_DURATION_SECONDS.to_string()),
+                HTTP_REQUEST_DURATION_BUCKETS,
+            )
+            .unwrap()
+            .build_recorder();
+        let labels = [
+            ("method", "GET".to_string()),
+            ("route", "/:hash".to_string()),
+            ("status", "2xx".to_string()),
+        ];
+        metrics::with_local_recorder(&recorder, || {
+            metrics::histogram!(HTTP_REQUEST_DURATION_SECONDS, &labels).record(0.3);
+            metrics::counter!(HTTP_REQUESTS, &labels).increment(1);
+        });
+        let mut exemplars = Exemplars::default();
+        exemplars.record(&labels, 0.3, "4bf92f3577b34da6a3ce929d0e0e4736");
+
+        let text = exemplars.annotate(&recorder.handle().render());
+        let annotated: Vec<&str> = text.lines().filter(|l| l.contains(" # {")).collect();
+        assert_eq!(annotated.len(), 1, "{text}");
+        assert!(annotated[0].starts_with(
+            r#"qpac_http_request_duration_seconds_bucket{method="GET",route="/:hash",status="2xx",le="0.5"} 1 # {trace_id="4bf92f3577b34da6a3ce929d0e0e4736"} 0.3 "#
+        ));
+        assert!(!text.lines().any(str::is_empty));
+        assert!(text.ends_with("\n# EOF\n"));
+    }
+
+    #[test]
+    fn keeps_latest_exemplar_per_bucket() {
+        let labels = [("method", "GET".to_string())];
+        let mut exemplars = Exemplars::default();
+        exemplars.record(&labels, 0.3, "a");
+        exemplars.record(&labels, 0.4, "b");
+        exemplars.record(&labels, 9.0, "c");
+        let trace_ids: Vec<&str> = exemplars.0.values().map(|e| e.trace_id.as_str()).collect();
+        assert_eq!(trace_ids, vec!["b", "c"]);
+        assert!(exemplars
+            .0
+            .contains_key(&(r#"method="GET""#.to_string(), f64::INFINITY.to_bits())));
+    }
+}
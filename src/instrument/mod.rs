@@ -1,2 +1,3 @@
 pub mod instrumentation;
 pub mod logger;
+pub mod metrics;
@@ -0,0 +1,18 @@
+pub mod args;
+pub mod cli;
+pub mod client;
+pub mod constants;
+pub mod error;
+pub mod host;
+pub mod http_client;
+pub mod init;
+pub mod instrument;
+mod metrics_layer;
+pub mod pac;
+pub mod rules;
+pub mod schedule;
+pub mod selftest;
+pub mod storage;
+mod trace_layer;
+pub mod utils;
+pub mod web;
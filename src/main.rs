@@ -1,21 +1,22 @@
-use argon2::{
-    password_hash::SaltString, Algorithm, Argon2, ParamsBuilder, PasswordHasher, Version,
-};
-use clap::Parser;
+use std::future::Future;
 
-use args::Args;
-use ring::rand::{SecureRandom, SystemRandom};
-use tracing::{debug, trace};
+use clap::Parser;
 
-mod args;
-mod constants;
-mod error;
-mod instrument;
-mod pac;
-mod storage;
-mod trace_layer;
-mod utils;
-mod web;
+use qpac::{
+    args::{self, Args},
+    cli,
+    client::ClientError,
+    error,
+    http_client::HttpClient,
+    init, selftest,
+    storage::{
+        migrate,
+        postgres_storage::{is_postgres_url, PostgresStorage},
+        sqlite_storage::{MigrationStatus, SqliteStorage},
+        InstanceState, Storage,
+    },
+    utils, web,
+};
 
 #[tokio::main]
 async fn main() -> error::Result<()> {
@@ -26,47 +27,132 @@ async fn main() -> error::Result<()> {
 
     tracing::trace!("{:?}", args);
 
+    let http_client = HttpClient::new(&args.http_client)?;
     match args.command {
-        args::Command::Serve {
-            bind,
-            token,
+        args::Command::Serve(serve_args) => {
+            web::run_web_server(*serve_args, args.sqlite, args.postgres, http_client).await?;
+        }
+        args::Command::Migrate(migrate_args) => {
+            let url = &migrate_args.database;
+            if is_postgres_url(url) {
+                let storage = PostgresStorage::connect_with(url, &args.postgres).await?;
+                let status = storage.migration_status().await?;
+                apply_migrations(migrate_args.check, status, storage.migrate()).await?;
+            } else {
+                let storage = SqliteStorage::connect_with(url, &args.sqlite).await?;
+                let status = storage.migration_status().await?;
+                apply_migrations(migrate_args.check, status, storage.migrate()).await?;
+            }
+        }
+        args::Command::Backup {
             database,
+            dir,
+            keep,
         } => {
-            web::run_web_server(bind, token, database).await?;
+            // Copied as is, migrating is left to the server
+            let storage = SqliteStorage::connect_with(&database, &args.sqlite).await?;
+            let path = storage.backup(&dir, keep).await?;
+            println!("Backed up to {}", path.display());
         }
+        args::Command::ExportAll { database, output } => {
+            let storage = SqliteStorage::new_with(&database, &args.sqlite).await?;
+            let state = storage.export_state().await?;
+            let json = serde_json::to_string_pretty(&state)?;
+            match output {
+                Some(path) => std::fs::write(path, json)?,
+                None => println!("{json}"),
+            }
+        }
+        args::Command::ImportAll { database, input } => {
+            let json = match input {
+                Some(path) => std::fs::read_to_string(path)?,
+                None => std::io::read_to_string(std::io::stdin())?,
+            };
+            let state: InstanceState = serde_json::from_str(&json)?;
+            let (hosts, snapshots, profiles) = (
+                state.hosts.len(),
+                state.snapshots.len(),
+                state.profiles.len(),
+            );
+            let storage = SqliteStorage::new_with(&database, &args.sqlite).await?;
+            storage.import_state(state).await?;
+            println!("Imported {hosts} hosts, {snapshots} snapshots and {profiles} profiles");
+        }
+        args::Command::MigrateData { from, to } => {
+            let report = migrate::copy(
+                migrate::open(&from, &args.sqlite, &args.postgres)
+                    .await?
+                    .as_ref(),
+                migrate::open_target(&to, &args.sqlite, &args.postgres)
+                    .await?
+                    .as_ref(),
+            )
+            .await?;
+            println!(
+                "Copied {} hosts, {} snapshots, {} profiles and {} files",
+                report.hosts, report.snapshots, report.profiles, report.versions
+            );
+        }
+        args::Command::Init(init_args) => init::run(init_args, &args.sqlite).await?,
         args::Command::Hash { token } => {
-            let hash = generate_hash(token.as_bytes());
+            let hash = utils::token::hash(token.as_bytes());
             println!("{hash}");
         }
-        args::Command::Add => {
-            unimplemented!();
+        args::Command::Add(add_args) => exit(cli::add(&http_client, add_args).await),
+        args::Command::Remove(hosts_args) => exit(cli::remove(&http_client, hosts_args).await),
+        args::Command::List(client_args) => exit(cli::list(&http_client, client_args).await),
+        args::Command::Versions(versions_args) => {
+            exit(cli::versions(&http_client, versions_args).await)
+        }
+        args::Command::Search(search_args) => exit(cli::search(&http_client, search_args).await),
+        args::Command::Selftest(selftest_args) => {
+            if !selftest::run(&http_client, selftest_args).await? {
+                std::process::exit(1);
+            }
         }
     }
 
     Ok(())
 }
 
-fn generate_hash(token: &[u8]) -> String {
-    let mut params = ParamsBuilder::new();
-    params.m_cost(65540).t_cost(3).p_cost(4);
-    debug!("Generating with params {:?}", &params);
-
-    let argon = Argon2::new(
-        Algorithm::Argon2id,
-        Version::V0x13,
-        params.build().expect("Should build params"),
-    );
-    trace!("Argon2 {:?}", argon);
-
-    let mut buf = [0; 32];
-    SystemRandom::new()
-        .fill(&mut buf)
-        .expect("Error generating random values");
-    let salt = SaltString::encode_b64(&buf).unwrap();
-    trace!("Generated salt");
+/// Prints `status` and applies the pending migrations with `apply`, or only
+/// checks with `check` and exits with 1 when the database doesn't match
+async fn apply_migrations(
+    check: bool,
+    status: MigrationStatus,
+    apply: impl Future<Output = error::Result<()>>,
+) -> error::Result<()> {
+    if check {
+        for (version, description) in status.pending.iter() {
+            println!("Pending {version} {description}");
+        }
+        for version in status.unknown.iter() {
+            println!("Unknown {version}, applied by a newer version");
+        }
+        for version in status.modified.iter() {
+            println!("Modified {version}, checksum differs");
+        }
+        if !status.is_current() {
+            std::process::exit(1);
+        }
+        println!("Database matches this binary");
+        return Ok(());
+    }
+    if status.pending.is_empty() {
+        println!("Database is up to date");
+    } else {
+        apply.await?;
+        for (version, description) in status.pending {
+            println!("Applied {version} {description}");
+        }
+    }
+    Ok(())
+}
 
-    argon
-        .hash_password(token, &salt)
-        .expect("Hashed password")
-        .to_string()
+/// Exits with the documented code of a client command
+fn exit(res: Result<(), ClientError>) {
+    if let Err(e) = res {
+        eprintln!("{e}");
+        std::process::exit(e.exit_code());
+    }
 }
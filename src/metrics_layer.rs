@@ -0,0 +1,55 @@
+use axum::{
+    body::Body,
+    extract::MatchedPath,
+    http::{Method, Request},
+    middleware::Next,
+    response::Response,
+};
+use std::time::Instant;
+
+use crate::{
+    instrument::metrics::{record_exemplar, HTTP_REQUESTS, HTTP_REQUEST_DURATION_SECONDS},
+    trace_layer,
+};
+
+/// Labels use route templates and status classes to keep cardinality bounded
+pub(crate) async fn track_metrics(request: Request<Body>, next: Next) -> Response {
+    let start = Instant::now();
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|p| p.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".to_string());
+    let method = method_label(request.method());
+    let trace_id = trace_layer::trace_id(request.headers()).map(str::to_string);
+
+    let response = next.run(request).await;
+
+    let status = format!("{}xx", response.status().as_u16() / 100);
+    let labels = [
+        ("method", method.to_string()),
+        ("route", route),
+        ("status", status),
+    ];
+    metrics::counter!(HTTP_REQUESTS, &labels).increment(1);
+    let seconds = start.elapsed().as_secs_f64();
+    metrics::histogram!(HTTP_REQUEST_DURATION_SECONDS, &labels).record(seconds);
+    if let Some(trace_id) = trace_id {
+        record_exemplar(&labels, seconds, &trace_id);
+    }
+
+    response
+}
+
+fn method_label(method: &Method) -> &'static str {
+    match *method {
+        Method::GET => "GET",
+        Method::HEAD => "HEAD",
+        Method::POST => "POST",
+        Method::PUT => "PUT",
+        Method::PATCH => "PATCH",
+        Method::DELETE => "DELETE",
+        Method::OPTIONS => "OPTIONS",
+        _ => "OTHER",
+    }
+}
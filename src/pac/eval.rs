@@ -0,0 +1,478 @@
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, Ipv4Addr},
+};
+
+use boa_engine::{js_string, Context, JsError, JsString, JsValue, Source};
+use thiserror::Error;
+
+use super::js_string;
+
+const PAC_ENV: &str = include_str!("./pac_env.js");
+/// Iterations of a single loop before the script is stopped
+const LOOP_ITERATION_LIMIT: u64 = 1_000_000;
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum EvalError {
+    #[error("script error: {0}")]
+    Script(String),
+
+    #[error("FindProxyForURL isn't defined")]
+    MissingFunction,
+
+    #[error("FindProxyForURL returned {0} instead of a string")]
+    NotAString(String),
+}
+
+impl From<JsError> for EvalError {
+    fn from(value: JsError) -> Self {
+        EvalError::Script(value.to_string())
+    }
+}
+
+/// What an evaluated file gets to see of the client and the network, nothing
+/// is resolved for real
+#[derive(Debug, Clone)]
+pub struct EvalEnv {
+    pub my_ip_address: IpAddr,
+    /// Answers of `dnsResolve`, other names don't resolve
+    pub resolved: BTreeMap<String, IpAddr>,
+    /// Unix time `weekdayRange` and `timeRange` see, the current time when
+    /// unset
+    pub now: Option<i64>,
+}
+
+impl Default for EvalEnv {
+    fn default() -> Self {
+        Self {
+            my_ip_address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            resolved: BTreeMap::new(),
+            now: None,
+        }
+    }
+}
+
+/// Pac file loaded into its own JS context with the helpers browsers provide.
+/// Not `Send`, servers evaluate on a blocking thread
+pub struct Evaluator {
+    context: Context,
+}
+
+impl Evaluator {
+    pub fn new(file: &str, env: &EvalEnv) -> Result<Self, EvalError> {
+        let mut context = Context::default();
+        context
+            .runtime_limits_mut()
+            .set_loop_iteration_limit(LOOP_ITERATION_LIMIT);
+
+        let resolved: Vec<String> = env
+            .resolved
+            .iter()
+            .map(|(host, ip)| format!("{}: {}", js_string(host), js_string(&ip.to_string())))
+            .collect();
+        let now = env.now.map_or("null".to_string(), |now| now.to_string());
+        let globals = format!(
+            "var __MY_IP_ADDRESS__ = {};\nvar __RESOLVED__ = {{{}}};\nvar __NOW__ = {now};\n",
+            js_string(&env.my_ip_address.to_string()),
+            resolved.join(", ")
+        );
+        for script in [globals.as_str(), PAC_ENV, file] {
+            context.eval(Source::from_bytes(script))?;
+        }
+        Ok(Self { context })
+    }
+
+    /// Proxy chain the file returns for `url`, `FindProxyForURLEx` is
+    /// preferred like clients supporting it do
+    pub fn find_proxy(&mut self, url: &str, host: &str) -> Result<String, EvalError> {
+        let global = self.context.global_object();
+        let mut function = global.get(js_string!("FindProxyForURLEx"), &mut self.context)?;
+        if !function.is_callable() {
+            function = global.get(js_string!("FindProxyForURL"), &mut self.context)?;
+        }
+        let function = function.as_callable().ok_or(EvalError::MissingFunction)?;
+        let args = [JsString::from(url).into(), JsString::from(host).into()];
+        let res = function.call(&JsValue::undefined(), &args, &mut self.context)?;
+        res.as_string()
+            .map(|s| s.to_std_string_escaped())
+            .ok_or_else(|| EvalError::NotAString(res.display().to_string()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pac::{
+        JsTarget, MatchStrategy, NetworkProfile, Pac, PacGroup, PacMode, PacOptions, Upstream,
+        DEFAULT_PROXY,
+    };
+
+    const STRATEGIES: [MatchStrategy; 4] = [
+        MatchStrategy::Binary,
+        MatchStrategy::Object,
+        MatchStrategy::Trie,
+        MatchStrategy::Chunked,
+    ];
+    const TARGETS: [JsTarget; 2] = [JsTarget::Es5, JsTarget::Es2015];
+
+    fn hosts(hosts: &[&str]) -> Vec<String> {
+        hosts.iter().map(|h| h.to_string()).collect()
+    }
+
+    fn evaluator(pac: &Pac) -> Evaluator {
+        Evaluator::new(&pac.file, &EvalEnv::default()).unwrap()
+    }
+
+    #[test]
+    fn routes_listed_hosts() {
+        for matching in STRATEGIES {
+            for js_target in TARGETS {
+                routes_listed_hosts_with(matching, js_target);
+            }
+        }
+    }
+
+    fn routes_listed_hosts_with(matching: MatchStrategy, js_target: JsTarget) {
+        let pac = Pac::generate_with_options(
+            hosts(&[
+                "example.com",
+                ".sub.org",
+                "*.wild.net",
+                "a.b.c.deep.io",
+                r"/^cdn[0-9]+\.img\.io$/",
+            ]),
+            &PacOptions {
+                matching,
+                js_target,
+                ..Default::default()
+            },
+        );
+        let mut eval = evaluator(&pac);
+        let mut route = |host: &str| eval.find_proxy(&format!("https://{host}/"), host).unwrap();
+        assert_eq!(
+            route("example.com"),
+            DEFAULT_PROXY,
+            "{matching:?} {js_target:?}"
+        );
+        assert_eq!(route("www.example.com"), "DIRECT;");
+        assert_eq!(route("sub.org"), DEFAULT_PROXY);
+        assert_eq!(route("a.sub.org"), DEFAULT_PROXY);
+        assert_eq!(route("wild.net"), "DIRECT;");
+        assert_eq!(route("a.wild.net"), DEFAULT_PROXY);
+        assert_eq!(route("cdn12.img.io"), DEFAULT_PROXY);
+        assert_eq!(route("cdn.img.io"), "DIRECT;");
+        assert_eq!(route("other.com"), "DIRECT;");
+        assert_eq!(route("a.b.c.deep.io"), DEFAULT_PROXY);
+        assert_eq!(route("b.c.deep.io"), "DIRECT;");
+        assert_eq!(route("deep.io"), "DIRECT;");
+        assert_eq!(route("com"), "DIRECT;");
+    }
+
+    #[test]
+    fn routes_prototype_labels() {
+        for matching in STRATEGIES {
+            for js_target in TARGETS {
+                let pac = Pac::generate_with_options(
+                    hosts(&["__proto__.example.com", "constructor"]),
+                    &PacOptions {
+                        matching,
+                        js_target,
+                        ..Default::default()
+                    },
+                );
+                let mut eval = evaluator(&pac);
+                let mut route = |host: &str| eval.find_proxy("", host).unwrap();
+                assert_eq!(
+                    route("__proto__.example.com"),
+                    DEFAULT_PROXY,
+                    "{matching:?} {js_target:?}"
+                );
+                assert_eq!(route("constructor"), DEFAULT_PROXY);
+                assert_eq!(route("__proto__"), "DIRECT;");
+                assert_eq!(route("example.com"), "DIRECT;");
+                assert_eq!(route("toString.example.com"), "DIRECT;");
+            }
+        }
+    }
+
+    #[test]
+    fn finds_hosts_in_every_chunk() {
+        let listed: Vec<String> = (0..3000).map(|i| format!("h{i}.example.com")).collect();
+        let pac = Pac::generate_with_options(
+            listed.clone(),
+            &PacOptions {
+                matching: MatchStrategy::Chunked,
+                ..Default::default()
+            },
+        );
+        let mut eval = evaluator(&pac);
+        for host in listed.iter().step_by(7) {
+            assert_eq!(eval.find_proxy("", host).unwrap(), DEFAULT_PROXY, "{host}");
+        }
+        assert_eq!(eval.find_proxy("", "h3000.example.com").unwrap(), "DIRECT;");
+    }
+
+    #[test]
+    fn applies_options() {
+        for matching in STRATEGIES {
+            for js_target in TARGETS {
+                applies_options_with(matching, js_target);
+            }
+        }
+    }
+
+    fn applies_options_with(matching: MatchStrategy, js_target: JsTarget) {
+        let groups = [PacGroup {
+            proxy: Some("PROXY work:3128".to_string()),
+            hosts: hosts(&[".corp.com"]),
+            schedule: None,
+        }];
+        let exclusions = hosts(&["direct.example.com"]);
+        let blocklist = hosts(&[".ads.corp.com"]);
+        let pac = Pac::generate_with_options(
+            hosts(&["example.com"]),
+            &PacOptions {
+                groups: &groups,
+                mode: PacMode::Blacklist,
+                exclusions: &exclusions,
+                blocklist: &blocklist,
+                bypass_private: true,
+                matching,
+                js_target,
+                ..Default::default()
+            },
+        );
+        let mut eval = evaluator(&pac);
+        let mut route = |host: &str| eval.find_proxy("", host).unwrap();
+        assert_eq!(
+            route("example.com"),
+            "DIRECT;",
+            "{matching:?} {js_target:?}"
+        );
+        assert_eq!(route("other.com"), DEFAULT_PROXY);
+        assert_eq!(route("direct.example.com"), "DIRECT;");
+        assert_eq!(route("a.corp.com"), "PROXY work:3128");
+        assert_eq!(route("x.ads.corp.com"), "PROXY 127.0.0.1:9;");
+        assert_eq!(route("intranet"), "DIRECT;");
+        assert_eq!(route("192.168.1.10"), "DIRECT;");
+        assert_eq!(route("8.8.8.8"), DEFAULT_PROXY);
+    }
+
+    #[test]
+    fn handles_ipv6_literals() {
+        let options = |ipv6| PacOptions {
+            mode: PacMode::Blacklist,
+            bypass_private: true,
+            ipv6,
+            ..Default::default()
+        };
+        let pac = Pac::generate_with_options(vec![], &options(true));
+        let mut eval = evaluator(&pac);
+        let mut route = |host: &str| eval.find_proxy("", host).unwrap();
+        assert_eq!(route("[::1]"), "DIRECT;");
+        assert_eq!(route("0:0:0:0:0:0:0:1"), "DIRECT;");
+        assert_eq!(route("[fd12:3456::1]"), "DIRECT;");
+        assert_eq!(route("FE80::1"), "DIRECT;");
+        assert_eq!(route("[2001:db8::1]"), DEFAULT_PROXY);
+        assert_eq!(route("fc::1"), DEFAULT_PROXY);
+        assert_eq!(route("10.0.0.1"), "DIRECT;");
+
+        // Without ipv6 literals aren't checked, nor taken for plain names
+        let pac = Pac::generate_with_options(vec![], &options(false));
+        let mut eval = evaluator(&pac);
+        let mut route = |host: &str| eval.find_proxy("", host).unwrap();
+        assert_eq!(route("2001:db8::1"), DEFAULT_PROXY);
+        assert_eq!(route("[::1]"), DEFAULT_PROXY);
+        assert_eq!(route("intranet"), "DIRECT;");
+    }
+
+    #[test]
+    fn spreads_over_upstreams() {
+        let upstreams = [
+            Upstream {
+                proxy: "PROXY a:3128".to_string(),
+                weight: 1,
+            },
+            Upstream {
+                proxy: "PROXY b:3128".to_string(),
+                weight: 1,
+            },
+        ];
+        let hosts: Vec<String> = (0..20).map(|i| format!("h{i}.com")).collect();
+        let pac = Pac::generate_with_options(
+            hosts.clone(),
+            &PacOptions {
+                upstreams: &upstreams,
+                ..Default::default()
+            },
+        );
+        let mut eval = evaluator(&pac);
+        let routes: Vec<String> = hosts
+            .iter()
+            .map(|h| eval.find_proxy("", h).unwrap())
+            .collect();
+        assert!(routes.iter().any(|r| r == "PROXY a:3128"));
+        assert!(routes.iter().any(|r| r == "PROXY b:3128"));
+        assert_eq!(eval.find_proxy("", "h0.com").unwrap(), routes[0]);
+    }
+
+    #[test]
+    fn provides_pac_helpers() {
+        let file = r#"
+function FindProxyForURL(url, host) {
+  return [
+    isPlainHostName(host),
+    dnsDomainIs(host, ".example.com"),
+    dnsDomainLevels(host),
+    shExpMatch(url, "*://*.example.com/*"),
+    isInNet(host, "10.0.0.0", "255.0.0.0"),
+    myIpAddress(),
+  ].join(" ");
+}"#;
+        let env = EvalEnv {
+            my_ip_address: "192.168.1.2".parse().unwrap(),
+            resolved: [("www.example.com".to_string(), "10.1.2.3".parse().unwrap())].into(),
+            ..Default::default()
+        };
+        let mut eval = Evaluator::new(file, &env).unwrap();
+        assert_eq!(
+            eval.find_proxy("https://www.example.com/a", "www.example.com"),
+            Ok("false true 2 true true 192.168.1.2".to_string())
+        );
+        assert_eq!(
+            eval.find_proxy("http://a.org/", "a.org"),
+            Ok("false false 1 false false 192.168.1.2".to_string())
+        );
+    }
+
+    #[test]
+    fn follows_schedules() {
+        let groups = [
+            PacGroup {
+                proxy: Some("PROXY work:3128".to_string()),
+                hosts: hosts(&[".corp.com"]),
+                schedule: Some("MON-FRI 9-17 GMT".parse().unwrap()),
+            },
+            PacGroup {
+                proxy: None,
+                hosts: hosts(&["news.com"]),
+                schedule: Some("SAT-SUN GMT".parse().unwrap()),
+            },
+        ];
+        let pac = Pac::generate_grouped(hosts(&["example.com"]), DEFAULT_PROXY, &groups);
+        let route = |now: i64, host: &str| {
+            let env = EvalEnv {
+                now: Some(now),
+                ..Default::default()
+            };
+            Evaluator::new(&pac.file, &env)
+                .unwrap()
+                .find_proxy("", host)
+                .unwrap()
+        };
+        // Friday 2024-11-15 10:00 and 20:00, Saturday 10:00 UTC
+        let (friday, evening, saturday) = (1731664800, 1731700800, 1731751200);
+        assert_eq!(route(friday, "a.corp.com"), "PROXY work:3128");
+        assert_eq!(route(friday, "news.com"), "DIRECT;");
+        assert_eq!(route(evening, "a.corp.com"), "DIRECT;");
+        assert_eq!(route(saturday, "a.corp.com"), "DIRECT;");
+        assert_eq!(route(saturday, "news.com"), DEFAULT_PROXY);
+        assert_eq!(route(saturday, "example.com"), DEFAULT_PROXY);
+    }
+
+    #[test]
+    fn switches_proxies_by_network() {
+        let network = |name: &str, network: &str, proxy: &str| NetworkProfile {
+            name: name.to_string(),
+            network: network.to_string(),
+            proxy: proxy.to_string(),
+        };
+        let networks = [
+            network("lan", "192.168.0.0/16", "PROXY lan:3128"),
+            network("home", "192.168.1.0/24", "DIRECT"),
+            network("vpn", "10.8.0.0/24", "PROXY vpn:3128"),
+        ];
+        let groups = [PacGroup {
+            proxy: Some("PROXY work:3128".to_string()),
+            hosts: hosts(&["corp.com"]),
+            schedule: None,
+        }];
+        let pac = Pac::generate_with_options(
+            hosts(&["example.com"]),
+            &PacOptions {
+                groups: &groups,
+                networks: &networks,
+                ..Default::default()
+            },
+        );
+        let route = |address: [u8; 4], host: &str| {
+            let env = EvalEnv {
+                my_ip_address: IpAddr::from(address),
+                ..Default::default()
+            };
+            Evaluator::new(&pac.file, &env)
+                .unwrap()
+                .find_proxy("", host)
+                .unwrap()
+        };
+        assert_eq!(route([192, 168, 1, 5], "example.com"), "DIRECT");
+        assert_eq!(route([192, 168, 7, 5], "example.com"), "PROXY lan:3128");
+        assert_eq!(route([10, 8, 0, 2], "example.com"), "PROXY vpn:3128");
+        assert_eq!(route([172, 20, 0, 2], "example.com"), DEFAULT_PROXY);
+        assert_eq!(route([10, 8, 0, 2], "other.com"), "DIRECT;");
+        assert_eq!(route([192, 168, 1, 5], "corp.com"), "PROXY work:3128");
+    }
+
+    #[test]
+    fn matches_resolved_ip_ranges() {
+        let ranges = hosts(&["203.0.113.0/24"]);
+        let resolved: BTreeMap<String, IpAddr> = [
+            ("cdn.net", [203, 0, 113, 9]),
+            ("other.net", [198, 51, 100, 1]),
+        ]
+        .into_iter()
+        .map(|(host, ip)| (host.to_string(), IpAddr::from(ip)))
+        .collect();
+        let env = EvalEnv {
+            resolved,
+            ..Default::default()
+        };
+        for mode in [PacMode::Whitelist, PacMode::Blacklist] {
+            let pac = Pac::generate_with_options(
+                hosts(&["example.com"]),
+                &PacOptions {
+                    mode,
+                    ip_ranges: &ranges,
+                    ..Default::default()
+                },
+            );
+            let (listed, unlisted) = match mode {
+                PacMode::Whitelist => (DEFAULT_PROXY, "DIRECT;"),
+                PacMode::Blacklist => ("DIRECT;", DEFAULT_PROXY),
+            };
+            let mut eval = Evaluator::new(&pac.file, &env).unwrap();
+            let mut route = |host: &str| eval.find_proxy("", host).unwrap();
+            assert_eq!(route("example.com"), listed);
+            assert_eq!(route("cdn.net"), listed);
+            assert_eq!(route("203.0.113.200"), listed);
+            assert_eq!(route("other.net"), unlisted);
+            assert_eq!(route("unresolved.net"), unlisted);
+        }
+    }
+
+    #[test]
+    fn reports_broken_files() {
+        let env = EvalEnv::default();
+        assert!(matches!(
+            Evaluator::new("function (", &env),
+            Err(EvalError::Script(_))
+        ));
+        let mut eval = Evaluator::new("var a = 1;", &env).unwrap();
+        assert_eq!(eval.find_proxy("", "a"), Err(EvalError::MissingFunction));
+        let mut eval = Evaluator::new("function FindProxyForURL() { return 1; }", &env).unwrap();
+        assert_eq!(
+            eval.find_proxy("", "a"),
+            Err(EvalError::NotAString("1".to_string()))
+        );
+    }
+}
@@ -1,39 +1,1256 @@
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashSet},
+    io::Write,
+    net::Ipv4Addr,
+    str::FromStr,
+};
+
 use base64::{engine::general_purpose::URL_SAFE, Engine as _};
+use serde::{Deserialize, Serialize};
 use sha2::Digest;
 
+use crate::{
+    host,
+    schedule::Schedule,
+    utils::time::{rfc3339, unix_now},
+};
+
+pub mod eval;
+
 #[derive(Debug)]
 pub struct Pac {
     pub file: String,
     pub hash: String,
+    /// Hosts the file was generated from, empty when loaded from storage
+    pub hosts: Vec<String>,
+    /// `None` when loaded from storage
+    pub meta: Option<PacMeta>,
+}
+
+/// How a file was generated, also written to its header comment
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PacMeta {
+    /// Unix seconds
+    pub generated_at: i64,
+    /// Hosts of the list and of every group
+    pub host_count: i64,
+    pub qpac_version: String,
+}
+
+/// Precompressed bodies of a file, stored along with it and served as is
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct PacEncodings {
+    pub gzip: Option<Vec<u8>>,
+    pub br: Option<Vec<u8>>,
+}
+
+impl PacEncodings {
+    /// Encodes `file` with every supported encoding, CPU heavy for big files
+    pub fn compress(file: &str) -> Self {
+        let gzip = {
+            let mut enc = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            enc.write_all(file.as_bytes())
+                .and_then(|_| enc.finish())
+                .ok()
+        };
+        let br = {
+            let mut out = Vec::new();
+            let params = brotli::enc::BrotliEncoderParams {
+                quality: 9,
+                ..Default::default()
+            };
+            brotli::BrotliCompress(&mut file.as_bytes(), &mut out, &params)
+                .ok()
+                .map(|_| out)
+        };
+        Self { gzip, br }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.gzip.is_some() && self.br.is_some()
+    }
 }
 
 const JS_SCRIPT: &str = include_str!("./pac.js");
+const IPV6_SCRIPT: &str = include_str!("./pac_ipv6.js");
+const CACHE_SCRIPT: &str = include_str!("./pac_cache.js");
+const CACHE_SCRIPT_ES2015: &str = include_str!("./pac_cache_es2015.js");
+
+/// Proxy chain used when no profile overrides it
+pub const DEFAULT_PROXY: &str = "SOCKS5 127.0.0.1:1080; SOCKS 127.0.0.1:1080; DIRECT;";
+
+/// Kinds of a proxy chain element besides `DIRECT`, each takes a `host:port`
+const PROXY_KINDS: [&str; 6] = ["PROXY", "HTTP", "HTTPS", "SOCKS", "SOCKS4", "SOCKS5"];
+
+/// Checks every element of a `;` separated proxy chain, e.g.
+/// `SOCKS5 a:1080; PROXY b:3128; DIRECT`, and joins them back in their
+/// canonical form. Empty elements are dropped, kinds are uppercased
+pub fn normalize_chain(chain: &str) -> Result<String, String> {
+    let mut elements = vec![];
+    for element in chain.split(';').map(str::trim).filter(|e| !e.is_empty()) {
+        let mut parts = element.split_whitespace();
+        let kind = parts.next().unwrap_or_default().to_ascii_uppercase();
+        let address = parts.next();
+        if parts.next().is_some() {
+            return Err(format!("{element}: expected a kind and an address"));
+        }
+        match (kind.as_str(), address) {
+            ("DIRECT", None) => elements.push(kind),
+            ("DIRECT", Some(_)) => return Err(format!("{element}: DIRECT takes no address")),
+            (kind, Some(address)) if PROXY_KINDS.contains(&kind) => {
+                validate_address(address).map_err(|e| format!("{element}: {e}"))?;
+                elements.push(format!("{kind} {address}"));
+            }
+            (kind, Some(_)) => {
+                return Err(format!(
+                    "{element}: unknown kind {kind}, expected DIRECT or one of {}",
+                    PROXY_KINDS.join(", ")
+                ))
+            }
+            (_, None) => return Err(format!("{element}: missing host:port")),
+        }
+    }
+    if elements.is_empty() {
+        return Err("proxy chain is empty".to_string());
+    }
+    Ok(elements.join("; "))
+}
+
+/// `host:port` with a name, an IPv4 or a bracketed IPv6 address
+fn validate_address(address: &str) -> Result<(), String> {
+    let (host, port) = address
+        .rsplit_once(':')
+        .ok_or_else(|| "missing port".to_string())?;
+    match port.parse::<u16>() {
+        Ok(port) if port > 0 => {}
+        _ => return Err(format!("invalid port {port}")),
+    }
+    let valid = match host.strip_prefix('[').and_then(|h| h.strip_suffix(']')) {
+        Some(ip) => ip.parse::<std::net::Ipv6Addr>().is_ok(),
+        None => {
+            !host.is_empty()
+                && host
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'))
+        }
+    };
+    if !valid {
+        return Err(format!("invalid host {host}"));
+    }
+    Ok(())
+}
+
+/// Hosts routed through their own proxy chain, checked before the default list
+#[derive(Debug, Clone, PartialEq)]
+pub struct PacGroup {
+    /// `None` routes the hosts like listed ones of the default list
+    pub proxy: Option<String>,
+    /// Sorted like the default hosts
+    pub hosts: Vec<String>,
+    /// Checked only at these times, the file stops caching decisions when a
+    /// group has one
+    pub schedule: Option<Schedule>,
+}
+
+/// One of several equivalent proxy chains the default hosts are spread over,
+/// each host is sent to the same upstream by every client
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Upstream {
+    pub proxy: String,
+    /// Share of hosts relative to the other upstreams
+    pub weight: u32,
+}
+
+/// Proxy chain the proxied hosts go through instead of the default one while
+/// the client's own address, `myIpAddress()`, is in `network`. Tells a home
+/// LAN or a VPN apart from everywhere else
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NetworkProfile {
+    pub name: String,
+    /// IPv4 network in CIDR notation, `192.168.1.0/24`
+    pub network: String,
+    pub proxy: String,
+}
+
+/// Checks an IPv4 network in CIDR notation and clears host bits of the address,
+/// a bare address is a `/32`
+pub fn normalize_network(network: &str) -> Result<String, String> {
+    let (address, prefix) = parse_network(network)?;
+    Ok(format!("{address}/{prefix}"))
+}
+
+fn parse_network(network: &str) -> Result<(Ipv4Addr, u8), String> {
+    let network = network.trim();
+    let (address, prefix) = network.split_once('/').unwrap_or((network, "32"));
+    let address: Ipv4Addr = address
+        .parse()
+        .map_err(|_| format!("invalid IPv4 address {address}"))?;
+    let prefix = prefix
+        .parse::<u8>()
+        .ok()
+        .filter(|p| *p <= 32)
+        .ok_or_else(|| format!("invalid prefix length {prefix}, expected 0 to 32"))?;
+    let masked = u32::from(address) & u32::from(prefix_mask(prefix));
+    Ok((Ipv4Addr::from(masked), prefix))
+}
+
+fn prefix_mask(prefix: u8) -> Ipv4Addr {
+    Ipv4Addr::from(u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0))
+}
+
+/// Which hosts the generated pac sends through the proxy
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PacMode {
+    /// Only listed hosts
+    #[default]
+    Whitelist,
+    /// Everything except listed hosts
+    Blacklist,
+}
+
+impl PacMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PacMode::Whitelist => "whitelist",
+            PacMode::Blacklist => "blacklist",
+        }
+    }
+}
+
+impl FromStr for PacMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "whitelist" => Ok(PacMode::Whitelist),
+            "blacklist" => Ok(PacMode::Blacklist),
+            _ => Err(format!("unknown mode {s}, expected whitelist or blacklist")),
+        }
+    }
+}
+
+/// How the generated file stores hosts to look them up
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MatchStrategy {
+    /// Sorted array searched by bisection, the smallest file
+    #[default]
+    Binary,
+    /// Object literal keyed by entry, constant time lookups
+    Object,
+    /// Object literals nested by label from the top level domain down, shared
+    /// suffixes are stored once
+    Trie,
+    /// Sorted arrays of [`CHUNK_SIZE`] entries on average, an entry's chunk
+    /// is picked by its hash so single chunks may hold more. Keeps every
+    /// literal small for interpreters that choke on huge ones
+    Chunked,
+}
+
+/// Average entries per chunk of [`MatchStrategy::Chunked`] lists, not a limit
+pub const CHUNK_SIZE: usize = 1024;
+
+impl MatchStrategy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MatchStrategy::Binary => "binary",
+            MatchStrategy::Object => "object",
+            MatchStrategy::Trie => "trie",
+            MatchStrategy::Chunked => "chunked",
+        }
+    }
+}
+
+impl FromStr for MatchStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "binary" => Ok(MatchStrategy::Binary),
+            "object" => Ok(MatchStrategy::Object),
+            "trie" => Ok(MatchStrategy::Trie),
+            "chunked" => Ok(MatchStrategy::Chunked),
+            _ => Err(format!(
+                "unknown match strategy {s}, expected binary, object, trie or chunked"
+            )),
+        }
+    }
+}
+
+/// Language level of the generated file
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JsTarget {
+    /// `var` and function expressions only, runs on every pac interpreter
+    #[default]
+    Es5,
+    /// `Set` lookups for the object strategy and a `Map` backed cache written
+    /// with `const`, classes and arrow functions
+    Es2015,
+}
+
+impl JsTarget {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JsTarget::Es5 => "es5",
+            JsTarget::Es2015 => "es2015",
+        }
+    }
+
+    fn cache_script(&self) -> &'static str {
+        match self {
+            JsTarget::Es5 => CACHE_SCRIPT,
+            JsTarget::Es2015 => CACHE_SCRIPT_ES2015,
+        }
+    }
+}
+
+impl FromStr for JsTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "es5" => Ok(JsTarget::Es5),
+            "es2015" => Ok(JsTarget::Es2015),
+            _ => Err(format!("unknown js target {s}, expected es5 or es2015")),
+        }
+    }
+}
+
+/// Everything besides the hosts a pac is generated from
+#[derive(Debug, Clone, Copy)]
+pub struct PacOptions<'a> {
+    /// Proxy chain of the hosts the mode proxies
+    pub proxy: &'a str,
+    pub groups: &'a [PacGroup],
+    /// Spread proxied hosts over these instead of `proxy` when not empty
+    pub upstreams: &'a [Upstream],
+    pub mode: PacMode,
+    /// Sorted patterns always sent DIRECT, checked before anything else but
+    /// `blocklist`
+    pub exclusions: &'a [String],
+    /// Sorted patterns sent to a proxy that refuses every connection, checked
+    /// first
+    pub blocklist: &'a [String],
+    /// Send plain host names and private network addresses DIRECT
+    pub bypass_private: bool,
+    pub matching: MatchStrategy,
+    /// Define `FindProxyForURLEx` and handle IPv6 literals
+    pub ipv6: bool,
+    /// Checked from the longest prefix down, the first one containing
+    /// `myIpAddress()` replaces `proxy` and `upstreams`
+    pub networks: &'a [NetworkProfile],
+    /// IPv4 networks in CIDR notation, hosts no name matched count as listed
+    /// when `dnsResolve` puts them into one. Blocks the client on DNS lookups
+    pub ip_ranges: &'a [String],
+    pub js_target: JsTarget,
+}
+
+impl Default for PacOptions<'_> {
+    fn default() -> Self {
+        Self {
+            proxy: DEFAULT_PROXY,
+            groups: &[],
+            upstreams: &[],
+            mode: PacMode::default(),
+            exclusions: &[],
+            blocklist: &[],
+            bypass_private: false,
+            matching: MatchStrategy::default(),
+            ipv6: false,
+            networks: &[],
+            ip_ranges: &[],
+            js_target: JsTarget::default(),
+        }
+    }
+}
 
 impl Pac {
     pub fn new(file: String, hash: String) -> Self {
-        Self { file, hash }
+        Self {
+            file,
+            hash,
+            hosts: vec![],
+            meta: None,
+        }
     }
 
-    /// `hosts` should be sorted for binary search in a pac file
+    /// `hosts` are sorted and deduplicated for binary search in a pac file, so
+    /// the same set always gets the same hash
     pub fn generate(hosts: Vec<String>) -> Self {
-        let hosts_bytes: usize = hosts.iter().map(|h| h.len()).sum();
+        Self::generate_with_proxy(hosts, DEFAULT_PROXY)
+    }
+
+    /// Same as [`Pac::generate`] with a custom proxy chain, which is part of the hash
+    pub fn generate_with_proxy(hosts: Vec<String>, proxy: &str) -> Self {
+        Self::generate_grouped(hosts, proxy, &[])
+    }
+
+    /// Same as [`Pac::generate_with_proxy`] with proxy groups, `hosts` of the
+    /// result lists the hosts of every group as well
+    pub fn generate_grouped(hosts: Vec<String>, proxy: &str, groups: &[PacGroup]) -> Self {
+        Self::generate_with_options(
+            hosts,
+            &PacOptions {
+                proxy,
+                groups,
+                ..Default::default()
+            },
+        )
+    }
+
+    /// Generates a pac with every option, the blocklist and exclusions are
+    /// checked first, then
+    /// groups before the mode decides about the rest of the hosts. Hosts of the
+    /// list, of each group and exclusions are hashed in sorted order, the order
+    /// of groups and upstreams is significant. Entries a suffix entry of the
+    /// same list already covers are left out of the file and the hash
+    pub fn generate_with_options(hosts: Vec<String>, options: &PacOptions) -> Self {
+        let PacOptions {
+            proxy,
+            groups,
+            upstreams,
+            mode,
+            exclusions,
+            blocklist,
+            bypass_private,
+            matching,
+            ipv6,
+            networks,
+            ip_ranges,
+            js_target,
+        } = *options;
+        let mut hosts = hosts;
+        hosts.sort_unstable();
+        hosts.dedup();
+        let listed = pruned(&hosts, "hosts");
+        let hosts_bytes: usize = listed.iter().map(|h| h.len()).sum();
         let mut hasher = sha2::Sha512::new();
         let mut file =
-            String::with_capacity(18 + 3 + JS_SCRIPT.len() + hosts_bytes + hosts.len() * 3);
-        file.push_str("var __HOSTS__ = [");
-        for host in hosts.into_iter() {
-            let s = format!(r#""{host}","#);
-            file.push_str(&s);
+            String::with_capacity(18 + 3 + JS_SCRIPT.len() + hosts_bytes + listed.len() * 3);
+        file.push_str("var __HOSTS__ = ");
+        file.push_str(&js_hosts(&listed, matching, js_target, &mut hasher));
+        file.push_str(";\n");
+        file.push_str(&format!("var __PROXY__ = {};\n", js_string(proxy)));
+        if proxy != DEFAULT_PROXY {
+            // Keeps hashes of default files unchanged
+            hasher.update(proxy.as_bytes());
+        }
+        file.push_str("var __GROUPS__ = [");
+        for (i, group) in groups.iter().enumerate() {
+            if i > 0 {
+                file.push(',');
+            }
+            hasher.update(b"\n");
+            let proxy = match &group.proxy {
+                Some(proxy) => {
+                    hasher.update(proxy.as_bytes());
+                    js_string(proxy)
+                }
+                None => {
+                    hasher.update(b"\0listed");
+                    "null".to_string()
+                }
+            };
+            file.push_str(&format!("{{proxy: {proxy}, hosts: "));
+            file.push_str(&js_hosts(
+                &pruned(&sorted(&group.hosts), "group hosts"),
+                matching,
+                js_target,
+                &mut hasher,
+            ));
+            if let Some(schedule) = &group.schedule {
+                hasher.update(b"\nschedule ");
+                hasher.update(schedule.to_string().as_bytes());
+                file.push_str(", schedule: ");
+                file.push_str(&js_schedule(schedule));
+            }
+            file.push('}');
+        }
+        file.push_str("];\n");
+        file.push_str("var __UPSTREAMS__ = [");
+        for (i, upstream) in upstreams.iter().enumerate() {
+            if i > 0 {
+                file.push(',');
+            }
+            let s = format!(
+                "{{proxy: {}, weight: {}}}",
+                js_string(&upstream.proxy),
+                upstream.weight
+            );
+            hasher.update(b"\n");
             hasher.update(s.as_bytes());
+            file.push_str(&s);
+        }
+        file.push_str("];\n");
+        file.push_str(&format!(
+            "var __INVERTED__ = {};\n",
+            mode == PacMode::Blacklist
+        ));
+        if mode != PacMode::Whitelist {
+            hasher.update(b"\n");
+            hasher.update(mode.as_str().as_bytes());
         }
-        if hosts_bytes > 0 {
-            file.pop();
+        file.push_str("var __EXCLUSIONS__ = ");
+        if !exclusions.is_empty() {
+            hasher.update(b"\nexclusions\n");
+        }
+        file.push_str(&js_hosts(
+            &sorted(exclusions),
+            matching,
+            js_target,
+            &mut hasher,
+        ));
+        file.push_str(";\n");
+        file.push_str("var __BLOCKLIST__ = ");
+        if !blocklist.is_empty() {
+            hasher.update(b"\nblocklist\n");
+        }
+        file.push_str(&js_hosts(
+            &sorted(blocklist),
+            matching,
+            js_target,
+            &mut hasher,
+        ));
+        file.push_str(";\n");
+        file.push_str(&format!("var __BYPASS_PRIVATE__ = {bypass_private};\n"));
+        if bypass_private {
+            hasher.update(b"\nbypass_private");
+        }
+        file.push_str(&format!(
+            "var __MATCH_STRATEGY__ = {};\n",
+            js_string(matching.as_str())
+        ));
+        if matching != MatchStrategy::Binary {
+            hasher.update(b"\nmatch\n");
+            hasher.update(matching.as_str().as_bytes());
+        }
+        file.push_str(&format!("var __IPV6__ = {ipv6};\n"));
+        file.push_str("var __NETWORKS__ = [");
+        for (i, (address, mask, network)) in sorted_networks(networks).iter().enumerate() {
+            if i > 0 {
+                file.push(',');
+            }
+            let s = format!(
+                "{{name: {}, address: \"{address}\", mask: \"{mask}\", proxy: {}}}",
+                js_string(&network.name),
+                js_string(&network.proxy)
+            );
+            hasher.update(b"\nnetwork ");
+            hasher.update(s.as_bytes());
+            file.push_str(&s);
         }
         file.push_str("];\n");
-        file.push_str(r#"var __PROXY__ = "SOCKS5 127.0.0.1:1080; SOCKS 127.0.0.1:1080; DIRECT;""#);
-        file.push('\n');
+        let ip_ranges: Vec<String> = sorted(ip_ranges)
+            .iter()
+            .filter_map(|range| {
+                let (address, prefix) = parse_network(range).ok()?;
+                Some(format!("[\"{address}\", \"{}\"]", prefix_mask(prefix)))
+            })
+            .collect();
+        for range in ip_ranges.iter() {
+            hasher.update(b"\nip_range ");
+            hasher.update(range.as_bytes());
+        }
+        file.push_str(&format!("var __IP_RANGES__ = [{}];\n", ip_ranges.join(",")));
+        file.push_str(&format!(
+            "var __JS_TARGET__ = {};\n",
+            js_string(js_target.as_str())
+        ));
+        if js_target != JsTarget::Es5 {
+            hasher.update(b"\njs_target ");
+            hasher.update(js_target.as_str().as_bytes());
+        }
         file.push_str(JS_SCRIPT);
+        file.push_str(js_target.cache_script());
+        if ipv6 {
+            hasher.update(b"\nipv6");
+            file.push_str(IPV6_SCRIPT);
+        }
         let hash = URL_SAFE.encode(hasher.finalize()).to_string();
-        Pac { file, hash }
+
+        if !groups.is_empty() {
+            hosts.extend(groups.iter().flat_map(|g| g.hosts.iter().cloned()));
+            hosts.sort();
+        }
+        let meta = PacMeta {
+            generated_at: unix_now(),
+            host_count: hosts.len() as i64,
+            qpac_version: env!("CARGO_PKG_VERSION").to_string(),
+        };
+        // Not part of the hash, the same hosts get the same hash whenever
+        // they are generated
+        file.insert_str(0, &meta.header(&hash));
+        Pac {
+            file,
+            hash,
+            hosts,
+            meta: Some(meta),
+        }
+    }
+}
+
+impl PacMeta {
+    /// Comment block starting a generated file
+    fn header(&self, hash: &str) -> String {
+        format!(
+            "// Generated by qpac {} at {}\n// Hosts: {}\n// Hash: {hash}\n",
+            self.qpac_version,
+            rfc3339(self.generated_at),
+            self.host_count
+        )
+    }
+}
+
+/// BLAKE3 of a file body, used to detect corrupted storage
+pub fn checksum(file: &str) -> String {
+    blake3::hash(file.as_bytes()).to_hex().to_string()
+}
+
+/// Valid networks with their base address and netmask as `isInNet` takes
+/// them, by descending prefix length so the most specific one containing the
+/// client wins, then by name
+fn sorted_networks(networks: &[NetworkProfile]) -> Vec<(Ipv4Addr, Ipv4Addr, &NetworkProfile)> {
+    let mut networks: Vec<(u8, Ipv4Addr, &NetworkProfile)> = networks
+        .iter()
+        .filter_map(|n| {
+            let (address, prefix) = parse_network(&n.network).ok()?;
+            Some((prefix, address, n))
+        })
+        .collect();
+    networks.sort_by(|(a, _, x), (b, _, y)| b.cmp(a).then_with(|| x.name.cmp(&y.name)));
+    networks
+        .into_iter()
+        .map(|(prefix, address, n)| (address, prefix_mask(prefix), n))
+        .collect()
+}
+
+/// Sorted and deduplicated `values`, borrowed when they already are
+fn sorted(values: &[String]) -> Cow<'_, [String]> {
+    if values.windows(2).all(|w| w[0] < w[1]) {
+        return Cow::Borrowed(values);
+    }
+    let mut values = values.to_vec();
+    values.sort_unstable();
+    values.dedup();
+    Cow::Owned(values)
+}
+
+/// Sorted `values` without the entries another one of them already matches,
+/// pruned ones are logged
+fn pruned<'a>(values: &'a [String], list: &str) -> Cow<'a, [String]> {
+    let entries: HashSet<&str> = values.iter().map(String::as_str).collect();
+    let (kept, pruned): (Vec<&String>, Vec<&String>) =
+        values.iter().partition(|v| !is_shadowed(v, &entries));
+    if pruned.is_empty() {
+        return Cow::Borrowed(values);
+    }
+    tracing::debug!(
+        "Pruned {} shadowed {list}: {}",
+        pruned.len(),
+        pruned
+            .iter()
+            .map(|v| v.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    Cow::Owned(kept.into_iter().cloned().collect())
+}
+
+/// Whether a suffix entry covers `entry`, `.example.com` does so for
+/// `www.example.com`, `*.example.com` and `.a.example.com`, `*.example.com`
+/// for everything below `example.com`. Regex entries are never shadowed
+fn is_shadowed(entry: &str, entries: &HashSet<&str>) -> bool {
+    if host::regex_pattern(entry).is_some() {
+        return false;
+    }
+    let name = entry
+        .strip_prefix('.')
+        .or_else(|| host::wildcard_domain(entry))
+        .unwrap_or(entry);
+    let suffix = format!(".{name}");
+    if entry != suffix && entries.contains(suffix.as_str()) {
+        return true;
+    }
+    name.match_indices('.').any(|(i, _)| {
+        let parent = &name[i..];
+        entries.contains(parent) || entries.contains(format!("*{parent}").as_str())
+    })
+}
+
+/// Sorted `values` as the JS lookup structure of `matching`, values are fed to
+/// `hasher`. Object and trie keep regex entries in a `patterns` array next to
+/// the `lookup`, the object one is a `Set` past ES5. Object keys start with
+/// `#`, so entries like `__proto__` can't replace the object's prototype
+fn js_hosts(
+    values: &[String],
+    matching: MatchStrategy,
+    target: JsTarget,
+    hasher: &mut sha2::Sha512,
+) -> String {
+    if matching == MatchStrategy::Binary {
+        return format!("[{}]", js_array(values, hasher));
+    }
+    let (patterns, names): (Vec<String>, Vec<String>) = values
+        .iter()
+        .cloned()
+        .partition(|v| host::regex_pattern(v).is_some());
+    let patterns = js_array(&patterns, hasher);
+    for name in names.iter() {
+        hasher.update(format!("{},", js_string(name)).as_bytes());
+    }
+    let lookup = match matching {
+        MatchStrategy::Trie => {
+            let mut trie = Trie::default();
+            for name in names.iter() {
+                trie.insert(name);
+            }
+            trie.to_js()
+        }
+        MatchStrategy::Chunked => {
+            let mut chunks = vec![vec![]; names.len().div_ceil(CHUNK_SIZE).max(1)];
+            for name in names.iter() {
+                let len = chunks.len();
+                chunks[hash_host(name) % len].push(format!("{},", js_string(name)));
+            }
+            let chunks: Vec<String> = chunks
+                .into_iter()
+                .map(|chunk| format!("[{}]", chunk.concat().trim_end_matches(',')))
+                .collect();
+            format!("[{}]", chunks.join(","))
+        }
+        _ if target == JsTarget::Es2015 => {
+            let names: Vec<String> = names.iter().map(|n| js_string(n)).collect();
+            format!("new Set([{}])", names.join(", "))
+        }
+        _ => {
+            let keys: Vec<String> = names
+                .iter()
+                .map(|n| format!("{}: 1", js_string(&format!("#{n}"))))
+                .collect();
+            format!("{{{}}}", keys.join(", "))
+        }
+    };
+    format!("{{patterns: [{patterns}], lookup: {lookup}}}")
+}
+
+/// Same as `hashHost` of the script, chunks of entries are picked by it
+fn hash_host(host: &str) -> usize {
+    host.encode_utf16()
+        .fold(0u64, |h, c| (h * 31 + c as u64) % 2147483647) as usize
+}
+
+/// Labels of entries from the last one, `$` marks where an entry ends. Labels
+/// are keyed with a leading `#`, so they never collide with `$` or with
+/// special properties like `__proto__`. Leading dots of entries become empty
+/// labels
+#[derive(Debug, Default)]
+struct Trie<'a> {
+    end: bool,
+    children: BTreeMap<&'a str, Trie<'a>>,
+}
+
+impl<'a> Trie<'a> {
+    fn insert(&mut self, name: &'a str) {
+        let mut node = self;
+        for label in name.rsplit('.') {
+            node = node.children.entry(label).or_default();
+        }
+        node.end = true;
+    }
+
+    fn to_js(&self) -> String {
+        let mut keys: Vec<String> = self
+            .children
+            .iter()
+            .map(|(label, child)| format!("{}: {}", js_string(&format!("#{label}")), child.to_js()))
+            .collect();
+        if self.end {
+            keys.insert(0, "\"$\": 1".to_string());
+        }
+        format!("{{{}}}", keys.join(", "))
+    }
+}
+
+/// Comma separated JS string literals, each one is fed to `hasher`
+fn js_array(values: &[String], hasher: &mut sha2::Sha512) -> String {
+    let mut out = String::new();
+    for value in values.iter() {
+        let s = format!("{},", js_string(value));
+        out.push_str(&s);
+        hasher.update(s.as_bytes());
+    }
+    out.pop();
+    out
+}
+
+/// Arguments of the `weekdayRange` and `timeRange` checks of `schedule`
+fn js_schedule(schedule: &Schedule) -> String {
+    let days = match schedule.day_names() {
+        Some((first, last)) => format!("[{}, {}]", js_string(first), js_string(last)),
+        None => "null".to_string(),
+    };
+    let hours = match schedule.hours {
+        Some((start, end)) => format!("[{start}, {end}]"),
+        None => "null".to_string(),
+    };
+    format!("{{days: {days}, hours: {hours}, gmt: {}}}", schedule.gmt)
+}
+
+/// Quotes `value` as a JS string literal
+fn js_string(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            // Line terminators inside string literals before ES2019
+            '\u{2028}' | '\u{2029}' => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn escapes_js_string() {
+        assert_eq!(js_string("example.com"), r#""example.com""#);
+        assert_eq!(js_string(r#"a"b\c"#), r#""a\"b\\c""#);
+        assert_eq!(js_string("a\nb\u{2028}"), r#""a\nb\u2028""#);
+        assert_eq!(js_string("\u{0}"), r#""\u0000""#);
+    }
+
+    #[test]
+    fn proxy_changes_hash() {
+        let hosts = vec!["a".to_string()];
+        let default = Pac::generate(hosts.clone());
+        let custom = Pac::generate_with_proxy(hosts, "PROXY 10.0.0.1:3128");
+        assert_ne!(default.hash, custom.hash);
+        assert!(custom
+            .file
+            .contains(r#"var __PROXY__ = "PROXY 10.0.0.1:3128";"#));
+    }
+
+    #[test]
+    fn groups_change_hash() {
+        let hosts = vec!["a".to_string()];
+        let default = Pac::generate(hosts.clone());
+        let empty = Pac::generate_grouped(hosts.clone(), DEFAULT_PROXY, &[]);
+        assert_eq!(default.hash, empty.hash);
+
+        let groups = [PacGroup {
+            proxy: Some("PROXY 10.0.0.1:3128".to_string()),
+            hosts: vec!["b".to_string()],
+            schedule: None,
+        }];
+        let grouped = Pac::generate_grouped(hosts, DEFAULT_PROXY, &groups);
+        assert_ne!(default.hash, grouped.hash);
+        assert_eq!(grouped.hosts, vec!["a", "b"]);
+        assert!(grouped
+            .file
+            .contains(r#"var __GROUPS__ = [{proxy: "PROXY 10.0.0.1:3128", hosts: ["b"]}];"#));
+    }
+
+    #[test]
+    fn schedules_change_hash() {
+        let group = |schedule: Option<&str>| PacGroup {
+            proxy: None,
+            hosts: vec!["b".to_string()],
+            schedule: schedule.map(|s| s.parse().unwrap()),
+        };
+        let pac = |group| Pac::generate_grouped(vec![], DEFAULT_PROXY, &[group]);
+        let always = pac(group(None));
+        let weekdays = pac(group(Some("MON-FRI 9-17")));
+        let nights = pac(group(Some("22-6 GMT")));
+        assert_ne!(always.hash, weekdays.hash);
+        assert_ne!(weekdays.hash, nights.hash);
+        assert!(always
+            .file
+            .contains(r#"var __GROUPS__ = [{proxy: null, hosts: ["b"]}];"#));
+        assert!(weekdays.file.contains(
+            r#"hosts: ["b"], schedule: {days: ["MON", "FRI"], hours: [9, 17], gmt: false}}"#
+        ));
+        assert!(nights
+            .file
+            .contains(r#"schedule: {days: null, hours: [22, 6], gmt: true}}"#));
+    }
+
+    #[test]
+    fn upstreams_change_hash() {
+        let hosts = vec!["a".to_string()];
+        let default = Pac::generate(hosts.clone());
+        let empty = Pac::generate_with_options(hosts.clone(), &PacOptions::default());
+        assert_eq!(default.hash, empty.hash);
+
+        let upstreams = [
+            Upstream {
+                proxy: "PROXY 10.0.0.1:3128".to_string(),
+                weight: 1,
+            },
+            Upstream {
+                proxy: "PROXY 10.0.0.2:3128".to_string(),
+                weight: 3,
+            },
+        ];
+        let balanced = Pac::generate_with_options(
+            hosts.clone(),
+            &PacOptions {
+                upstreams: &upstreams,
+                ..Default::default()
+            },
+        );
+        assert_ne!(default.hash, balanced.hash);
+        assert!(balanced.file.contains(
+            r#"var __UPSTREAMS__ = [{proxy: "PROXY 10.0.0.1:3128", weight: 1},{proxy: "PROXY 10.0.0.2:3128", weight: 3}];"#
+        ));
+
+        let reweighted = Pac::generate_with_options(
+            hosts,
+            &PacOptions {
+                upstreams: &[upstreams[1].clone(), upstreams[0].clone()],
+                ..Default::default()
+            },
+        );
+        assert_ne!(balanced.hash, reweighted.hash);
+    }
+
+    #[test]
+    fn inverted_mode_changes_hash() {
+        let hosts = vec!["a".to_string()];
+        let default = Pac::generate(hosts.clone());
+        assert!(default.file.contains("var __INVERTED__ = false;\n"));
+
+        let inverted = Pac::generate_with_options(
+            hosts,
+            &PacOptions {
+                mode: PacMode::Blacklist,
+                ..Default::default()
+            },
+        );
+        assert_ne!(default.hash, inverted.hash);
+        assert!(inverted.file.contains("var __INVERTED__ = true;\n"));
+        assert_eq!("blacklist".parse(), Ok(PacMode::Blacklist));
+        assert!("greylist".parse::<PacMode>().is_err());
+    }
+
+    #[test]
+    fn options_change_hash() {
+        let hosts = vec!["a".to_string()];
+        let default = Pac::generate(hosts.clone());
+        let patterns = ["b.a".to_string()];
+        // Option set, line of the default file, line with the option
+        let cases = [
+            (
+                PacOptions {
+                    exclusions: &patterns,
+                    ..Default::default()
+                },
+                "var __EXCLUSIONS__ = [];",
+                r#"var __EXCLUSIONS__ = ["b.a"];"#,
+            ),
+            (
+                PacOptions {
+                    blocklist: &patterns,
+                    ..Default::default()
+                },
+                "var __BLOCKLIST__ = [];",
+                r#"var __BLOCKLIST__ = ["b.a"];"#,
+            ),
+            (
+                PacOptions {
+                    bypass_private: true,
+                    ..Default::default()
+                },
+                "var __BYPASS_PRIVATE__ = false;",
+                "var __BYPASS_PRIVATE__ = true;",
+            ),
+            (
+                PacOptions {
+                    ipv6: true,
+                    ..Default::default()
+                },
+                "var __IPV6__ = false;",
+                "var __IPV6__ = true;",
+            ),
+        ];
+        for (options, before, after) in cases {
+            let pac = Pac::generate_with_options(hosts.clone(), &options);
+            assert_ne!(default.hash, pac.hash, "{after}");
+            assert!(default.file.lines().any(|l| l == before), "{before}");
+            assert!(pac.file.lines().any(|l| l == after), "{after}");
+        }
+        assert!(!default.file.contains("FindProxyForURLEx"));
+        let ipv6 = Pac::generate_with_options(
+            hosts,
+            &PacOptions {
+                ipv6: true,
+                ..Default::default()
+            },
+        );
+        assert!(ipv6.file.contains("function FindProxyForURLEx("));
+    }
+
+    #[test]
+    fn match_strategy_changes_hash() {
+        let hosts = vec![
+            ".a.com".to_string(),
+            "/^b$/".to_string(),
+            "c.b.com".to_string(),
+        ];
+        let default = Pac::generate(hosts.clone());
+        let trie = Pac::generate_with_options(
+            hosts.clone(),
+            &PacOptions {
+                matching: MatchStrategy::Trie,
+                ..Default::default()
+            },
+        );
+        let object = Pac::generate_with_options(
+            hosts.clone(),
+            &PacOptions {
+                matching: MatchStrategy::Object,
+                ..Default::default()
+            },
+        );
+        assert_ne!(default.hash, trie.hash);
+        assert_ne!(trie.hash, object.hash);
+        assert!(trie.file.contains(
+            r##"var __HOSTS__ = {patterns: ["/^b$/"], lookup: {"#com": {"#a": {"#": {"$": 1}}, "#b": {"#c": {"$": 1}}}}};"##
+        ));
+        assert!(object.file.contains(
+            r##"var __HOSTS__ = {patterns: ["/^b$/"], lookup: {"#.a.com": 1, "#c.b.com": 1}};"##
+        ));
+        assert!(trie.file.contains(r#"var __MATCH_STRATEGY__ = "trie";"#));
+
+        let chunked = Pac::generate_with_options(
+            hosts.clone(),
+            &PacOptions {
+                matching: MatchStrategy::Chunked,
+                ..Default::default()
+            },
+        );
+        assert_ne!(object.hash, chunked.hash);
+        assert!(chunked
+            .file
+            .contains(r#"var __HOSTS__ = {patterns: ["/^b$/"], lookup: [[".a.com","c.b.com"]]};"#));
+
+        let many: Vec<String> = (0..CHUNK_SIZE * 2 + 1).map(|i| format!("h{i}")).collect();
+        let chunked = Pac::generate_with_options(
+            many,
+            &PacOptions {
+                matching: MatchStrategy::Chunked,
+                ..Default::default()
+            },
+        );
+        let line = chunked
+            .file
+            .lines()
+            .find(|l| l.starts_with("var __HOSTS__"))
+            .unwrap();
+        assert_eq!(line.matches('[').count(), 2 + 3);
+    }
+
+    #[test]
+    fn normalizes_chains() {
+        assert_eq!(
+            normalize_chain(" socks5 a:1080 ;PROXY [::1]:3128;;direct; ").as_deref(),
+            Ok("SOCKS5 a:1080; PROXY [::1]:3128; DIRECT")
+        );
+        assert_eq!(
+            normalize_chain(DEFAULT_PROXY).as_deref(),
+            Ok("SOCKS5 127.0.0.1:1080; SOCKS 127.0.0.1:1080; DIRECT")
+        );
+        for invalid in [
+            "",
+            " ; ",
+            "PROXY",
+            "PROXY a",
+            "PROXY a:0",
+            "PROXY a:65536",
+            "PROXY :3128",
+            "PROXY a\":3128",
+            "PROXY [a]:3128",
+            "PROXY a:3128 b:3128",
+            "DIRECT a:3128",
+            "FTP a:21",
+        ] {
+            assert!(normalize_chain(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn js_target_changes_hash() {
+        let hosts = vec!["a".to_string()];
+        let default = Pac::generate(hosts.clone());
+        assert!(default.file.contains(r#"var __JS_TARGET__ = "es5";"#));
+        assert!(!default.file.contains("const "));
+
+        let modern = |matching| {
+            Pac::generate_with_options(
+                hosts.clone(),
+                &PacOptions {
+                    matching,
+                    js_target: JsTarget::Es2015,
+                    ..Default::default()
+                },
+            )
+        };
+        let es2015 = modern(MatchStrategy::Binary);
+        assert_ne!(default.hash, es2015.hash);
+        assert!(es2015.file.contains("class LRUCache"));
+        assert!(modern(MatchStrategy::Object)
+            .file
+            .contains(r#"lookup: new Set(["a"])"#));
+    }
+
+    #[test]
+    fn normalizes_networks() {
+        assert_eq!(
+            normalize_network(" 192.168.1.7/24").as_deref(),
+            Ok("192.168.1.0/24")
+        );
+        assert_eq!(normalize_network("10.8.0.1").as_deref(), Ok("10.8.0.1/32"));
+        assert_eq!(normalize_network("1.2.3.4/0").as_deref(), Ok("0.0.0.0/0"));
+        for invalid in ["", "10.0.0.0/33", "10.0.0/8", "::1/128", "10.0.0.0/x"] {
+            assert!(normalize_network(invalid).is_err(), "{invalid}");
+        }
+        assert_eq!(prefix_mask(20), Ipv4Addr::new(255, 255, 240, 0));
+    }
+
+    #[test]
+    fn networks_change_hash() {
+        let hosts = vec!["a".to_string()];
+        let default = Pac::generate(hosts.clone());
+        assert!(default.file.contains("var __NETWORKS__ = [];\n"));
+
+        let network = |name: &str, network: &str| NetworkProfile {
+            name: name.to_string(),
+            network: network.to_string(),
+            proxy: "DIRECT".to_string(),
+        };
+        let networks = [
+            network("home", "192.168.0.0/16"),
+            network("vpn", "10.8.0.0/24"),
+        ];
+        let pac = Pac::generate_with_options(
+            hosts,
+            &PacOptions {
+                networks: &networks,
+                ..Default::default()
+            },
+        );
+        assert_ne!(default.hash, pac.hash);
+        assert!(pac.file.contains(concat!(
+            r#"var __NETWORKS__ = [{name: "vpn", address: "10.8.0.0", mask: "255.255.255.0", "#,
+            r#"proxy: "DIRECT"},{name: "home", address: "192.168.0.0", mask: "255.255.0.0", "#,
+            r#"proxy: "DIRECT"}];"#
+        )));
+    }
+
+    #[test]
+    fn ip_ranges_change_hash() {
+        let hosts = vec!["a".to_string()];
+        let default = Pac::generate(hosts.clone());
+        assert!(default.file.contains("var __IP_RANGES__ = [];\n"));
+
+        let ranges = |ranges: &[&str]| {
+            let ranges: Vec<String> = ranges.iter().map(|r| r.to_string()).collect();
+            Pac::generate_with_options(
+                hosts.clone(),
+                &PacOptions {
+                    ip_ranges: &ranges,
+                    ..Default::default()
+                },
+            )
+        };
+        let pac = ranges(&["203.0.113.0/24", "198.51.100.7/32"]);
+        assert_ne!(default.hash, pac.hash);
+        assert_eq!(
+            pac.hash,
+            ranges(&["198.51.100.7/32", "203.0.113.0/24"]).hash
+        );
+        assert!(pac.file.contains(concat!(
+            r#"var __IP_RANGES__ = [["198.51.100.7", "255.255.255.255"],"#,
+            r#"["203.0.113.0", "255.255.255.0"]];"#
+        )));
+    }
+
+    #[test]
+    fn prunes_shadowed_entries() {
+        let hosts = |hosts: &[&str]| hosts.iter().map(|h| h.to_string()).collect::<Vec<_>>();
+        let kept = ["*.org", "*.other.net", ".example.com", "/^a$/", "other.net"];
+        let pac = Pac::generate(hosts(&[
+            ".example.com",
+            "www.example.com",
+            "*.example.com",
+            ".a.example.com",
+            "example.com",
+            "*.org",
+            "x.y.org",
+            "/^a$/",
+            "other.net",
+            "*.other.net",
+        ]));
+        assert!(pac.file.contains(&format!(
+            "var __HOSTS__ = [{}];",
+            kept.map(js_string).join(",")
+        )));
+        assert_eq!(pac.hash, Pac::generate(hosts(&kept)).hash);
+        assert_eq!(pac.hosts.len(), 10);
+    }
+
+    #[test]
+    fn hash_ignores_host_order() {
+        let hosts = |hosts: &[&str]| hosts.iter().map(|h| h.to_string()).collect::<Vec<_>>();
+        let a = Pac::generate(hosts(&["a.com", "b.com", "c.com"]));
+        let b = Pac::generate(hosts(&["c.com", "a.com", "b.com", "a.com"]));
+        assert_eq!(a.hash, b.hash);
+        assert_eq!(a.meta.map(|m| m.host_count), Some(3));
+
+        let group = |h: &[&str]| PacGroup {
+            proxy: Some("DIRECT".to_string()),
+            hosts: hosts(h),
+            schedule: None,
+        };
+        let a = Pac::generate_grouped(vec![], DEFAULT_PROXY, &[group(&["x", "y"])]);
+        let b = Pac::generate_grouped(vec![], DEFAULT_PROXY, &[group(&["y", "x"])]);
+        assert_eq!(a.hash, b.hash);
+    }
+
+    #[test]
+    fn writes_header() {
+        let pac = Pac::generate(vec!["a".to_string(), "b".to_string()]);
+        let mut lines = pac.file.lines();
+        assert!(lines.next().is_some_and(|l| l.starts_with(concat!(
+            "// Generated by qpac ",
+            env!("CARGO_PKG_VERSION"),
+            " at "
+        ))));
+        assert_eq!(lines.next(), Some("// Hosts: 2"));
+        assert_eq!(
+            lines.next(),
+            Some(format!("// Hash: {}", pac.hash).as_str())
+        );
+        assert_eq!(lines.next(), Some(r#"var __HOSTS__ = ["a","b"];"#));
+    }
+
+    #[test]
+    fn hosts_cant_break_out_of_array() {
+        let pac = Pac::generate(vec![r#"a"];alert(1);//"#.to_string()]);
+        assert_eq!(
+            pac.file.lines().find(|l| l.starts_with("var __HOSTS__")),
+            Some(r#"var __HOSTS__ = ["a\"];alert(1);//"];"#)
+        );
     }
 }
@@ -1,30 +1,279 @@
 var hosts = __HOSTS__;
 var proxy = __PROXY__;
+var groups = __GROUPS__;
+var upstreams = __UPSTREAMS__;
+var inverted = __INVERTED__;
+var exclusions = __EXCLUSIONS__;
+var blocklist = __BLOCKLIST__;
+var bypassPrivate = __BYPASS_PRIVATE__;
+var matchStrategy = __MATCH_STRATEGY__;
+var ipv6 = __IPV6__;
+var networks = __NETWORKS__;
+var ipRanges = __IP_RANGES__;
+var jsTarget = __JS_TARGET__;
 var DIRECT = "DIRECT;";
+// Nothing listens on the discard port, connections fail right away
+var BLOCKED = "PROXY 127.0.0.1:9;";
+// RFC 1918, loopback and link-local
+var PRIVATE_NETWORKS = [
+  ["10.0.0.0", "255.0.0.0"],
+  ["172.16.0.0", "255.240.0.0"],
+  ["192.168.0.0", "255.255.0.0"],
+  ["127.0.0.0", "255.0.0.0"],
+  ["169.254.0.0", "255.255.0.0"],
+];
+var IPV4 = /^\d{1,3}\.\d{1,3}\.\d{1,3}\.\d{1,3}$/;
 
-var cache = new LRUCache({ capacity: 1000 });
+var hostRegexes = regexesOf(hosts);
+var exclusionRegexes = regexesOf(exclusions);
+var blocklistRegexes = regexesOf(blocklist);
+// Decisions of scheduled groups change over the day, they aren't cached
+var scheduled = false;
+for (var k = 0; k < groups.length; k++) {
+  groups[k].regexes = regexesOf(groups[k].hosts);
+  scheduled = scheduled || !!groups[k].schedule;
+}
+// Set by the cache script of the JS target, appended to this one
+var cache;
 
 function FindProxyForURL(_url, host) {
-  var cachedValue = cache.get(host);
+  if (ipv6) {
+    host = unbracket(host);
+  }
+  // Clients move between networks while the file stays loaded. Keys always
+  // hold a space, so a host like __proto__ can't clobber the cache object
+  var network = currentNetwork();
+  var key = (network ? network.name : "") + " " + host;
+  var cachedValue = cache.get(key);
   if (cachedValue) {
     return cachedValue;
   }
 
-  if (binarySearch(host)) {
-    cache.put(host, proxy);
-    return proxy;
+  var result = lookup(host, network);
+  if (!scheduled) {
+    cache.put(key, result);
   }
+  return result;
+}
 
-  cache.put(host, DIRECT);
+// Blocked hosts never connect and exclusions always go direct, then groups are
+// checked before the default list, which holds the proxied hosts or, in
+// blacklist mode, the only direct ones
+function lookup(host, network) {
+  if (matches(blocklist, blocklistRegexes, host)) {
+    return BLOCKED;
+  }
+  if (bypassPrivate && isPrivate(host)) {
+    return DIRECT;
+  }
+  if (matches(exclusions, exclusionRegexes, host)) {
+    return DIRECT;
+  }
+  for (var g = 0; g < groups.length; g++) {
+    var group = groups[g];
+    if (isActive(group.schedule) && matches(group.hosts, group.regexes, host)) {
+      // Scheduled hosts without a group of their own go like listed ones
+      if (group.proxy === null) {
+        return inverted ? DIRECT : proxied(host, network);
+      }
+      return group.proxy;
+    }
+  }
+  if ((matches(hosts, hostRegexes, host) || inIpRanges(host)) !== inverted) {
+    return proxied(host, network);
+  }
   return DIRECT;
 }
 
-function binarySearch(host) {
+// Names are resolved only when no name rule matched, the lookup blocks the
+// client and the decision is cached like any other
+function inIpRanges(host) {
+  if (!ipRanges.length || host.indexOf(":") !== -1) {
+    return false;
+  }
+  var address = IPV4.test(host) ? host : dnsResolve(host);
+  if (!address) {
+    return false;
+  }
+  for (var i = 0; i < ipRanges.length; i++) {
+    if (isInNet(address, ipRanges[i][0], ipRanges[i][1])) {
+      return true;
+    }
+  }
+  return false;
+}
+
+function proxied(host, network) {
+  if (network) {
+    return network.proxy;
+  }
+  return upstreams.length ? pickUpstream(host) : proxy;
+}
+
+// Most specific network holding the client's address, null elsewhere
+function currentNetwork() {
+  if (!networks.length) {
+    return null;
+  }
+  var address = myIpAddress();
+  for (var n = 0; n < networks.length; n++) {
+    if (isInNet(address, networks[n].address, networks[n].mask)) {
+      return networks[n];
+    }
+  }
+  return null;
+}
+
+// Ranges are checked on the client clock, GMT ones in UTC
+function isActive(schedule) {
+  if (!schedule) {
+    return true;
+  }
+  var days = schedule.days;
+  var hours = schedule.hours;
+  if (schedule.gmt) {
+    return (
+      (!days || weekdayRange(days[0], days[1], "GMT")) &&
+      (!hours || timeRange(hours[0], hours[1], "GMT"))
+    );
+  }
+  return (
+    (!days || weekdayRange(days[0], days[1])) &&
+    (!hours || timeRange(hours[0], hours[1]))
+  );
+}
+
+// Only address literals are checked, isInNet on a name would resolve it.
+// IPv6 literals have no dots either, they are never taken for plain names
+function isPrivate(host) {
+  if (host.indexOf(":") !== -1) {
+    return ipv6 && isPrivateV6(host);
+  }
+  if (isPlainHostName(host)) {
+    return true;
+  }
+  if (!IPV4.test(host)) {
+    return false;
+  }
+  for (var i = 0; i < PRIVATE_NETWORKS.length; i++) {
+    if (isInNet(host, PRIVATE_NETWORKS[i][0], PRIVATE_NETWORKS[i][1])) {
+      return true;
+    }
+  }
+  return false;
+}
+
+// Same host always lands on the same upstream, spread by weight
+function pickUpstream(host) {
+  var total = 0;
+  for (var i = 0; i < upstreams.length; i++) {
+    total += upstreams[i].weight;
+  }
+
+  var n = hashHost(host) % total;
+  for (var j = 0; j < upstreams.length; j++) {
+    n -= upstreams[j].weight;
+    if (n < 0) {
+      return upstreams[j].proxy;
+    }
+  }
+  return proxy;
+}
+
+// Stays within integer precision of doubles, Math.imul isn't available
+// everywhere. Also picks the chunk of an entry, the server hashes alike
+function hashHost(host) {
+  var h = 0;
+  for (var i = 0; i < host.length; i++) {
+    h = (h * 31 + host.charCodeAt(i)) % 2147483647;
+  }
+  return h;
+}
+
+// Entries between slashes are regex patterns, they sort before every entry
+// starting with a digit or a letter. Other strategies keep them apart
+function regexesOf(hosts) {
+  var entries = matchStrategy === "binary" ? hosts : hosts.patterns;
+  var regexes = [];
+  for (var i = 0; i < entries.length && entries[i] < "0"; i++) {
+    var entry = entries[i];
+    if (entry.length > 2 && entry.charAt(0) === "/") {
+      try {
+        regexes.push(new RegExp(entry.substring(1, entry.length - 1)));
+      } catch (_e) {
+        // Skipped like the server does, a broken pattern mustn't break the file
+      }
+    }
+  }
+  return regexes;
+}
+
+// Entries starting with a dot match the domain itself and all of its subdomains,
+// ones starting with "*." only its subdomains
+function matches(hosts, regexes, host) {
+  if (contains(hosts, host) || contains(hosts, "." + host)) {
+    return true;
+  }
+
+  var i = host.indexOf(".");
+  while (i !== -1) {
+    var suffix = host.substring(i);
+    if (contains(hosts, suffix) || contains(hosts, "*" + suffix)) {
+      return true;
+    }
+    i = host.indexOf(".", i + 1);
+  }
+
+  for (var r = 0; r < regexes.length; r++) {
+    if (regexes[r].test(host)) {
+      return true;
+    }
+  }
+  return false;
+}
+
+function contains(hosts, entry) {
+  if (matchStrategy === "object") {
+    // Newer targets get a Set instead of an object literal
+    return jsTarget === "es5"
+      ? hasOwn(hosts.lookup, "#" + entry)
+      : hosts.lookup.has(entry);
+  }
+  if (matchStrategy === "trie") {
+    return inTrie(hosts.lookup, entry);
+  }
+  if (matchStrategy === "chunked") {
+    var chunks = hosts.lookup;
+    return binarySearch(chunks[hashHost(entry) % chunks.length], entry);
+  }
+  return binarySearch(hosts, entry);
+}
+
+function hasOwn(object, key) {
+  return Object.prototype.hasOwnProperty.call(object, key);
+}
+
+// Walks the labels from the last one, keyed with a "#" in front, "$" marks the
+// end of an entry
+function inTrie(trie, entry) {
+  var labels = entry.split(".");
+  var node = trie;
+  for (var i = labels.length - 1; i >= 0; i--) {
+    var key = "#" + labels[i];
+    if (!hasOwn(node, key)) {
+      return false;
+    }
+    node = node[key];
+  }
+  return hasOwn(node, "$");
+}
+
+function binarySearch(hosts, host) {
   var left = 0;
   var right = hosts.length - 1;
 
   while (left <= right) {
-    var mid = (left + right) / 2;
+    var mid = Math.floor((left + right) / 2);
 
     if (hosts[mid] === host) {
       return true;
@@ -39,68 +288,3 @@ function binarySearch(host) {
 
   return false;
 }
-
-// https://gist.github.com/lucaong/cc6ac6e65e598217fc6f
-function LRUCache(options) {
-  this._options = options || {};
-  this._map = {};
-  this._queue = {};
-  this._capacity = this._options.capacity || 10;
-  this._size = 0;
-}
-
-var _detachFromQueue = function (node, queue) {
-  if (node === queue.first) queue.first = node.next;
-  if (node === queue.last) queue.last = node.prev;
-  if (node.prev != null) node.prev.next = node.next;
-  if (node.next != null) node.next.prev = node.prev;
-};
-
-var _moveToLast = function (node, queue) {
-  node.prev = queue.last;
-  node.next = null;
-  if (queue.last != null) queue.last.next = node;
-  queue.last = node;
-  if (queue.first == null) queue.first = node;
-};
-
-LRUCache.prototype.put = function (key, value) {
-  var replaced = this.delete(key);
-  var queue = this._queue;
-  var node = { value: value, key: key };
-  _moveToLast(node, queue);
-  this._map[key] = node;
-  this._size += 1;
-  if (this._size > this._capacity) this.delete(this._queue.first.key);
-  return replaced;
-};
-
-LRUCache.prototype.get = function (key) {
-  var node = this._map[key];
-  if (node == null) return null;
-  if (this._options.touchOnGet) {
-    _detachFromQueue(node, this._queue);
-    _moveToLast(node, this._queue);
-  }
-  return node.value;
-};
-
-LRUCache.prototype.delete = function (key) {
-  var node = this._map[key];
-  if (node == null) {
-    return false;
-  } else {
-    _detachFromQueue(node, this._queue);
-    delete this._map[key];
-    this._size -= 1;
-    return true;
-  }
-};
-
-LRUCache.prototype.forEach = function (callback, thisArg) {
-  var node = this._queue.first;
-  while (node != null) {
-    callback.call(thisArg, node.value, node.key);
-    node = node.next;
-  }
-};
@@ -0,0 +1,38 @@
+// Included with --js-target es2015. Map keeps insertion order, its first key
+// is the least recently used one
+class LRUCache {
+  constructor({ capacity = 10, touchOnGet = false } = {}) {
+    this._capacity = capacity;
+    this._touchOnGet = touchOnGet;
+    this._map = new Map();
+  }
+
+  put(key, value) {
+    const replaced = this._map.delete(key);
+    this._map.set(key, value);
+    if (this._map.size > this._capacity) {
+      this._map.delete(this._map.keys().next().value);
+    }
+    return replaced;
+  }
+
+  get(key) {
+    if (!this._map.has(key)) return null;
+    const value = this._map.get(key);
+    if (this._touchOnGet) {
+      this._map.delete(key);
+      this._map.set(key, value);
+    }
+    return value;
+  }
+
+  delete(key) {
+    return this._map.delete(key);
+  }
+
+  forEach(callback, thisArg) {
+    this._map.forEach((value, key) => callback.call(thisArg, value, key));
+  }
+}
+
+cache = new LRUCache({ capacity: 1000 });
@@ -0,0 +1,104 @@
+// Pac helpers browsers provide, for the embedded evaluator. Names resolve only
+// to what __RESOLVED__ holds, myIpAddress returns __MY_IP_ADDRESS__ and the
+// date functions see __NOW__ unless it's null
+var IPV4_LITERAL = /^\d{1,3}\.\d{1,3}\.\d{1,3}\.\d{1,3}$/;
+
+function isPlainHostName(host) {
+  return host.indexOf(".") === -1;
+}
+
+function dnsDomainIs(host, domain) {
+  return (
+    host.length >= domain.length &&
+    host.substring(host.length - domain.length) === domain
+  );
+}
+
+function localHostOrDomainIs(host, hostdom) {
+  return host === hostdom || hostdom.lastIndexOf(host + ".", 0) === 0;
+}
+
+function dnsDomainLevels(host) {
+  return host.split(".").length - 1;
+}
+
+function dnsResolve(host) {
+  if (IPV4_LITERAL.test(host)) {
+    return host;
+  }
+  if (Object.prototype.hasOwnProperty.call(__RESOLVED__, host)) {
+    return __RESOLVED__[host];
+  }
+  return null;
+}
+
+function isResolvable(host) {
+  return dnsResolve(host) !== null;
+}
+
+function myIpAddress() {
+  return __MY_IP_ADDRESS__;
+}
+
+function convertAddr(ip) {
+  var bytes = ip.split(".");
+  return (
+    ((bytes[0] << 24) | (bytes[1] << 16) | (bytes[2] << 8) | bytes[3]) >>> 0
+  );
+}
+
+function isInNet(host, pattern, mask) {
+  var ip = dnsResolve(host);
+  if (ip === null || !IPV4_LITERAL.test(ip)) {
+    return false;
+  }
+  var m = convertAddr(mask);
+  return (convertAddr(ip) & m) >>> 0 === (convertAddr(pattern) & m) >>> 0;
+}
+
+function shExpMatch(str, shexp) {
+  var pattern = shexp
+    .replace(/[.+^${}()|[\]\\]/g, "\\$&")
+    .replace(/\*/g, ".*")
+    .replace(/\?/g, ".");
+  return new RegExp("^" + pattern + "$").test(str);
+}
+
+var WEEKDAYS = ["SUN", "MON", "TUE", "WED", "THU", "FRI", "SAT"];
+
+function now() {
+  return __NOW__ === null ? new Date() : new Date(__NOW__ * 1000);
+}
+
+function inRange(value, first, last) {
+  return first <= last
+    ? value >= first && value <= last
+    : value >= first || value <= last;
+}
+
+function weekdayRange(wd1, wd2, gmt) {
+  if (wd2 === "GMT") {
+    gmt = wd2;
+    wd2 = undefined;
+  }
+  var date = now();
+  var day = gmt === "GMT" ? date.getUTCDay() : date.getDay();
+  var first = WEEKDAYS.indexOf(wd1);
+  var last = wd2 === undefined ? first : WEEKDAYS.indexOf(wd2);
+  return inRange(day, first, last);
+}
+
+// Only the hour forms, the end hour itself is outside the range
+function timeRange() {
+  var args = Array.prototype.slice.call(arguments);
+  var gmt = args[args.length - 1] === "GMT";
+  if (gmt) {
+    args.pop();
+  }
+  var date = now();
+  var hour = gmt ? date.getUTCHours() : date.getHours();
+  if (args.length === 1) {
+    return hour === args[0];
+  }
+  return args.length === 2 && inRange(hour, args[0], args[1] - 1);
+}
@@ -0,0 +1,36 @@
+
+// Included with --ipv6. Clients supporting the Ex functions call
+// FindProxyForURLEx instead, which may hand over IPv6 literals in brackets
+function FindProxyForURLEx(url, host) {
+  return FindProxyForURL(url, host);
+}
+
+// Unique local, link-local and loopback
+var PRIVATE_NETWORKS_V6 = ["fc00::/7", "fe80::/10", "::1/128"];
+
+function unbracket(host) {
+  if (host.charAt(0) === "[" && host.charAt(host.length - 1) === "]") {
+    return host.substring(1, host.length - 1);
+  }
+  return host;
+}
+
+// isInNetEx is only there along with FindProxyForURLEx support, others get a
+// prefix check of the literal
+function isPrivateV6(host) {
+  if (typeof isInNetEx === "function") {
+    for (var i = 0; i < PRIVATE_NETWORKS_V6.length; i++) {
+      if (isInNetEx(host, PRIVATE_NETWORKS_V6[i])) {
+        return true;
+      }
+    }
+    return false;
+  }
+  var address = host.toLowerCase();
+  return (
+    address === "::1" ||
+    /^(0{1,4}:){7}0{0,3}1$/.test(address) ||
+    /^f[cd][0-9a-f]{2}:/.test(address) ||
+    /^fe[89ab][0-9a-f]:/.test(address)
+  );
+}
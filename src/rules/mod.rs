@@ -0,0 +1,245 @@
+use std::str::FromStr;
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+/// Host matching shared by the pac generator and anything evaluating hosts
+/// on the server side
+pub trait Matcher {
+    /// Whether requests to `host` go through the proxy
+    fn matches(&self, host: &str) -> bool;
+}
+
+/// How a single entry matches hosts, new kinds are added here and to the
+/// pac.js lookup
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Rule {
+    /// The host itself
+    Exact(String),
+    /// The host and all of its subdomains
+    Subdomains(String),
+    /// Subdomains of the host but not the host itself, `*.example.com`
+    Wildcard(String),
+    /// Hosts the pattern matches, `/^cdn[0-9]+\.example\.com$/`
+    Regex(String),
+}
+
+impl Rule {
+    /// Exact rule for a plain host, wildcard one for `*.domain` and regex one
+    /// for `/pattern/`
+    pub fn from_host(host: &str) -> Self {
+        if let Some(pattern) = crate::host::regex_pattern(host) {
+            Rule::Regex(pattern.to_string())
+        } else if let Some(domain) = crate::host::wildcard_domain(host) {
+            Rule::Wildcard(domain.to_string())
+        } else {
+            Rule::Exact(host.to_string())
+        }
+    }
+
+    /// Entry as written to the pac host list, subdomain rules get a leading
+    /// dot, wildcard ones a leading `*.` and regex ones are put between slashes
+    pub fn pac_pattern(&self) -> String {
+        match self {
+            Rule::Exact(host) => host.clone(),
+            Rule::Subdomains(host) => format!(".{host}"),
+            Rule::Wildcard(host) => format!("*.{host}"),
+            Rule::Regex(pattern) => format!("/{pattern}/"),
+        }
+    }
+}
+
+impl Matcher for Rule {
+    fn matches(&self, host: &str) -> bool {
+        match self {
+            Rule::Exact(h) => host == h,
+            Rule::Subdomains(h) => host
+                .strip_suffix(h.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.ends_with('.')),
+            Rule::Wildcard(h) => host
+                .strip_suffix(h.as_str())
+                .is_some_and(|rest| rest.len() > 1 && rest.ends_with('.')),
+            Rule::Regex(pattern) => Regex::new(pattern).is_ok_and(|r| r.is_match(host)),
+        }
+    }
+}
+
+/// How a plain host entry matches, picked when it's added instead of being
+/// guessed from the name. Wildcard and regex entries are always `exact`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryKind {
+    /// Only the host itself, `example.com`
+    #[default]
+    Exact,
+    /// The host and its whole domain tree, `example.com` and `a.example.com`
+    Suffix,
+}
+
+impl EntryKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EntryKind::Exact => "exact",
+            EntryKind::Suffix => "suffix",
+        }
+    }
+
+    /// Rule of an entry `host` of this kind, errors for suffix entries that
+    /// aren't plain hosts
+    pub fn rule(&self, host: &str) -> Result<Rule, String> {
+        match (Rule::from_host(host), self) {
+            (Rule::Exact(host), EntryKind::Suffix) => Ok(Rule::Subdomains(host)),
+            (_, EntryKind::Suffix) => Err(format!(
+                "{host} is a wildcard or regex entry, only plain hosts can be suffix entries"
+            )),
+            (rule, EntryKind::Exact) => Ok(rule),
+        }
+    }
+}
+
+impl FromStr for EntryKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "exact" => Ok(EntryKind::Exact),
+            "suffix" => Ok(EntryKind::Suffix),
+            _ => Err(format!("unknown entry kind {s}, expected exact or suffix")),
+        }
+    }
+}
+
+/// Rules compiled into the sorted pattern list a pac file is generated from
+#[derive(Debug, Default, Clone)]
+pub struct RuleSet {
+    patterns: Vec<String>,
+    /// Compiled regex patterns, ones that don't compile never match
+    regexes: Vec<Regex>,
+}
+
+impl RuleSet {
+    pub fn new(rules: impl IntoIterator<Item = Rule>) -> Self {
+        let mut patterns: Vec<String> = rules.into_iter().map(|r| r.pac_pattern()).collect();
+        // Dot prefixed patterns have to be resorted for the binary search
+        patterns.sort();
+        let regexes = patterns
+            .iter()
+            .filter_map(|p| crate::host::regex_pattern(p))
+            .filter_map(|p| Regex::new(p).ok())
+            .collect();
+        Self { patterns, regexes }
+    }
+
+    pub fn patterns(&self) -> &[String] {
+        &self.patterns
+    }
+
+    pub fn into_patterns(self) -> Vec<String> {
+        self.patterns
+    }
+
+    fn contains(&self, pattern: &str) -> bool {
+        self.patterns
+            .binary_search_by(|p| p.as_str().cmp(pattern))
+            .is_ok()
+    }
+}
+
+impl Matcher for RuleSet {
+    /// Same lookup as `matches` in pac.js
+    fn matches(&self, host: &str) -> bool {
+        if self.contains(host) || self.contains(&format!(".{host}")) {
+            return true;
+        }
+        host.match_indices('.')
+            .any(|(i, _)| self.contains(&host[i..]) || self.contains(&format!("*{}", &host[i..])))
+            || self.regexes.iter().any(|r| r.is_match(host))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn matches_rules() {
+        let exact = Rule::Exact("example.com".to_string());
+        assert!(exact.matches("example.com"));
+        assert!(!exact.matches("www.example.com"));
+
+        let subdomains = Rule::Subdomains("example.com".to_string());
+        assert!(subdomains.matches("example.com"));
+        assert!(subdomains.matches("a.b.example.com"));
+        assert!(!subdomains.matches("badexample.com"));
+
+        let wildcard = Rule::Wildcard("example.com".to_string());
+        assert!(!wildcard.matches("example.com"));
+        assert!(wildcard.matches("a.b.example.com"));
+        assert!(!wildcard.matches(".example.com"));
+        assert!(!wildcard.matches("badexample.com"));
+
+        let regex = Rule::Regex(r"^cdn[0-9]+\.example\.com$".to_string());
+        assert!(regex.matches("cdn12.example.com"));
+        assert!(!regex.matches("cdn.example.com"));
+    }
+
+    #[test]
+    fn rules_of_entry_kinds() {
+        assert_eq!(
+            EntryKind::Exact.rule("example.com"),
+            Ok(Rule::Exact("example.com".to_string()))
+        );
+        assert_eq!(
+            EntryKind::Suffix.rule("example.com"),
+            Ok(Rule::Subdomains("example.com".to_string()))
+        );
+        assert_eq!(
+            EntryKind::Exact.rule("*.example.com"),
+            Ok(Rule::Wildcard("example.com".to_string()))
+        );
+        assert!(EntryKind::Suffix.rule("*.example.com").is_err());
+        assert!(EntryKind::Suffix.rule("/^a$/").is_err());
+        assert_eq!("suffix".parse(), Ok(EntryKind::Suffix));
+        assert!("domain".parse::<EntryKind>().is_err());
+    }
+
+    #[test]
+    fn rule_set_agrees_with_rules() {
+        let rules = vec![
+            Rule::Exact("a.com".to_string()),
+            Rule::Subdomains("b.com".to_string()),
+            Rule::Exact("x.c.com".to_string()),
+            Rule::Wildcard("d.com".to_string()),
+            Rule::Regex(r"^cdn[0-9]+\.e\.(com|net)$".to_string()),
+        ];
+        let set = RuleSet::new(rules.clone());
+        assert_eq!(
+            set.patterns(),
+            [
+                "*.d.com",
+                ".b.com",
+                r"/^cdn[0-9]+\.e\.(com|net)$/",
+                "a.com",
+                "x.c.com"
+            ]
+        );
+        for host in [
+            "a.com",
+            "www.a.com",
+            "b.com",
+            "x.b.com",
+            "c.com",
+            "x.c.com",
+            "xb.com",
+            "d.com",
+            "x.d.com",
+            "y.x.d.com",
+            "cdn1.e.com",
+            "cdn.e.net",
+            "cdn12.e.net",
+        ] {
+            let expected = rules.iter().any(|r| r.matches(host));
+            assert_eq!(set.matches(host), expected, "{host}");
+        }
+    }
+}
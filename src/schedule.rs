@@ -0,0 +1,190 @@
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// Day names `weekdayRange` takes, in its order
+pub const WEEKDAYS: [&str; 7] = ["SUN", "MON", "TUE", "WED", "THU", "FRI", "SAT"];
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum ScheduleError {
+    #[error("schedule is empty")]
+    Empty,
+
+    #[error("unknown weekday {0}, expected one of SUN, MON, TUE, WED, THU, FRI, SAT")]
+    UnknownDay(String),
+
+    #[error("invalid hour range {0}, expected e.g. 9-17 with distinct hours up to 23")]
+    InvalidHours(String),
+
+    #[error("{0} is given twice")]
+    Duplicate(&'static str),
+}
+
+/// When a host or group is routed, checked by the pac against the client clock
+/// with `weekdayRange` and `timeRange`. Written as e.g. `MON-FRI 9-17`, `SAT`,
+/// `22-6 GMT`, ranges wrap around like the pac functions do
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Schedule {
+    /// First and last day as indices of [`WEEKDAYS`]
+    pub days: Option<(u8, u8)>,
+    /// From the start of the first hour up to the second one
+    pub hours: Option<(u8, u8)>,
+    /// UTC instead of the client's time zone
+    pub gmt: bool,
+}
+
+impl Schedule {
+    pub fn day_names(&self) -> Option<(&'static str, &'static str)> {
+        self.days
+            .map(|(first, last)| (WEEKDAYS[first as usize], WEEKDAYS[last as usize]))
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = ScheduleError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut schedule = Schedule {
+            days: None,
+            hours: None,
+            gmt: false,
+        };
+        for token in s.split_whitespace() {
+            let token = token.to_ascii_uppercase();
+            if token == "GMT" {
+                if schedule.gmt {
+                    return Err(ScheduleError::Duplicate("GMT"));
+                }
+                schedule.gmt = true;
+            } else if token.starts_with(|c: char| c.is_ascii_digit()) {
+                if schedule.hours.is_some() {
+                    return Err(ScheduleError::Duplicate("hour range"));
+                }
+                schedule.hours = Some(parse_hours(&token)?);
+            } else {
+                if schedule.days.is_some() {
+                    return Err(ScheduleError::Duplicate("day range"));
+                }
+                let (first, last) = token.split_once('-').unwrap_or((&token, &token));
+                schedule.days = Some((parse_day(first)?, parse_day(last)?));
+            }
+        }
+        if schedule.days.is_none() && schedule.hours.is_none() {
+            return Err(ScheduleError::Empty);
+        }
+        Ok(schedule)
+    }
+}
+
+fn parse_day(day: &str) -> Result<u8, ScheduleError> {
+    WEEKDAYS
+        .iter()
+        .position(|d| *d == day)
+        .map(|i| i as u8)
+        .ok_or_else(|| ScheduleError::UnknownDay(day.to_string()))
+}
+
+fn parse_hours(range: &str) -> Result<(u8, u8), ScheduleError> {
+    let invalid = || ScheduleError::InvalidHours(range.to_string());
+    let (start, end) = range.split_once('-').ok_or_else(invalid)?;
+    let hour = |h: &str| h.parse::<u8>().ok().filter(|h| *h < 24).ok_or_else(invalid);
+    let (start, end) = (hour(start)?, hour(end)?);
+    if start == end {
+        return Err(invalid());
+    }
+    Ok((start, end))
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = vec![];
+        if let Some((first, last)) = self.day_names() {
+            parts.push(if first == last {
+                first.to_string()
+            } else {
+                format!("{first}-{last}")
+            });
+        }
+        if let Some((start, end)) = self.hours {
+            parts.push(format!("{start}-{end}"));
+        }
+        if self.gmt {
+            parts.push("GMT".to_string());
+        }
+        f.write_str(&parts.join(" "))
+    }
+}
+
+impl TryFrom<String> for Schedule {
+    type Error = ScheduleError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Schedule> for String {
+    fn from(value: Schedule) -> Self {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_and_formats() {
+        let cases = [
+            ("mon-fri 9-17", "MON-FRI 9-17"),
+            ("SAT", "SAT"),
+            ("sun-sun", "SUN"),
+            ("22-6 gmt", "22-6 GMT"),
+            ("  FRI-MON   0-23 GMT ", "FRI-MON 0-23 GMT"),
+        ];
+        for (input, expected) in cases {
+            let schedule: Schedule = input.parse().unwrap();
+            assert_eq!(schedule.to_string(), expected);
+            assert_eq!(expected.parse::<Schedule>(), Ok(schedule));
+        }
+    }
+
+    #[test]
+    fn rejects_invalid() {
+        assert_eq!("".parse::<Schedule>(), Err(ScheduleError::Empty));
+        assert_eq!("GMT".parse::<Schedule>(), Err(ScheduleError::Empty));
+        assert!(matches!(
+            "MON-FRIDAY".parse::<Schedule>(),
+            Err(ScheduleError::UnknownDay(_))
+        ));
+        assert!(matches!(
+            "9-24".parse::<Schedule>(),
+            Err(ScheduleError::InvalidHours(_))
+        ));
+        assert!(matches!(
+            "9-9".parse::<Schedule>(),
+            Err(ScheduleError::InvalidHours(_))
+        ));
+        assert!(matches!(
+            "9".parse::<Schedule>(),
+            Err(ScheduleError::InvalidHours(_))
+        ));
+        assert_eq!(
+            "MON TUE".parse::<Schedule>(),
+            Err(ScheduleError::Duplicate("day range"))
+        );
+    }
+
+    #[test]
+    fn serializes_as_string() {
+        let schedule: Schedule = serde_json::from_str(r#""mon-fri 9-17""#).unwrap();
+        assert_eq!(schedule.days, Some((1, 5)));
+        assert_eq!(
+            serde_json::to_string(&schedule).unwrap(),
+            r#""MON-FRI 9-17""#
+        );
+        assert!(serde_json::from_str::<Schedule>(r#""nope""#).is_err());
+    }
+}
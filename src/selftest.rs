@@ -0,0 +1,245 @@
+use std::{
+    future::Future,
+    net::{Ipv4Addr, SocketAddr, TcpListener},
+    process::Stdio,
+    time::Duration,
+};
+
+use reqwest::{Client, StatusCode};
+use serde_json::json;
+use tokio::process::{Child, Command};
+
+use crate::{args::SelftestArgs, error::Result, http_client::HttpClient, utils};
+
+const HOST: &str = "selftest.example.com";
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+const STEPS: [&str; 8] = [
+    "start", "auth", "add", "list", "host", "pac", "check", "remove",
+];
+
+/// Runs every step against a server started from this binary, returns
+/// whether all of them passed
+pub async fn run(http_client: &HttpClient, args: SelftestArgs) -> Result<bool> {
+    let addr = free_addr()?;
+    let token = utils::token::generate();
+    let mut server = spawn_server(addr, &token, args.server_output)?;
+    println!("Started server on {addr}");
+
+    let selftest = Selftest {
+        client: http_client.client().clone(),
+        base: format!("http://{addr}"),
+        token,
+        timeout: Duration::from_secs(args.timeout),
+    };
+    let passed = selftest.run_steps().await;
+    server.kill().await?;
+    if passed {
+        println!("All steps passed");
+    } else {
+        println!("Selftest failed");
+    }
+    Ok(passed)
+}
+
+/// Port the OS considers free, released right before the server binds it
+fn free_addr() -> std::io::Result<SocketAddr> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+    listener.local_addr()
+}
+
+/// Same binary, without the `QPAC_*` settings of the environment so that the
+/// server can't pick up a real database
+fn spawn_server(addr: SocketAddr, token: &str, output: bool) -> std::io::Result<Child> {
+    let mut command = Command::new(std::env::current_exe()?);
+    command
+        .args(["serve", "--bind", &addr.to_string(), "--token", token])
+        .kill_on_drop(true);
+    for (key, _) in std::env::vars_os() {
+        if key.to_string_lossy().starts_with("QPAC_") {
+            command.env_remove(key);
+        }
+    }
+    if !output {
+        command.stdout(Stdio::null()).stderr(Stdio::null());
+    }
+    command.spawn()
+}
+
+struct Selftest {
+    client: Client,
+    base: String,
+    token: String,
+    timeout: Duration,
+}
+
+impl Selftest {
+    /// Stops at the first failed step, later ones depend on it
+    async fn run_steps(&self) -> bool {
+        for name in STEPS {
+            match self.step(name).await {
+                Ok(()) => println!("ok   {name}"),
+                Err(e) => {
+                    println!("FAIL {name}: {e}");
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    async fn step(&self, name: &str) -> StepResult {
+        match name {
+            "start" => self.start().await,
+            "auth" => self.auth().await,
+            "add" => self.change("add").await,
+            "list" => self.list(true).await,
+            "host" => self.host().await,
+            "pac" => self.pac(true).await,
+            "check" => self.check().await,
+            "remove" => self.remove().await,
+            _ => Err(format!("unknown step {name}")),
+        }
+    }
+
+    async fn start(&self) -> StepResult {
+        self.poll(|| async {
+            let res = self.client.get(self.url("poll-hint")).send().await;
+            res.is_ok_and(|r| r.status().is_success())
+        })
+        .await
+        .map_err(|_| "server didn't come up".to_string())
+    }
+
+    /// Mutations need the token
+    async fn auth(&self) -> StepResult {
+        let res = self
+            .client
+            .post(self.url("add"))
+            .json(&json!({ "hosts": [HOST] }))
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        match res.status() {
+            StatusCode::UNAUTHORIZED => Ok(()),
+            status => Err(format!("expected 401 without a token, got {status}")),
+        }
+    }
+
+    async fn change(&self, path: &str) -> StepResult {
+        let res = self
+            .client
+            .post(self.url(path))
+            .bearer_auth(&self.token)
+            .json(&json!({ "hosts": [HOST] }))
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        let status = res.status();
+        let body: serde_json::Value = res.json().await.map_err(|e| e.to_string())?;
+        match body["success"].as_bool() {
+            Some(true) => Ok(()),
+            _ => Err(format!("{status}: {body}")),
+        }
+    }
+
+    async fn list(&self, listed: bool) -> StepResult {
+        let hosts: Vec<String> = self
+            .client
+            .get(self.url("list"))
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| e.to_string())?
+            .json()
+            .await
+            .map_err(|e| e.to_string())?;
+        if hosts.iter().any(|h| h == HOST) != listed {
+            return Err(format!("unexpected list {hosts:?}"));
+        }
+        Ok(())
+    }
+
+    /// Waits for the regenerated file, which is debounced
+    async fn pac(&self, listed: bool) -> StepResult {
+        let quoted = format!("\"{HOST}\"");
+        self.poll(|| async {
+            let Ok(res) = self.client.get(self.url("")).send().await else {
+                return false;
+            };
+            let is_js = res
+                .headers()
+                .get(reqwest::header::CONTENT_TYPE)
+                .is_some_and(|v| v == "text/javascript");
+            let body = res.text().await.unwrap_or_default();
+            is_js && body.contains("function FindProxyForURL") && body.contains(&quoted) == listed
+        })
+        .await
+        .map_err(|_| {
+            if listed {
+                format!("{HOST} never showed up in the pac")
+            } else {
+                format!("{HOST} is still in the pac")
+            }
+        })
+    }
+
+    async fn host(&self) -> StepResult {
+        let res = self
+            .client
+            .get(self.url(&format!("api/v1/hosts/{HOST}")))
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        match res.status() {
+            StatusCode::OK => Ok(()),
+            status => Err(format!("expected {HOST} to exist, got {status}")),
+        }
+    }
+
+    /// Runs the published pac through `/check`, the host has to be proxied
+    async fn check(&self) -> StepResult {
+        let body: serde_json::Value = self
+            .client
+            .get(self.url("check"))
+            .query(&[("url", format!("https://{HOST}/"))])
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| e.to_string())?
+            .json()
+            .await
+            .map_err(|e| e.to_string())?;
+        match body["direct"].as_bool() {
+            Some(false) => Ok(()),
+            _ => Err(format!("expected {HOST} to be proxied, got {body}")),
+        }
+    }
+
+    async fn remove(&self) -> StepResult {
+        self.change("remove").await?;
+        self.list(false).await?;
+        self.pac(false).await
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}/{path}", self.base)
+    }
+
+    /// Retries `ok` until it holds or the step timeout passes
+    async fn poll<F, Fut>(&self, ok: F) -> std::result::Result<(), ()>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = bool>,
+    {
+        let deadline = tokio::time::Instant::now() + self.timeout;
+        while tokio::time::Instant::now() < deadline {
+            if ok().await {
+                return Ok(());
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+        Err(())
+    }
+}
+
+type StepResult = std::result::Result<(), String>;
@@ -0,0 +1,125 @@
+use std::sync::Arc;
+
+use object_store::{
+    aws::AmazonS3Builder, path::Path, Attribute, Attributes, ObjectStore, PutOptions, PutPayload,
+};
+
+use crate::{args::BucketArgs, error::AppError};
+
+/// Key of the copy of the latest file
+const LATEST_KEY: &str = "latest.pac";
+
+/// S3 compatible bucket generated files are mirrored to, so a CDN in front of
+/// it can serve them. Storage stays the source of truth, nothing is read back
+#[derive(Debug, Clone)]
+pub struct PacBucket {
+    store: Arc<dyn ObjectStore>,
+    prefix: String,
+}
+
+impl PacBucket {
+    /// Objects are written to `<prefix><hash>.pac` and `<prefix>latest.pac`
+    pub fn new(store: Arc<dyn ObjectStore>, prefix: impl Into<String>) -> Self {
+        Self {
+            store,
+            prefix: prefix.into(),
+        }
+    }
+
+    /// `None` without a bucket name, credentials and region are taken from
+    /// the `AWS_*` variables
+    pub fn from_args(args: &BucketArgs) -> Result<Option<Self>, AppError> {
+        let Some(bucket) = &args.pac_bucket else {
+            return Ok(None);
+        };
+        let mut builder = AmazonS3Builder::from_env().with_bucket_name(bucket);
+        if let Some(endpoint) = &args.pac_bucket_endpoint {
+            builder = builder
+                .with_allow_http(endpoint.starts_with("http://"))
+                .with_endpoint(endpoint);
+        }
+        let store = builder.build().map_err(|e| AppError::Validation {
+            field: "pac_bucket".to_string(),
+            message: e.to_string(),
+        })?;
+        Ok(Some(Self::new(Arc::new(store), &args.pac_bucket_prefix)))
+    }
+
+    /// Same bucket with the keys of `tenant` under `<prefix><tenant>/`
+    pub fn for_tenant(&self, tenant: &str) -> Self {
+        Self::new(self.store.clone(), format!("{}{tenant}/", self.prefix))
+    }
+
+    fn path(&self, name: &str) -> Path {
+        Path::from(format!("{}{name}", self.prefix))
+    }
+
+    /// Writes the file under its hash, cached for good like `/:hash`
+    pub async fn put_file(&self, hash: &str, file: &str) -> Result<(), AppError> {
+        self.put(
+            &format!("{hash}.pac"),
+            file,
+            "public, max-age=31536000, immutable",
+        )
+        .await
+    }
+
+    /// Deletes the file stored under `hash`, missing ones are fine
+    pub async fn delete_file(&self, hash: &str) -> Result<(), AppError> {
+        match self.store.delete(&self.path(&format!("{hash}.pac"))).await {
+            Ok(()) | Err(object_store::Error::NotFound { .. }) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Replaces the copy of the latest file, CDNs revalidate it every minute
+    pub async fn put_latest(&self, file: &str) -> Result<(), AppError> {
+        self.put(LATEST_KEY, file, "public, max-age=60").await
+    }
+
+    async fn put(&self, name: &str, file: &str, cache_control: &str) -> Result<(), AppError> {
+        let mut attributes = Attributes::new();
+        attributes.insert(Attribute::ContentType, "text/javascript".into());
+        attributes.insert(Attribute::CacheControl, cache_control.to_string().into());
+        let opts = PutOptions {
+            attributes,
+            ..Default::default()
+        };
+        let payload = PutPayload::from(file.as_bytes().to_vec());
+        self.store.put_opts(&self.path(name), payload, opts).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use object_store::memory::InMemory;
+
+    use super::*;
+    use crate::{error::Result, pac::Pac};
+
+    #[tokio::test]
+    async fn writes_files_under_prefix() -> Result<()> {
+        let store = Arc::new(InMemory::new());
+        let bucket = PacBucket::new(store.clone(), "pac/");
+        let pac = Pac::generate(vec!["a".to_string()]);
+        bucket.put_file(&pac.hash, &pac.file).await?;
+        bucket.put_latest(&pac.file).await?;
+
+        let stored = store
+            .get(&Path::from(format!("pac/{}.pac", pac.hash)))
+            .await?;
+        assert_eq!(
+            stored.attributes.get(&Attribute::ContentType),
+            Some(&"text/javascript".into())
+        );
+        assert_eq!(stored.bytes().await?, pac.file.as_bytes());
+        let latest = store.get(&Path::from("pac/latest.pac")).await?;
+        assert_eq!(latest.bytes().await?, pac.file.as_bytes());
+
+        bucket.for_tenant("team").put_latest(&pac.file).await?;
+        let tenant = store.get(&Path::from("pac/team/latest.pac")).await?;
+        assert_eq!(tenant.bytes().await?, pac.file.as_bytes());
+        Ok(())
+    }
+}
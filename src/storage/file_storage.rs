@@ -0,0 +1,572 @@
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use futures::stream::BoxStream;
+use tokio::{io::AsyncWriteExt, sync::Mutex};
+
+use crate::{
+    error::{AppError, Result},
+    pac::{NetworkProfile, Pac, PacEncodings, PacMode, Upstream},
+};
+
+use super::{
+    memory_storage::MemoryStorage, AuditEntry, BlocklistEntry, ChangeEvent, ClientFetches,
+    DeletedHost, HostEntry, HostPatch, HostsDiff, ImportMode, InstanceState, PacVersion, Profile,
+    ProxyGroup, SnapshotInfo, Storage, StorageStats, TagInfo,
+};
+
+/// [`MemoryStorage`] written to a json file after every change, and read back
+/// from it on start. Leases and precompressed bodies aren't kept, the bodies
+/// are compressed again when the file is served
+#[derive(Debug)]
+pub struct FileStorage {
+    memory: MemoryStorage,
+    path: PathBuf,
+    /// Held while saving, so an older dump never replaces a newer one
+    saves: Mutex<()>,
+}
+
+impl FileStorage {
+    /// Loads `path`, or creates it when missing
+    pub async fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let memory = match tokio::fs::read(&path).await {
+            Ok(json) => MemoryStorage::restore(serde_json::from_slice(&json)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => MemoryStorage::default(),
+            Err(e) => Err(e)?,
+        };
+        let storage = Self {
+            memory,
+            path,
+            saves: Mutex::default(),
+        };
+        storage.save().await?;
+        Ok(storage)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Writes everything to a temporary file next to the state file and moves
+    /// it over, so a crash leaves either the old or the new state
+    async fn save(&self) -> Result<(), AppError> {
+        let _save = self.saves.lock().await;
+        let json = serde_json::to_vec(&self.memory.dump().await)
+            .map_err(|e| AppError::Other(e.to_string()))?;
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        let mut file = tokio::fs::File::create(&tmp)
+            .await
+            .map_err(|e| self.io_error(e))?;
+        file.write_all(&json).await.map_err(|e| self.io_error(e))?;
+        file.sync_all().await.map_err(|e| self.io_error(e))?;
+        tokio::fs::rename(&tmp, &self.path)
+            .await
+            .map_err(|e| self.io_error(e))?;
+        Ok(())
+    }
+
+    /// The change is kept in memory but isn't on disk, so callers should retry
+    fn io_error(&self, e: std::io::Error) -> AppError {
+        AppError::Unavailable(format!("{}: {e}", self.path.display()))
+    }
+}
+
+#[async_trait]
+impl Storage for FileStorage {
+    fn watch(&self) -> BoxStream<'static, ChangeEvent> {
+        self.memory.watch()
+    }
+
+    async fn watch_shared(&self) -> Result<Option<BoxStream<'static, ChangeEvent>>, AppError> {
+        self.memory.watch_shared().await
+    }
+
+    async fn all_hosts(&self) -> Result<Vec<String>, AppError> {
+        self.memory.all_hosts().await
+    }
+
+    async fn host_entries(&self) -> Result<Vec<HostEntry>, AppError> {
+        self.memory.host_entries().await
+    }
+
+    async fn hosts_page(
+        &self,
+        after: Option<&str>,
+        limit: u32,
+    ) -> Result<Vec<HostEntry>, AppError> {
+        self.memory.hosts_page(after, limit).await
+    }
+
+    async fn search_hosts(&self, pattern: &str) -> Result<Vec<String>, AppError> {
+        self.memory.search_hosts(pattern).await
+    }
+
+    async fn get_host(&self, host: &str) -> Result<HostEntry, AppError> {
+        self.memory.get_host(host).await
+    }
+
+    async fn update_host(&self, host: &str, patch: HostPatch) -> Result<HostEntry, AppError> {
+        let res = self.memory.update_host(host, patch).await?;
+        self.save().await?;
+        Ok(res)
+    }
+
+    async fn get_file(&self, hash: &str) -> Result<String, AppError> {
+        self.memory.get_file(hash).await
+    }
+
+    async fn get_file_latest(&self) -> Result<Pac, AppError> {
+        self.memory.get_file_latest().await
+    }
+
+    async fn latest_hash(&self) -> Result<String, AppError> {
+        self.memory.latest_hash().await
+    }
+
+    async fn get_checksum(&self, hash: &str) -> Result<String, AppError> {
+        self.memory.get_checksum(hash).await
+    }
+
+    async fn get_manifest(&self, hash: &str) -> Result<Vec<String>, AppError> {
+        self.memory.get_manifest(hash).await
+    }
+
+    async fn upload_file(&self, file: &Pac) -> Result<(), AppError> {
+        self.memory.upload_file(file).await?;
+        self.save().await
+    }
+
+    async fn upload_encodings(&self, hash: &str, encodings: &PacEncodings) -> Result<(), AppError> {
+        self.memory.upload_encodings(hash, encodings).await
+    }
+
+    async fn get_encodings(&self, hash: &str) -> Result<PacEncodings, AppError> {
+        self.memory.get_encodings(hash).await
+    }
+
+    async fn list_versions(&self, limit: u32) -> Result<Vec<PacVersion>, AppError> {
+        self.memory.list_versions(limit).await
+    }
+
+    async fn prune_files(&self, keep: u32, before: i64) -> Result<u64, AppError> {
+        let res = self.memory.prune_files(keep, before).await?;
+        self.save().await?;
+        Ok(res)
+    }
+
+    async fn get_version(&self, hash: &str) -> Result<i64, AppError> {
+        self.memory.get_version(hash).await
+    }
+
+    async fn get_version_hash(&self, version: i64) -> Result<String, AppError> {
+        self.memory.get_version_hash(version).await
+    }
+
+    async fn set_latest(&self, hash: &str) -> Result<(), AppError> {
+        self.memory.set_latest(hash).await?;
+        self.save().await
+    }
+
+    async fn staged_hash(&self) -> Result<Option<String>, AppError> {
+        self.memory.staged_hash().await
+    }
+
+    async fn set_staged_hash(&self, hash: Option<String>) -> Result<(), AppError> {
+        self.memory.set_staged_hash(hash).await?;
+        self.save().await
+    }
+
+    async fn promote_staged(&self) -> Result<String, AppError> {
+        let res = self.memory.promote_staged().await?;
+        self.save().await?;
+        Ok(res)
+    }
+
+    async fn add_host(&self, host: &str, patch: HostPatch) -> Result<(), AppError> {
+        self.memory.add_host(host, patch).await?;
+        self.save().await
+    }
+
+    async fn upsert_host(&self, host: &str, patch: HostPatch) -> Result<bool, AppError> {
+        let res = self.memory.upsert_host(host, patch).await?;
+        self.save().await?;
+        Ok(res)
+    }
+
+    async fn remove_host(&self, host: &str) -> Result<(), AppError> {
+        self.memory.remove_host(host).await?;
+        self.save().await
+    }
+
+    async fn add_hosts(
+        &self,
+        hosts: Vec<String>,
+        patch: HostPatch,
+    ) -> Result<Vec<String>, AppError> {
+        let res = self.memory.add_hosts(hosts, patch).await?;
+        self.save().await?;
+        Ok(res)
+    }
+
+    async fn upsert_hosts(
+        &self,
+        hosts: Vec<String>,
+        patch: HostPatch,
+    ) -> Result<Vec<String>, AppError> {
+        let res = self.memory.upsert_hosts(hosts, patch).await?;
+        self.save().await?;
+        Ok(res)
+    }
+
+    async fn remove_hosts(&self, hosts: Vec<String>) -> Result<Vec<String>, AppError> {
+        let res = self.memory.remove_hosts(hosts).await?;
+        self.save().await?;
+        Ok(res)
+    }
+
+    async fn deleted_hosts(&self) -> Result<Vec<DeletedHost>, AppError> {
+        self.memory.deleted_hosts().await
+    }
+
+    async fn restore_host(&self, host: &str) -> Result<HostEntry, AppError> {
+        let res = self.memory.restore_host(host).await?;
+        self.save().await?;
+        Ok(res)
+    }
+
+    async fn purge_deleted(&self, before: i64) -> Result<u64, AppError> {
+        let res = self.memory.purge_deleted(before).await?;
+        self.save().await?;
+        Ok(res)
+    }
+
+    async fn remove_expired(&self, now: i64) -> Result<Vec<String>, AppError> {
+        let res = self.memory.remove_expired(now).await?;
+        self.save().await?;
+        Ok(res)
+    }
+
+    async fn set_pinned(&self, host: &str, pinned: bool) -> Result<(), AppError> {
+        self.memory.set_pinned(host, pinned).await?;
+        self.save().await
+    }
+
+    async fn pinned_hosts(&self) -> Result<Vec<String>, AppError> {
+        self.memory.pinned_hosts().await
+    }
+
+    async fn set_tags(&self, host: &str, tags: Vec<String>) -> Result<(), AppError> {
+        self.memory.set_tags(host, tags).await?;
+        self.save().await
+    }
+
+    async fn hosts_by_tag(&self, tag: &str) -> Result<Vec<String>, AppError> {
+        self.memory.hosts_by_tag(tag).await
+    }
+
+    async fn remove_hosts_by_tag(&self, tag: &str) -> Result<Vec<String>, AppError> {
+        let res = self.memory.remove_hosts_by_tag(tag).await?;
+        self.save().await?;
+        Ok(res)
+    }
+
+    async fn list_tags(&self) -> Result<Vec<TagInfo>, AppError> {
+        self.memory.list_tags().await
+    }
+
+    async fn rename_tag(&self, tag: &str, to: &str) -> Result<(), AppError> {
+        self.memory.rename_tag(tag, to).await?;
+        self.save().await
+    }
+
+    async fn delete_tag(&self, tag: &str) -> Result<(), AppError> {
+        self.memory.delete_tag(tag).await?;
+        self.save().await
+    }
+
+    async fn import_hosts(
+        &self,
+        hosts: Vec<String>,
+        mode: ImportMode,
+        dry_run: bool,
+    ) -> Result<HostsDiff, AppError> {
+        let diff = self.memory.import_hosts(hosts, mode, dry_run).await?;
+        if !dry_run {
+            self.save().await?;
+        }
+        Ok(diff)
+    }
+
+    async fn create_snapshot(&self, name: &str) -> Result<SnapshotInfo, AppError> {
+        let res = self.memory.create_snapshot(name).await?;
+        self.save().await?;
+        Ok(res)
+    }
+
+    async fn list_snapshots(&self) -> Result<Vec<SnapshotInfo>, AppError> {
+        self.memory.list_snapshots().await
+    }
+
+    async fn restore_snapshot(&self, name: &str) -> Result<HostsDiff, AppError> {
+        let res = self.memory.restore_snapshot(name).await?;
+        self.save().await?;
+        Ok(res)
+    }
+
+    async fn delete_snapshot(&self, name: &str) -> Result<(), AppError> {
+        self.memory.delete_snapshot(name).await?;
+        self.save().await
+    }
+
+    async fn set_profile(&self, profile: Profile) -> Result<(), AppError> {
+        self.memory.set_profile(profile).await?;
+        self.save().await
+    }
+
+    async fn get_profile(&self, name: &str) -> Result<Profile, AppError> {
+        self.memory.get_profile(name).await
+    }
+
+    async fn list_profiles(&self) -> Result<Vec<Profile>, AppError> {
+        self.memory.list_profiles().await
+    }
+
+    async fn remove_profile(&self, name: &str) -> Result<(), AppError> {
+        self.memory.remove_profile(name).await?;
+        self.save().await
+    }
+
+    async fn set_group(&self, group: ProxyGroup) -> Result<(), AppError> {
+        self.memory.set_group(group).await?;
+        self.save().await
+    }
+
+    async fn list_groups(&self) -> Result<Vec<ProxyGroup>, AppError> {
+        self.memory.list_groups().await
+    }
+
+    async fn remove_group(&self, name: &str) -> Result<(), AppError> {
+        self.memory.remove_group(name).await?;
+        self.save().await
+    }
+
+    async fn set_network(&self, network: NetworkProfile) -> Result<(), AppError> {
+        self.memory.set_network(network).await?;
+        self.save().await
+    }
+
+    async fn list_networks(&self) -> Result<Vec<NetworkProfile>, AppError> {
+        self.memory.list_networks().await
+    }
+
+    async fn remove_network(&self, name: &str) -> Result<(), AppError> {
+        self.memory.remove_network(name).await?;
+        self.save().await
+    }
+
+    async fn hosts_version(&self) -> Result<i64, AppError> {
+        self.memory.hosts_version().await
+    }
+
+    async fn config_version(&self) -> Result<i64, AppError> {
+        self.memory.config_version().await
+    }
+
+    async fn get_proxy(&self) -> Result<Option<String>, AppError> {
+        self.memory.get_proxy().await
+    }
+
+    async fn set_proxy(&self, proxy: &str) -> Result<(), AppError> {
+        self.memory.set_proxy(proxy).await?;
+        self.save().await
+    }
+
+    async fn get_mode(&self) -> Result<PacMode, AppError> {
+        self.memory.get_mode().await
+    }
+
+    async fn set_mode(&self, mode: PacMode) -> Result<(), AppError> {
+        self.memory.set_mode(mode).await?;
+        self.save().await
+    }
+
+    async fn get_bypass_private(&self) -> Result<bool, AppError> {
+        self.memory.get_bypass_private().await
+    }
+
+    async fn set_bypass_private(&self, enabled: bool) -> Result<(), AppError> {
+        self.memory.set_bypass_private(enabled).await?;
+        self.save().await
+    }
+
+    async fn list_upstreams(&self) -> Result<Vec<Upstream>, AppError> {
+        self.memory.list_upstreams().await
+    }
+
+    async fn set_upstreams(&self, upstreams: Vec<Upstream>) -> Result<(), AppError> {
+        self.memory.set_upstreams(upstreams).await?;
+        self.save().await
+    }
+
+    async fn list_ip_ranges(&self) -> Result<Vec<String>, AppError> {
+        self.memory.list_ip_ranges().await
+    }
+
+    async fn set_ip_ranges(&self, ranges: Vec<String>) -> Result<(), AppError> {
+        self.memory.set_ip_ranges(ranges).await?;
+        self.save().await
+    }
+
+    async fn list_exclusions(&self) -> Result<Vec<String>, AppError> {
+        self.memory.list_exclusions().await
+    }
+
+    async fn add_exclusions(&self, hosts: Vec<String>) -> Result<Vec<String>, AppError> {
+        let res = self.memory.add_exclusions(hosts).await?;
+        self.save().await?;
+        Ok(res)
+    }
+
+    async fn remove_exclusion(&self, host: &str) -> Result<(), AppError> {
+        self.memory.remove_exclusion(host).await?;
+        self.save().await
+    }
+
+    async fn list_blocklist(&self) -> Result<Vec<BlocklistEntry>, AppError> {
+        self.memory.list_blocklist().await
+    }
+
+    async fn set_blocklisted(&self, entry: BlocklistEntry) -> Result<(), AppError> {
+        self.memory.set_blocklisted(entry).await?;
+        self.save().await
+    }
+
+    async fn remove_blocklisted(&self, host: &str) -> Result<(), AppError> {
+        self.memory.remove_blocklisted(host).await?;
+        self.save().await
+    }
+
+    async fn record_client_fetches(&self, fetches: Vec<ClientFetches>) -> Result<(), AppError> {
+        self.memory.record_client_fetches(fetches).await?;
+        self.save().await
+    }
+
+    async fn client_fetches(&self, since: i64) -> Result<Vec<ClientFetches>, AppError> {
+        self.memory.client_fetches(since).await
+    }
+
+    async fn prune_client_fetches(&self, before: i64) -> Result<u64, AppError> {
+        let res = self.memory.prune_client_fetches(before).await?;
+        self.save().await?;
+        Ok(res)
+    }
+
+    async fn record_audit(&self, entry: AuditEntry) -> Result<(), AppError> {
+        self.memory.record_audit(entry).await?;
+        self.save().await
+    }
+
+    async fn audit_log(&self, since: i64, limit: u32) -> Result<Vec<AuditEntry>, AppError> {
+        self.memory.audit_log(since, limit).await
+    }
+
+    async fn prune_audit(&self, before: i64) -> Result<u64, AppError> {
+        let res = self.memory.prune_audit(before).await?;
+        self.save().await?;
+        Ok(res)
+    }
+
+    async fn try_lease(&self, name: &str, owner: &str, ttl: Duration) -> Result<bool, AppError> {
+        self.memory.try_lease(name, owner, ttl).await
+    }
+
+    async fn request_regeneration(&self) -> Result<(), AppError> {
+        self.memory.request_regeneration().await?;
+        self.save().await
+    }
+
+    async fn regeneration_requests(&self) -> Result<i64, AppError> {
+        self.memory.regeneration_requests().await
+    }
+
+    async fn storage_stats(&self) -> Result<StorageStats, AppError> {
+        let stats = self.memory.storage_stats().await?;
+        let meta = tokio::fs::metadata(&self.path)
+            .await
+            .map_err(|e| self.io_error(e))?;
+        Ok(StorageStats {
+            db_bytes: Some(meta.len()),
+            ..stats
+        })
+    }
+
+    async fn export_state(&self) -> Result<InstanceState, AppError> {
+        self.memory.export_state().await
+    }
+
+    async fn import_state(&self, state: InstanceState) -> Result<(), AppError> {
+        self.memory.import_state(state).await?;
+        self.save().await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+    use crate::storage::tests::conformance;
+
+    /// Path of a state file not there yet
+    fn state_path() -> PathBuf {
+        static FILES: AtomicU32 = AtomicU32::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "qpac_state_{}_{}.json",
+            std::process::id(),
+            FILES.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    conformance!(FileStorage, FileStorage::open(state_path()).await?);
+
+    #[tokio::test]
+    async fn reopens_saved_state() -> Result<()> {
+        let path = state_path();
+        let storage = FileStorage::open(&path).await?;
+        storage
+            .add_host("example.com", HostPatch::default())
+            .await?;
+        storage
+            .set_tags("example.com", vec!["work".to_string()])
+            .await?;
+        storage.set_proxy("SOCKS5 127.0.0.1:1080").await?;
+        let pac = Pac::new(
+            "function FindProxyForURL() {}".to_string(),
+            "h1".to_string(),
+        );
+        storage.upload_file(&pac).await?;
+        storage.set_latest("h1").await?;
+        storage.remove_host("example.com").await?;
+        let state = storage.export_state().await?;
+        let revision = storage.hosts_version().await?;
+        drop(storage);
+
+        let storage = FileStorage::open(&path).await?;
+        assert_eq!(storage.export_state().await?, state);
+        assert_eq!(storage.hosts_version().await?, revision);
+        assert_eq!(storage.get_file_latest().await?.hash, "h1");
+        assert_eq!(storage.get_version("h1").await?, 1);
+        let deleted = storage.deleted_hosts().await?;
+        assert_eq!(deleted.len(), 1);
+        assert_eq!(deleted[0].entry.tags, vec!["work"]);
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}
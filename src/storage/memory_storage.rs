@@ -1,28 +1,179 @@
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    ops::Bound,
+    time::Duration,
+};
 
-use tokio::sync::Mutex;
+use async_trait::async_trait;
+use futures::stream::BoxStream;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Mutex, MutexGuard};
 
-use crate::{error::AppError, pac::Pac};
+use crate::{
+    error::AppError,
+    pac::{self, NetworkProfile, Pac, PacEncodings, PacMeta, PacMode, Upstream},
+    utils::time::unix_now,
+};
 
-use super::Storage;
+use super::{
+    check_state_version, expected_revision, renamed_tag, AuditEntry, BlocklistEntry, ChangeEvent,
+    ChangeFeed, ClientFetches, ClientKey, DeletedHost, HostEntry, HostPatch, HostsDiff, ImportMode,
+    InstanceState, PacVersion, Profile, ProxyGroup, Snapshot, SnapshotInfo, Storage, StorageStats,
+    TagInfo, STATE_VERSION,
+};
 
 #[derive(Debug, Default)]
 pub struct MemoryStorage {
-    hosts: Mutex<Vec<String>>,
+    hosts: Mutex<BTreeMap<String, HostEntry>>,
     files: Mutex<HashMap<String, String>>,
+    manifests: Mutex<HashMap<String, Vec<String>>>,
+    metas: Mutex<HashMap<String, PacMeta>>,
+    encodings: Mutex<HashMap<String, PacEncodings>>,
+    /// Unix seconds of the first upload by hash
+    created: Mutex<HashMap<String, i64>>,
+    /// Hashes in upload order, versions start at 1. `None` once pruned
+    versions: Mutex<Vec<Option<String>>>,
     latest: Mutex<Option<String>>,
+    staged: Mutex<Option<String>>,
+    snapshots: Mutex<BTreeMap<String, (i64, Vec<HostEntry>)>>,
+    profiles: Mutex<BTreeMap<String, Profile>>,
+    /// Owner and expiry by lease name
+    leases: Mutex<HashMap<String, (String, i64)>>,
+    regeneration_requests: Mutex<i64>,
+    proxy: Mutex<Option<String>>,
+    hosts_version: Mutex<i64>,
+    /// Held by host list writes from checking the expected revision until
+    /// they're done, see [`MemoryStorage::begin_hosts_write`]
+    hosts_writes: Mutex<()>,
+    config_version: Mutex<i64>,
+    groups: Mutex<BTreeMap<String, ProxyGroup>>,
+    upstreams: Mutex<Vec<Upstream>>,
+    mode: Mutex<PacMode>,
+    exclusions: Mutex<BTreeSet<String>>,
+    bypass_private: Mutex<bool>,
+    networks: Mutex<BTreeMap<String, NetworkProfile>>,
+    ip_ranges: Mutex<BTreeSet<String>>,
+    blocklist: Mutex<BTreeMap<String, BlocklistEntry>>,
+    /// Fetches by day, client and profile
+    client_fetches: Mutex<BTreeMap<ClientKey, i64>>,
+    /// Oldest first
+    audit_log: Mutex<Vec<AuditEntry>>,
+    deleted: Mutex<BTreeMap<String, DeletedHost>>,
+    changes: ChangeFeed,
 }
 
+/// Everything a [`MemoryStorage`] keeps but leases and precompressed bodies,
+/// see [`MemoryStorage::dump`]
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub(super) struct MemoryDump {
+    hosts: Vec<HostEntry>,
+    files: Vec<DumpedFile>,
+    versions: Vec<Option<String>>,
+    latest: Option<String>,
+    staged: Option<String>,
+    snapshots: Vec<Snapshot>,
+    profiles: Vec<Profile>,
+    regeneration_requests: i64,
+    proxy: Option<String>,
+    hosts_version: i64,
+    config_version: i64,
+    groups: Vec<ProxyGroup>,
+    upstreams: Vec<Upstream>,
+    mode: PacMode,
+    exclusions: BTreeSet<String>,
+    bypass_private: bool,
+    networks: Vec<NetworkProfile>,
+    ip_ranges: BTreeSet<String>,
+    blocklist: Vec<BlocklistEntry>,
+    client_fetches: Vec<ClientFetches>,
+    audit_log: Vec<AuditEntry>,
+    deleted: Vec<DeletedHost>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct DumpedFile {
+    hash: String,
+    file: String,
+    manifest: Vec<String>,
+    meta: Option<PacMeta>,
+    created_at: Option<i64>,
+}
+
+#[async_trait]
 impl Storage for MemoryStorage {
+    fn watch(&self) -> BoxStream<'static, ChangeEvent> {
+        self.changes.watch()
+    }
+
+    async fn watch_shared(&self) -> Result<Option<BoxStream<'static, ChangeEvent>>, AppError> {
+        // Nothing else shares it
+        Ok(None)
+    }
+
     async fn all_hosts(&self) -> Result<Vec<String>, AppError> {
-        Ok(self.hosts.lock().await.clone())
+        Ok(self.hosts.lock().await.keys().cloned().collect())
+    }
+
+    async fn host_entries(&self) -> Result<Vec<HostEntry>, AppError> {
+        Ok(self.hosts.lock().await.values().cloned().collect())
+    }
+
+    async fn search_hosts(&self, pattern: &str) -> Result<Vec<String>, AppError> {
+        let pattern: Vec<u8> = pattern.bytes().map(|b| b.to_ascii_lowercase()).collect();
+        Ok(self
+            .hosts
+            .lock()
+            .await
+            .keys()
+            .filter(|h| like(&pattern, h.to_ascii_lowercase().as_bytes()))
+            .cloned()
+            .collect())
+    }
+
+    async fn hosts_page(
+        &self,
+        after: Option<&str>,
+        limit: u32,
+    ) -> Result<Vec<HostEntry>, AppError> {
+        let start = match after {
+            Some(after) => Bound::Excluded(after),
+            None => Bound::Unbounded,
+        };
+        Ok(self
+            .hosts
+            .lock()
+            .await
+            .range::<str, _>((start, Bound::Unbounded))
+            .take(limit as usize)
+            .map(|(_, e)| e.clone())
+            .collect())
+    }
+
+    async fn get_host(&self, host: &str) -> Result<HostEntry, AppError> {
+        self.hosts
+            .lock()
+            .await
+            .get(host)
+            .cloned()
+            .ok_or(AppError::NotFound)
     }
 
-    async fn get_file(&self, hash: impl Into<String>) -> Result<String, AppError> {
+    async fn update_host(&self, host: &str, patch: HostPatch) -> Result<HostEntry, AppError> {
+        let _write = self.begin_hosts_write().await?;
+        let mut hosts = self.hosts.lock().await;
+        let entry = hosts.get_mut(host).ok_or(AppError::NotFound)?;
+        apply_patch(entry, patch);
+        let entry = entry.clone();
+        self.bump_hosts_version().await;
+        Ok(entry)
+    }
+
+    async fn get_file(&self, hash: &str) -> Result<String, AppError> {
         self.files
             .lock()
             .await
-            .get(&hash.into())
+            .get(hash)
             .cloned()
             .ok_or(AppError::NotFound)
     }
@@ -45,107 +196,1174 @@ impl Storage for MemoryStorage {
         Ok(Pac::new(file, hash))
     }
 
-    async fn upload_file(&self, pac: &Pac) -> Result<(), AppError> {
+    async fn latest_hash(&self) -> Result<String, AppError> {
+        self.latest.lock().await.clone().ok_or(AppError::NotFound)
+    }
+
+    async fn get_checksum(&self, hash: &str) -> Result<String, AppError> {
         self.files
             .lock()
             .await
-            .insert(pac.hash.clone(), pac.file.clone());
+            .get(hash)
+            .map(|f| pac::checksum(f))
+            .ok_or(AppError::NotFound)
+    }
+
+    async fn get_manifest(&self, hash: &str) -> Result<Vec<String>, AppError> {
+        self.manifests
+            .lock()
+            .await
+            .get(hash)
+            .cloned()
+            .ok_or(AppError::NotFound)
+    }
+
+    async fn upload_file(&self, pac: &Pac) -> Result<(), AppError> {
+        let known = self
+            .files
+            .lock()
+            .await
+            .insert(pac.hash.clone(), pac.file.clone())
+            .is_some();
+        if !known {
+            self.versions.lock().await.push(Some(pac.hash.clone()));
+            self.created
+                .lock()
+                .await
+                .insert(pac.hash.clone(), unix_now());
+        }
+        self.manifests
+            .lock()
+            .await
+            .insert(pac.hash.clone(), pac.hosts.clone());
+        let mut metas = self.metas.lock().await;
+        match &pac.meta {
+            Some(meta) => metas.insert(pac.hash.clone(), meta.clone()),
+            None => metas.remove(&pac.hash),
+        };
         Ok(())
     }
 
-    async fn set_latest(&self, hash: impl Into<String>) -> Result<(), AppError> {
+    async fn upload_encodings(&self, hash: &str, encodings: &PacEncodings) -> Result<(), AppError> {
+        let hash = hash.to_string();
+        if !self.files.lock().await.contains_key(&hash) {
+            Err(AppError::NotFound)?
+        }
+        let mut stored = self.encodings.lock().await;
+        let stored = stored.entry(hash).or_default();
+        if let Some(gzip) = &encodings.gzip {
+            stored.gzip = Some(gzip.clone());
+        }
+        if let Some(br) = &encodings.br {
+            stored.br = Some(br.clone());
+        }
+        Ok(())
+    }
+
+    async fn get_encodings(&self, hash: &str) -> Result<PacEncodings, AppError> {
+        Ok(self
+            .encodings
+            .lock()
+            .await
+            .get(hash)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    async fn list_versions(&self, limit: u32) -> Result<Vec<PacVersion>, AppError> {
+        let metas = self.metas.lock().await;
+        let files = self.files.lock().await;
+        let created = self.created.lock().await;
+        Ok(self
+            .versions
+            .lock()
+            .await
+            .iter()
+            .enumerate()
+            .rev()
+            .filter_map(|(i, hash)| Some((i, hash.as_ref()?)))
+            .take(limit as usize)
+            .map(|(i, hash)| PacVersion {
+                version: i as i64 + 1,
+                hash: hash.clone(),
+                size: files.get(hash).map_or(0, |f| f.len() as i64),
+                created_at: created.get(hash).copied(),
+                meta: metas.get(hash).cloned(),
+            })
+            .collect())
+    }
+
+    async fn prune_files(&self, keep: u32, before: i64) -> Result<u64, AppError> {
+        let protected = [
+            self.latest.lock().await.clone(),
+            self.staged.lock().await.clone(),
+        ];
+        let mut metas = self.metas.lock().await;
+        let mut files = self.files.lock().await;
+        let mut versions = self.versions.lock().await;
+        let mut manifests = self.manifests.lock().await;
+        let mut encodings = self.encodings.lock().await;
+        let mut created = self.created.lock().await;
+        let mut newer = 0;
+        let mut pruned = 0;
+        for slot in versions.iter_mut().rev() {
+            let Some(hash) = slot else {
+                continue;
+            };
+            newer += 1;
+            let recent = metas.get(hash).is_some_and(|m| m.generated_at >= before);
+            if newer <= keep.max(1) || recent || protected.contains(&Some(hash.clone())) {
+                continue;
+            }
+            files.remove(hash);
+            manifests.remove(hash);
+            metas.remove(hash);
+            encodings.remove(hash);
+            created.remove(hash);
+            *slot = None;
+            pruned += 1;
+        }
+        Ok(pruned)
+    }
+
+    async fn get_version(&self, hash: &str) -> Result<i64, AppError> {
+        let hash = hash.to_string();
+        self.versions
+            .lock()
+            .await
+            .iter()
+            .position(|h| h.as_ref() == Some(&hash))
+            .map(|i| i as i64 + 1)
+            .ok_or(AppError::NotFound)
+    }
+
+    async fn get_version_hash(&self, version: i64) -> Result<String, AppError> {
+        let versions = self.versions.lock().await;
+        usize::try_from(version - 1)
+            .ok()
+            .and_then(|i| versions.get(i))
+            .cloned()
+            .flatten()
+            .ok_or(AppError::NotFound)
+    }
+
+    async fn set_latest(&self, hash: &str) -> Result<(), AppError> {
         let mut l = self.latest.lock().await;
         *l = Some(hash.into());
         Ok(())
     }
 
-    async fn add_host(&self, host: impl Into<String>) -> Result<(), AppError> {
-        let host = host.into();
+    async fn staged_hash(&self) -> Result<Option<String>, AppError> {
+        Ok(self.staged.lock().await.clone())
+    }
+
+    async fn set_staged_hash(&self, hash: Option<String>) -> Result<(), AppError> {
+        *self.staged.lock().await = hash;
+        Ok(())
+    }
+
+    async fn promote_staged(&self) -> Result<String, AppError> {
+        let mut staged = self.staged.lock().await;
+        let hash = staged.take().ok_or(AppError::NotFound)?;
+        *self.latest.lock().await = Some(hash.clone());
+        Ok(hash)
+    }
+
+    async fn add_host(&self, host: &str, patch: HostPatch) -> Result<(), AppError> {
+        let _write = self.begin_hosts_write().await?;
+        let host = host.to_string();
         let mut hosts = self.hosts.lock().await;
-        if hosts.binary_search(&host).is_ok() {
+        if hosts.contains_key(&host) {
             Err(AppError::PreconditionFailed(
                 "Host already exists".to_string(),
             ))?
         };
-        let idx = hosts.partition_point(|x| x <= &host);
-        hosts.insert(idx, host);
+        let mut entry = new_entry(host.clone());
+        apply_patch(&mut entry, patch);
+        hosts.insert(host, entry);
+        self.bump_hosts_version().await;
         Ok(())
     }
 
-    async fn remove_host(&self, host: impl Into<String>) -> Result<(), AppError> {
+    async fn upsert_host(&self, host: &str, patch: HostPatch) -> Result<bool, AppError> {
+        let _write = self.begin_hosts_write().await?;
         let mut hosts = self.hosts.lock().await;
-        let Ok(i) = hosts.binary_search(&host.into()) else {
+        let added = !hosts.contains_key(host);
+        let entry = hosts
+            .entry(host.to_string())
+            .or_insert_with(|| new_entry(host.to_string()));
+        apply_patch(entry, patch);
+        self.bump_hosts_version().await;
+        Ok(added)
+    }
+
+    async fn remove_host(&self, host: &str) -> Result<(), AppError> {
+        let _write = self.begin_hosts_write().await?;
+        let Some(entry) = self.hosts.lock().await.remove(host) else {
             Err(AppError::NotFound)?
         };
-        hosts.remove(i);
+        self.trash(vec![entry]).await;
+        self.bump_hosts_version().await;
         Ok(())
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use crate::error::Result;
-
-    #[tokio::test]
-    async fn adds_sorted() -> Result<()> {
-        let storage = MemoryStorage::default();
-        let test = vec![
-            "a".to_string(),
-            "aa".to_string(),
-            "ab".to_string(),
-            "abc".to_string(),
-            "b".to_string(),
-            "bac".to_string(),
-            "sa".to_string(),
-            "z".to_string(),
-        ];
-        for s in test.iter() {
-            storage.add_host(s).await?;
+    async fn add_hosts(
+        &self,
+        hosts: Vec<String>,
+        patch: HostPatch,
+    ) -> Result<Vec<String>, AppError> {
+        let _write = self.begin_hosts_write().await?;
+        let mut current = self.hosts.lock().await;
+        let mut added = vec![];
+        for host in hosts {
+            if !current.contains_key(&host) {
+                let mut entry = new_entry(host.clone());
+                apply_patch(&mut entry, patch.clone());
+                current.insert(host.clone(), entry);
+                added.push(host);
+            }
+        }
+        if !added.is_empty() {
+            self.bump_hosts_version().await;
+        }
+        added.sort();
+        Ok(added)
+    }
+
+    async fn upsert_hosts(
+        &self,
+        hosts: Vec<String>,
+        patch: HostPatch,
+    ) -> Result<Vec<String>, AppError> {
+        let _write = self.begin_hosts_write().await?;
+        let mut current = self.hosts.lock().await;
+        let mut added = vec![];
+        let changed = !hosts.is_empty();
+        for host in hosts {
+            let entry = current.entry(host.clone()).or_insert_with(|| {
+                added.push(host.clone());
+                new_entry(host)
+            });
+            apply_patch(entry, patch.clone());
+        }
+        if changed {
+            self.bump_hosts_version().await;
+        }
+        added.sort();
+        Ok(added)
+    }
+
+    async fn remove_hosts(&self, hosts: Vec<String>) -> Result<Vec<String>, AppError> {
+        let _write = self.begin_hosts_write().await?;
+        let mut current = self.hosts.lock().await;
+        let mut removed = vec![];
+        for host in hosts {
+            if current.get(&host).is_some_and(|e| !e.pinned) {
+                removed.extend(current.remove(&host));
+            }
+        }
+        if !removed.is_empty() {
+            self.bump_hosts_version().await;
+        }
+        let mut removed = self.trash(removed).await;
+        removed.sort();
+        Ok(removed)
+    }
+
+    async fn deleted_hosts(&self) -> Result<Vec<DeletedHost>, AppError> {
+        Ok(self.deleted.lock().await.values().cloned().collect())
+    }
+
+    async fn restore_host(&self, host: &str) -> Result<HostEntry, AppError> {
+        let _write = self.begin_hosts_write().await?;
+        let mut hosts = self.hosts.lock().await;
+        let mut deleted = self.deleted.lock().await;
+        let Some(DeletedHost { mut entry, .. }) = deleted.get(host).cloned() else {
+            Err(AppError::NotFound)?
+        };
+        if hosts.contains_key(host) {
+            Err(AppError::Conflict("Host already exists".to_string()))?
         }
-        let res = storage.all_hosts().await?;
-        assert_eq!(res, test);
+        deleted.remove(host);
+        entry.updated_at = unix_now();
+        hosts.insert(entry.host.clone(), entry.clone());
+        self.bump_hosts_version().await;
+        Ok(entry)
+    }
+
+    async fn purge_deleted(&self, before: i64) -> Result<u64, AppError> {
+        let mut deleted = self.deleted.lock().await;
+        let len = deleted.len();
+        deleted.retain(|_, d| d.deleted_at >= before);
+        Ok((len - deleted.len()) as u64)
+    }
+
+    async fn remove_expired(&self, now: i64) -> Result<Vec<String>, AppError> {
+        let _write = self.begin_hosts_write().await?;
+        let mut current = self.hosts.lock().await;
+        let expired: Vec<String> = current
+            .values()
+            .filter(|e| !e.pinned && e.expires_at.is_some_and(|at| at <= now))
+            .map(|e| e.host.clone())
+            .collect();
+        let removed: Vec<HostEntry> = expired.iter().filter_map(|h| current.remove(h)).collect();
+        if !removed.is_empty() {
+            self.bump_hosts_version().await;
+        }
+        Ok(self.trash(removed).await)
+    }
+
+    async fn set_pinned(&self, host: &str, pinned: bool) -> Result<(), AppError> {
+        let patch = HostPatch {
+            pinned: Some(pinned),
+            ..Default::default()
+        };
+        self.update_host(host, patch).await.map(|_| ())
+    }
+
+    async fn pinned_hosts(&self) -> Result<Vec<String>, AppError> {
+        Ok(self
+            .hosts
+            .lock()
+            .await
+            .values()
+            .filter(|e| e.pinned)
+            .map(|e| e.host.clone())
+            .collect())
+    }
+
+    async fn import_hosts(
+        &self,
+        hosts: Vec<String>,
+        mode: ImportMode,
+        dry_run: bool,
+    ) -> Result<HostsDiff, AppError> {
+        let _write = self.begin_hosts_write().await?;
+        let mut current = self.hosts.lock().await;
+        let wanted: BTreeSet<String> = hosts.into_iter().collect();
+
+        let diff = HostsDiff {
+            added: wanted
+                .iter()
+                .filter(|h| !current.contains_key(*h))
+                .cloned()
+                .collect(),
+            removed: match mode {
+                ImportMode::Merge => vec![],
+                ImportMode::Mirror => current
+                    .values()
+                    .filter(|e| !wanted.contains(&e.host) && !e.pinned)
+                    .map(|e| e.host.clone())
+                    .collect(),
+            },
+        };
+        if !dry_run && !diff.is_empty() {
+            for host in diff.removed.iter() {
+                current.remove(host);
+            }
+            for host in diff.added.iter() {
+                current.insert(host.clone(), new_entry(host.clone()));
+            }
+            self.bump_hosts_version().await;
+        }
+        Ok(diff)
+    }
+
+    async fn set_tags(&self, host: &str, tags: Vec<String>) -> Result<(), AppError> {
+        let patch = HostPatch {
+            tags: Some(tags),
+            ..Default::default()
+        };
+        self.update_host(host, patch).await.map(|_| ())
+    }
+
+    async fn hosts_by_tag(&self, tag: &str) -> Result<Vec<String>, AppError> {
+        let tag = tag.to_string();
+        Ok(self
+            .hosts
+            .lock()
+            .await
+            .values()
+            .filter(|e| e.tags.contains(&tag))
+            .map(|e| e.host.clone())
+            .collect())
+    }
+
+    async fn remove_hosts_by_tag(&self, tag: &str) -> Result<Vec<String>, AppError> {
+        let _write = self.begin_hosts_write().await?;
+        let tag = tag.to_string();
+        let mut hosts = self.hosts.lock().await;
+        let removed: Vec<String> = hosts
+            .values()
+            .filter(|e| e.tags.contains(&tag) && !e.pinned)
+            .map(|e| e.host.clone())
+            .collect();
+        let entries = removed
+            .iter()
+            .filter_map(|host| hosts.remove(host))
+            .collect();
+        self.trash(entries).await;
+        if !removed.is_empty() {
+            self.bump_hosts_version().await;
+        }
+        Ok(removed)
+    }
+
+    async fn list_tags(&self) -> Result<Vec<TagInfo>, AppError> {
+        let mut counts: BTreeMap<String, usize> = BTreeMap::new();
+        for entry in self.hosts.lock().await.values() {
+            for tag in entry.tags.iter() {
+                *counts.entry(tag.clone()).or_default() += 1;
+            }
+        }
+        Ok(counts
+            .into_iter()
+            .map(|(name, hosts)| TagInfo { name, hosts })
+            .collect())
+    }
+
+    async fn rename_tag(&self, tag: &str, to: &str) -> Result<(), AppError> {
+        let _write = self.begin_hosts_write().await?;
+        let mut hosts = self.hosts.lock().await;
+        let mut renamed = false;
+        for entry in hosts
+            .values_mut()
+            .filter(|e| e.tags.iter().any(|t| t == tag))
+        {
+            renamed = true;
+            let tags = entry
+                .tags
+                .drain(..)
+                .map(|t| if t == tag { to.to_string() } else { t })
+                .collect();
+            entry.tags = sorted_tags(tags);
+        }
+        if !renamed {
+            Err(AppError::NotFound)?
+        }
+        // Profiles listing the tag would otherwise lose its hosts
+        let mut profiles = self.profiles.lock().await;
+        for profile in profiles.values_mut() {
+            if let Some(tags) = renamed_tag(&profile.tags, tag, to) {
+                profile.tags = tags;
+            }
+        }
+        drop(profiles);
+        self.bump_hosts_version().await;
         Ok(())
     }
 
-    #[tokio::test]
-    async fn fails_to_add_non_uniq() -> Result<()> {
-        let storage = MemoryStorage::default();
-        let test = vec!["a", "aa"];
-        for s in test.into_iter() {
-            storage.add_host(s).await?;
+    async fn delete_tag(&self, tag: &str) -> Result<(), AppError> {
+        let _write = self.begin_hosts_write().await?;
+        let mut hosts = self.hosts.lock().await;
+        let mut deleted = false;
+        for entry in hosts.values_mut() {
+            let before = entry.tags.len();
+            entry.tags.retain(|t| t != tag);
+            deleted |= entry.tags.len() != before;
         }
-        assert_eq!(
-            storage.add_host("aa").await,
-            Err(AppError::PreconditionFailed(
-                "Host already exists".to_string()
-            ))
+        if !deleted {
+            Err(AppError::NotFound)?
+        }
+        self.bump_hosts_version().await;
+        Ok(())
+    }
+
+    async fn create_snapshot(&self, name: &str) -> Result<SnapshotInfo, AppError> {
+        let name = name.to_string();
+        let hosts = self.hosts.lock().await;
+        let mut snapshots = self.snapshots.lock().await;
+        if snapshots.contains_key(&name) {
+            Err(AppError::Conflict("Snapshot already exists".to_string()))?
+        }
+        let info = SnapshotInfo {
+            name: name.clone(),
+            created_at: unix_now(),
+            hosts: hosts.len(),
+        };
+        snapshots.insert(name, (info.created_at, hosts.values().cloned().collect()));
+        Ok(info)
+    }
+
+    async fn list_snapshots(&self) -> Result<Vec<SnapshotInfo>, AppError> {
+        Ok(self
+            .snapshots
+            .lock()
+            .await
+            .iter()
+            .map(|(name, (created_at, hosts))| SnapshotInfo {
+                name: name.clone(),
+                created_at: *created_at,
+                hosts: hosts.len(),
+            })
+            .collect())
+    }
+
+    async fn restore_snapshot(&self, name: &str) -> Result<HostsDiff, AppError> {
+        let _write = self.begin_hosts_write().await?;
+        let mut hosts = self.hosts.lock().await;
+        let snapshots = self.snapshots.lock().await;
+        let (_, entries) = snapshots.get(name).ok_or(AppError::NotFound)?;
+        let current: Vec<String> = hosts.keys().cloned().collect();
+        let wanted: Vec<String> = entries.iter().map(|e| e.host.clone()).collect();
+        *hosts = entries
+            .iter()
+            .map(|e| (e.host.clone(), e.clone()))
+            .collect();
+        self.bump_hosts_version().await;
+        Ok(HostsDiff::between(&current, &wanted))
+    }
+
+    async fn delete_snapshot(&self, name: &str) -> Result<(), AppError> {
+        if self.snapshots.lock().await.remove(name).is_none() {
+            Err(AppError::NotFound)?
+        }
+        Ok(())
+    }
+
+    async fn set_profile(&self, profile: Profile) -> Result<(), AppError> {
+        self.profiles
+            .lock()
+            .await
+            .insert(profile.name.clone(), profile);
+        self.bump_config_version().await;
+        Ok(())
+    }
+
+    async fn get_profile(&self, name: &str) -> Result<Profile, AppError> {
+        self.profiles
+            .lock()
+            .await
+            .get(name)
+            .cloned()
+            .ok_or(AppError::NotFound)
+    }
+
+    async fn list_profiles(&self) -> Result<Vec<Profile>, AppError> {
+        Ok(self.profiles.lock().await.values().cloned().collect())
+    }
+
+    async fn remove_profile(&self, name: &str) -> Result<(), AppError> {
+        if self.profiles.lock().await.remove(name).is_none() {
+            Err(AppError::NotFound)?
+        }
+        self.bump_config_version().await;
+        Ok(())
+    }
+
+    async fn get_proxy(&self) -> Result<Option<String>, AppError> {
+        Ok(self.proxy.lock().await.clone())
+    }
+
+    async fn set_proxy(&self, proxy: &str) -> Result<(), AppError> {
+        *self.proxy.lock().await = Some(proxy.into());
+        self.bump_config_version().await;
+        Ok(())
+    }
+
+    async fn get_mode(&self) -> Result<PacMode, AppError> {
+        Ok(*self.mode.lock().await)
+    }
+
+    async fn set_mode(&self, mode: PacMode) -> Result<(), AppError> {
+        *self.mode.lock().await = mode;
+        self.bump_config_version().await;
+        Ok(())
+    }
+
+    async fn get_bypass_private(&self) -> Result<bool, AppError> {
+        Ok(*self.bypass_private.lock().await)
+    }
+
+    async fn set_bypass_private(&self, enabled: bool) -> Result<(), AppError> {
+        *self.bypass_private.lock().await = enabled;
+        self.bump_config_version().await;
+        Ok(())
+    }
+
+    async fn list_upstreams(&self) -> Result<Vec<Upstream>, AppError> {
+        Ok(self.upstreams.lock().await.clone())
+    }
+
+    async fn set_upstreams(&self, upstreams: Vec<Upstream>) -> Result<(), AppError> {
+        *self.upstreams.lock().await = upstreams;
+        self.bump_config_version().await;
+        Ok(())
+    }
+
+    async fn list_exclusions(&self) -> Result<Vec<String>, AppError> {
+        Ok(self.exclusions.lock().await.iter().cloned().collect())
+    }
+
+    async fn add_exclusions(&self, hosts: Vec<String>) -> Result<Vec<String>, AppError> {
+        let _write = self.begin_hosts_write().await?;
+        let mut exclusions = self.exclusions.lock().await;
+        let added: BTreeSet<String> = hosts
+            .into_iter()
+            .filter(|h| exclusions.insert(h.clone()))
+            .collect();
+        drop(exclusions);
+        if !added.is_empty() {
+            self.bump_hosts_version().await;
+        }
+        Ok(added.into_iter().collect())
+    }
+
+    async fn remove_exclusion(&self, host: &str) -> Result<(), AppError> {
+        let _write = self.begin_hosts_write().await?;
+        if !self.exclusions.lock().await.remove(host) {
+            Err(AppError::NotFound)?
+        }
+        self.bump_hosts_version().await;
+        Ok(())
+    }
+
+    async fn list_blocklist(&self) -> Result<Vec<BlocklistEntry>, AppError> {
+        Ok(self.blocklist.lock().await.values().cloned().collect())
+    }
+
+    async fn set_blocklisted(&self, entry: BlocklistEntry) -> Result<(), AppError> {
+        let _write = self.begin_hosts_write().await?;
+        let mut blocklist = self.blocklist.lock().await;
+        let created_at = blocklist
+            .get(&entry.host)
+            .map_or_else(unix_now, |e| e.created_at);
+        blocklist.insert(
+            entry.host.clone(),
+            BlocklistEntry {
+                created_at,
+                ..entry
+            },
         );
+        drop(blocklist);
+        self.bump_hosts_version().await;
+        Ok(())
+    }
+
+    async fn remove_blocklisted(&self, host: &str) -> Result<(), AppError> {
+        let _write = self.begin_hosts_write().await?;
+        if self.blocklist.lock().await.remove(host).is_none() {
+            Err(AppError::NotFound)?
+        }
+        self.bump_hosts_version().await;
+        Ok(())
+    }
+
+    async fn record_client_fetches(&self, fetches: Vec<ClientFetches>) -> Result<(), AppError> {
+        let mut counts = self.client_fetches.lock().await;
+        for f in fetches {
+            *counts.entry((f.day, f.client, f.profile)).or_default() += f.fetches;
+        }
+        Ok(())
+    }
+
+    async fn client_fetches(&self, since: i64) -> Result<Vec<ClientFetches>, AppError> {
+        Ok(self
+            .client_fetches
+            .lock()
+            .await
+            .iter()
+            .filter(|((day, _, _), _)| *day >= since)
+            .map(|((day, client, profile), fetches)| ClientFetches {
+                day: *day,
+                client: client.clone(),
+                profile: profile.clone(),
+                fetches: *fetches,
+            })
+            .collect())
+    }
+
+    async fn prune_client_fetches(&self, before: i64) -> Result<u64, AppError> {
+        let mut counts = self.client_fetches.lock().await;
+        let len = counts.len();
+        counts.retain(|(day, _, _), _| *day >= before);
+        Ok((len - counts.len()) as u64)
+    }
+
+    async fn record_audit(&self, entry: AuditEntry) -> Result<(), AppError> {
+        self.audit_log.lock().await.push(entry);
+        Ok(())
+    }
+
+    async fn audit_log(&self, since: i64, limit: u32) -> Result<Vec<AuditEntry>, AppError> {
+        Ok(self
+            .audit_log
+            .lock()
+            .await
+            .iter()
+            .rev()
+            .filter(|e| e.at >= since)
+            .take(limit as usize)
+            .cloned()
+            .collect())
+    }
+
+    async fn prune_audit(&self, before: i64) -> Result<u64, AppError> {
+        let mut log = self.audit_log.lock().await;
+        let len = log.len();
+        log.retain(|e| e.at >= before);
+        Ok((len - log.len()) as u64)
+    }
+
+    async fn set_group(&self, group: ProxyGroup) -> Result<(), AppError> {
+        self.groups.lock().await.insert(group.name.clone(), group);
+        self.bump_config_version().await;
+        Ok(())
+    }
+
+    async fn list_groups(&self) -> Result<Vec<ProxyGroup>, AppError> {
+        Ok(self.groups.lock().await.values().cloned().collect())
+    }
+
+    async fn remove_group(&self, name: &str) -> Result<(), AppError> {
+        let _write = self.begin_hosts_write().await?;
+        let name = name.to_string();
+        if self.groups.lock().await.remove(&name).is_none() {
+            Err(AppError::NotFound)?
+        }
+        let mut hosts = self.hosts.lock().await;
+        for entry in hosts.values_mut() {
+            if entry.group.as_ref() == Some(&name) {
+                entry.group = None;
+            }
+        }
+        self.bump_hosts_version().await;
+        self.bump_config_version().await;
         Ok(())
     }
 
-    #[tokio::test]
-    async fn remove_existing() -> Result<()> {
-        let storage = MemoryStorage::default();
-        let test = ["a", "aa", "ab"];
-        for s in test.into_iter() {
-            storage.add_host(s).await?;
+    async fn list_ip_ranges(&self) -> Result<Vec<String>, AppError> {
+        Ok(self.ip_ranges.lock().await.iter().cloned().collect())
+    }
+
+    async fn set_ip_ranges(&self, ranges: Vec<String>) -> Result<(), AppError> {
+        *self.ip_ranges.lock().await = ranges.into_iter().collect();
+        self.bump_config_version().await;
+        Ok(())
+    }
+
+    async fn set_network(&self, network: NetworkProfile) -> Result<(), AppError> {
+        self.networks
+            .lock()
+            .await
+            .insert(network.name.clone(), network);
+        self.bump_config_version().await;
+        Ok(())
+    }
+
+    async fn list_networks(&self) -> Result<Vec<NetworkProfile>, AppError> {
+        Ok(self.networks.lock().await.values().cloned().collect())
+    }
+
+    async fn remove_network(&self, name: &str) -> Result<(), AppError> {
+        if self.networks.lock().await.remove(name).is_none() {
+            Err(AppError::NotFound)?
         }
-        storage.remove_host("ab").await?;
-        storage.remove_host("aa").await?;
-        let res = storage.all_hosts().await?;
-        assert_eq!(res, vec!["a".to_string()]);
+        self.bump_config_version().await;
         Ok(())
     }
 
-    #[tokio::test]
-    async fn fails_to_remove_missing() -> Result<()> {
-        let storage = MemoryStorage::default();
-        let test = vec!["a", "aa"];
-        for s in test.into_iter() {
-            storage.add_host(s).await?;
+    async fn hosts_version(&self) -> Result<i64, AppError> {
+        Ok(*self.hosts_version.lock().await)
+    }
+
+    async fn config_version(&self) -> Result<i64, AppError> {
+        Ok(*self.config_version.lock().await)
+    }
+
+    async fn try_lease(&self, name: &str, owner: &str, ttl: Duration) -> Result<bool, AppError> {
+        let owner = owner.to_string();
+        let now = unix_now();
+        let mut leases = self.leases.lock().await;
+        let lease = leases.entry(name.into()).or_insert((owner.clone(), now));
+        if lease.0 != owner && lease.1 > now {
+            return Ok(false);
         }
-        assert_eq!(storage.remove_host("ab").await, Err(AppError::NotFound));
+        *lease = (owner, now + ttl.as_secs() as i64);
+        Ok(true)
+    }
+
+    async fn request_regeneration(&self) -> Result<(), AppError> {
+        *self.regeneration_requests.lock().await += 1;
         Ok(())
     }
+
+    async fn regeneration_requests(&self) -> Result<i64, AppError> {
+        Ok(*self.regeneration_requests.lock().await)
+    }
+
+    async fn storage_stats(&self) -> Result<StorageStats, AppError> {
+        let latest = self.latest.lock().await.clone();
+        let files = self.files.lock().await;
+        Ok(StorageStats {
+            hosts: self.hosts.lock().await.len() as u64,
+            deleted_hosts: self.deleted.lock().await.len() as u64,
+            files: self.versions.lock().await.iter().flatten().count() as u64,
+            snapshots: self.snapshots.lock().await.len() as u64,
+            audit_entries: self.audit_log.lock().await.len() as u64,
+            latest_pac_bytes: latest.and_then(|h| files.get(&h)).map(|f| f.len() as u64),
+            db_bytes: None,
+            wal_bytes: None,
+        })
+    }
+
+    async fn export_state(&self) -> Result<InstanceState, AppError> {
+        let hosts = self.hosts.lock().await;
+        let snapshots = self.snapshots.lock().await;
+        let profiles = self.list_profiles().await?;
+        Ok(InstanceState {
+            version: STATE_VERSION,
+            hosts: hosts.values().cloned().collect(),
+            snapshots: snapshots
+                .iter()
+                .map(|(name, (created_at, hosts))| Snapshot {
+                    name: name.clone(),
+                    created_at: *created_at,
+                    hosts: hosts.clone(),
+                })
+                .collect(),
+            profiles,
+            proxy: self.get_proxy().await?,
+            groups: self.list_groups().await?,
+            upstreams: self.list_upstreams().await?,
+            mode: self.get_mode().await?,
+            exclusions: self.list_exclusions().await?,
+            bypass_private: self.get_bypass_private().await?,
+            networks: self.list_networks().await?,
+            ip_ranges: self.list_ip_ranges().await?,
+            blocklist: self.list_blocklist().await?,
+        })
+    }
+
+    async fn import_state(&self, state: InstanceState) -> Result<(), AppError> {
+        check_state_version(&state)?;
+        let _write = self.begin_hosts_write().await?;
+        let mut hosts = self.hosts.lock().await;
+        let mut snapshots = self.snapshots.lock().await;
+        *hosts = state
+            .hosts
+            .into_iter()
+            .map(|e| (e.host.clone(), e))
+            .collect();
+        *snapshots = state
+            .snapshots
+            .into_iter()
+            .map(|s| (s.name, (s.created_at, s.hosts)))
+            .collect();
+        *self.profiles.lock().await = state
+            .profiles
+            .into_iter()
+            .map(|p| (p.name.clone(), p))
+            .collect();
+        *self.proxy.lock().await = state.proxy;
+        *self.groups.lock().await = state
+            .groups
+            .into_iter()
+            .map(|g| (g.name.clone(), g))
+            .collect();
+        *self.upstreams.lock().await = state.upstreams;
+        *self.mode.lock().await = state.mode;
+        *self.exclusions.lock().await = state.exclusions.into_iter().collect();
+        *self.bypass_private.lock().await = state.bypass_private;
+        *self.networks.lock().await = state
+            .networks
+            .into_iter()
+            .map(|n| (n.name.clone(), n))
+            .collect();
+        *self.ip_ranges.lock().await = state.ip_ranges.into_iter().collect();
+        *self.blocklist.lock().await = state
+            .blocklist
+            .into_iter()
+            .map(|e| (e.host.clone(), e))
+            .collect();
+        self.bump_hosts_version().await;
+        self.bump_config_version().await;
+        Ok(())
+    }
+}
+
+impl MemoryStorage {
+    /// Copies everything out, one field at a time. Writes going on meanwhile
+    /// may be partly included, a dump taken after they're done has them all
+    pub(super) async fn dump(&self) -> MemoryDump {
+        let manifests = self.manifests.lock().await.clone();
+        let metas = self.metas.lock().await.clone();
+        let created = self.created.lock().await.clone();
+        let mut files: Vec<DumpedFile> = self
+            .files
+            .lock()
+            .await
+            .iter()
+            .map(|(hash, file)| DumpedFile {
+                hash: hash.clone(),
+                file: file.clone(),
+                manifest: manifests.get(hash).cloned().unwrap_or_default(),
+                meta: metas.get(hash).cloned(),
+                created_at: created.get(hash).copied(),
+            })
+            .collect();
+        files.sort_by(|a, b| a.hash.cmp(&b.hash));
+        MemoryDump {
+            hosts: self.hosts.lock().await.values().cloned().collect(),
+            files,
+            versions: self.versions.lock().await.clone(),
+            latest: self.latest.lock().await.clone(),
+            staged: self.staged.lock().await.clone(),
+            snapshots: self
+                .snapshots
+                .lock()
+                .await
+                .iter()
+                .map(|(name, (created_at, hosts))| Snapshot {
+                    name: name.clone(),
+                    created_at: *created_at,
+                    hosts: hosts.clone(),
+                })
+                .collect(),
+            profiles: self.profiles.lock().await.values().cloned().collect(),
+            regeneration_requests: *self.regeneration_requests.lock().await,
+            proxy: self.proxy.lock().await.clone(),
+            hosts_version: *self.hosts_version.lock().await,
+            config_version: *self.config_version.lock().await,
+            groups: self.groups.lock().await.values().cloned().collect(),
+            upstreams: self.upstreams.lock().await.clone(),
+            mode: *self.mode.lock().await,
+            exclusions: self.exclusions.lock().await.clone(),
+            bypass_private: *self.bypass_private.lock().await,
+            networks: self.networks.lock().await.values().cloned().collect(),
+            ip_ranges: self.ip_ranges.lock().await.clone(),
+            blocklist: self.blocklist.lock().await.values().cloned().collect(),
+            client_fetches: self
+                .client_fetches
+                .lock()
+                .await
+                .iter()
+                .map(|((day, client, profile), fetches)| ClientFetches {
+                    day: *day,
+                    client: client.clone(),
+                    profile: profile.clone(),
+                    fetches: *fetches,
+                })
+                .collect(),
+            audit_log: self.audit_log.lock().await.clone(),
+            deleted: self.deleted.lock().await.values().cloned().collect(),
+        }
+    }
+
+    /// Storage holding what [`MemoryStorage::dump`] copied out
+    pub(super) fn restore(dump: MemoryDump) -> Self {
+        let by_hash = |f: &DumpedFile| f.hash.clone();
+        Self {
+            hosts: Mutex::new(
+                dump.hosts
+                    .into_iter()
+                    .map(|e| (e.host.clone(), e))
+                    .collect(),
+            ),
+            manifests: Mutex::new(
+                dump.files
+                    .iter()
+                    .map(|f| (by_hash(f), f.manifest.clone()))
+                    .collect(),
+            ),
+            metas: Mutex::new(
+                dump.files
+                    .iter()
+                    .filter_map(|f| Some((by_hash(f), f.meta.clone()?)))
+                    .collect(),
+            ),
+            created: Mutex::new(
+                dump.files
+                    .iter()
+                    .filter_map(|f| Some((by_hash(f), f.created_at?)))
+                    .collect(),
+            ),
+            files: Mutex::new(dump.files.into_iter().map(|f| (f.hash, f.file)).collect()),
+            versions: Mutex::new(dump.versions),
+            latest: Mutex::new(dump.latest),
+            staged: Mutex::new(dump.staged),
+            snapshots: Mutex::new(
+                dump.snapshots
+                    .into_iter()
+                    .map(|s| (s.name, (s.created_at, s.hosts)))
+                    .collect(),
+            ),
+            profiles: Mutex::new(
+                dump.profiles
+                    .into_iter()
+                    .map(|p| (p.name.clone(), p))
+                    .collect(),
+            ),
+            regeneration_requests: Mutex::new(dump.regeneration_requests),
+            proxy: Mutex::new(dump.proxy),
+            hosts_version: Mutex::new(dump.hosts_version),
+            config_version: Mutex::new(dump.config_version),
+            groups: Mutex::new(
+                dump.groups
+                    .into_iter()
+                    .map(|g| (g.name.clone(), g))
+                    .collect(),
+            ),
+            upstreams: Mutex::new(dump.upstreams),
+            mode: Mutex::new(dump.mode),
+            exclusions: Mutex::new(dump.exclusions),
+            bypass_private: Mutex::new(dump.bypass_private),
+            networks: Mutex::new(
+                dump.networks
+                    .into_iter()
+                    .map(|n| (n.name.clone(), n))
+                    .collect(),
+            ),
+            ip_ranges: Mutex::new(dump.ip_ranges),
+            blocklist: Mutex::new(
+                dump.blocklist
+                    .into_iter()
+                    .map(|b| (b.host.clone(), b))
+                    .collect(),
+            ),
+            client_fetches: Mutex::new(
+                dump.client_fetches
+                    .into_iter()
+                    .map(|f| ((f.day, f.client, f.profile), f.fetches))
+                    .collect(),
+            ),
+            audit_log: Mutex::new(dump.audit_log),
+            deleted: Mutex::new(
+                dump.deleted
+                    .into_iter()
+                    .map(|d| (d.entry.host.clone(), d))
+                    .collect(),
+            ),
+            ..Default::default()
+        }
+    }
+
+    /// Keeps removed entries for [`Storage::restore_host`], returns their hosts
+    async fn trash(&self, entries: Vec<HostEntry>) -> Vec<String> {
+        let deleted_at = unix_now();
+        let mut deleted = self.deleted.lock().await;
+        entries
+            .into_iter()
+            .map(|entry| {
+                let host = entry.host.clone();
+                deleted.insert(host.clone(), DeletedHost { entry, deleted_at });
+                host
+            })
+            .collect()
+    }
+
+    /// Fails when the list moved past the revision the request expects, see
+    /// [`expected_revision`]. Hold the guard until the write is done
+    async fn begin_hosts_write(&self) -> Result<MutexGuard<'_, ()>, AppError> {
+        let guard = self.hosts_writes.lock().await;
+        if let Some(expected) = expected_revision() {
+            let current = *self.hosts_version.lock().await;
+            if current != expected {
+                return Err(AppError::StaleRevision { expected, current });
+            }
+        }
+        Ok(guard)
+    }
+
+    async fn bump_hosts_version(&self) {
+        *self.hosts_version.lock().await += 1;
+        self.changes.send(ChangeEvent::Hosts);
+    }
+
+    async fn bump_config_version(&self) {
+        *self.config_version.lock().await += 1;
+        self.changes.send(ChangeEvent::Config);
+    }
+}
+
+fn new_entry(host: String) -> HostEntry {
+    let now = unix_now();
+    HostEntry {
+        host,
+        created_at: now,
+        updated_at: now,
+        ..Default::default()
+    }
+}
+
+/// SQL `LIKE` without an escape character on lowercased bytes, stored hosts
+/// are ascii. Only the last `%` is backtracked to, which keeps it at
+/// O(pattern * value) whatever the pattern
+fn like(pattern: &[u8], value: &[u8]) -> bool {
+    let (mut p, mut v) = (0, 0);
+    // Position after the last `%` and the value position it's retried from
+    let mut retry: Option<(usize, usize)> = None;
+    while v < value.len() {
+        match pattern.get(p) {
+            Some(b'%') => {
+                p += 1;
+                retry = Some((p, v));
+            }
+            Some(&c) if c == b'_' || c == value[v] => {
+                p += 1;
+                v += 1;
+            }
+            _ => match retry {
+                Some((after, from)) => {
+                    p = after;
+                    v = from + 1;
+                    retry = Some((after, from + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'%')
+}
+
+fn apply_patch(entry: &mut HostEntry, patch: HostPatch) {
+    if let Some(note) = patch.note {
+        entry.note = note;
+    }
+    if let Some(tags) = patch.tags {
+        entry.tags = sorted_tags(tags);
+    }
+    if let Some(expires_at) = patch.expires_at {
+        entry.expires_at = expires_at;
+    }
+    if let Some(kind) = patch.kind {
+        entry.kind = kind;
+    }
+    if let Some(pinned) = patch.pinned {
+        entry.pinned = pinned;
+    }
+    if let Some(group) = patch.group {
+        entry.group = group;
+    }
+    if let Some(schedule) = patch.schedule {
+        entry.schedule = schedule;
+    }
+    entry.updated_at = unix_now();
+}
+
+fn sorted_tags(tags: Vec<String>) -> Vec<String> {
+    let tags: BTreeSet<String> = tags.into_iter().collect();
+    tags.into_iter().collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::tests::conformance;
+
+    conformance!(MemoryStorage);
+
+    #[test]
+    fn matches_like_patterns() {
+        assert!(like(b"%google%", b"www.google.com"));
+        assert!(like(b"_.b", b"a.b"));
+        assert!(like(b"a%", b"a"));
+        assert!(like(b"%", b""));
+        assert!(!like(b"a_", b"a"));
+        assert!(!like(b"%b", b"bba"));
+
+        // Exponential when every `%` is backtracked to
+        let pattern = format!("{}b", "%a".repeat(100));
+        assert!(!like(pattern.as_bytes(), "a".repeat(250).as_bytes()));
+    }
 }
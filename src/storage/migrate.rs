@@ -0,0 +1,214 @@
+use std::sync::Arc;
+
+use super::{
+    file_storage::FileStorage,
+    memory_storage::MemoryStorage,
+    postgres_storage::{is_postgres_url, PostgresStorage},
+    sqlite_storage::SqliteStorage,
+    Storage,
+};
+use crate::{
+    args::{PostgresArgs, SqliteArgs},
+    error::{AppError, Result},
+    pac::Pac,
+};
+
+/// What [`copy`] moved over
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CopyReport {
+    pub hosts: usize,
+    pub snapshots: usize,
+    pub profiles: usize,
+    pub versions: usize,
+}
+
+/// Opens `sqlite:...` and `postgres://...` urls with pending migrations
+/// applied, `file:<path>` state files and `memory`, an empty memory storage
+pub async fn open(
+    url: &str,
+    sqlite: &SqliteArgs,
+    postgres: &PostgresArgs,
+) -> Result<Arc<dyn Storage>> {
+    if url.starts_with("sqlite:") {
+        return Ok(Arc::new(SqliteStorage::new_with(url, sqlite).await?));
+    }
+    if is_postgres_url(url) {
+        return Ok(Arc::new(PostgresStorage::new_with(url, postgres).await?));
+    }
+    if let Some(path) = url
+        .strip_prefix("file://")
+        .or_else(|| url.strip_prefix("file:"))
+    {
+        return Ok(Arc::new(FileStorage::open(path).await?));
+    }
+    if is_memory_url(url) {
+        return Ok(Arc::new(MemoryStorage::default()));
+    }
+    Err(AppError::Validation {
+        field: "url".to_string(),
+        message: format!(
+            "unsupported storage {url}, expected sqlite:..., postgres://..., file:... or memory"
+        ),
+    })?
+}
+
+/// [`open`] for the target of [`copy`], refusing `memory` where the copy
+/// would be gone on exit
+pub async fn open_target(
+    url: &str,
+    sqlite: &SqliteArgs,
+    postgres: &PostgresArgs,
+) -> Result<Arc<dyn Storage>> {
+    if is_memory_url(url) {
+        Err(AppError::Validation {
+            field: "to".to_string(),
+            message: "memory storage can't be copied to, it's gone on exit".to_string(),
+        })?
+    }
+    open(url, sqlite, postgres).await
+}
+
+fn is_memory_url(url: &str) -> bool {
+    url == "memory" || url == "memory:"
+}
+
+/// Copies hosts, settings, snapshots, profiles and every stored file with its
+/// encodings from `from` into the empty `to`, then checks that the counts
+/// match. Files keep their order but get versions numbered from 1 again
+pub async fn copy(from: &dyn Storage, to: &dyn Storage) -> Result<CopyReport> {
+    if !to.all_hosts().await?.is_empty() || !to.list_versions(1).await?.is_empty() {
+        Err(AppError::Conflict(
+            "Target storage is not empty".to_string(),
+        ))?
+    }
+
+    let state = from.export_state().await?;
+    let expected = CopyReport {
+        hosts: state.hosts.len(),
+        snapshots: state.snapshots.len(),
+        profiles: state.profiles.len(),
+        versions: 0,
+    };
+    to.import_state(state).await?;
+
+    let versions = from.list_versions(u32::MAX).await?;
+    for version in versions.iter().rev() {
+        let file = from.get_file(&version.hash).await?;
+        let hosts = match from.get_manifest(&version.hash).await {
+            Ok(hosts) => hosts,
+            Err(AppError::NotFound) => vec![],
+            Err(e) => Err(e)?,
+        };
+        let pac = Pac {
+            file,
+            hash: version.hash.clone(),
+            hosts,
+            meta: version.meta.clone(),
+        };
+        to.upload_file(&pac).await?;
+        let encodings = from.get_encodings(&version.hash).await?;
+        if encodings != Default::default() {
+            to.upload_encodings(&version.hash, &encodings).await?;
+        }
+    }
+    match from.latest_hash().await {
+        Ok(hash) => to.set_latest(&hash).await?,
+        Err(AppError::NotFound) => {}
+        Err(e) => Err(e)?,
+    }
+    if let Some(hash) = from.staged_hash().await? {
+        to.set_staged_hash(Some(hash)).await?;
+    }
+    let expected = CopyReport {
+        versions: versions.len(),
+        ..expected
+    };
+
+    let copied = to.export_state().await?;
+    let report = CopyReport {
+        hosts: copied.hosts.len(),
+        snapshots: copied.snapshots.len(),
+        profiles: copied.profiles.len(),
+        versions: to.list_versions(u32::MAX).await?.len(),
+    };
+    if report != expected {
+        Err(AppError::Other(format!(
+            "Copy doesn't match the source, expected {expected:?}, got {report:?}"
+        )))?
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::HostPatch;
+
+    #[tokio::test]
+    async fn copies_between_backends() -> Result<()> {
+        let from = MemoryStorage::default();
+        from.add_host("a", HostPatch::default()).await?;
+        from.add_host("b", HostPatch::default()).await?;
+        from.create_snapshot("before").await?;
+        for hosts in [
+            vec!["a".to_string()],
+            vec!["a".to_string(), "b".to_string()],
+        ] {
+            let pac = Pac::generate(hosts);
+            from.upload_file(&pac).await?;
+            from.set_latest(&pac.hash).await?;
+        }
+
+        let to = open(
+            "sqlite::memory:",
+            &SqliteArgs::default(),
+            &PostgresArgs::default(),
+        )
+        .await?;
+        let report = copy(&from, to.as_ref()).await?;
+        assert_eq!(
+            report,
+            CopyReport {
+                hosts: 2,
+                snapshots: 1,
+                profiles: 0,
+                versions: 2,
+            }
+        );
+        assert_eq!(to.latest_hash().await?, from.latest_hash().await?);
+        let latest = from.latest_hash().await?;
+        assert_eq!(to.get_manifest(&latest).await?, vec!["a", "b"]);
+        assert!(copy(&from, to.as_ref()).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn copies_into_state_file() -> Result<()> {
+        let from = MemoryStorage::default();
+        from.add_host("a", HostPatch::default()).await?;
+        let pac = Pac::generate(vec!["a".to_string()]);
+        from.upload_file(&pac).await?;
+        from.set_latest(&pac.hash).await?;
+
+        let path = std::env::temp_dir().join(format!("qpac_copy_{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let url = format!("file:{}", path.display());
+        let args = (SqliteArgs::default(), PostgresArgs::default());
+        copy(&from, open_target(&url, &args.0, &args.1).await?.as_ref()).await?;
+
+        let copied = open(&url, &args.0, &args.1).await?;
+        assert_eq!(copied.all_hosts().await?, vec!["a"]);
+        assert_eq!(copied.latest_hash().await?, pac.hash);
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn refuses_memory_target() -> Result<()> {
+        let args = (SqliteArgs::default(), PostgresArgs::default());
+        let err = open_target("memory", &args.0, &args.1).await.unwrap_err();
+        assert!(format!("{err:?}").contains("Validation: to: memory storage"));
+        assert!(open("memory", &args.0, &args.1).await.is_ok());
+        Ok(())
+    }
+}
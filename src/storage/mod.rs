@@ -1,28 +1,663 @@
-use crate::{error::AppError, pac::Pac};
+use std::{cell::Cell, fmt::Debug, future::Future, str::FromStr, time::Duration};
 
+use async_trait::async_trait;
+use futures::stream::BoxStream;
+use serde::{Deserialize, Deserializer, Serialize};
+use tokio::sync::broadcast;
+use tokio_stream::{wrappers::BroadcastStream, StreamExt};
+
+use crate::{
+    error::AppError,
+    pac::{NetworkProfile, Pac, PacEncodings, PacMeta, PacMode, Upstream},
+    rules::{EntryKind, Rule},
+    schedule::Schedule,
+};
+
+pub mod bucket;
+pub mod file_storage;
 pub mod memory_storage;
+pub mod migrate;
+pub mod postgres_storage;
 pub mod sqlite_storage;
+#[cfg(test)]
+mod tests;
+
+/// Backend a server keeps its state in, picked at startup
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum StorageKind {
+    /// [`sqlite_storage::SqliteStorage`]
+    #[default]
+    Sqlite,
+    /// [`memory_storage::MemoryStorage`], gone on shutdown. For tests and demos
+    Memory,
+    /// [`postgres_storage::PostgresStorage`], for instances sharing a server
+    Postgres,
+    /// [`file_storage::FileStorage`], a json file rewritten on every change.
+    /// For small single instance setups
+    File,
+}
+
+impl StorageKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StorageKind::Sqlite => "sqlite",
+            StorageKind::Memory => "memory",
+            StorageKind::Postgres => "postgres",
+            StorageKind::File => "file",
+        }
+    }
+}
+
+impl FromStr for StorageKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sqlite" => Ok(StorageKind::Sqlite),
+            "memory" => Ok(StorageKind::Memory),
+            "postgres" => Ok(StorageKind::Postgres),
+            "file" => Ok(StorageKind::File),
+            _ => Err(format!(
+                "unknown storage {s}, expected sqlite, memory, postgres or file"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportMode {
+    /// Only adds missing hosts
+    #[default]
+    Merge,
+    /// Adds missing and removes absent non-pinned hosts
+    Mirror,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct HostsDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+}
 
-pub trait Storage {
-    fn all_hosts(&self) -> impl futures::Future<Output = Result<Vec<String>, AppError>>;
+impl HostsDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
+
+    /// Diff turning sorted `current` into sorted `wanted`
+    pub fn between(current: &[String], wanted: &[String]) -> Self {
+        Self {
+            added: wanted
+                .iter()
+                .filter(|h| current.binary_search(h).is_err())
+                .cloned()
+                .collect(),
+            removed: current
+                .iter()
+                .filter(|h| wanted.binary_search(h).is_err())
+                .cloned()
+                .collect(),
+        }
+    }
+}
+
+/// Host with its metadata, timestamps are unix seconds
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HostEntry {
+    pub host: String,
+    pub note: Option<String>,
+    pub tags: Vec<String>,
+    pub expires_at: Option<i64>,
+    /// Whether the generated file matches the whole domain tree or just the
+    /// host
+    pub kind: EntryKind,
+    pub pinned: bool,
+    /// Proxy group routing the host instead of the default proxy
+    pub group: Option<String>,
+    /// Routed only at these times, overrides the schedule of the group
+    pub schedule: Option<Schedule>,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+impl HostEntry {
+    /// Wildcard hosts always cover just the subdomains, regex ones whatever
+    /// they match
+    pub fn rule(&self) -> Rule {
+        self.kind
+            .rule(&self.host)
+            .unwrap_or_else(|_| Rule::from_host(&self.host))
+    }
+}
+
+/// What the pac does with a host on the blocklist
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BlocklistAction {
+    /// Connect without a proxy, like an exclusion
+    Direct,
+    /// Send through a proxy that doesn't exist, so the connection fails
+    #[default]
+    Block,
+}
+
+impl BlocklistAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BlocklistAction::Direct => "direct",
+            BlocklistAction::Block => "block",
+        }
+    }
+}
+
+impl FromStr for BlocklistAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "direct" => Ok(BlocklistAction::Direct),
+            "block" => Ok(BlocklistAction::Block),
+            _ => Err(format!(
+                "unknown blocklist action {s}, expected direct or block"
+            )),
+        }
+    }
+}
+
+/// Host handled by its action before exclusions, groups and the list are
+/// checked, see [`Storage::set_blocklisted`]
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BlocklistEntry {
+    pub host: String,
+    pub kind: EntryKind,
+    pub action: BlocklistAction,
+    pub created_at: i64,
+}
+
+impl BlocklistEntry {
+    /// Same as [`HostEntry::rule`]
+    pub fn rule(&self) -> Rule {
+        self.kind
+            .rule(&self.host)
+            .unwrap_or_else(|_| Rule::from_host(&self.host))
+    }
+}
+
+/// Host removed with [`Storage::remove_host`] or the like, kept until
+/// [`Storage::purge_deleted`] so it can be restored
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeletedHost {
+    #[serde(flatten)]
+    pub entry: HostEntry,
+    pub deleted_at: i64,
+}
+
+/// Partial update of [`HostEntry`], `None` keeps the current value
+///
+/// `note`, `expires_at`, `group` and `schedule` are cleared with an explicit
+/// `null`
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+pub struct HostPatch {
+    #[serde(default, deserialize_with = "double_option")]
+    pub note: Option<Option<String>>,
+    pub tags: Option<Vec<String>>,
+    #[serde(default, deserialize_with = "double_option")]
+    pub expires_at: Option<Option<i64>>,
+    pub kind: Option<EntryKind>,
+    pub pinned: Option<bool>,
+    #[serde(default, deserialize_with = "double_option")]
+    pub group: Option<Option<String>>,
+    #[serde(default, deserialize_with = "double_option")]
+    pub schedule: Option<Option<Schedule>>,
+}
+
+fn double_option<'de, T, D>(de: D) -> Result<Option<Option<T>>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: Deserializer<'de>,
+{
+    Option::<T>::deserialize(de).map(Some)
+}
+
+/// Named copy of the host list, see [`Storage::create_snapshot`]
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SnapshotInfo {
+    pub name: String,
+    pub created_at: i64,
+    pub hosts: usize,
+}
+
+/// Tag in use, see [`Storage::list_tags`]
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TagInfo {
+    pub name: String,
+    pub hosts: usize,
+}
+
+/// Sequential number assigned to a stored file on its first upload
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PacVersion {
+    pub version: i64,
+    pub hash: String,
+    /// Bytes of the file
+    pub size: i64,
+    /// Unix seconds of the first upload, `None` for files stored before it
+    /// was recorded without metadata
+    pub created_at: Option<i64>,
+    /// `None` for files stored before it was recorded
+    pub meta: Option<PacMeta>,
+}
+
+/// Named variant of the pac with its own proxy chain, served on `/?profile=`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Profile {
+    pub name: String,
+    pub proxy: String,
+    /// Only hosts bearing one of the tags are listed, all of them when empty
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+/// Hosts assigned to a group are routed through its proxy chain in every
+/// generated pac
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProxyGroup {
+    pub name: String,
+    pub proxy: String,
+    /// Hosts of the group are routed only at these times
+    #[serde(default)]
+    pub schedule: Option<Schedule>,
+}
+
+/// PAC fetches from one client network with one profile on one day
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClientFetches {
+    /// Unix time of the UTC midnight starting the day
+    pub day: i64,
+    /// Client address with the host part cleared
+    pub client: String,
+    /// `None` for the default pac
+    pub profile: Option<String>,
+    pub fetches: i64,
+}
+
+/// Day, client and profile [`ClientFetches`] are counted by
+pub type ClientKey = (i64, String, Option<String>);
+
+/// Admin operation kept in the audit log
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// Unix time
+    pub at: i64,
+    /// How the caller authenticated, `token`, `session` or `anonymous`, and
+    /// `server` for the server's own operations
+    pub actor: String,
+    /// Client address, `None` for the server's own operations
+    pub ip: Option<String>,
+    /// Method and path such as `POST /add`, or `regenerate`
+    pub action: String,
+    /// Request body or details of the operation, `null` without any
+    pub payload: serde_json::Value,
+}
 
-    fn get_file(
+/// Sizes for capacity monitoring, see [`Storage::storage_stats`]
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct StorageStats {
+    pub hosts: u64,
+    pub deleted_hosts: u64,
+    /// Stored generated files
+    pub files: u64,
+    pub snapshots: u64,
+    pub audit_entries: u64,
+    /// Bytes of the latest file, `None` before the first one
+    pub latest_pac_bytes: Option<u64>,
+    /// Bytes of the database, `None` for backends without one
+    pub db_bytes: Option<u64>,
+    /// Bytes of the write-ahead log, `None` for backends without one
+    pub wal_bytes: Option<u64>,
+}
+
+/// Format version of [`InstanceState`], 1 only held hosts and snapshots and
+/// imports with the fields added since defaulted
+pub const STATE_VERSION: u32 = 2;
+
+/// Everything needed to move an instance to another storage backend
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InstanceState {
+    pub version: u32,
+    pub hosts: Vec<HostEntry>,
+    pub snapshots: Vec<Snapshot>,
+    #[serde(default)]
+    pub profiles: Vec<Profile>,
+    #[serde(default)]
+    pub proxy: Option<String>,
+    #[serde(default)]
+    pub groups: Vec<ProxyGroup>,
+    #[serde(default)]
+    pub upstreams: Vec<Upstream>,
+    #[serde(default)]
+    pub mode: PacMode,
+    #[serde(default)]
+    pub exclusions: Vec<String>,
+    #[serde(default)]
+    pub bypass_private: bool,
+    #[serde(default)]
+    pub networks: Vec<NetworkProfile>,
+    #[serde(default)]
+    pub ip_ranges: Vec<String>,
+    #[serde(default)]
+    pub blocklist: Vec<BlocklistEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub name: String,
+    pub created_at: i64,
+    pub hosts: Vec<HostEntry>,
+}
+
+/// Change made through a [`Storage`] that the generated files depend on, see
+/// [`Storage::watch`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeEvent {
+    /// Hosts, their metadata, exclusions or the blocklist
+    Hosts,
+    /// Proxy, mode, groups, profiles or other settings files are generated with
+    Config,
+}
+
+/// Events buffered per watcher, slower watchers skip the oldest ones
+const CHANGE_FEED_CAPACITY: usize = 64;
+
+/// Fans [`ChangeEvent`]s out to the streams of [`Storage::watch`]
+#[derive(Debug, Clone)]
+pub struct ChangeFeed(broadcast::Sender<ChangeEvent>);
+
+impl Default for ChangeFeed {
+    fn default() -> Self {
+        Self(broadcast::channel(CHANGE_FEED_CAPACITY).0)
+    }
+}
+
+impl ChangeFeed {
+    pub fn send(&self, event: ChangeEvent) {
+        // Nobody watching is fine
+        let _ = self.0.send(event);
+    }
+
+    /// Events sent from now on. A lagging watcher still gets the latest ones,
+    /// which is all regeneration needs
+    pub fn watch(&self) -> BoxStream<'static, ChangeEvent> {
+        Box::pin(BroadcastStream::new(self.0.subscribe()).filter_map(Result::ok))
+    }
+}
+
+/// Object safe so the backend can be picked at runtime and shared as
+/// `Arc<dyn Storage>`
+#[async_trait]
+pub trait Storage: Debug + Send + Sync {
+    /// Changes committed through this instance from now on, changes made by
+    /// other processes sharing the database are not seen
+    fn watch(&self) -> BoxStream<'static, ChangeEvent>;
+    /// Changes committed by every process sharing the database from now on,
+    /// this one included, pushed by the backend. `None` when it can't push,
+    /// the versions are polled then, see `--change-poll-interval`
+    async fn watch_shared(&self) -> Result<Option<BoxStream<'static, ChangeEvent>>, AppError>;
+    async fn all_hosts(&self) -> Result<Vec<String>, AppError>;
+    /// All hosts with metadata, sorted by host
+    async fn host_entries(&self) -> Result<Vec<HostEntry>, AppError>;
+    /// Up to `limit` hosts with metadata sorted by host, the ones following
+    /// `after` when given. Pass the last host of a page to get the next one
+    async fn hosts_page(&self, after: Option<&str>, limit: u32)
+        -> Result<Vec<HostEntry>, AppError>;
+    /// Hosts matching a SQL `LIKE` pattern, sorted. `%` matches any run of
+    /// characters and `_` a single one, ascii letters match in any case
+    async fn search_hosts(&self, pattern: &str) -> Result<Vec<String>, AppError>;
+    async fn get_host(&self, host: &str) -> Result<HostEntry, AppError>;
+    /// Applies `patch` in place, keeping `created_at`
+    async fn update_host(&self, host: &str, patch: HostPatch) -> Result<HostEntry, AppError>;
+
+    async fn get_file(&self, hash: &str) -> Result<String, AppError>;
+    async fn get_file_latest(&self) -> Result<Pac, AppError>;
+    /// Hash of the latest file without loading it
+    async fn latest_hash(&self) -> Result<String, AppError>;
+    /// Checksum recorded when the file was uploaded, see [`crate::pac::checksum`]
+    async fn get_checksum(&self, hash: &str) -> Result<String, AppError>;
+    /// Hosts a stored file was generated from
+    async fn get_manifest(&self, hash: &str) -> Result<Vec<String>, AppError>;
+    /// Stores a file, new hashes get the next version
+    async fn upload_file(&self, file: &Pac) -> Result<(), AppError>;
+    /// Stores precompressed bodies of an uploaded file, replacing the ones of
+    /// the same encoding
+    async fn upload_encodings(&self, hash: &str, encodings: &PacEncodings) -> Result<(), AppError>;
+    /// Precompressed bodies of a file, empty when none were stored
+    async fn get_encodings(&self, hash: &str) -> Result<PacEncodings, AppError>;
+    /// Up to `limit` stored files, newest first. Pass `u32::MAX` for the whole
+    /// history
+    async fn list_versions(&self, limit: u32) -> Result<Vec<PacVersion>, AppError>;
+    /// Deletes stored files except the `keep` newest, the ones generated at
+    /// or after `before`, the latest and the staged one, returns how many were
+    /// deleted. The newest is always kept so that versions aren't reused
+    async fn prune_files(&self, keep: u32, before: i64) -> Result<u64, AppError>;
+    async fn get_version(&self, hash: &str) -> Result<i64, AppError>;
+    async fn get_version_hash(&self, version: i64) -> Result<String, AppError>;
+    async fn set_latest(&self, hash: &str) -> Result<(), AppError>;
+    /// Candidate file regenerations store instead of moving the latest
+    /// pointer, `None` unless staging
+    async fn staged_hash(&self) -> Result<Option<String>, AppError>;
+    /// `None` stops staging without publishing the candidate
+    async fn set_staged_hash(&self, hash: Option<String>) -> Result<(), AppError>;
+    /// Makes the candidate the latest file and stops staging atomically,
+    /// returns its hash. Not found unless staging
+    async fn promote_staged(&self) -> Result<String, AppError>;
+
+    /// Adds `host` with `patch` applied in one go, fails when it's listed
+    async fn add_host(&self, host: &str, patch: HostPatch) -> Result<(), AppError>;
+    /// Adds `host` unless listed and applies `patch` to it in one go, returns
+    /// whether it was added. Unlike [`Storage::add_host`] a listed host is fine
+    async fn upsert_host(&self, host: &str, patch: HostPatch) -> Result<bool, AppError>;
+    /// Removals here, in [`Storage::remove_hosts`] and
+    /// [`Storage::remove_hosts_by_tag`] keep the entry as a [`DeletedHost`]
+    async fn remove_host(&self, host: &str) -> Result<(), AppError>;
+    /// Adds hosts with `patch` applied in a single transaction, returns the
+    /// ones that weren't listed yet, sorted. Listed ones are left alone
+    async fn add_hosts(
         &self,
-        hash: impl Into<String>,
-    ) -> impl futures::Future<Output = Result<String, AppError>>;
-    fn get_file_latest(&self) -> impl futures::Future<Output = Result<Pac, AppError>>;
-    fn upload_file(&self, file: &Pac) -> impl futures::Future<Output = Result<(), AppError>>;
-    fn set_latest(
+        hosts: Vec<String>,
+        patch: HostPatch,
+    ) -> Result<Vec<String>, AppError>;
+    /// Adds the hosts not listed yet and applies `patch` to every one of them
+    /// in a single transaction, returns the added ones, sorted
+    async fn upsert_hosts(
         &self,
-        hash: impl Into<String>,
-    ) -> impl futures::Future<Output = Result<(), AppError>>;
+        hosts: Vec<String>,
+        patch: HostPatch,
+    ) -> Result<Vec<String>, AppError>;
+    /// Removes non-pinned hosts in a single transaction, returns the removed
+    /// ones, sorted
+    async fn remove_hosts(&self, hosts: Vec<String>) -> Result<Vec<String>, AppError>;
 
-    fn add_host(
-        &self,
-        host: impl Into<String>,
-    ) -> impl futures::Future<Output = Result<(), AppError>>;
-    fn remove_host(
+    /// Removed hosts, sorted
+    async fn deleted_hosts(&self) -> Result<Vec<DeletedHost>, AppError>;
+    /// Lists a removed host again with the metadata it had, conflicts when the
+    /// host was added since
+    async fn restore_host(&self, host: &str) -> Result<HostEntry, AppError>;
+    /// Forgets hosts removed before `before`, returns how many were forgotten
+    async fn purge_deleted(&self, before: i64) -> Result<u64, AppError>;
+    /// Removes non-pinned hosts whose `expires_at` is at or before `now` like
+    /// [`Storage::remove_hosts`], returns them sorted
+    async fn remove_expired(&self, now: i64) -> Result<Vec<String>, AppError>;
+
+    /// Pinned hosts are never touched by bulk operations
+    async fn set_pinned(&self, host: &str, pinned: bool) -> Result<(), AppError>;
+    async fn pinned_hosts(&self) -> Result<Vec<String>, AppError>;
+
+    /// Replaces tags of an existing host
+    async fn set_tags(&self, host: &str, tags: Vec<String>) -> Result<(), AppError>;
+    async fn hosts_by_tag(&self, tag: &str) -> Result<Vec<String>, AppError>;
+    /// Removes every non-pinned host bearing `tag` atomically, returns removed hosts
+    async fn remove_hosts_by_tag(&self, tag: &str) -> Result<Vec<String>, AppError>;
+    /// Tags borne by at least one host, sorted by name
+    async fn list_tags(&self) -> Result<Vec<TagInfo>, AppError>;
+    /// Moves `tag` to `to` on every host bearing it, merging into `to` when it
+    /// exists. Not found when no host bears `tag`
+    async fn rename_tag(&self, tag: &str, to: &str) -> Result<(), AppError>;
+    /// Takes `tag` off every host, the hosts stay. Not found when no host
+    /// bears it
+    async fn delete_tag(&self, tag: &str) -> Result<(), AppError>;
+
+    /// Applies `hosts` atomically, with `dry_run` only the diff is computed
+    async fn import_hosts(
         &self,
-        host: impl Into<String>,
-    ) -> impl futures::Future<Output = Result<(), AppError>>;
+        hosts: Vec<String>,
+        mode: ImportMode,
+        dry_run: bool,
+    ) -> Result<HostsDiff, AppError>;
+
+    /// Saves current hosts with metadata under `name`, names are unique
+    async fn create_snapshot(&self, name: &str) -> Result<SnapshotInfo, AppError>;
+    async fn list_snapshots(&self) -> Result<Vec<SnapshotInfo>, AppError>;
+    /// Replaces all hosts, pinned included, with the snapshot atomically
+    async fn restore_snapshot(&self, name: &str) -> Result<HostsDiff, AppError>;
+    async fn delete_snapshot(&self, name: &str) -> Result<(), AppError>;
+
+    /// Creates or replaces a profile
+    async fn set_profile(&self, profile: Profile) -> Result<(), AppError>;
+    async fn get_profile(&self, name: &str) -> Result<Profile, AppError>;
+    async fn list_profiles(&self) -> Result<Vec<Profile>, AppError>;
+    async fn remove_profile(&self, name: &str) -> Result<(), AppError>;
+
+    /// Creates or replaces a proxy group
+    async fn set_group(&self, group: ProxyGroup) -> Result<(), AppError>;
+    async fn list_groups(&self) -> Result<Vec<ProxyGroup>, AppError>;
+    /// Removes a group, its hosts go back to the default proxy
+    async fn remove_group(&self, name: &str) -> Result<(), AppError>;
+
+    /// Creates or replaces a network profile
+    async fn set_network(&self, network: NetworkProfile) -> Result<(), AppError>;
+    /// Sorted by name
+    async fn list_networks(&self) -> Result<Vec<NetworkProfile>, AppError>;
+    async fn remove_network(&self, name: &str) -> Result<(), AppError>;
+
+    /// Changes after every committed host change, also ones made by other
+    /// instances sharing the database
+    async fn hosts_version(&self) -> Result<i64, AppError>;
+
+    /// Changes after every committed change of the settings files are
+    /// generated with, also ones made by other instances sharing the database
+    async fn config_version(&self) -> Result<i64, AppError>;
+
+    /// Proxy chain of the default pac, `None` until configured
+    async fn get_proxy(&self) -> Result<Option<String>, AppError>;
+    async fn set_proxy(&self, proxy: &str) -> Result<(), AppError>;
+
+    /// Whether listed hosts are the proxied or the direct ones
+    async fn get_mode(&self) -> Result<PacMode, AppError>;
+    async fn set_mode(&self, mode: PacMode) -> Result<(), AppError>;
+
+    /// Whether private network addresses and plain host names go DIRECT
+    async fn get_bypass_private(&self) -> Result<bool, AppError>;
+    async fn set_bypass_private(&self, enabled: bool) -> Result<(), AppError>;
+
+    /// Upstreams the default hosts are spread over in order, empty when they
+    /// all use the default proxy
+    async fn list_upstreams(&self) -> Result<Vec<Upstream>, AppError>;
+    /// Replaces every upstream
+    async fn set_upstreams(&self, upstreams: Vec<Upstream>) -> Result<(), AppError>;
+
+    /// Networks in CIDR notation hosts are resolved into, sorted
+    async fn list_ip_ranges(&self) -> Result<Vec<String>, AppError>;
+    /// Replaces every IP range
+    async fn set_ip_ranges(&self, ranges: Vec<String>) -> Result<(), AppError>;
+
+    /// Hosts always sent DIRECT, sorted
+    async fn list_exclusions(&self) -> Result<Vec<String>, AppError>;
+    /// Adds exclusions, returns the ones that weren't excluded yet
+    async fn add_exclusions(&self, hosts: Vec<String>) -> Result<Vec<String>, AppError>;
+    async fn remove_exclusion(&self, host: &str) -> Result<(), AppError>;
+
+    /// Sorted by host
+    async fn list_blocklist(&self) -> Result<Vec<BlocklistEntry>, AppError>;
+    /// Creates or replaces the entry of a host, `created_at` is set when it's
+    /// created
+    async fn set_blocklisted(&self, entry: BlocklistEntry) -> Result<(), AppError>;
+    async fn remove_blocklisted(&self, host: &str) -> Result<(), AppError>;
+
+    /// Adds `fetches` to the counts stored for the same day, client and profile
+    async fn record_client_fetches(&self, fetches: Vec<ClientFetches>) -> Result<(), AppError>;
+    /// Counts of days starting at or after `since`, ordered by day, client and profile
+    async fn client_fetches(&self, since: i64) -> Result<Vec<ClientFetches>, AppError>;
+    /// Drops counts of days starting before `before`, returns how many were dropped
+    async fn prune_client_fetches(&self, before: i64) -> Result<u64, AppError>;
+
+    async fn record_audit(&self, entry: AuditEntry) -> Result<(), AppError>;
+    /// Up to `limit` entries recorded at or after `since`, newest first
+    async fn audit_log(&self, since: i64, limit: u32) -> Result<Vec<AuditEntry>, AppError>;
+    /// Drops entries recorded before `before`, returns how many were dropped
+    async fn prune_audit(&self, before: i64) -> Result<u64, AppError>;
+
+    /// Takes or renews lease `name` for `owner`, false when another owner
+    /// holds an unexpired lease
+    async fn try_lease(&self, name: &str, owner: &str, ttl: Duration) -> Result<bool, AppError>;
+    /// Asks the lease holder to regenerate, see [`Storage::regeneration_requests`]
+    async fn request_regeneration(&self) -> Result<(), AppError>;
+    /// Counter bumped by every [`Storage::request_regeneration`]
+    async fn regeneration_requests(&self) -> Result<i64, AppError>;
+
+    async fn storage_stats(&self) -> Result<StorageStats, AppError>;
+
+    async fn export_state(&self) -> Result<InstanceState, AppError>;
+    /// Replaces hosts, snapshots, profiles, groups, the proxy, upstreams,
+    /// the mode, exclusions, the private network bypass, network profiles, IP
+    /// ranges and the blocklist atomically
+    async fn import_state(&self, state: InstanceState) -> Result<(), AppError>;
+}
+
+tokio::task_local! {
+    static EXPECTED_REVISION: Cell<Option<i64>>;
+}
+
+/// Runs `f` with its first host list write failing with
+/// [`AppError::StaleRevision`] unless the list is still at `revision`, see
+/// [`Storage::hosts_version`]. Backends compare it inside the write's
+/// transaction, writes after the first one build on it and aren't checked
+pub async fn expect_revision<F: Future>(revision: i64, f: F) -> F::Output {
+    EXPECTED_REVISION.scope(Cell::new(Some(revision)), f).await
+}
+
+/// Revision the running host list write has to find, taken so only the first
+/// write of a request checks it
+pub fn expected_revision() -> Option<i64> {
+    EXPECTED_REVISION.try_with(Cell::take).ok().flatten()
+}
+
+/// Rejects documents written by a newer version
+pub fn check_state_version(state: &InstanceState) -> Result<(), AppError> {
+    if state.version == 0 || state.version > STATE_VERSION {
+        return Err(AppError::Validation {
+            field: "version".to_string(),
+            message: format!(
+                "unsupported state version {}, expected at most {STATE_VERSION}",
+                state.version
+            ),
+        });
+    }
+    Ok(())
+}
+
+/// Profile `tags` with `tag` renamed to `to`, `None` when `tag` isn't listed
+pub fn renamed_tag(tags: &[String], tag: &str, to: &str) -> Option<Vec<String>> {
+    if !tags.iter().any(|t| t == tag) {
+        return None;
+    }
+    let mut renamed: Vec<String> = Vec::with_capacity(tags.len());
+    for t in tags.iter().map(|t| if t == tag { to } else { t }) {
+        if !renamed.iter().any(|r| r == t) {
+            renamed.push(t.to_string());
+        }
+    }
+    Some(renamed)
 }
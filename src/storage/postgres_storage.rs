@@ -0,0 +1,1969 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use futures::stream::{self, BoxStream};
+use serde_json::json;
+use sqlx::{
+    migrate,
+    migrate::Migrate,
+    pool::PoolConnection,
+    postgres::{PgConnectOptions, PgListener, PgPoolOptions},
+    ConnectOptions, FromRow, PgConnection, PgPool, Postgres, Transaction,
+};
+use tracing::{debug, log::LevelFilter, warn};
+
+use crate::{
+    args::PostgresArgs,
+    error::{AppError, Result},
+    instrument::metrics::{DB_POOL_ACQUIRE_SECONDS, DB_POOL_CONNECTIONS},
+    pac::{self, NetworkProfile, Pac, PacEncodings, PacMeta, PacMode, Upstream},
+    utils::time::unix_now,
+};
+
+use super::{
+    check_state_version, expected_revision, renamed_tag,
+    sqlite_storage::{
+        decode_file, encode_file, parse_kind, parse_schedule, split_tags, MigrationStatus,
+    },
+    AuditEntry, BlocklistEntry, ChangeEvent, ChangeFeed, ClientFetches, DeletedHost, HostEntry,
+    HostPatch, HostsDiff, ImportMode, InstanceState, PacVersion, Profile, ProxyGroup, Snapshot,
+    SnapshotInfo, Storage, StorageStats, TagInfo, STATE_VERSION,
+};
+
+/// Whether `url` points at a postgres database rather than a sqlite one
+pub fn is_postgres_url(url: &str) -> bool {
+    url.starts_with("postgres://") || url.starts_with("postgresql://")
+}
+
+/// Storage in a postgres database, which several instances can share like a
+/// sqlite file. Same tables as [`super::sqlite_storage::SqliteStorage`], from
+/// the migrations in `migrations/postgres`
+#[derive(Debug)]
+pub struct PostgresStorage {
+    pool: PgPool,
+    changes: ChangeFeed,
+}
+
+/// Columns of [`HostRow`], in the order of the table
+const HOST_COLUMNS: &str =
+    "host, note, expires_at, kind, pinned, proxy_group, schedule, created_at, updated_at";
+
+#[derive(Debug, FromRow)]
+struct HostRow {
+    host: String,
+    note: Option<String>,
+    expires_at: Option<i64>,
+    kind: String,
+    pinned: bool,
+    proxy_group: Option<String>,
+    schedule: Option<String>,
+    created_at: i64,
+    updated_at: i64,
+}
+
+impl HostRow {
+    fn into_entry(self, tags: Vec<String>) -> Result<HostEntry, AppError> {
+        Ok(HostEntry {
+            host: self.host,
+            note: self.note,
+            tags,
+            expires_at: self.expires_at,
+            kind: parse_kind(&self.kind)?,
+            pinned: self.pinned,
+            group: self.proxy_group,
+            schedule: parse_schedule(self.schedule)?,
+            created_at: self.created_at,
+            updated_at: self.updated_at,
+        })
+    }
+}
+
+impl PostgresStorage {
+    /// Connects and applies pending migrations
+    pub async fn new(url: &str) -> Result<Self> {
+        Self::new_with(url, &PostgresArgs::default()).await
+    }
+
+    /// [`PostgresStorage::new`] with the options of `args`
+    pub async fn new_with(url: &str, args: &PostgresArgs) -> Result<Self> {
+        let storage = Self::connect_with(url, args).await?;
+        storage.migrate().await?;
+        Ok(storage)
+    }
+
+    /// Connects without touching the schema. `?options=-csearch_path%3D<schema>`
+    /// in `url` keeps the tables in their own schema
+    pub async fn connect_with(url: &str, args: &PostgresArgs) -> Result<Self> {
+        let mut conf = PgConnectOptions::from_str(url)?.log_statements(LevelFilter::Trace);
+        if let Some(timeout) = args.pg_statement_timeout {
+            conf = conf.options([("statement_timeout", timeout.to_string())]);
+        }
+        let pool = PgPoolOptions::new()
+            .max_connections(args.pg_pool_size)
+            .connect_with(conf)
+            .await?;
+        Ok(Self {
+            pool,
+            changes: ChangeFeed::default(),
+        })
+    }
+
+    pub async fn migrate(&self) -> Result<()> {
+        migrate!("./migrations/postgres").run(&self.pool).await?;
+        Ok(())
+    }
+
+    /// Versions and descriptions of migrations not applied yet
+    pub async fn pending_migrations(&self) -> Result<Vec<(i64, String)>> {
+        Ok(self.migration_status().await?.pending)
+    }
+
+    /// Compares the compiled-in migrations with the ones applied to the database
+    pub async fn migration_status(&self) -> Result<MigrationStatus> {
+        let mut conn = self.acquire().await?;
+        let has_table: bool =
+            sqlx::query_scalar("SELECT to_regclass('_sqlx_migrations') IS NOT NULL;")
+                .fetch_one(conn.as_mut())
+                .await?;
+        let applied: HashMap<i64, Vec<u8>> = if has_table {
+            conn.list_applied_migrations()
+                .await?
+                .into_iter()
+                .map(|m| (m.version, m.checksum.into_owned()))
+                .collect()
+        } else {
+            HashMap::new()
+        };
+
+        let migrator = migrate!("./migrations/postgres");
+        let mut status = MigrationStatus::default();
+        for m in migrator
+            .iter()
+            .filter(|m| !m.migration_type.is_down_migration())
+        {
+            match applied.get(&m.version) {
+                None => status.pending.push((m.version, m.description.to_string())),
+                Some(checksum) if *checksum != *m.checksum => status.modified.push(m.version),
+                Some(_) => {}
+            }
+        }
+        status.unknown = applied
+            .keys()
+            .filter(|v| !migrator.iter().any(|m| m.version == **v))
+            .copied()
+            .collect();
+        status.unknown.sort();
+        Ok(status)
+    }
+
+    async fn acquire(&self) -> Result<PoolConnection<Postgres>, AppError> {
+        let start = Instant::now();
+        let conn = self.pool.acquire().await;
+        metrics::histogram!(DB_POOL_ACQUIRE_SECONDS).record(start.elapsed().as_secs_f64());
+        let size = self.pool.size();
+        let idle = self.pool.num_idle() as u32;
+        metrics::gauge!(DB_POOL_CONNECTIONS, "state" => "idle").set(idle);
+        metrics::gauge!(DB_POOL_CONNECTIONS, "state" => "in_use").set(size.saturating_sub(idle));
+        Ok(conn?)
+    }
+
+    /// Begins a host list write. When the request expects a list revision,
+    /// see [`expected_revision`], the first statement checks it and locks the
+    /// counter row, a concurrent write waits for the commit and then finds the
+    /// revision it bumped
+    async fn begin_hosts_write(&self) -> Result<Transaction<'static, Postgres>, AppError> {
+        let mut tx = self.pool.begin().await?;
+        let Some(expected) = expected_revision() else {
+            return Ok(tx);
+        };
+        let res = sqlx::query(
+            r#"
+UPDATE conf SET value = value
+    WHERE key = 'hosts_version' AND value::BIGINT = $1"#,
+        )
+        .bind(expected)
+        .execute(tx.as_mut())
+        .await?;
+        if res.rows_affected() == 0 {
+            let current = fetch_version(tx.as_mut(), "hosts_version").await?;
+            return Err(AppError::StaleRevision { expected, current });
+        }
+        Ok(tx)
+    }
+}
+
+/// Notified by the version triggers with the name of the bumped counter
+const CHANGES_CHANNEL: &str = "qpac_changes";
+
+/// Pause before listening again after the connection failed
+const RELISTEN_DELAY: Duration = Duration::from_secs(1);
+
+/// Waits for the next notification on [`CHANGES_CHANNEL`]. Notifications sent
+/// while the connection was lost are gone, so a reconnect counts as a change
+/// of everything
+async fn next_change(listener: &mut PgListener) -> ChangeEvent {
+    loop {
+        match listener.try_recv().await {
+            Ok(Some(notification)) => match notification.payload() {
+                "hosts_version" => return ChangeEvent::Hosts,
+                "config_version" => return ChangeEvent::Config,
+                payload => debug!("Unknown change notification {payload}"),
+            },
+            Ok(None) => {
+                warn!("Change notifications reconnected, some may be missed");
+                return ChangeEvent::Hosts;
+            }
+            Err(e) => {
+                warn!("Error receiving change notifications: {e}");
+                tokio::time::sleep(RELISTEN_DELAY).await;
+            }
+        }
+    }
+}
+
+async fn fetch_conf(conn: &mut PgConnection, key: &str) -> Result<Option<String>, AppError> {
+    let value = sqlx::query_scalar("SELECT value FROM conf WHERE key = $1;")
+        .bind(key)
+        .fetch_optional(conn)
+        .await?;
+    Ok(value)
+}
+
+async fn store_conf(conn: &mut PgConnection, key: &str, value: &str) -> Result<(), AppError> {
+    sqlx::query(
+        r#"
+INSERT INTO conf(key, value) VALUES ($1, $2)
+    ON CONFLICT(key) DO UPDATE SET value=excluded.value"#,
+    )
+    .bind(key)
+    .bind(value)
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+async fn fetch_version(conn: &mut PgConnection, key: &str) -> Result<i64, AppError> {
+    let value = fetch_conf(conn, key).await?;
+    Ok(value.and_then(|v| v.parse().ok()).unwrap_or_default())
+}
+
+async fn fetch_entry(conn: &mut PgConnection, host: &str) -> Result<HostEntry, AppError> {
+    let row: HostRow = sqlx::query_as(&format!(
+        "SELECT {HOST_COLUMNS} FROM white_list WHERE host = $1;"
+    ))
+    .bind(host)
+    .fetch_one(&mut *conn)
+    .await?;
+    let tags = sqlx::query_scalar("SELECT tag FROM host_tags WHERE host = $1 ORDER BY tag;")
+        .bind(host)
+        .fetch_all(&mut *conn)
+        .await?;
+    row.into_entry(tags)
+}
+
+async fn fetch_entries(conn: &mut PgConnection) -> Result<Vec<HostEntry>, AppError> {
+    let mut tags: HashMap<String, Vec<String>> = HashMap::new();
+    let rows: Vec<(String, String)> =
+        sqlx::query_as("SELECT host, tag FROM host_tags ORDER BY host, tag;")
+            .fetch_all(&mut *conn)
+            .await?;
+    for (host, tag) in rows {
+        tags.entry(host).or_default().push(tag);
+    }
+    let rows: Vec<HostRow> = sqlx::query_as(&format!(
+        "SELECT {HOST_COLUMNS} FROM white_list ORDER BY host;"
+    ))
+    .fetch_all(&mut *conn)
+    .await?;
+    rows.into_iter()
+        .map(|r| {
+            let tags = tags.remove(&r.host).unwrap_or_default();
+            r.into_entry(tags)
+        })
+        .collect()
+}
+
+async fn fetch_hosts(conn: &mut PgConnection, query: &str) -> Result<Vec<String>, AppError> {
+    let hosts = sqlx::query_scalar(query).fetch_all(conn).await?;
+    Ok(hosts)
+}
+
+async fn insert_entry(conn: &mut PgConnection, e: &HostEntry) -> Result<(), AppError> {
+    sqlx::query(&format!(
+        "INSERT INTO white_list({HOST_COLUMNS}) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)"
+    ))
+    .bind(&e.host)
+    .bind(&e.note)
+    .bind(e.expires_at)
+    .bind(e.kind.as_str())
+    .bind(e.pinned)
+    .bind(&e.group)
+    .bind(e.schedule.map(|s| s.to_string()))
+    .bind(e.created_at)
+    .bind(e.updated_at)
+    .execute(&mut *conn)
+    .await?;
+    for tag in e.tags.iter() {
+        sqlx::query("INSERT INTO host_tags(host, tag) VALUES ($1, $2)")
+            .bind(&e.host)
+            .bind(tag)
+            .execute(&mut *conn)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Inserts `host` unless listed, returns whether it was inserted
+async fn insert_host(conn: &mut PgConnection, host: &str, now: i64) -> Result<bool, AppError> {
+    let res = sqlx::query(
+        r#"
+INSERT INTO white_list(host, created_at, updated_at) VALUES ($1, $2, $2)
+    ON CONFLICT(host) DO NOTHING"#,
+    )
+    .bind(host)
+    .bind(now)
+    .execute(conn)
+    .await?;
+    Ok(res.rows_affected() > 0)
+}
+
+/// Replaces every host with `entries`, run inside a transaction
+async fn replace_entries(conn: &mut PgConnection, entries: &[HostEntry]) -> Result<(), AppError> {
+    // Tags go along through the cascade
+    sqlx::query("DELETE FROM white_list")
+        .execute(&mut *conn)
+        .await?;
+    for e in entries.iter() {
+        insert_entry(conn, e).await?;
+    }
+    Ok(())
+}
+
+/// Applies the fields set in `patch` to a listed host, returns the result
+async fn patch_entry(
+    conn: &mut PgConnection,
+    host: &str,
+    patch: HostPatch,
+) -> Result<HostEntry, AppError> {
+    let current = fetch_entry(&mut *conn, host).await?;
+    sqlx::query(
+        r#"
+UPDATE white_list SET note = $1, expires_at = $2, kind = $3, pinned = $4,
+    proxy_group = $5, schedule = $6, updated_at = $7
+    WHERE host = $8"#,
+    )
+    .bind(patch.note.unwrap_or(current.note))
+    .bind(patch.expires_at.unwrap_or(current.expires_at))
+    .bind(patch.kind.unwrap_or(current.kind).as_str())
+    .bind(patch.pinned.unwrap_or(current.pinned))
+    .bind(patch.group.unwrap_or(current.group))
+    .bind(
+        patch
+            .schedule
+            .unwrap_or(current.schedule)
+            .map(|s| s.to_string()),
+    )
+    .bind(unix_now())
+    .bind(host)
+    .execute(&mut *conn)
+    .await?;
+    if let Some(tags) = patch.tags {
+        sqlx::query("DELETE FROM host_tags WHERE host = $1")
+            .bind(host)
+            .execute(&mut *conn)
+            .await?;
+        for tag in tags.iter() {
+            sqlx::query("INSERT INTO host_tags(host, tag) VALUES ($1, $2) ON CONFLICT DO NOTHING")
+                .bind(host)
+                .bind(tag)
+                .execute(&mut *conn)
+                .await?;
+        }
+    }
+    fetch_entry(conn, host).await
+}
+
+/// Moves a listed host to `deleted_hosts`, replacing an earlier removal of it
+async fn trash_entry(conn: &mut PgConnection, e: &HostEntry) -> Result<(), AppError> {
+    let entry = serde_json::to_string(e).map_err(|e| AppError::Other(e.to_string()))?;
+    sqlx::query(
+        r#"
+INSERT INTO deleted_hosts(host, entry, deleted_at) VALUES ($1, $2, $3)
+    ON CONFLICT(host) DO UPDATE SET entry=excluded.entry, deleted_at=excluded.deleted_at"#,
+    )
+    .bind(&e.host)
+    .bind(entry)
+    .bind(unix_now())
+    .execute(&mut *conn)
+    .await?;
+    // Tags go along through the cascade
+    sqlx::query("DELETE FROM white_list WHERE host = $1")
+        .bind(&e.host)
+        .execute(&mut *conn)
+        .await?;
+    Ok(())
+}
+
+/// Trashes the listed ones of `hosts`, returns the trashed ones
+async fn trash_hosts(conn: &mut PgConnection, hosts: &[String]) -> Result<(), AppError> {
+    for host in hosts.iter() {
+        let entry = fetch_entry(&mut *conn, host).await?;
+        trash_entry(&mut *conn, &entry).await?;
+    }
+    Ok(())
+}
+
+async fn fetch_profiles(conn: &mut PgConnection) -> Result<Vec<Profile>, AppError> {
+    let rows: Vec<(String, String, String)> =
+        sqlx::query_as("SELECT name, proxy, tags FROM profiles ORDER BY name;")
+            .fetch_all(conn)
+            .await?;
+    Ok(rows
+        .into_iter()
+        .map(|(name, proxy, tags)| Profile {
+            name,
+            proxy,
+            tags: split_tags(&tags),
+        })
+        .collect())
+}
+
+async fn fetch_groups(conn: &mut PgConnection) -> Result<Vec<ProxyGroup>, AppError> {
+    let rows: Vec<(String, String, Option<String>)> =
+        sqlx::query_as("SELECT name, proxy, schedule FROM proxy_groups ORDER BY name;")
+            .fetch_all(conn)
+            .await?;
+    rows.into_iter()
+        .map(|(name, proxy, schedule)| {
+            Ok(ProxyGroup {
+                name,
+                proxy,
+                schedule: parse_schedule(schedule)?,
+            })
+        })
+        .collect()
+}
+
+async fn fetch_mode(conn: &mut PgConnection) -> Result<PacMode, AppError> {
+    match fetch_conf(conn, "mode").await? {
+        Some(mode) => mode.parse().map_err(AppError::Other),
+        None => Ok(PacMode::default()),
+    }
+}
+
+async fn fetch_bypass_private(conn: &mut PgConnection) -> Result<bool, AppError> {
+    Ok(fetch_conf(conn, "bypass_private").await?.as_deref() == Some("true"))
+}
+
+async fn fetch_upstreams(conn: &mut PgConnection) -> Result<Vec<Upstream>, AppError> {
+    let rows: Vec<(String, i64)> =
+        sqlx::query_as("SELECT proxy, weight FROM upstreams ORDER BY position;")
+            .fetch_all(conn)
+            .await?;
+    Ok(rows
+        .into_iter()
+        .map(|(proxy, weight)| Upstream {
+            proxy,
+            weight: weight as u32,
+        })
+        .collect())
+}
+
+/// Replaces every upstream with `upstreams`, run inside a transaction
+async fn replace_upstreams(
+    conn: &mut PgConnection,
+    upstreams: &[Upstream],
+) -> Result<(), AppError> {
+    sqlx::query("DELETE FROM upstreams")
+        .execute(&mut *conn)
+        .await?;
+    for (position, upstream) in upstreams.iter().enumerate() {
+        sqlx::query("INSERT INTO upstreams(position, proxy, weight) VALUES ($1, $2, $3)")
+            .bind(position as i64)
+            .bind(&upstream.proxy)
+            .bind(upstream.weight as i64)
+            .execute(&mut *conn)
+            .await?;
+    }
+    Ok(())
+}
+
+async fn fetch_networks(conn: &mut PgConnection) -> Result<Vec<NetworkProfile>, AppError> {
+    let rows: Vec<(String, String, String)> =
+        sqlx::query_as("SELECT name, network, proxy FROM networks ORDER BY name;")
+            .fetch_all(conn)
+            .await?;
+    Ok(rows
+        .into_iter()
+        .map(|(name, network, proxy)| NetworkProfile {
+            name,
+            network,
+            proxy,
+        })
+        .collect())
+}
+
+/// Replaces every IP range with `ranges`, run inside a transaction
+async fn replace_ip_ranges(conn: &mut PgConnection, ranges: &[String]) -> Result<(), AppError> {
+    sqlx::query("DELETE FROM ip_ranges")
+        .execute(&mut *conn)
+        .await?;
+    for range in ranges.iter() {
+        sqlx::query("INSERT INTO ip_ranges(network) VALUES ($1) ON CONFLICT(network) DO NOTHING")
+            .bind(range)
+            .execute(&mut *conn)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Inserts `hosts` not excluded yet, returns the inserted ones
+async fn insert_exclusions(
+    conn: &mut PgConnection,
+    hosts: &[String],
+) -> Result<Vec<String>, AppError> {
+    let now = unix_now();
+    let mut added = vec![];
+    for host in hosts.iter() {
+        let res = sqlx::query(
+            "INSERT INTO exclusions(host, created_at) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+        )
+        .bind(host)
+        .bind(now)
+        .execute(&mut *conn)
+        .await?;
+        if res.rows_affected() > 0 {
+            added.push(host.clone());
+        }
+    }
+    added.sort();
+    Ok(added)
+}
+
+async fn fetch_blocklist(conn: &mut PgConnection) -> Result<Vec<BlocklistEntry>, AppError> {
+    let rows: Vec<(String, String, String, i64)> =
+        sqlx::query_as("SELECT host, kind, action, created_at FROM blocklist ORDER BY host;")
+            .fetch_all(conn)
+            .await?;
+    rows.into_iter()
+        .map(|(host, kind, action, created_at)| {
+            Ok(BlocklistEntry {
+                host,
+                kind: parse_kind(&kind)?,
+                action: action.parse().map_err(AppError::Other)?,
+                created_at,
+            })
+        })
+        .collect()
+}
+
+/// Inserts `entry`, an existing entry of the host keeps its `created_at`
+async fn upsert_blocklisted(
+    conn: &mut PgConnection,
+    entry: &BlocklistEntry,
+) -> Result<(), AppError> {
+    sqlx::query(
+        r#"
+INSERT INTO blocklist(host, kind, action, created_at) VALUES ($1, $2, $3, $4)
+    ON CONFLICT(host) DO UPDATE SET kind=excluded.kind, action=excluded.action"#,
+    )
+    .bind(&entry.host)
+    .bind(entry.kind.as_str())
+    .bind(entry.action.as_str())
+    .bind(entry.created_at)
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+#[async_trait]
+impl Storage for PostgresStorage {
+    fn watch(&self) -> BoxStream<'static, ChangeEvent> {
+        self.changes.watch()
+    }
+
+    async fn watch_shared(&self) -> Result<Option<BoxStream<'static, ChangeEvent>>, AppError> {
+        let mut listener = PgListener::connect_with(&self.pool).await?;
+        listener.listen(CHANGES_CHANNEL).await?;
+        Ok(Some(Box::pin(stream::unfold(
+            listener,
+            |mut listener| async move {
+                let event = next_change(&mut listener).await;
+                Some((event, listener))
+            },
+        ))))
+    }
+
+    async fn all_hosts(&self) -> Result<Vec<String>, AppError> {
+        let mut conn = self.acquire().await?;
+        fetch_hosts(conn.as_mut(), "SELECT host FROM white_list ORDER BY host;").await
+    }
+
+    async fn host_entries(&self) -> Result<Vec<HostEntry>, AppError> {
+        let mut conn = self.acquire().await?;
+        fetch_entries(conn.as_mut()).await
+    }
+
+    async fn search_hosts(&self, pattern: &str) -> Result<Vec<String>, AppError> {
+        let mut conn = self.acquire().await?;
+        // Like sqlite's LIKE, which ignores the case of ascii letters
+        let hosts =
+            sqlx::query_scalar("SELECT host FROM white_list WHERE host ILIKE $1 ORDER BY host;")
+                .bind(pattern)
+                .fetch_all(conn.as_mut())
+                .await?;
+        Ok(hosts)
+    }
+
+    async fn hosts_page(
+        &self,
+        after: Option<&str>,
+        limit: u32,
+    ) -> Result<Vec<HostEntry>, AppError> {
+        let mut tx = self.pool.begin().await?;
+        let after = after.unwrap_or_default();
+        let rows: Vec<HostRow> = sqlx::query_as(&format!(
+            "SELECT {HOST_COLUMNS} FROM white_list WHERE host > $1 ORDER BY host LIMIT $2;"
+        ))
+        .bind(after)
+        .bind(limit as i64)
+        .fetch_all(tx.as_mut())
+        .await?;
+        let Some(last) = rows.last().map(|r| r.host.clone()) else {
+            return Ok(Vec::new());
+        };
+        let mut tags: HashMap<String, Vec<String>> = HashMap::new();
+        let tagged: Vec<(String, String)> = sqlx::query_as(
+            "SELECT host, tag FROM host_tags WHERE host > $1 AND host <= $2 ORDER BY host, tag;",
+        )
+        .bind(after)
+        .bind(last)
+        .fetch_all(tx.as_mut())
+        .await?;
+        for (host, tag) in tagged {
+            tags.entry(host).or_default().push(tag);
+        }
+        tx.commit().await?;
+        rows.into_iter()
+            .map(|r| {
+                let tags = tags.remove(&r.host).unwrap_or_default();
+                r.into_entry(tags)
+            })
+            .collect()
+    }
+
+    async fn get_host(&self, host: &str) -> Result<HostEntry, AppError> {
+        let mut conn = self.acquire().await?;
+        fetch_entry(conn.as_mut(), host).await
+    }
+
+    async fn update_host(&self, host: &str, patch: HostPatch) -> Result<HostEntry, AppError> {
+        let mut tx = self.begin_hosts_write().await?;
+        let entry = patch_entry(tx.as_mut(), host, patch).await?;
+        tx.commit().await?;
+        self.changes.send(ChangeEvent::Hosts);
+        Ok(entry)
+    }
+
+    async fn get_file(&self, hash: &str) -> Result<String, AppError> {
+        let mut conn = self.acquire().await?;
+        let (file, encoding): (Vec<u8>, String) =
+            sqlx::query_as("SELECT file, file_encoding FROM pac WHERE hash = $1;")
+                .bind(hash)
+                .fetch_one(conn.as_mut())
+                .await?;
+        decode_file(file, &encoding)
+    }
+
+    async fn get_file_latest(&self) -> Result<Pac, AppError> {
+        let hash = self.latest_hash().await?;
+        let file = self.get_file(&hash).await?;
+        Ok(Pac::new(file, hash))
+    }
+
+    async fn latest_hash(&self) -> Result<String, AppError> {
+        let mut conn = self.acquire().await?;
+        fetch_conf(conn.as_mut(), "latest_pac_file")
+            .await?
+            .ok_or(AppError::NotFound)
+    }
+
+    async fn get_checksum(&self, hash: &str) -> Result<String, AppError> {
+        let mut conn = self.acquire().await?;
+        let checksum: Option<String> =
+            sqlx::query_scalar("SELECT checksum FROM pac WHERE hash = $1;")
+                .bind(hash)
+                .fetch_one(conn.as_mut())
+                .await?;
+        checksum.ok_or(AppError::NotFound)
+    }
+
+    async fn get_manifest(&self, hash: &str) -> Result<Vec<String>, AppError> {
+        let mut conn = self.acquire().await?;
+        let hosts: Option<String> = sqlx::query_scalar("SELECT hosts FROM pac WHERE hash = $1;")
+            .bind(hash)
+            .fetch_one(conn.as_mut())
+            .await?;
+        let hosts = hosts.ok_or(AppError::NotFound)?;
+        serde_json::from_str(&hosts).map_err(|e| AppError::Other(e.to_string()))
+    }
+
+    async fn upload_file(&self, pac: &Pac) -> Result<(), AppError> {
+        let hosts =
+            serde_json::to_string(&pac.hosts).map_err(|e| AppError::Other(e.to_string()))?;
+        let meta = pac.meta.as_ref();
+        let mut tx = self.pool.begin().await?;
+        // Concurrent uploads would pick the same next version otherwise
+        sqlx::query("LOCK TABLE pac IN SHARE ROW EXCLUSIVE MODE")
+            .execute(tx.as_mut())
+            .await?;
+        sqlx::query(
+            r#"
+INSERT INTO pac(
+    hash, file, file_encoding, size, hosts, checksum, version, generated_at, host_count,
+    qpac_version, created_at
+)
+    VALUES($1, $2, 'zstd', $3, $4, $5, (SELECT COALESCE(MAX(version), 0) + 1 FROM pac),
+        $6, $7, $8, $9)
+    ON CONFLICT(hash) DO UPDATE SET
+        file=excluded.file, file_encoding=excluded.file_encoding, size=excluded.size,
+        hosts=excluded.hosts, checksum=excluded.checksum,
+        generated_at=excluded.generated_at, host_count=excluded.host_count,
+        qpac_version=excluded.qpac_version;"#,
+        )
+        .bind(&pac.hash)
+        .bind(encode_file(&pac.file)?)
+        .bind(pac.file.len() as i64)
+        .bind(hosts)
+        .bind(pac::checksum(&pac.file))
+        .bind(meta.map(|m| m.generated_at))
+        .bind(meta.map(|m| m.host_count))
+        .bind(meta.map(|m| m.qpac_version.as_str()))
+        .bind(unix_now())
+        .execute(tx.as_mut())
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn upload_encodings(&self, hash: &str, encodings: &PacEncodings) -> Result<(), AppError> {
+        let mut tx = self.pool.begin().await?;
+        let known: Option<String> = sqlx::query_scalar("SELECT hash FROM pac WHERE hash = $1")
+            .bind(hash)
+            .fetch_optional(tx.as_mut())
+            .await?;
+        if known.is_none() {
+            Err(AppError::NotFound)?
+        }
+        let bodies = [("gzip", &encodings.gzip), ("br", &encodings.br)];
+        for (encoding, body) in bodies {
+            let Some(body) = body else {
+                continue;
+            };
+            sqlx::query(
+                r#"
+INSERT INTO pac_encodings(hash, encoding, body) VALUES ($1, $2, $3)
+    ON CONFLICT(hash, encoding) DO UPDATE SET body=excluded.body"#,
+            )
+            .bind(hash)
+            .bind(encoding)
+            .bind(body)
+            .execute(tx.as_mut())
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn get_encodings(&self, hash: &str) -> Result<PacEncodings, AppError> {
+        let mut conn = self.acquire().await?;
+        let mut encodings = PacEncodings::default();
+        let rows: Vec<(String, Vec<u8>)> =
+            sqlx::query_as("SELECT encoding, body FROM pac_encodings WHERE hash = $1")
+                .bind(hash)
+                .fetch_all(conn.as_mut())
+                .await?;
+        for (encoding, body) in rows {
+            match encoding.as_str() {
+                "gzip" => encodings.gzip = Some(body),
+                "br" => encodings.br = Some(body),
+                _ => {}
+            }
+        }
+        Ok(encodings)
+    }
+
+    async fn list_versions(&self, limit: u32) -> Result<Vec<PacVersion>, AppError> {
+        let mut conn = self.acquire().await?;
+        #[allow(clippy::type_complexity)]
+        let rows: Vec<(
+            i64,
+            String,
+            i64,
+            Option<i64>,
+            Option<i64>,
+            Option<String>,
+            Option<i64>,
+        )> = sqlx::query_as(
+            r#"
+SELECT version, hash, size, generated_at, host_count, qpac_version, created_at FROM pac
+    WHERE version IS NOT NULL
+    ORDER BY version DESC
+    LIMIT $1"#,
+        )
+        .bind(limit as i64)
+        .fetch_all(conn.as_mut())
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(
+                |(version, hash, size, generated_at, host_count, qpac_version, created_at)| {
+                    PacVersion {
+                        version,
+                        hash,
+                        size,
+                        created_at,
+                        meta: match (generated_at, host_count, qpac_version) {
+                            (Some(generated_at), Some(host_count), Some(qpac_version)) => {
+                                Some(PacMeta {
+                                    generated_at,
+                                    host_count,
+                                    qpac_version,
+                                })
+                            }
+                            _ => None,
+                        },
+                    }
+                },
+            )
+            .collect())
+    }
+
+    async fn prune_files(&self, keep: u32, before: i64) -> Result<u64, AppError> {
+        let mut conn = self.acquire().await?;
+        let keep = keep.max(1);
+        let res = sqlx::query(
+            r#"
+DELETE FROM pac
+    WHERE version NOT IN (
+        SELECT version FROM pac WHERE version IS NOT NULL ORDER BY version DESC LIMIT $1
+    )
+    AND COALESCE(generated_at, 0) < $2
+    AND hash NOT IN (
+        SELECT value FROM conf WHERE key IN ('latest_pac_file', 'staged_pac_file')
+    );"#,
+        )
+        .bind(keep as i64)
+        .bind(before)
+        .execute(conn.as_mut())
+        .await?;
+        Ok(res.rows_affected())
+    }
+
+    async fn get_version(&self, hash: &str) -> Result<i64, AppError> {
+        let mut conn = self.acquire().await?;
+        let version: Option<i64> = sqlx::query_scalar("SELECT version FROM pac WHERE hash = $1;")
+            .bind(hash)
+            .fetch_one(conn.as_mut())
+            .await?;
+        version.ok_or(AppError::NotFound)
+    }
+
+    async fn get_version_hash(&self, version: i64) -> Result<String, AppError> {
+        let mut conn = self.acquire().await?;
+        let hash = sqlx::query_scalar("SELECT hash FROM pac WHERE version = $1;")
+            .bind(version)
+            .fetch_one(conn.as_mut())
+            .await?;
+        Ok(hash)
+    }
+
+    async fn set_latest(&self, hash: &str) -> Result<(), AppError> {
+        let mut conn = self.acquire().await?;
+        store_conf(conn.as_mut(), "latest_pac_file", hash).await
+    }
+
+    async fn staged_hash(&self) -> Result<Option<String>, AppError> {
+        let mut conn = self.acquire().await?;
+        fetch_conf(conn.as_mut(), "staged_pac_file").await
+    }
+
+    async fn set_staged_hash(&self, hash: Option<String>) -> Result<(), AppError> {
+        let mut conn = self.acquire().await?;
+        match hash {
+            Some(hash) => store_conf(conn.as_mut(), "staged_pac_file", &hash).await?,
+            None => {
+                sqlx::query("DELETE FROM conf WHERE key = 'staged_pac_file'")
+                    .execute(conn.as_mut())
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn promote_staged(&self) -> Result<String, AppError> {
+        let mut tx = self.pool.begin().await?;
+        let staged: String =
+            sqlx::query_scalar("DELETE FROM conf WHERE key = 'staged_pac_file' RETURNING value;")
+                .fetch_one(tx.as_mut())
+                .await?;
+        store_conf(tx.as_mut(), "latest_pac_file", &staged).await?;
+        tx.commit().await?;
+        Ok(staged)
+    }
+
+    async fn add_host(&self, host: &str, patch: HostPatch) -> Result<(), AppError> {
+        let mut tx = self.begin_hosts_write().await?;
+        if !insert_host(tx.as_mut(), host, unix_now()).await? {
+            Err(AppError::PreconditionFailed(
+                "Host already exists".to_string(),
+            ))?
+        }
+        if patch != HostPatch::default() {
+            patch_entry(tx.as_mut(), host, patch).await?;
+        }
+        tx.commit().await?;
+        self.changes.send(ChangeEvent::Hosts);
+        Ok(())
+    }
+
+    async fn upsert_host(&self, host: &str, patch: HostPatch) -> Result<bool, AppError> {
+        let mut tx = self.begin_hosts_write().await?;
+        let added = insert_host(tx.as_mut(), host, unix_now()).await?;
+        patch_entry(tx.as_mut(), host, patch).await?;
+        tx.commit().await?;
+        self.changes.send(ChangeEvent::Hosts);
+        Ok(added)
+    }
+
+    async fn remove_host(&self, host: &str) -> Result<(), AppError> {
+        let mut tx = self.begin_hosts_write().await?;
+        let entry = fetch_entry(tx.as_mut(), host).await?;
+        trash_entry(tx.as_mut(), &entry).await?;
+        tx.commit().await?;
+        self.changes.send(ChangeEvent::Hosts);
+        Ok(())
+    }
+
+    async fn add_hosts(
+        &self,
+        hosts: Vec<String>,
+        patch: HostPatch,
+    ) -> Result<Vec<String>, AppError> {
+        let patched = patch != HostPatch::default();
+        let mut tx = self.begin_hosts_write().await?;
+        let now = unix_now();
+        let mut added = vec![];
+        for host in hosts {
+            if insert_host(tx.as_mut(), &host, now).await? {
+                if patched {
+                    patch_entry(tx.as_mut(), &host, patch.clone()).await?;
+                }
+                added.push(host);
+            }
+        }
+        tx.commit().await?;
+        added.sort();
+        if !added.is_empty() {
+            self.changes.send(ChangeEvent::Hosts);
+        }
+        Ok(added)
+    }
+
+    async fn upsert_hosts(
+        &self,
+        hosts: Vec<String>,
+        patch: HostPatch,
+    ) -> Result<Vec<String>, AppError> {
+        let changed = !hosts.is_empty();
+        let mut tx = self.begin_hosts_write().await?;
+        let now = unix_now();
+        let mut added = vec![];
+        for host in hosts {
+            let inserted = insert_host(tx.as_mut(), &host, now).await?;
+            patch_entry(tx.as_mut(), &host, patch.clone()).await?;
+            if inserted {
+                added.push(host);
+            }
+        }
+        tx.commit().await?;
+        added.sort();
+        if changed {
+            self.changes.send(ChangeEvent::Hosts);
+        }
+        Ok(added)
+    }
+
+    async fn remove_hosts(&self, hosts: Vec<String>) -> Result<Vec<String>, AppError> {
+        let mut tx = self.begin_hosts_write().await?;
+        let mut removed = vec![];
+        for host in hosts {
+            let entry = match fetch_entry(tx.as_mut(), &host).await {
+                Ok(entry) => entry,
+                Err(AppError::NotFound) => continue,
+                Err(e) => Err(e)?,
+            };
+            if !entry.pinned {
+                trash_entry(tx.as_mut(), &entry).await?;
+                removed.push(host);
+            }
+        }
+        tx.commit().await?;
+        removed.sort();
+        if !removed.is_empty() {
+            self.changes.send(ChangeEvent::Hosts);
+        }
+        Ok(removed)
+    }
+
+    async fn deleted_hosts(&self) -> Result<Vec<DeletedHost>, AppError> {
+        let mut conn = self.acquire().await?;
+        let rows: Vec<(String, i64)> =
+            sqlx::query_as("SELECT entry, deleted_at FROM deleted_hosts ORDER BY host;")
+                .fetch_all(conn.as_mut())
+                .await?;
+        rows.into_iter()
+            .map(|(entry, deleted_at)| {
+                Ok(DeletedHost {
+                    entry: serde_json::from_str(&entry)
+                        .map_err(|e| AppError::Other(e.to_string()))?,
+                    deleted_at,
+                })
+            })
+            .collect()
+    }
+
+    async fn restore_host(&self, host: &str) -> Result<HostEntry, AppError> {
+        let mut tx = self.begin_hosts_write().await?;
+        let deleted: String =
+            sqlx::query_scalar("SELECT entry FROM deleted_hosts WHERE host = $1;")
+                .bind(host)
+                .fetch_one(tx.as_mut())
+                .await?;
+        let listed: Option<String> =
+            sqlx::query_scalar("SELECT host FROM white_list WHERE host = $1;")
+                .bind(host)
+                .fetch_optional(tx.as_mut())
+                .await?;
+        if listed.is_some() {
+            Err(AppError::Conflict("Host already exists".to_string()))?
+        }
+        let mut entry: HostEntry =
+            serde_json::from_str(&deleted).map_err(|e| AppError::Other(e.to_string()))?;
+        entry.updated_at = unix_now();
+        insert_entry(tx.as_mut(), &entry).await?;
+        sqlx::query("DELETE FROM deleted_hosts WHERE host = $1")
+            .bind(host)
+            .execute(tx.as_mut())
+            .await?;
+        tx.commit().await?;
+        self.changes.send(ChangeEvent::Hosts);
+        Ok(entry)
+    }
+
+    async fn purge_deleted(&self, before: i64) -> Result<u64, AppError> {
+        let mut conn = self.acquire().await?;
+        let res = sqlx::query("DELETE FROM deleted_hosts WHERE deleted_at < $1")
+            .bind(before)
+            .execute(conn.as_mut())
+            .await?;
+        Ok(res.rows_affected())
+    }
+
+    async fn remove_expired(&self, now: i64) -> Result<Vec<String>, AppError> {
+        let mut tx = self.begin_hosts_write().await?;
+        let hosts: Vec<String> = sqlx::query_scalar(
+            "SELECT host FROM white_list WHERE expires_at <= $1 AND NOT pinned ORDER BY host;",
+        )
+        .bind(now)
+        .fetch_all(tx.as_mut())
+        .await?;
+        trash_hosts(tx.as_mut(), &hosts).await?;
+        tx.commit().await?;
+        if !hosts.is_empty() {
+            self.changes.send(ChangeEvent::Hosts);
+        }
+        Ok(hosts)
+    }
+
+    async fn set_pinned(&self, host: &str, pinned: bool) -> Result<(), AppError> {
+        let patch = HostPatch {
+            pinned: Some(pinned),
+            ..Default::default()
+        };
+        self.update_host(host, patch).await.map(|_| ())
+    }
+
+    async fn pinned_hosts(&self) -> Result<Vec<String>, AppError> {
+        let mut conn = self.acquire().await?;
+        fetch_hosts(
+            conn.as_mut(),
+            "SELECT host FROM white_list WHERE pinned ORDER BY host;",
+        )
+        .await
+    }
+
+    async fn import_hosts(
+        &self,
+        hosts: Vec<String>,
+        mode: ImportMode,
+        dry_run: bool,
+    ) -> Result<HostsDiff, AppError> {
+        let mut tx = self.begin_hosts_write().await?;
+        let current: Vec<(String, bool)> =
+            sqlx::query_as("SELECT host, pinned FROM white_list ORDER BY host;")
+                .fetch_all(tx.as_mut())
+                .await?;
+        let wanted: BTreeSet<String> = hosts.into_iter().collect();
+        let existing: BTreeSet<&str> = current.iter().map(|(h, _)| h.as_str()).collect();
+
+        let diff = HostsDiff {
+            added: wanted
+                .iter()
+                .filter(|h| !existing.contains(h.as_str()))
+                .cloned()
+                .collect(),
+            removed: match mode {
+                ImportMode::Merge => vec![],
+                ImportMode::Mirror => current
+                    .iter()
+                    .filter(|(h, pinned)| !wanted.contains(h) && !pinned)
+                    .map(|(h, _)| h.clone())
+                    .collect(),
+            },
+        };
+        if dry_run {
+            return Ok(diff);
+        }
+
+        let now = unix_now();
+        for host in diff.added.iter() {
+            insert_host(tx.as_mut(), host, now).await?;
+        }
+        for host in diff.removed.iter() {
+            sqlx::query("DELETE FROM white_list WHERE host = $1")
+                .bind(host)
+                .execute(tx.as_mut())
+                .await?;
+        }
+        tx.commit().await?;
+        if !diff.is_empty() {
+            self.changes.send(ChangeEvent::Hosts);
+        }
+        Ok(diff)
+    }
+
+    async fn set_tags(&self, host: &str, tags: Vec<String>) -> Result<(), AppError> {
+        let patch = HostPatch {
+            tags: Some(tags),
+            ..Default::default()
+        };
+        self.update_host(host, patch).await.map(|_| ())
+    }
+
+    async fn hosts_by_tag(&self, tag: &str) -> Result<Vec<String>, AppError> {
+        let mut conn = self.acquire().await?;
+        let hosts = sqlx::query_scalar("SELECT host FROM host_tags WHERE tag = $1 ORDER BY host;")
+            .bind(tag)
+            .fetch_all(conn.as_mut())
+            .await?;
+        Ok(hosts)
+    }
+
+    async fn remove_hosts_by_tag(&self, tag: &str) -> Result<Vec<String>, AppError> {
+        let mut tx = self.begin_hosts_write().await?;
+        let removed: Vec<String> = sqlx::query_scalar(
+            r#"
+SELECT w.host FROM white_list w JOIN host_tags t ON t.host = w.host
+    WHERE t.tag = $1 AND NOT w.pinned ORDER BY w.host;"#,
+        )
+        .bind(tag)
+        .fetch_all(tx.as_mut())
+        .await?;
+        trash_hosts(tx.as_mut(), &removed).await?;
+        tx.commit().await?;
+        if !removed.is_empty() {
+            self.changes.send(ChangeEvent::Hosts);
+        }
+        Ok(removed)
+    }
+
+    async fn list_tags(&self) -> Result<Vec<TagInfo>, AppError> {
+        let mut conn = self.acquire().await?;
+        let rows: Vec<(String, i64)> =
+            sqlx::query_as("SELECT tag, COUNT(*) FROM host_tags GROUP BY tag ORDER BY tag;")
+                .fetch_all(conn.as_mut())
+                .await?;
+        Ok(rows
+            .into_iter()
+            .map(|(name, hosts)| TagInfo {
+                name,
+                hosts: hosts as usize,
+            })
+            .collect())
+    }
+
+    async fn rename_tag(&self, tag: &str, to: &str) -> Result<(), AppError> {
+        let mut tx = self.begin_hosts_write().await?;
+        let tagged: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM host_tags WHERE tag = $1")
+            .bind(tag)
+            .fetch_one(tx.as_mut())
+            .await?;
+        if tagged == 0 {
+            Err(AppError::NotFound)?
+        }
+        if tag != to {
+            sqlx::query(
+                r#"
+INSERT INTO host_tags(host, tag) SELECT host, $1 FROM host_tags WHERE tag = $2
+    ON CONFLICT DO NOTHING"#,
+            )
+            .bind(to)
+            .bind(tag)
+            .execute(tx.as_mut())
+            .await?;
+            sqlx::query("DELETE FROM host_tags WHERE tag = $1")
+                .bind(tag)
+                .execute(tx.as_mut())
+                .await?;
+            // Profiles listing the tag would otherwise lose its hosts
+            for profile in fetch_profiles(tx.as_mut()).await? {
+                let Some(tags) = renamed_tag(&profile.tags, tag, to) else {
+                    continue;
+                };
+                sqlx::query("UPDATE profiles SET tags = $1 WHERE name = $2;")
+                    .bind(tags.join(","))
+                    .bind(&profile.name)
+                    .execute(tx.as_mut())
+                    .await?;
+            }
+        }
+        tx.commit().await?;
+        self.changes.send(ChangeEvent::Hosts);
+        Ok(())
+    }
+
+    async fn delete_tag(&self, tag: &str) -> Result<(), AppError> {
+        let mut tx = self.begin_hosts_write().await?;
+        let res = sqlx::query("DELETE FROM host_tags WHERE tag = $1")
+            .bind(tag)
+            .execute(tx.as_mut())
+            .await?;
+        if res.rows_affected() == 0 {
+            Err(AppError::NotFound)?
+        }
+        tx.commit().await?;
+        self.changes.send(ChangeEvent::Hosts);
+        Ok(())
+    }
+
+    async fn create_snapshot(&self, name: &str) -> Result<SnapshotInfo, AppError> {
+        let mut tx = self.pool.begin().await?;
+        let entries = fetch_entries(tx.as_mut()).await?;
+        let hosts = serde_json::to_string(&entries).map_err(|e| AppError::Other(e.to_string()))?;
+        let created_at = unix_now();
+        sqlx::query("INSERT INTO snapshots(name, created_at, hosts) VALUES ($1, $2, $3)")
+            .bind(name)
+            .bind(created_at)
+            .bind(hosts)
+            .execute(tx.as_mut())
+            .await?;
+        tx.commit().await?;
+        Ok(SnapshotInfo {
+            name: name.to_string(),
+            created_at,
+            hosts: entries.len(),
+        })
+    }
+
+    async fn list_snapshots(&self) -> Result<Vec<SnapshotInfo>, AppError> {
+        let mut conn = self.acquire().await?;
+        let rows: Vec<(String, i64, i32)> = sqlx::query_as(
+            "SELECT name, created_at, json_array_length(hosts::json) FROM snapshots ORDER BY name;",
+        )
+        .fetch_all(conn.as_mut())
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|(name, created_at, hosts)| SnapshotInfo {
+                name,
+                created_at,
+                hosts: hosts as usize,
+            })
+            .collect())
+    }
+
+    async fn restore_snapshot(&self, name: &str) -> Result<HostsDiff, AppError> {
+        let mut tx = self.begin_hosts_write().await?;
+        let hosts: String = sqlx::query_scalar("SELECT hosts FROM snapshots WHERE name = $1;")
+            .bind(name)
+            .fetch_one(tx.as_mut())
+            .await?;
+        let entries: Vec<HostEntry> =
+            serde_json::from_str(&hosts).map_err(|e| AppError::Other(e.to_string()))?;
+        let current =
+            fetch_hosts(tx.as_mut(), "SELECT host FROM white_list ORDER BY host;").await?;
+        let wanted: Vec<String> = entries.iter().map(|e| e.host.clone()).collect();
+
+        replace_entries(tx.as_mut(), &entries).await?;
+        tx.commit().await?;
+        self.changes.send(ChangeEvent::Hosts);
+        Ok(HostsDiff::between(&current, &wanted))
+    }
+
+    async fn delete_snapshot(&self, name: &str) -> Result<(), AppError> {
+        let mut conn = self.acquire().await?;
+        let res = sqlx::query("DELETE FROM snapshots WHERE name = $1")
+            .bind(name)
+            .execute(conn.as_mut())
+            .await?;
+        if res.rows_affected() == 0 {
+            Err(AppError::NotFound)?
+        }
+        Ok(())
+    }
+
+    async fn set_profile(&self, profile: Profile) -> Result<(), AppError> {
+        let mut conn = self.acquire().await?;
+        sqlx::query(
+            r#"
+INSERT INTO profiles(name, proxy, tags) VALUES ($1, $2, $3)
+    ON CONFLICT(name) DO UPDATE SET proxy=excluded.proxy, tags=excluded.tags"#,
+        )
+        .bind(&profile.name)
+        .bind(&profile.proxy)
+        .bind(profile.tags.join(","))
+        .execute(conn.as_mut())
+        .await?;
+        self.changes.send(ChangeEvent::Config);
+        Ok(())
+    }
+
+    async fn get_profile(&self, name: &str) -> Result<Profile, AppError> {
+        let mut conn = self.acquire().await?;
+        let (name, proxy, tags): (String, String, String) =
+            sqlx::query_as("SELECT name, proxy, tags FROM profiles WHERE name = $1;")
+                .bind(name)
+                .fetch_one(conn.as_mut())
+                .await?;
+        Ok(Profile {
+            name,
+            proxy,
+            tags: split_tags(&tags),
+        })
+    }
+
+    async fn list_profiles(&self) -> Result<Vec<Profile>, AppError> {
+        let mut conn = self.acquire().await?;
+        fetch_profiles(conn.as_mut()).await
+    }
+
+    async fn remove_profile(&self, name: &str) -> Result<(), AppError> {
+        let mut conn = self.acquire().await?;
+        let res = sqlx::query("DELETE FROM profiles WHERE name = $1")
+            .bind(name)
+            .execute(conn.as_mut())
+            .await?;
+        if res.rows_affected() == 0 {
+            Err(AppError::NotFound)?
+        }
+        self.changes.send(ChangeEvent::Config);
+        Ok(())
+    }
+
+    async fn set_group(&self, group: ProxyGroup) -> Result<(), AppError> {
+        let mut conn = self.acquire().await?;
+        sqlx::query(
+            r#"
+INSERT INTO proxy_groups(name, proxy, schedule) VALUES ($1, $2, $3)
+    ON CONFLICT(name) DO UPDATE SET proxy=excluded.proxy, schedule=excluded.schedule"#,
+        )
+        .bind(&group.name)
+        .bind(&group.proxy)
+        .bind(group.schedule.map(|s| s.to_string()))
+        .execute(conn.as_mut())
+        .await?;
+        self.changes.send(ChangeEvent::Config);
+        Ok(())
+    }
+
+    async fn list_groups(&self) -> Result<Vec<ProxyGroup>, AppError> {
+        let mut conn = self.acquire().await?;
+        fetch_groups(conn.as_mut()).await
+    }
+
+    async fn remove_group(&self, name: &str) -> Result<(), AppError> {
+        let mut tx = self.begin_hosts_write().await?;
+        let res = sqlx::query("DELETE FROM proxy_groups WHERE name = $1")
+            .bind(name)
+            .execute(tx.as_mut())
+            .await?;
+        if res.rows_affected() == 0 {
+            Err(AppError::NotFound)?
+        }
+        sqlx::query("UPDATE white_list SET proxy_group = NULL WHERE proxy_group = $1")
+            .bind(name)
+            .execute(tx.as_mut())
+            .await?;
+        tx.commit().await?;
+        self.changes.send(ChangeEvent::Config);
+        Ok(())
+    }
+
+    async fn set_network(&self, network: NetworkProfile) -> Result<(), AppError> {
+        let mut conn = self.acquire().await?;
+        sqlx::query(
+            r#"
+INSERT INTO networks(name, network, proxy) VALUES ($1, $2, $3)
+    ON CONFLICT(name) DO UPDATE SET network=excluded.network, proxy=excluded.proxy"#,
+        )
+        .bind(&network.name)
+        .bind(&network.network)
+        .bind(&network.proxy)
+        .execute(conn.as_mut())
+        .await?;
+        self.changes.send(ChangeEvent::Config);
+        Ok(())
+    }
+
+    async fn list_networks(&self) -> Result<Vec<NetworkProfile>, AppError> {
+        let mut conn = self.acquire().await?;
+        fetch_networks(conn.as_mut()).await
+    }
+
+    async fn remove_network(&self, name: &str) -> Result<(), AppError> {
+        let mut conn = self.acquire().await?;
+        let res = sqlx::query("DELETE FROM networks WHERE name = $1")
+            .bind(name)
+            .execute(conn.as_mut())
+            .await?;
+        if res.rows_affected() == 0 {
+            Err(AppError::NotFound)?
+        }
+        self.changes.send(ChangeEvent::Config);
+        Ok(())
+    }
+
+    async fn hosts_version(&self) -> Result<i64, AppError> {
+        let mut conn = self.acquire().await?;
+        fetch_version(conn.as_mut(), "hosts_version").await
+    }
+
+    async fn config_version(&self) -> Result<i64, AppError> {
+        let mut conn = self.acquire().await?;
+        fetch_version(conn.as_mut(), "config_version").await
+    }
+
+    async fn get_proxy(&self) -> Result<Option<String>, AppError> {
+        let mut conn = self.acquire().await?;
+        fetch_conf(conn.as_mut(), "proxy").await
+    }
+
+    async fn set_proxy(&self, proxy: &str) -> Result<(), AppError> {
+        let mut conn = self.acquire().await?;
+        store_conf(conn.as_mut(), "proxy", proxy).await?;
+        self.changes.send(ChangeEvent::Config);
+        Ok(())
+    }
+
+    async fn get_mode(&self) -> Result<PacMode, AppError> {
+        let mut conn = self.acquire().await?;
+        fetch_mode(conn.as_mut()).await
+    }
+
+    async fn set_mode(&self, mode: PacMode) -> Result<(), AppError> {
+        let mut conn = self.acquire().await?;
+        store_conf(conn.as_mut(), "mode", mode.as_str()).await?;
+        self.changes.send(ChangeEvent::Config);
+        Ok(())
+    }
+
+    async fn get_bypass_private(&self) -> Result<bool, AppError> {
+        let mut conn = self.acquire().await?;
+        fetch_bypass_private(conn.as_mut()).await
+    }
+
+    async fn set_bypass_private(&self, enabled: bool) -> Result<(), AppError> {
+        let mut conn = self.acquire().await?;
+        store_conf(conn.as_mut(), "bypass_private", &enabled.to_string()).await?;
+        self.changes.send(ChangeEvent::Config);
+        Ok(())
+    }
+
+    async fn list_upstreams(&self) -> Result<Vec<Upstream>, AppError> {
+        let mut conn = self.acquire().await?;
+        fetch_upstreams(conn.as_mut()).await
+    }
+
+    async fn set_upstreams(&self, upstreams: Vec<Upstream>) -> Result<(), AppError> {
+        let mut tx = self.pool.begin().await?;
+        replace_upstreams(tx.as_mut(), &upstreams).await?;
+        tx.commit().await?;
+        self.changes.send(ChangeEvent::Config);
+        Ok(())
+    }
+
+    async fn list_ip_ranges(&self) -> Result<Vec<String>, AppError> {
+        let mut conn = self.acquire().await?;
+        let ranges = sqlx::query_scalar("SELECT network FROM ip_ranges ORDER BY network;")
+            .fetch_all(conn.as_mut())
+            .await?;
+        Ok(ranges)
+    }
+
+    async fn set_ip_ranges(&self, ranges: Vec<String>) -> Result<(), AppError> {
+        let mut tx = self.pool.begin().await?;
+        replace_ip_ranges(tx.as_mut(), &ranges).await?;
+        tx.commit().await?;
+        self.changes.send(ChangeEvent::Config);
+        Ok(())
+    }
+
+    async fn list_exclusions(&self) -> Result<Vec<String>, AppError> {
+        let mut conn = self.acquire().await?;
+        fetch_hosts(conn.as_mut(), "SELECT host FROM exclusions ORDER BY host;").await
+    }
+
+    async fn add_exclusions(&self, hosts: Vec<String>) -> Result<Vec<String>, AppError> {
+        let mut tx = self.begin_hosts_write().await?;
+        let added = insert_exclusions(tx.as_mut(), &hosts).await?;
+        tx.commit().await?;
+        if !added.is_empty() {
+            self.changes.send(ChangeEvent::Hosts);
+        }
+        Ok(added)
+    }
+
+    async fn remove_exclusion(&self, host: &str) -> Result<(), AppError> {
+        let mut tx = self.begin_hosts_write().await?;
+        let res = sqlx::query("DELETE FROM exclusions WHERE host = $1")
+            .bind(host)
+            .execute(tx.as_mut())
+            .await?;
+        if res.rows_affected() == 0 {
+            Err(AppError::NotFound)?
+        }
+        tx.commit().await?;
+        self.changes.send(ChangeEvent::Hosts);
+        Ok(())
+    }
+
+    async fn list_blocklist(&self) -> Result<Vec<BlocklistEntry>, AppError> {
+        let mut conn = self.acquire().await?;
+        fetch_blocklist(conn.as_mut()).await
+    }
+
+    async fn set_blocklisted(&self, entry: BlocklistEntry) -> Result<(), AppError> {
+        let mut tx = self.begin_hosts_write().await?;
+        let entry = BlocklistEntry {
+            created_at: unix_now(),
+            ..entry
+        };
+        upsert_blocklisted(tx.as_mut(), &entry).await?;
+        tx.commit().await?;
+        self.changes.send(ChangeEvent::Hosts);
+        Ok(())
+    }
+
+    async fn remove_blocklisted(&self, host: &str) -> Result<(), AppError> {
+        let mut tx = self.begin_hosts_write().await?;
+        let res = sqlx::query("DELETE FROM blocklist WHERE host = $1")
+            .bind(host)
+            .execute(tx.as_mut())
+            .await?;
+        if res.rows_affected() == 0 {
+            Err(AppError::NotFound)?
+        }
+        tx.commit().await?;
+        self.changes.send(ChangeEvent::Hosts);
+        Ok(())
+    }
+
+    async fn record_client_fetches(&self, fetches: Vec<ClientFetches>) -> Result<(), AppError> {
+        let mut tx = self.pool.begin().await?;
+        for f in fetches.iter() {
+            sqlx::query(
+                r#"
+INSERT INTO client_fetches(day, client, profile, fetches) VALUES ($1, $2, $3, $4)
+    ON CONFLICT(day, client, profile)
+    DO UPDATE SET fetches = client_fetches.fetches + excluded.fetches"#,
+            )
+            .bind(f.day)
+            .bind(&f.client)
+            .bind(f.profile.as_deref().unwrap_or_default())
+            .bind(f.fetches)
+            .execute(tx.as_mut())
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn client_fetches(&self, since: i64) -> Result<Vec<ClientFetches>, AppError> {
+        let mut conn = self.acquire().await?;
+        let rows: Vec<(i64, String, String, i64)> = sqlx::query_as(
+            r#"
+SELECT day, client, profile, fetches FROM client_fetches
+    WHERE day >= $1
+    ORDER BY day, client, profile"#,
+        )
+        .bind(since)
+        .fetch_all(conn.as_mut())
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|(day, client, profile, fetches)| ClientFetches {
+                day,
+                client,
+                profile: (!profile.is_empty()).then_some(profile),
+                fetches,
+            })
+            .collect())
+    }
+
+    async fn prune_client_fetches(&self, before: i64) -> Result<u64, AppError> {
+        let mut conn = self.acquire().await?;
+        let res = sqlx::query("DELETE FROM client_fetches WHERE day < $1")
+            .bind(before)
+            .execute(conn.as_mut())
+            .await?;
+        Ok(res.rows_affected())
+    }
+
+    async fn record_audit(&self, entry: AuditEntry) -> Result<(), AppError> {
+        let mut conn = self.acquire().await?;
+        sqlx::query(
+            "INSERT INTO audit_log(at, actor, ip, action, payload) VALUES ($1, $2, $3, $4, $5)",
+        )
+        .bind(entry.at)
+        .bind(&entry.actor)
+        .bind(&entry.ip)
+        .bind(&entry.action)
+        .bind(entry.payload.to_string())
+        .execute(conn.as_mut())
+        .await?;
+        Ok(())
+    }
+
+    async fn audit_log(&self, since: i64, limit: u32) -> Result<Vec<AuditEntry>, AppError> {
+        let mut conn = self.acquire().await?;
+        let rows: Vec<(i64, String, Option<String>, String, String)> = sqlx::query_as(
+            r#"
+SELECT at, actor, ip, action, payload FROM audit_log
+    WHERE at >= $1
+    ORDER BY id DESC
+    LIMIT $2"#,
+        )
+        .bind(since)
+        .bind(limit as i64)
+        .fetch_all(conn.as_mut())
+        .await?;
+        rows.into_iter()
+            .map(|(at, actor, ip, action, payload)| {
+                Ok(AuditEntry {
+                    at,
+                    actor,
+                    ip,
+                    action,
+                    payload: serde_json::from_str(&payload)
+                        .map_err(|e| AppError::Other(e.to_string()))?,
+                })
+            })
+            .collect()
+    }
+
+    async fn prune_audit(&self, before: i64) -> Result<u64, AppError> {
+        let mut conn = self.acquire().await?;
+        let res = sqlx::query("DELETE FROM audit_log WHERE at < $1")
+            .bind(before)
+            .execute(conn.as_mut())
+            .await?;
+        Ok(res.rows_affected())
+    }
+
+    async fn try_lease(&self, name: &str, owner: &str, ttl: Duration) -> Result<bool, AppError> {
+        let mut conn = self.acquire().await?;
+        let now = unix_now();
+        let value = serde_json::to_string(&json!({
+            "owner": owner,
+            "expires_at": now + ttl.as_secs() as i64,
+        }))
+        .map_err(|e| AppError::Other(e.to_string()))?;
+        let res = sqlx::query(
+            r#"
+INSERT INTO conf(key, value) VALUES ($1, $2)
+    ON CONFLICT(key) DO UPDATE SET value=excluded.value
+    WHERE conf.value::json->>'owner' = $3
+        OR (conf.value::json->>'expires_at')::BIGINT <= $4"#,
+        )
+        .bind(format!("lease:{name}"))
+        .bind(value)
+        .bind(owner)
+        .bind(now)
+        .execute(conn.as_mut())
+        .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    async fn request_regeneration(&self) -> Result<(), AppError> {
+        let mut conn = self.acquire().await?;
+        sqlx::query(
+            r#"
+INSERT INTO conf(key, value) VALUES ('regeneration_requests', '1')
+    ON CONFLICT(key) DO UPDATE SET value = (conf.value::BIGINT + 1)::TEXT"#,
+        )
+        .execute(conn.as_mut())
+        .await?;
+        Ok(())
+    }
+
+    async fn regeneration_requests(&self) -> Result<i64, AppError> {
+        let mut conn = self.acquire().await?;
+        fetch_version(conn.as_mut(), "regeneration_requests").await
+    }
+
+    async fn storage_stats(&self) -> Result<StorageStats, AppError> {
+        let mut conn = self.acquire().await?;
+        #[allow(clippy::type_complexity)]
+        let (hosts, deleted_hosts, files, snapshots, audit_entries, latest_pac_bytes, db_bytes): (
+            i64,
+            i64,
+            i64,
+            i64,
+            i64,
+            Option<i64>,
+            i64,
+        ) = sqlx::query_as(
+            r#"
+SELECT (SELECT COUNT(*) FROM white_list),
+    (SELECT COUNT(*) FROM deleted_hosts),
+    (SELECT COUNT(*) FROM pac WHERE version IS NOT NULL),
+    (SELECT COUNT(*) FROM snapshots),
+    (SELECT COUNT(*) FROM audit_log),
+    (SELECT size FROM pac WHERE hash =
+        (SELECT value FROM conf WHERE key = 'latest_pac_file')),
+    pg_database_size(current_database());"#,
+        )
+        .fetch_one(conn.as_mut())
+        .await?;
+        Ok(StorageStats {
+            hosts: hosts as u64,
+            deleted_hosts: deleted_hosts as u64,
+            files: files as u64,
+            snapshots: snapshots as u64,
+            audit_entries: audit_entries as u64,
+            latest_pac_bytes: latest_pac_bytes.map(|b| b as u64),
+            db_bytes: Some(db_bytes as u64),
+            // Shared by every database of the server
+            wal_bytes: None,
+        })
+    }
+
+    async fn export_state(&self) -> Result<InstanceState, AppError> {
+        let mut tx = self.pool.begin().await?;
+        let hosts = fetch_entries(tx.as_mut()).await?;
+        let mut snapshots = vec![];
+        let rows: Vec<(String, i64, String)> =
+            sqlx::query_as("SELECT name, created_at, hosts FROM snapshots ORDER BY name;")
+                .fetch_all(tx.as_mut())
+                .await?;
+        for (name, created_at, hosts) in rows {
+            snapshots.push(Snapshot {
+                name,
+                created_at,
+                hosts: serde_json::from_str(&hosts).map_err(|e| AppError::Other(e.to_string()))?,
+            });
+        }
+        let profiles = fetch_profiles(tx.as_mut()).await?;
+        let proxy = fetch_conf(tx.as_mut(), "proxy").await?;
+        let groups = fetch_groups(tx.as_mut()).await?;
+        let upstreams = fetch_upstreams(tx.as_mut()).await?;
+        let mode = fetch_mode(tx.as_mut()).await?;
+        let exclusions =
+            fetch_hosts(tx.as_mut(), "SELECT host FROM exclusions ORDER BY host;").await?;
+        let bypass_private = fetch_bypass_private(tx.as_mut()).await?;
+        let networks = fetch_networks(tx.as_mut()).await?;
+        let ip_ranges = sqlx::query_scalar("SELECT network FROM ip_ranges ORDER BY network;")
+            .fetch_all(tx.as_mut())
+            .await?;
+        let blocklist = fetch_blocklist(tx.as_mut()).await?;
+        tx.commit().await?;
+        Ok(InstanceState {
+            version: STATE_VERSION,
+            hosts,
+            snapshots,
+            profiles,
+            proxy,
+            groups,
+            upstreams,
+            mode,
+            exclusions,
+            bypass_private,
+            networks,
+            ip_ranges,
+            blocklist,
+        })
+    }
+
+    async fn import_state(&self, state: InstanceState) -> Result<(), AppError> {
+        check_state_version(&state)?;
+        let mut tx = self.begin_hosts_write().await?;
+        replace_entries(tx.as_mut(), &state.hosts).await?;
+        sqlx::query("DELETE FROM snapshots")
+            .execute(tx.as_mut())
+            .await?;
+        for snapshot in state.snapshots.iter() {
+            let hosts = serde_json::to_string(&snapshot.hosts)
+                .map_err(|e| AppError::Other(e.to_string()))?;
+            sqlx::query("INSERT INTO snapshots(name, created_at, hosts) VALUES ($1, $2, $3)")
+                .bind(&snapshot.name)
+                .bind(snapshot.created_at)
+                .bind(hosts)
+                .execute(tx.as_mut())
+                .await?;
+        }
+        sqlx::query("DELETE FROM profiles")
+            .execute(tx.as_mut())
+            .await?;
+        for profile in state.profiles.iter() {
+            sqlx::query("INSERT INTO profiles(name, proxy, tags) VALUES ($1, $2, $3)")
+                .bind(&profile.name)
+                .bind(&profile.proxy)
+                .bind(profile.tags.join(","))
+                .execute(tx.as_mut())
+                .await?;
+        }
+        sqlx::query("DELETE FROM proxy_groups")
+            .execute(tx.as_mut())
+            .await?;
+        for group in state.groups.iter() {
+            sqlx::query("INSERT INTO proxy_groups(name, proxy, schedule) VALUES ($1, $2, $3)")
+                .bind(&group.name)
+                .bind(&group.proxy)
+                .bind(group.schedule.map(|s| s.to_string()))
+                .execute(tx.as_mut())
+                .await?;
+        }
+        replace_upstreams(tx.as_mut(), &state.upstreams).await?;
+        store_conf(tx.as_mut(), "mode", state.mode.as_str()).await?;
+        sqlx::query("DELETE FROM exclusions")
+            .execute(tx.as_mut())
+            .await?;
+        insert_exclusions(tx.as_mut(), &state.exclusions).await?;
+        store_conf(
+            tx.as_mut(),
+            "bypass_private",
+            &state.bypass_private.to_string(),
+        )
+        .await?;
+        sqlx::query("DELETE FROM networks")
+            .execute(tx.as_mut())
+            .await?;
+        for network in state.networks.iter() {
+            sqlx::query("INSERT INTO networks(name, network, proxy) VALUES ($1, $2, $3)")
+                .bind(&network.name)
+                .bind(&network.network)
+                .bind(&network.proxy)
+                .execute(tx.as_mut())
+                .await?;
+        }
+        replace_ip_ranges(tx.as_mut(), &state.ip_ranges).await?;
+        sqlx::query("DELETE FROM blocklist")
+            .execute(tx.as_mut())
+            .await?;
+        for entry in state.blocklist.iter() {
+            upsert_blocklisted(tx.as_mut(), entry).await?;
+        }
+        match &state.proxy {
+            Some(proxy) => store_conf(tx.as_mut(), "proxy", proxy).await?,
+            None => {
+                sqlx::query("DELETE FROM conf WHERE key = 'proxy'")
+                    .execute(tx.as_mut())
+                    .await?;
+            }
+        }
+        tx.commit().await?;
+        self.changes.send(ChangeEvent::Hosts);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use futures::StreamExt;
+
+    use super::*;
+    use crate::{error::Result, storage::tests::conformance};
+
+    /// Database the tests run against, they're skipped without one
+    const TEST_URL_ENV: &str = "QPAC_TEST_POSTGRES_URL";
+
+    /// Url of an empty schema of its own, `None` without a test database
+    async fn test_url() -> Result<Option<String>> {
+        static SCHEMAS: AtomicU32 = AtomicU32::new(0);
+        let Ok(url) = std::env::var(TEST_URL_ENV) else {
+            return Ok(None);
+        };
+        let schema = format!(
+            "qpac_test_{}_{}",
+            std::process::id(),
+            SCHEMAS.fetch_add(1, Ordering::Relaxed)
+        );
+        let admin = PgPool::connect(&url).await?;
+        sqlx::raw_sql(&format!(
+            "DROP SCHEMA IF EXISTS {schema} CASCADE; CREATE SCHEMA {schema};"
+        ))
+        .execute(&admin)
+        .await?;
+        Ok(Some(format!("{url}?options=-csearch_path%3D{schema}")))
+    }
+
+    /// Migrated storage in a schema of its own, `None` without a test database
+    async fn test_storage() -> Result<Option<PostgresStorage>> {
+        match test_url().await? {
+            Some(url) => Ok(Some(PostgresStorage::new(&url).await?)),
+            None => Ok(None),
+        }
+    }
+
+    conformance!(
+        PostgresStorage,
+        match test_storage().await? {
+            Some(storage) => storage,
+            None => return Ok(()),
+        }
+    );
+
+    #[tokio::test]
+    async fn reports_migration_status() -> Result<()> {
+        let Some(storage) = test_storage().await? else {
+            return Ok(());
+        };
+        assert!(storage.migration_status().await?.is_current());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pushes_shared_changes() -> Result<()> {
+        let Some(url) = test_url().await? else {
+            return Ok(());
+        };
+        let storage = PostgresStorage::new(&url).await?;
+        let other = PostgresStorage::new(&url).await?;
+        let mut changes = storage.watch_shared().await?.expect("postgres pushes");
+
+        other.add_host("a.com", HostPatch::default()).await?;
+        other.set_proxy("DIRECT").await?;
+        for expected in [ChangeEvent::Hosts, ChangeEvent::Config] {
+            let event = tokio::time::timeout(Duration::from_secs(5), changes.next()).await?;
+            assert_eq!(event, Some(expected));
+        }
+        Ok(())
+    }
+}
@@ -1,54 +1,883 @@
-use std::{str::FromStr, time::Duration};
+use std::{
+    collections::{BTreeSet, HashMap},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
 
+use async_trait::async_trait;
+use futures::stream::BoxStream;
+use serde_json::json;
 use sqlx::{
     migrate,
-    sqlite::{
-        SqliteAutoVacuum, SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions,
-        SqliteSynchronous,
-    },
-    ConnectOptions, SqlitePool,
+    migrate::Migrate,
+    pool::PoolConnection,
+    sqlite::{SqliteAutoVacuum, SqliteConnectOptions, SqlitePoolOptions, SqliteSynchronous},
+    ConnectOptions, Sqlite, SqliteConnection, SqlitePool, Transaction,
 };
-use tracing::log::LevelFilter;
+use tracing::{log::LevelFilter, warn};
 
 use crate::{
+    args::SqliteArgs,
     error::{AppError, Result},
-    pac::Pac,
+    instrument::metrics::{
+        BUCKET_MIRROR_FAILURES, DB_MAINTENANCE_SECONDS, DB_POOL_ACQUIRE_SECONDS,
+        DB_POOL_CONNECTIONS,
+    },
+    pac::{self, NetworkProfile, Pac, PacEncodings, PacMeta, PacMode, Upstream},
+    rules::EntryKind,
+    schedule::{Schedule, ScheduleError},
+    utils::time::unix_now,
+};
+
+use super::{
+    bucket::PacBucket, check_state_version, expected_revision, renamed_tag, AuditEntry,
+    BlocklistEntry, ChangeEvent, ChangeFeed, ClientFetches, DeletedHost, HostEntry, HostPatch,
+    HostsDiff, ImportMode, InstanceState, PacVersion, Profile, ProxyGroup, Snapshot, SnapshotInfo,
+    Storage, StorageStats, TagInfo, STATE_VERSION,
 };
 
-use super::Storage;
+/// Backups are named `qpac-<unix time>.db`
+const BACKUP_PREFIX: &str = "qpac-";
+const BACKUP_SUFFIX: &str = ".db";
+
+/// Differences between the compiled-in migrations and a database
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct MigrationStatus {
+    /// Versions and descriptions of migrations not applied yet
+    pub pending: Vec<(i64, String)>,
+    /// Applied versions this binary doesn't know, a newer version migrated the database
+    pub unknown: Vec<i64>,
+    /// Applied versions whose checksum differs from the compiled-in migration
+    pub modified: Vec<i64>,
+}
+
+impl MigrationStatus {
+    /// Whether this binary can serve the database as is
+    pub fn is_current(&self) -> bool {
+        self.pending.is_empty() && self.unknown.is_empty() && self.modified.is_empty()
+    }
+}
 
 #[derive(Debug)]
 pub struct SqliteStorage {
     pool: SqlitePool,
+    /// Uploaded and latest files are mirrored here when set
+    bucket: Option<PacBucket>,
+    /// Bucket writes that failed, retried with the next upload
+    mirror_backlog: Mutex<MirrorBacklog>,
+    changes: ChangeFeed,
+}
+
+/// Bucket copies that are missing, outdated or should be gone
+#[derive(Debug, Default)]
+struct MirrorBacklog {
+    files: BTreeSet<String>,
+    deleted: BTreeSet<String>,
+    latest: bool,
 }
 
 impl SqliteStorage {
+    /// Connects and applies pending migrations
     pub async fn new(url: &str) -> Result<Self> {
-        let conf = SqliteConnectOptions::from_str(url)?
+        Self::new_with(url, &SqliteArgs::default()).await
+    }
+
+    /// Connects without touching the schema
+    pub async fn connect(url: &str) -> Result<Self> {
+        Self::connect_with(url, &SqliteArgs::default()).await
+    }
+
+    /// [`SqliteStorage::new`] with the options of `args`
+    pub async fn new_with(url: &str, args: &SqliteArgs) -> Result<Self> {
+        let storage = Self::connect_with(url, args).await?;
+        storage.migrate().await?;
+        Ok(storage)
+    }
+
+    /// [`SqliteStorage::connect`] with the options of `args`
+    pub async fn connect_with(url: &str, args: &SqliteArgs) -> Result<Self> {
+        let mut conf = SqliteConnectOptions::from_str(url)?;
+        let key = args.key()?;
+        if let Some(key) = &key {
+            // Has to come first, sqlx sends it before the other pragmas
+            conf = conf.pragma("key", format!("'{}'", key.replace('\'', "''")));
+        }
+        let conf = conf
             .log_statements(LevelFilter::Trace)
-            .journal_mode(SqliteJournalMode::Wal)
+            .journal_mode(args.db_journal_mode)
             .create_if_missing(true)
             .foreign_keys(true)
             .optimize_on_close(true, None)
             .synchronous(SqliteSynchronous::Normal)
-            .auto_vacuum(SqliteAutoVacuum::Full)
-            .busy_timeout(Duration::from_secs(3))
-            .pragma("cache_size", "10000")
+            // Free pages are reclaimed by `maintenance`
+            .auto_vacuum(SqliteAutoVacuum::Incremental)
+            .busy_timeout(Duration::from_millis(args.db_busy_timeout))
+            .pragma("cache_size", args.db_cache_size.to_string())
             .pragma("temp_store", "MEMORY")
             .pragma("encoding", "'UTF-8'")
             .pragma("mmap_size", "268435456");
 
-        let pool = SqlitePoolOptions::new().connect_with(conf).await?;
+        let pool = SqlitePoolOptions::new()
+            .max_connections(args.db_pool_size)
+            .connect_with(conf)
+            .await?;
+        if key.is_some() {
+            // Plain sqlite ignores the key pragma and would write plaintext
+            let cipher: Option<String> = sqlx::query_scalar("PRAGMA cipher_version;")
+                .fetch_optional(&pool)
+                .await?;
+            if cipher.is_none() {
+                return Err(color_eyre::eyre::eyre!(
+                    "--db-key needs qpac built with the sqlcipher feature"
+                )
+                .into());
+            }
+        }
+
+        Ok(Self {
+            pool,
+            bucket: None,
+            mirror_backlog: Mutex::default(),
+            changes: ChangeFeed::default(),
+        })
+    }
+
+    /// Mirrors uploaded files and every change of the latest one to `bucket`.
+    /// Failing writes are logged and retried with the next upload, the storage
+    /// call still succeeds
+    pub fn with_bucket(mut self, bucket: PacBucket) -> Self {
+        self.bucket = Some(bucket);
+        self
+    }
+
+    fn mirror_failed(&self, what: &str, hash: &str, e: AppError) {
+        warn!("Error mirroring {what} {hash} to the pac bucket, retrying later: {e}");
+        metrics::counter!(BUCKET_MIRROR_FAILURES, "op" => what.to_string()).increment(1);
+    }
+
+    async fn mirror_file(&self, bucket: &PacBucket, hash: &str, file: &str) {
+        if let Err(e) = bucket.put_file(hash, file).await {
+            self.mirror_failed("file", hash, e);
+            self.mirror_backlog
+                .lock()
+                .expect("Poisoned mirror backlog")
+                .files
+                .insert(hash.to_string());
+        }
+    }
+
+    async fn mirror_latest(&self, hash: &str) {
+        let Some(bucket) = &self.bucket else {
+            return;
+        };
+        // Supersedes a failed one
+        self.mirror_backlog
+            .lock()
+            .expect("Poisoned mirror backlog")
+            .latest = false;
+        let res = match self.get_file(hash).await {
+            Ok(file) => bucket.put_latest(&file).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = res {
+            self.mirror_failed("latest", hash, e);
+            self.mirror_backlog
+                .lock()
+                .expect("Poisoned mirror backlog")
+                .latest = true;
+        }
+    }
+
+    async fn mirror_delete(&self, bucket: &PacBucket, hash: &str) {
+        if let Err(e) = bucket.delete_file(hash).await {
+            self.mirror_failed("delete", hash, e);
+            self.mirror_backlog
+                .lock()
+                .expect("Poisoned mirror backlog")
+                .deleted
+                .insert(hash.to_string());
+        }
+    }
+
+    /// Retries the bucket writes that failed so far, ones failing again stay
+    /// in the backlog
+    async fn retry_mirror(&self) {
+        let Some(bucket) = &self.bucket else {
+            return;
+        };
+        let backlog =
+            std::mem::take(&mut *self.mirror_backlog.lock().expect("Poisoned mirror backlog"));
+        for hash in backlog.files.iter() {
+            match self.get_file(hash).await {
+                Ok(file) => self.mirror_file(bucket, hash, &file).await,
+                // Pruned since
+                Err(AppError::NotFound) => {}
+                Err(e) => {
+                    self.mirror_failed("file", hash, e);
+                    self.mirror_backlog
+                        .lock()
+                        .expect("Poisoned mirror backlog")
+                        .files
+                        .insert(hash.clone());
+                }
+            }
+        }
+        if backlog.latest {
+            match self.latest_hash().await {
+                Ok(hash) => self.mirror_latest(&hash).await,
+                Err(AppError::NotFound) => {}
+                Err(e) => {
+                    warn!("Error reading the latest file to mirror, retrying later: {e}");
+                    self.mirror_backlog
+                        .lock()
+                        .expect("Poisoned mirror backlog")
+                        .latest = true;
+                }
+            }
+        }
+        for hash in backlog.deleted.iter() {
+            self.mirror_delete(bucket, hash).await;
+        }
+    }
+
+    pub async fn migrate(&self) -> Result<()> {
+        migrate!().run(&self.pool).await?;
+        Ok(())
+    }
+
+    /// Versions and descriptions of migrations not applied yet
+    pub async fn pending_migrations(&self) -> Result<Vec<(i64, String)>> {
+        Ok(self.migration_status().await?.pending)
+    }
+
+    /// Compares the compiled-in migrations with the ones applied to the database
+    pub async fn migration_status(&self) -> Result<MigrationStatus> {
+        let mut conn = self.acquire().await?;
+        let has_table = sqlx::query!(
+            "SELECT name FROM sqlite_master WHERE type = 'table' AND name = '_sqlx_migrations';"
+        )
+        .fetch_optional(conn.as_mut())
+        .await?
+        .is_some();
+        let applied: HashMap<i64, Vec<u8>> = if has_table {
+            conn.list_applied_migrations()
+                .await?
+                .into_iter()
+                .map(|m| (m.version, m.checksum.into_owned()))
+                .collect()
+        } else {
+            HashMap::new()
+        };
+
+        let migrator = migrate!();
+        let mut status = MigrationStatus::default();
+        for m in migrator
+            .iter()
+            .filter(|m| !m.migration_type.is_down_migration())
+        {
+            match applied.get(&m.version) {
+                None => status.pending.push((m.version, m.description.to_string())),
+                Some(checksum) if *checksum != *m.checksum => status.modified.push(m.version),
+                Some(_) => {}
+            }
+        }
+        status.unknown = applied
+            .keys()
+            .filter(|v| !migrator.iter().any(|m| m.version == **v))
+            .copied()
+            .collect();
+        status.unknown.sort();
+        Ok(status)
+    }
+
+    /// Compresses files stored before compression was introduced, returns how
+    /// many were compressed
+    pub async fn compress_files(&self) -> Result<u64, AppError> {
+        let mut tx = self.pool.begin().await?;
+        let rows = sqlx::query!("SELECT hash, file FROM pac WHERE file_encoding = 'identity';")
+            .fetch_all(tx.as_mut())
+            .await?;
+        for r in rows.iter() {
+            let file = encode_file(&r.file)?;
+            sqlx::query!(
+                "UPDATE pac SET file = ?, file_encoding = 'zstd' WHERE hash = ?;",
+                file,
+                r.hash
+            )
+            .execute(tx.as_mut())
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(rows.len() as u64)
+    }
+
+    /// Compresses old files, checkpoints and truncates the WAL, refreshes
+    /// planner statistics and reclaims free pages
+    pub async fn maintenance(&self) -> Result<(), AppError> {
+        let compressed = self.compress_files().await?;
+        if compressed > 0 {
+            tracing::info!("Compressed {compressed} stored files");
+        }
+        let mut conn = self.acquire().await?;
+        for (task, pragma) in [
+            ("checkpoint", "PRAGMA wal_checkpoint(TRUNCATE);"),
+            ("optimize", "PRAGMA optimize;"),
+            ("vacuum", "PRAGMA incremental_vacuum;"),
+        ] {
+            let start = Instant::now();
+            sqlx::query(pragma).execute(conn.as_mut()).await?;
+            let elapsed = start.elapsed();
+            metrics::histogram!(DB_MAINTENANCE_SECONDS, "task" => task)
+                .record(elapsed.as_secs_f64());
+            tracing::debug!("Database {task} took {elapsed:?}");
+        }
+        Ok(())
+    }
+
+    /// Writes a consistent copy of the database into `dir` with `VACUUM INTO`
+    /// and deletes the oldest copies beyond `keep`, returns the new copy
+    pub async fn backup(&self, dir: &Path, keep: u32) -> Result<PathBuf, AppError> {
+        let io_error = |e: std::io::Error| AppError::Other(format!("{}: {e}", dir.display()));
+        tokio::fs::create_dir_all(dir).await.map_err(io_error)?;
+        let now = unix_now();
+        // Later backups of the same second get a counter, `VACUUM INTO`
+        // refuses existing files
+        let mut path = dir.join(format!("{BACKUP_PREFIX}{now}{BACKUP_SUFFIX}"));
+        let mut n = 0;
+        while tokio::fs::try_exists(&path).await.map_err(io_error)? {
+            n += 1;
+            path = dir.join(format!("{BACKUP_PREFIX}{now}-{n}{BACKUP_SUFFIX}"));
+        }
+        let target = path.to_string_lossy().into_owned();
+
+        let start = Instant::now();
+        let mut conn = self.acquire().await?;
+        sqlx::query("VACUUM INTO ?")
+            .bind(&target)
+            .execute(conn.as_mut())
+            .await?;
+        let elapsed = start.elapsed();
+        metrics::histogram!(DB_MAINTENANCE_SECONDS, "task" => "backup")
+            .record(elapsed.as_secs_f64());
+        tracing::debug!("Database backup to {target} took {elapsed:?}");
+
+        let mut backups = vec![];
+        let mut entries = tokio::fs::read_dir(dir).await.map_err(io_error)?;
+        while let Some(entry) = entries.next_entry().await.map_err(io_error)? {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if let Some(order) = backup_order(&name) {
+                backups.push((order, entry.path()));
+            }
+        }
+        backups.sort();
+        let stale = backups.len().saturating_sub(keep.max(1) as usize);
+        for (_, old) in backups.into_iter().take(stale) {
+            tokio::fs::remove_file(&old).await.map_err(io_error)?;
+            tracing::debug!("Deleted backup {}", old.display());
+        }
+        Ok(path)
+    }
+
+    async fn acquire(&self) -> Result<PoolConnection<Sqlite>, AppError> {
+        let start = Instant::now();
+        let conn = self.pool.acquire().await;
+        metrics::histogram!(DB_POOL_ACQUIRE_SECONDS).record(start.elapsed().as_secs_f64());
+        self.record_pool_gauges();
+        Ok(conn?)
+    }
+
+    /// Begins a host list write. When the request expects a list revision,
+    /// see [`expected_revision`], the first statement checks it as a write,
+    /// holding the database write lock until commit so nobody gets in between
+    async fn begin_hosts_write(&self) -> Result<Transaction<'static, Sqlite>, AppError> {
+        let mut tx = self.pool.begin().await?;
+        let Some(expected) = expected_revision() else {
+            return Ok(tx);
+        };
+        let res = sqlx::query!(
+            r#"
+UPDATE conf SET value = value
+    WHERE key = 'hosts_version' AND CAST(value AS INTEGER) = ?"#,
+            expected
+        )
+        .execute(tx.as_mut())
+        .await?;
+        if res.rows_affected() == 0 {
+            let current =
+                sqlx::query_scalar!("SELECT value FROM conf WHERE key = 'hosts_version';")
+                    .fetch_optional(tx.as_mut())
+                    .await?
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or_default();
+            return Err(AppError::StaleRevision { expected, current });
+        }
+        Ok(tx)
+    }
+
+    fn record_pool_gauges(&self) {
+        let size = self.pool.size();
+        let idle = self.pool.num_idle() as u32;
+        metrics::gauge!(DB_POOL_CONNECTIONS, "state" => "idle").set(idle);
+        metrics::gauge!(DB_POOL_CONNECTIONS, "state" => "in_use").set(size.saturating_sub(idle));
+    }
+}
+
+/// Compression level of stored files, zstd's default
+const FILE_ZSTD_LEVEL: i32 = 3;
+
+/// Stored bytes of a file body, see [`decode_file`]
+pub(super) fn encode_file(file: &str) -> Result<Vec<u8>, AppError> {
+    zstd::encode_all(file.as_bytes(), FILE_ZSTD_LEVEL).map_err(|e| AppError::Other(e.to_string()))
+}
+
+/// Body of a stored file, `encoding` is its `file_encoding`
+pub(super) fn decode_file(file: Vec<u8>, encoding: &str) -> Result<String, AppError> {
+    let bytes = match encoding {
+        "identity" => file,
+        "zstd" => zstd::decode_all(file.as_slice()).map_err(|e| AppError::Other(e.to_string()))?,
+        other => return Err(AppError::Other(format!("Unknown file encoding {other}"))),
+    };
+    String::from_utf8(bytes).map_err(|e| AppError::Other(e.to_string()))
+}
+
+/// Time and counter of a backup named `qpac-<unix time>[-<n>].db`
+fn backup_order(name: &str) -> Option<(i64, u32)> {
+    let stem = name
+        .strip_prefix(BACKUP_PREFIX)?
+        .strip_suffix(BACKUP_SUFFIX)?;
+    match stem.split_once('-') {
+        Some((at, n)) => Some((at.parse().ok()?, n.parse().ok()?)),
+        None => Some((stem.parse().ok()?, 0)),
+    }
+}
+
+async fn fetch_entry(conn: &mut SqliteConnection, host: &str) -> Result<HostEntry, AppError> {
+    let row = sqlx::query!(
+        r#"
+SELECT host, note, expires_at, kind,
+    pinned as "pinned: bool", proxy_group, schedule, created_at, updated_at
+    FROM white_list WHERE host = ?;"#,
+        host
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+    let tags = sqlx::query!(
+        "SELECT tag FROM host_tags WHERE host = ? ORDER BY tag;",
+        host
+    )
+    .fetch_all(&mut *conn)
+    .await?
+    .into_iter()
+    .map(|r| r.tag)
+    .collect();
+    Ok(HostEntry {
+        host: row.host,
+        note: row.note,
+        tags,
+        expires_at: row.expires_at,
+        kind: parse_kind(&row.kind)?,
+        pinned: row.pinned,
+        group: row.proxy_group,
+        schedule: parse_schedule(row.schedule)?,
+        created_at: row.created_at,
+        updated_at: row.updated_at,
+    })
+}
+
+async fn fetch_entries(conn: &mut SqliteConnection) -> Result<Vec<HostEntry>, AppError> {
+    let mut tags: HashMap<String, Vec<String>> = HashMap::new();
+    for r in sqlx::query!("SELECT host, tag FROM host_tags ORDER BY host, tag;")
+        .fetch_all(&mut *conn)
+        .await?
+    {
+        tags.entry(r.host).or_default().push(r.tag);
+    }
+    let res = sqlx::query!(
+        r#"
+SELECT host, note, expires_at, kind,
+    pinned as "pinned: bool", proxy_group, schedule, created_at, updated_at
+    FROM white_list ORDER BY host;"#
+    )
+    .fetch_all(&mut *conn)
+    .await?
+    .into_iter()
+    .map(|r| {
+        Ok(HostEntry {
+            tags: tags.remove(&r.host).unwrap_or_default(),
+            host: r.host,
+            note: r.note,
+            expires_at: r.expires_at,
+            kind: parse_kind(&r.kind)?,
+            pinned: r.pinned,
+            group: r.proxy_group,
+            schedule: parse_schedule(r.schedule)?,
+            created_at: r.created_at,
+            updated_at: r.updated_at,
+        })
+    })
+    .collect::<Result<_, AppError>>()?;
+    Ok(res)
+}
+
+pub(super) fn parse_schedule(value: Option<String>) -> Result<Option<Schedule>, AppError> {
+    value
+        .map(|s| {
+            s.parse()
+                .map_err(|e: ScheduleError| AppError::Other(e.to_string()))
+        })
+        .transpose()
+}
+
+pub(super) fn parse_kind(value: &str) -> Result<EntryKind, AppError> {
+    value.parse().map_err(AppError::Other)
+}
+
+async fn fetch_ip_ranges(conn: &mut SqliteConnection) -> Result<Vec<String>, AppError> {
+    let res = sqlx::query!("SELECT network FROM ip_ranges ORDER BY network;")
+        .fetch_all(conn)
+        .await?;
+    Ok(res.into_iter().map(|r| r.network).collect())
+}
+
+/// Replaces every IP range with `ranges`, run inside a transaction
+async fn replace_ip_ranges(conn: &mut SqliteConnection, ranges: &[String]) -> Result<(), AppError> {
+    sqlx::query!("DELETE FROM ip_ranges")
+        .execute(&mut *conn)
+        .await?;
+    for range in ranges.iter() {
+        sqlx::query!(
+            "INSERT INTO ip_ranges(network) VALUES (?) ON CONFLICT(network) DO NOTHING",
+            range
+        )
+        .execute(&mut *conn)
+        .await?;
+    }
+    Ok(())
+}
+
+async fn fetch_networks(conn: &mut SqliteConnection) -> Result<Vec<NetworkProfile>, AppError> {
+    let networks = sqlx::query_as!(
+        NetworkProfile,
+        "SELECT name, network, proxy FROM networks ORDER BY name;"
+    )
+    .fetch_all(conn)
+    .await?;
+    Ok(networks)
+}
+
+/// Profile tags are stored comma separated, names can't contain commas
+pub(super) fn split_tags(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .filter(|t| !t.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+async fn fetch_profiles(conn: &mut SqliteConnection) -> Result<Vec<Profile>, AppError> {
+    let profiles = sqlx::query!("SELECT name, proxy, tags FROM profiles ORDER BY name;")
+        .fetch_all(conn)
+        .await?
+        .into_iter()
+        .map(|r| Profile {
+            name: r.name,
+            proxy: r.proxy,
+            tags: split_tags(&r.tags),
+        })
+        .collect();
+    Ok(profiles)
+}
+
+async fn fetch_groups(conn: &mut SqliteConnection) -> Result<Vec<ProxyGroup>, AppError> {
+    sqlx::query!("SELECT name, proxy, schedule FROM proxy_groups ORDER BY name;")
+        .fetch_all(conn)
+        .await?
+        .into_iter()
+        .map(|r| {
+            Ok(ProxyGroup {
+                name: r.name,
+                proxy: r.proxy,
+                schedule: parse_schedule(r.schedule)?,
+            })
+        })
+        .collect()
+}
+
+async fn fetch_mode(conn: &mut SqliteConnection) -> Result<PacMode, AppError> {
+    let res = sqlx::query!("SELECT value FROM conf WHERE key = 'mode';")
+        .fetch_optional(conn)
+        .await?;
+    match res {
+        Some(r) => r.value.parse().map_err(AppError::Other),
+        None => Ok(PacMode::default()),
+    }
+}
+
+async fn store_mode(conn: &mut SqliteConnection, mode: PacMode) -> Result<(), AppError> {
+    let mode = mode.as_str();
+    sqlx::query!(
+        r#"
+INSERT INTO conf(key, value) VALUES ('mode', ?)
+    ON CONFLICT(key) DO UPDATE SET value=excluded.value"#,
+        mode
+    )
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+async fn fetch_bypass_private(conn: &mut SqliteConnection) -> Result<bool, AppError> {
+    let res = sqlx::query!("SELECT value FROM conf WHERE key = 'bypass_private';")
+        .fetch_optional(conn)
+        .await?;
+    Ok(res.is_some_and(|r| r.value == "true"))
+}
+
+async fn store_bypass_private(conn: &mut SqliteConnection, enabled: bool) -> Result<(), AppError> {
+    let enabled = enabled.to_string();
+    sqlx::query!(
+        r#"
+INSERT INTO conf(key, value) VALUES ('bypass_private', ?)
+    ON CONFLICT(key) DO UPDATE SET value=excluded.value"#,
+        enabled
+    )
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+async fn fetch_upstreams(conn: &mut SqliteConnection) -> Result<Vec<Upstream>, AppError> {
+    let res = sqlx::query_as!(
+        Upstream,
+        r#"SELECT proxy, weight as "weight: u32" FROM upstreams ORDER BY position;"#
+    )
+    .fetch_all(conn)
+    .await?;
+    Ok(res)
+}
+
+/// Replaces every upstream with `upstreams`, run inside a transaction
+async fn replace_upstreams(
+    conn: &mut SqliteConnection,
+    upstreams: &[Upstream],
+) -> Result<(), AppError> {
+    sqlx::query!("DELETE FROM upstreams")
+        .execute(&mut *conn)
+        .await?;
+    for (position, upstream) in upstreams.iter().enumerate() {
+        let position = position as i64;
+        sqlx::query!(
+            "INSERT INTO upstreams(position, proxy, weight) VALUES (?, ?, ?)",
+            position,
+            upstream.proxy,
+            upstream.weight
+        )
+        .execute(&mut *conn)
+        .await?;
+    }
+    Ok(())
+}
+
+async fn fetch_exclusions(conn: &mut SqliteConnection) -> Result<Vec<String>, AppError> {
+    let res = sqlx::query!("SELECT host FROM exclusions ORDER BY host;")
+        .fetch_all(conn)
+        .await?;
+    Ok(res.into_iter().map(|r| r.host).collect())
+}
+
+/// Inserts `hosts` not excluded yet, returns the inserted ones
+async fn insert_exclusions(
+    conn: &mut SqliteConnection,
+    hosts: &[String],
+) -> Result<Vec<String>, AppError> {
+    let now = unix_now();
+    let mut added = vec![];
+    for host in hosts.iter() {
+        let res = sqlx::query!(
+            "INSERT OR IGNORE INTO exclusions(host, created_at) VALUES (?, ?)",
+            host,
+            now
+        )
+        .execute(&mut *conn)
+        .await?;
+        if res.rows_affected() > 0 {
+            added.push(host.clone());
+        }
+    }
+    added.sort();
+    Ok(added)
+}
+
+async fn fetch_blocklist(conn: &mut SqliteConnection) -> Result<Vec<BlocklistEntry>, AppError> {
+    sqlx::query!("SELECT host, kind, action, created_at FROM blocklist ORDER BY host;")
+        .fetch_all(conn)
+        .await?
+        .into_iter()
+        .map(|r| {
+            Ok(BlocklistEntry {
+                host: r.host,
+                kind: parse_kind(&r.kind)?,
+                action: r.action.parse().map_err(AppError::Other)?,
+                created_at: r.created_at,
+            })
+        })
+        .collect()
+}
+
+/// Inserts `entry`, an existing entry of the host keeps its `created_at`
+async fn upsert_blocklisted(
+    conn: &mut SqliteConnection,
+    entry: &BlocklistEntry,
+) -> Result<(), AppError> {
+    let kind = entry.kind.as_str();
+    let action = entry.action.as_str();
+    sqlx::query!(
+        r#"
+INSERT INTO blocklist(host, kind, action, created_at) VALUES (?, ?, ?, ?)
+    ON CONFLICT(host) DO UPDATE SET kind=excluded.kind, action=excluded.action"#,
+        entry.host,
+        kind,
+        action,
+        entry.created_at
+    )
+    .execute(conn)
+    .await?;
+    Ok(())
+}
 
-        migrate!().run(&pool).await?;
+/// Replaces every host with `entries`, run inside a transaction
+async fn replace_entries(
+    conn: &mut SqliteConnection,
+    entries: &[HostEntry],
+) -> Result<(), AppError> {
+    // Tags go along through the cascade
+    sqlx::query!("DELETE FROM white_list")
+        .execute(&mut *conn)
+        .await?;
+    for e in entries.iter() {
+        insert_entry(conn, e).await?;
+    }
+    Ok(())
+}
+
+async fn insert_entry(conn: &mut SqliteConnection, e: &HostEntry) -> Result<(), AppError> {
+    let schedule = e.schedule.map(|s| s.to_string());
+    let kind = e.kind.as_str();
+    sqlx::query!(
+        r#"
+INSERT INTO white_list(host, note, expires_at, kind, pinned, proxy_group,
+    schedule, created_at, updated_at)
+    VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)"#,
+        e.host,
+        e.note,
+        e.expires_at,
+        kind,
+        e.pinned,
+        e.group,
+        schedule,
+        e.created_at,
+        e.updated_at
+    )
+    .execute(&mut *conn)
+    .await?;
+    for tag in e.tags.iter() {
+        sqlx::query!(
+            "INSERT INTO host_tags(host, tag) VALUES (?, ?)",
+            e.host,
+            tag
+        )
+        .execute(&mut *conn)
+        .await?;
+    }
+    Ok(())
+}
 
-        Ok(Self { pool })
+/// Moves a listed host to `deleted_hosts`, replacing an earlier removal of it
+/// Applies the fields set in `patch` to a listed host, returns the result
+async fn patch_entry(
+    conn: &mut SqliteConnection,
+    host: &str,
+    patch: HostPatch,
+) -> Result<HostEntry, AppError> {
+    let current = fetch_entry(&mut *conn, host).await?;
+    let note = patch.note.unwrap_or(current.note);
+    let expires_at = patch.expires_at.unwrap_or(current.expires_at);
+    let kind = patch.kind.unwrap_or(current.kind).as_str();
+    let pinned = patch.pinned.unwrap_or(current.pinned);
+    let group = patch.group.unwrap_or(current.group);
+    let schedule = patch
+        .schedule
+        .unwrap_or(current.schedule)
+        .map(|s| s.to_string());
+    let now = unix_now();
+    sqlx::query!(
+        r#"
+UPDATE white_list SET note = ?, expires_at = ?, kind = ?, pinned = ?,
+    proxy_group = ?, schedule = ?, updated_at = ?
+    WHERE host = ?"#,
+        note,
+        expires_at,
+        kind,
+        pinned,
+        group,
+        schedule,
+        now,
+        host
+    )
+    .execute(&mut *conn)
+    .await?;
+    if let Some(tags) = patch.tags {
+        sqlx::query!("DELETE FROM host_tags WHERE host = ?", host)
+            .execute(&mut *conn)
+            .await?;
+        for tag in tags.iter() {
+            sqlx::query!(
+                "INSERT INTO host_tags(host, tag) VALUES (?, ?) ON CONFLICT DO NOTHING",
+                host,
+                tag
+            )
+            .execute(&mut *conn)
+            .await?;
+        }
     }
+    fetch_entry(conn, host).await
 }
 
+async fn trash_entry(conn: &mut SqliteConnection, e: &HostEntry) -> Result<(), AppError> {
+    let entry = serde_json::to_string(e).map_err(|e| AppError::Other(e.to_string()))?;
+    let now = unix_now();
+    sqlx::query!(
+        r#"
+INSERT INTO deleted_hosts(host, entry, deleted_at) VALUES (?, ?, ?)
+    ON CONFLICT(host) DO UPDATE SET entry=excluded.entry, deleted_at=excluded.deleted_at"#,
+        e.host,
+        entry,
+        now
+    )
+    .execute(&mut *conn)
+    .await?;
+    // Tags go along through the cascade
+    sqlx::query!("DELETE FROM white_list WHERE host = ?", e.host)
+        .execute(&mut *conn)
+        .await?;
+    Ok(())
+}
+
+#[async_trait]
 impl Storage for SqliteStorage {
+    fn watch(&self) -> BoxStream<'static, ChangeEvent> {
+        self.changes.watch()
+    }
+
+    async fn watch_shared(&self) -> Result<Option<BoxStream<'static, ChangeEvent>>, AppError> {
+        // Sqlite has no notifications across processes
+        Ok(None)
+    }
+
     async fn all_hosts(&self) -> Result<Vec<String>, AppError> {
-        let mut conn = self.pool.acquire().await?;
+        let mut conn = self.acquire().await?;
         let res = sqlx::query!("SELECT host FROM white_list;")
             .fetch_all(conn.as_mut())
             .await?
@@ -58,140 +887,1743 @@ impl Storage for SqliteStorage {
         Ok(res)
     }
 
-    async fn get_file(&self, hash: impl Into<String>) -> Result<String, AppError> {
-        let mut conn = self.pool.acquire().await?;
-        let host = hash.into();
-        let res = sqlx::query!("SELECT file FROM pac WHERE hash = ?;", host)
+    async fn host_entries(&self) -> Result<Vec<HostEntry>, AppError> {
+        let mut conn = self.acquire().await?;
+        fetch_entries(conn.as_mut()).await
+    }
+
+    async fn search_hosts(&self, pattern: &str) -> Result<Vec<String>, AppError> {
+        let mut conn = self.acquire().await?;
+        let res = sqlx::query!(
+            "SELECT host FROM white_list WHERE host LIKE ? ORDER BY host;",
+            pattern
+        )
+        .fetch_all(conn.as_mut())
+        .await?
+        .into_iter()
+        .map(|r| r.host)
+        .collect();
+        Ok(res)
+    }
+
+    async fn hosts_page(
+        &self,
+        after: Option<&str>,
+        limit: u32,
+    ) -> Result<Vec<HostEntry>, AppError> {
+        let mut tx = self.pool.begin().await?;
+        let after = after.unwrap_or_default();
+        let rows = sqlx::query!(
+            r#"
+SELECT host, note, expires_at, kind,
+    pinned as "pinned: bool", proxy_group, schedule, created_at, updated_at
+    FROM white_list WHERE host > ? ORDER BY host LIMIT ?;"#,
+            after,
+            limit
+        )
+        .fetch_all(tx.as_mut())
+        .await?;
+        let Some(last) = rows.last().map(|r| r.host.clone()) else {
+            return Ok(Vec::new());
+        };
+        let mut tags: HashMap<String, Vec<String>> = HashMap::new();
+        for r in sqlx::query!(
+            "SELECT host, tag FROM host_tags WHERE host > ? AND host <= ? ORDER BY host, tag;",
+            after,
+            last
+        )
+        .fetch_all(tx.as_mut())
+        .await?
+        {
+            tags.entry(r.host).or_default().push(r.tag);
+        }
+        tx.commit().await?;
+        rows.into_iter()
+            .map(|r| {
+                Ok(HostEntry {
+                    tags: tags.remove(&r.host).unwrap_or_default(),
+                    host: r.host,
+                    note: r.note,
+                    expires_at: r.expires_at,
+                    kind: parse_kind(&r.kind)?,
+                    pinned: r.pinned,
+                    group: r.proxy_group,
+                    schedule: parse_schedule(r.schedule)?,
+                    created_at: r.created_at,
+                    updated_at: r.updated_at,
+                })
+            })
+            .collect()
+    }
+
+    async fn get_host(&self, host: &str) -> Result<HostEntry, AppError> {
+        let mut conn = self.acquire().await?;
+        fetch_entry(conn.as_mut(), host).await
+    }
+
+    async fn update_host(&self, host: &str, patch: HostPatch) -> Result<HostEntry, AppError> {
+        let mut tx = self.begin_hosts_write().await?;
+        let entry = patch_entry(tx.as_mut(), host, patch).await?;
+        tx.commit().await?;
+        self.changes.send(ChangeEvent::Hosts);
+        Ok(entry)
+    }
+
+    async fn get_file(&self, hash: &str) -> Result<String, AppError> {
+        let mut conn = self.acquire().await?;
+        let res = sqlx::query!(
+            r#"SELECT file as "file: Vec<u8>", file_encoding FROM pac WHERE hash = ?;"#,
+            hash
+        )
+        .fetch_one(conn.as_mut())
+        .await?;
+        decode_file(res.file, &res.file_encoding)
+    }
+
+    async fn get_file_latest(&self) -> Result<Pac, AppError> {
+        let mut conn = self.acquire().await?;
+        let conf = sqlx::query!("SELECT value FROM conf WHERE key = 'latest_pac_file';")
             .fetch_one(conn.as_mut())
             .await?;
-        Ok(res.file)
+        let res = sqlx::query!(
+            r#"SELECT file as "file: Vec<u8>", file_encoding FROM pac WHERE hash = ?;"#,
+            conf.value
+        )
+        .fetch_one(conn.as_mut())
+        .await?;
+        Ok(Pac::new(
+            decode_file(res.file, &res.file_encoding)?,
+            conf.value,
+        ))
     }
 
-    async fn get_file_latest(&self) -> Result<Pac, AppError> {
-        let mut conn = self.pool.acquire().await?;
+    async fn latest_hash(&self) -> Result<String, AppError> {
+        let mut conn = self.acquire().await?;
         let conf = sqlx::query!("SELECT value FROM conf WHERE key = 'latest_pac_file';")
             .fetch_one(conn.as_mut())
             .await?;
-        let res = sqlx::query!("SELECT file FROM pac WHERE hash = ?;", conf.value)
+        Ok(conf.value)
+    }
+
+    async fn get_checksum(&self, hash: &str) -> Result<String, AppError> {
+        let mut conn = self.acquire().await?;
+        let res = sqlx::query!("SELECT checksum FROM pac WHERE hash = ?;", hash)
+            .fetch_one(conn.as_mut())
+            .await?;
+        // Files uploaded before checksums were introduced
+        res.checksum.ok_or(AppError::NotFound)
+    }
+
+    async fn get_manifest(&self, hash: &str) -> Result<Vec<String>, AppError> {
+        let mut conn = self.acquire().await?;
+        let res = sqlx::query!("SELECT hosts FROM pac WHERE hash = ?;", hash)
             .fetch_one(conn.as_mut())
             .await?;
-        Ok(Pac::new(res.file, conf.value))
+        // Files uploaded before manifests were introduced
+        let hosts = res.hosts.ok_or(AppError::NotFound)?;
+        serde_json::from_str(&hosts).map_err(|e| AppError::Other(e.to_string()))
     }
 
     async fn upload_file(&self, pac: &Pac) -> Result<(), AppError> {
-        let mut conn = self.pool.acquire().await?;
+        let mut conn = self.acquire().await?;
+        let hosts =
+            serde_json::to_string(&pac.hosts).map_err(|e| AppError::Other(e.to_string()))?;
+        let checksum = pac::checksum(&pac.file);
+        let file = encode_file(&pac.file)?;
+        let size = pac.file.len() as i64;
+        let meta = pac.meta.as_ref();
+        let generated_at = meta.map(|m| m.generated_at);
+        let host_count = meta.map(|m| m.host_count);
+        let qpac_version = meta.map(|m| m.qpac_version.as_str());
+        let now = unix_now();
         sqlx::query!(
             r#"
-INSERT INTO pac(hash, file) VALUES(?, ?)
-    ON CONFLICT(hash) DO UPDATE SET file=excluded.file;"#,
+INSERT INTO pac(
+    hash, file, file_encoding, size, hosts, checksum, version, generated_at, host_count,
+    qpac_version, created_at
+)
+    VALUES(?, ?, 'zstd', ?, ?, ?, (SELECT COALESCE(MAX(version), 0) + 1 FROM pac), ?, ?, ?, ?)
+    ON CONFLICT(hash) DO UPDATE SET
+        file=excluded.file, file_encoding=excluded.file_encoding, size=excluded.size,
+        hosts=excluded.hosts, checksum=excluded.checksum,
+        generated_at=excluded.generated_at, host_count=excluded.host_count,
+        qpac_version=excluded.qpac_version;"#,
             pac.hash,
-            pac.file
+            file,
+            size,
+            hosts,
+            checksum,
+            generated_at,
+            host_count,
+            qpac_version,
+            now
         )
         .execute(conn.as_mut())
         .await?;
+        drop(conn);
+        if let Some(bucket) = &self.bucket {
+            self.retry_mirror().await;
+            self.mirror_backlog
+                .lock()
+                .expect("Poisoned mirror backlog")
+                .deleted
+                .remove(&pac.hash);
+            self.mirror_file(bucket, &pac.hash, &pac.file).await;
+        }
         Ok(())
     }
 
-    async fn set_latest(&self, hash: impl Into<String>) -> Result<(), AppError> {
-        let mut conn = self.pool.acquire().await?;
-        let hash = hash.into();
-        sqlx::query!(
-            r#"
-INSERT INTO conf(key, value) VALUES ('latest_pac_file', ?)
-    ON CONFLICT(key) DO UPDATE SET value=excluded.value"#,
+    async fn upload_encodings(&self, hash: &str, encodings: &PacEncodings) -> Result<(), AppError> {
+        let mut tx = self.pool.begin().await?;
+        let known = sqlx::query!("SELECT hash FROM pac WHERE hash = ?", hash)
+            .fetch_optional(tx.as_mut())
+            .await?;
+        if known.is_none() {
+            Err(AppError::NotFound)?
+        }
+        let bodies = [("gzip", &encodings.gzip), ("br", &encodings.br)];
+        for (encoding, body) in bodies {
+            let Some(body) = body else {
+                continue;
+            };
+            sqlx::query!(
+                r#"
+INSERT INTO pac_encodings(hash, encoding, body) VALUES (?, ?, ?)
+    ON CONFLICT(hash, encoding) DO UPDATE SET body=excluded.body"#,
+                hash,
+                encoding,
+                body
+            )
+            .execute(tx.as_mut())
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn get_encodings(&self, hash: &str) -> Result<PacEncodings, AppError> {
+        let mut conn = self.acquire().await?;
+        let mut encodings = PacEncodings::default();
+        for r in sqlx::query!(
+            "SELECT encoding, body FROM pac_encodings WHERE hash = ?",
             hash
         )
-        .execute(conn.as_mut())
+        .fetch_all(conn.as_mut())
+        .await?
+        {
+            match r.encoding.as_str() {
+                "gzip" => encodings.gzip = Some(r.body),
+                "br" => encodings.br = Some(r.body),
+                _ => {}
+            }
+        }
+        Ok(encodings)
+    }
+
+    async fn list_versions(&self, limit: u32) -> Result<Vec<PacVersion>, AppError> {
+        let mut conn = self.acquire().await?;
+        let res = sqlx::query!(
+            r#"
+SELECT version as "version!", hash, size as "size!: i64",
+    generated_at, host_count, qpac_version, created_at FROM pac
+    WHERE version IS NOT NULL
+    ORDER BY version DESC
+    LIMIT ?"#,
+            limit
+        )
+        .fetch_all(conn.as_mut())
         .await?;
-        Ok(())
+        Ok(res
+            .into_iter()
+            .map(|r| PacVersion {
+                version: r.version,
+                hash: r.hash,
+                size: r.size,
+                created_at: r.created_at,
+                meta: match (r.generated_at, r.host_count, r.qpac_version) {
+                    (Some(generated_at), Some(host_count), Some(qpac_version)) => Some(PacMeta {
+                        generated_at,
+                        host_count,
+                        qpac_version,
+                    }),
+                    _ => None,
+                },
+            })
+            .collect())
     }
 
-    async fn add_host(&self, host: impl Into<String>) -> Result<(), AppError> {
-        let mut conn = self.pool.acquire().await?;
-        let host = host.into();
+    async fn prune_files(&self, keep: u32, before: i64) -> Result<u64, AppError> {
+        let mut conn = self.acquire().await?;
+        let keep = keep.max(1);
+        let pruned = sqlx::query!(
+            r#"
+DELETE FROM pac
+    WHERE version NOT IN (SELECT version FROM pac ORDER BY version DESC LIMIT ?)
+    AND COALESCE(generated_at, 0) < ?
+    AND hash NOT IN (
+        SELECT value FROM conf WHERE key IN ('latest_pac_file', 'staged_pac_file')
+    )
+    RETURNING hash;"#,
+            keep,
+            before
+        )
+        .fetch_all(conn.as_mut())
+        .await?;
+        drop(conn);
+        if let Some(bucket) = &self.bucket {
+            for r in pruned.iter() {
+                self.mirror_backlog
+                    .lock()
+                    .expect("Poisoned mirror backlog")
+                    .files
+                    .remove(&r.hash);
+                self.mirror_delete(bucket, &r.hash).await;
+            }
+        }
+        Ok(pruned.len() as u64)
+    }
+
+    async fn get_version(&self, hash: &str) -> Result<i64, AppError> {
+        let mut conn = self.acquire().await?;
+        let res = sqlx::query!("SELECT version FROM pac WHERE hash = ?;", hash)
+            .fetch_one(conn.as_mut())
+            .await?;
+        res.version.ok_or(AppError::NotFound)
+    }
+
+    async fn get_version_hash(&self, version: i64) -> Result<String, AppError> {
+        let mut conn = self.acquire().await?;
+        let res = sqlx::query!("SELECT hash FROM pac WHERE version = ?;", version)
+            .fetch_one(conn.as_mut())
+            .await?;
+        Ok(res.hash)
+    }
+
+    async fn set_latest(&self, hash: &str) -> Result<(), AppError> {
+        let mut conn = self.acquire().await?;
         sqlx::query!(
-            "INSERT INTO white_list(host) VALUES (?) ON CONFLICT(host) DO NOTHING",
-            host
+            r#"
+INSERT INTO conf(key, value) VALUES ('latest_pac_file', ?)
+    ON CONFLICT(key) DO UPDATE SET value=excluded.value"#,
+            hash
         )
         .execute(conn.as_mut())
         .await?;
+        drop(conn);
+        self.mirror_latest(hash).await;
         Ok(())
     }
 
-    async fn remove_host(&self, host: impl Into<String>) -> Result<(), AppError> {
-        let mut conn = self.pool.acquire().await?;
-        let host = host.into();
-        sqlx::query!("DELETE FROM white_list WHERE host = ?", host)
-            .execute(conn.as_mut())
+    async fn staged_hash(&self) -> Result<Option<String>, AppError> {
+        let mut conn = self.acquire().await?;
+        let res = sqlx::query!("SELECT value FROM conf WHERE key = 'staged_pac_file';")
+            .fetch_optional(conn.as_mut())
             .await?;
-        Ok(())
+        Ok(res.map(|r| r.value))
     }
-}
-
-#[cfg(test)]
-mod test {
-    use super::*;
-    use crate::error::Result;
 
-    #[tokio::test]
-    async fn adds_sorted() -> Result<()> {
-        let storage = SqliteStorage::new("sqlite::memory:").await?;
-        let test = vec![
-            "a".to_string(),
-            "aa".to_string(),
-            "ab".to_string(),
-            "abc".to_string(),
-            "b".to_string(),
-            "bac".to_string(),
-            "sa".to_string(),
-            "z".to_string(),
-        ];
-        for s in test.iter() {
-            storage.add_host(s).await?;
+    async fn set_staged_hash(&self, hash: Option<String>) -> Result<(), AppError> {
+        let mut conn = self.acquire().await?;
+        match hash {
+            Some(hash) => {
+                sqlx::query!(
+                    r#"
+INSERT INTO conf(key, value) VALUES ('staged_pac_file', ?)
+    ON CONFLICT(key) DO UPDATE SET value=excluded.value"#,
+                    hash
+                )
+                .execute(conn.as_mut())
+                .await?;
+            }
+            None => {
+                sqlx::query!("DELETE FROM conf WHERE key = 'staged_pac_file'")
+                    .execute(conn.as_mut())
+                    .await?;
+            }
         }
-        let res = storage.all_hosts().await?;
-        assert_eq!(res, test);
         Ok(())
     }
 
-    #[tokio::test]
-    async fn fails_to_add_non_uniq() -> Result<()> {
-        let storage = SqliteStorage::new("sqlite::memory:").await?;
-        let test = vec!["a", "aa"];
-        for s in test.into_iter() {
-            storage.add_host(s).await?;
-        }
-        assert_eq!(
-            storage.add_host("aa").await,
+    async fn promote_staged(&self) -> Result<String, AppError> {
+        let mut tx = self.pool.begin().await?;
+        let staged = sqlx::query!("SELECT value FROM conf WHERE key = 'staged_pac_file';")
+            .fetch_one(tx.as_mut())
+            .await?;
+        sqlx::query!(
+            r#"
+INSERT INTO conf(key, value) VALUES ('latest_pac_file', ?)
+    ON CONFLICT(key) DO UPDATE SET value=excluded.value"#,
+            staged.value
+        )
+        .execute(tx.as_mut())
+        .await?;
+        sqlx::query!("DELETE FROM conf WHERE key = 'staged_pac_file'")
+            .execute(tx.as_mut())
+            .await?;
+        tx.commit().await?;
+        self.mirror_latest(&staged.value).await;
+        Ok(staged.value)
+    }
+
+    async fn add_host(&self, host: &str, patch: HostPatch) -> Result<(), AppError> {
+        let mut tx = self.begin_hosts_write().await?;
+        let now = unix_now();
+        let res = sqlx::query!(
+            r#"
+INSERT INTO white_list(host, created_at, updated_at) VALUES (?, ?, ?)
+    ON CONFLICT(host) DO NOTHING"#,
+            host,
+            now,
+            now
+        )
+        .execute(tx.as_mut())
+        .await?;
+        if res.rows_affected() == 0 {
             Err(AppError::PreconditionFailed(
-                "Host already exists".to_string()
-            ))
+                "Host already exists".to_string(),
+            ))?
+        }
+        if patch != HostPatch::default() {
+            patch_entry(tx.as_mut(), host, patch).await?;
+        }
+        tx.commit().await?;
+        self.changes.send(ChangeEvent::Hosts);
+        Ok(())
+    }
+
+    async fn upsert_host(&self, host: &str, patch: HostPatch) -> Result<bool, AppError> {
+        let mut tx = self.begin_hosts_write().await?;
+        let now = unix_now();
+        let res = sqlx::query!(
+            r#"
+INSERT INTO white_list(host, created_at, updated_at) VALUES (?, ?, ?)
+    ON CONFLICT(host) DO NOTHING"#,
+            host,
+            now,
+            now
+        )
+        .execute(tx.as_mut())
+        .await?;
+        patch_entry(tx.as_mut(), host, patch).await?;
+        tx.commit().await?;
+        self.changes.send(ChangeEvent::Hosts);
+        Ok(res.rows_affected() > 0)
+    }
+
+    async fn remove_host(&self, host: &str) -> Result<(), AppError> {
+        let mut tx = self.begin_hosts_write().await?;
+        let entry = fetch_entry(tx.as_mut(), host).await?;
+        trash_entry(tx.as_mut(), &entry).await?;
+        tx.commit().await?;
+        self.changes.send(ChangeEvent::Hosts);
+        Ok(())
+    }
+
+    async fn add_hosts(
+        &self,
+        hosts: Vec<String>,
+        patch: HostPatch,
+    ) -> Result<Vec<String>, AppError> {
+        let patched = patch != HostPatch::default();
+        let mut tx = self.begin_hosts_write().await?;
+        let now = unix_now();
+        let mut added = vec![];
+        for host in hosts {
+            let res = sqlx::query!(
+                r#"
+INSERT INTO white_list(host, created_at, updated_at) VALUES (?, ?, ?)
+    ON CONFLICT(host) DO NOTHING"#,
+                host,
+                now,
+                now
+            )
+            .execute(tx.as_mut())
+            .await?;
+            if res.rows_affected() > 0 {
+                if patched {
+                    patch_entry(tx.as_mut(), &host, patch.clone()).await?;
+                }
+                added.push(host);
+            }
+        }
+        tx.commit().await?;
+        added.sort();
+        if !added.is_empty() {
+            self.changes.send(ChangeEvent::Hosts);
+        }
+        Ok(added)
+    }
+
+    async fn upsert_hosts(
+        &self,
+        hosts: Vec<String>,
+        patch: HostPatch,
+    ) -> Result<Vec<String>, AppError> {
+        let changed = !hosts.is_empty();
+        let mut tx = self.begin_hosts_write().await?;
+        let now = unix_now();
+        let mut added = vec![];
+        for host in hosts {
+            let res = sqlx::query!(
+                r#"
+INSERT INTO white_list(host, created_at, updated_at) VALUES (?, ?, ?)
+    ON CONFLICT(host) DO NOTHING"#,
+                host,
+                now,
+                now
+            )
+            .execute(tx.as_mut())
+            .await?;
+            patch_entry(tx.as_mut(), &host, patch.clone()).await?;
+            if res.rows_affected() > 0 {
+                added.push(host);
+            }
+        }
+        tx.commit().await?;
+        added.sort();
+        if changed {
+            self.changes.send(ChangeEvent::Hosts);
+        }
+        Ok(added)
+    }
+
+    async fn remove_hosts(&self, hosts: Vec<String>) -> Result<Vec<String>, AppError> {
+        let mut tx = self.begin_hosts_write().await?;
+        let mut removed = vec![];
+        for host in hosts {
+            let entry = match fetch_entry(tx.as_mut(), &host).await {
+                Ok(entry) => entry,
+                Err(AppError::NotFound) => continue,
+                Err(e) => Err(e)?,
+            };
+            if !entry.pinned {
+                trash_entry(tx.as_mut(), &entry).await?;
+                removed.push(host);
+            }
+        }
+        tx.commit().await?;
+        removed.sort();
+        if !removed.is_empty() {
+            self.changes.send(ChangeEvent::Hosts);
+        }
+        Ok(removed)
+    }
+
+    async fn deleted_hosts(&self) -> Result<Vec<DeletedHost>, AppError> {
+        let mut conn = self.acquire().await?;
+        sqlx::query!("SELECT entry, deleted_at FROM deleted_hosts ORDER BY host;")
+            .fetch_all(conn.as_mut())
+            .await?
+            .into_iter()
+            .map(|r| {
+                Ok(DeletedHost {
+                    entry: serde_json::from_str(&r.entry)
+                        .map_err(|e| AppError::Other(e.to_string()))?,
+                    deleted_at: r.deleted_at,
+                })
+            })
+            .collect()
+    }
+
+    async fn restore_host(&self, host: &str) -> Result<HostEntry, AppError> {
+        let mut tx = self.begin_hosts_write().await?;
+        let deleted = sqlx::query!("SELECT entry FROM deleted_hosts WHERE host = ?;", host)
+            .fetch_one(tx.as_mut())
+            .await?;
+        let listed = sqlx::query!("SELECT host FROM white_list WHERE host = ?;", host)
+            .fetch_optional(tx.as_mut())
+            .await?;
+        if listed.is_some() {
+            Err(AppError::Conflict("Host already exists".to_string()))?
+        }
+        let mut entry: HostEntry =
+            serde_json::from_str(&deleted.entry).map_err(|e| AppError::Other(e.to_string()))?;
+        entry.updated_at = unix_now();
+        insert_entry(tx.as_mut(), &entry).await?;
+        sqlx::query!("DELETE FROM deleted_hosts WHERE host = ?", host)
+            .execute(tx.as_mut())
+            .await?;
+        tx.commit().await?;
+        self.changes.send(ChangeEvent::Hosts);
+        Ok(entry)
+    }
+
+    async fn purge_deleted(&self, before: i64) -> Result<u64, AppError> {
+        let mut conn = self.acquire().await?;
+        let res = sqlx::query!("DELETE FROM deleted_hosts WHERE deleted_at < ?", before)
+            .execute(conn.as_mut())
+            .await?;
+        Ok(res.rows_affected())
+    }
+
+    async fn remove_expired(&self, now: i64) -> Result<Vec<String>, AppError> {
+        let mut tx = self.begin_hosts_write().await?;
+        let hosts = sqlx::query_scalar!(
+            "SELECT host FROM white_list WHERE expires_at <= ? AND pinned = 0 ORDER BY host;",
+            now
+        )
+        .fetch_all(tx.as_mut())
+        .await?;
+        for host in hosts.iter() {
+            let entry = fetch_entry(tx.as_mut(), host).await?;
+            trash_entry(tx.as_mut(), &entry).await?;
+        }
+        tx.commit().await?;
+        if !hosts.is_empty() {
+            self.changes.send(ChangeEvent::Hosts);
+        }
+        Ok(hosts)
+    }
+
+    async fn set_pinned(&self, host: &str, pinned: bool) -> Result<(), AppError> {
+        let patch = HostPatch {
+            pinned: Some(pinned),
+            ..Default::default()
+        };
+        self.update_host(host, patch).await.map(|_| ())
+    }
+
+    async fn import_hosts(
+        &self,
+        hosts: Vec<String>,
+        mode: ImportMode,
+        dry_run: bool,
+    ) -> Result<HostsDiff, AppError> {
+        let mut tx = self.begin_hosts_write().await?;
+        let current =
+            sqlx::query!(r#"SELECT host, pinned as "pinned: bool" FROM white_list ORDER BY host;"#)
+                .fetch_all(tx.as_mut())
+                .await?;
+        let wanted: BTreeSet<String> = hosts.into_iter().collect();
+        let existing: BTreeSet<&str> = current.iter().map(|r| r.host.as_str()).collect();
+
+        let diff = HostsDiff {
+            added: wanted
+                .iter()
+                .filter(|h| !existing.contains(h.as_str()))
+                .cloned()
+                .collect(),
+            removed: match mode {
+                ImportMode::Merge => vec![],
+                ImportMode::Mirror => current
+                    .iter()
+                    .filter(|r| !wanted.contains(&r.host) && !r.pinned)
+                    .map(|r| r.host.clone())
+                    .collect(),
+            },
+        };
+        if dry_run {
+            return Ok(diff);
+        }
+
+        let now = unix_now();
+        for host in diff.added.iter() {
+            sqlx::query!(
+                "INSERT INTO white_list(host, created_at, updated_at) VALUES (?, ?, ?)",
+                host,
+                now,
+                now
+            )
+            .execute(tx.as_mut())
+            .await?;
+        }
+        for host in diff.removed.iter() {
+            sqlx::query!("DELETE FROM white_list WHERE host = ?", host)
+                .execute(tx.as_mut())
+                .await?;
+        }
+        tx.commit().await?;
+        if !diff.added.is_empty() || !diff.removed.is_empty() {
+            self.changes.send(ChangeEvent::Hosts);
+        }
+        Ok(diff)
+    }
+
+    async fn pinned_hosts(&self) -> Result<Vec<String>, AppError> {
+        let mut conn = self.acquire().await?;
+        let res = sqlx::query!("SELECT host FROM white_list WHERE pinned = 1 ORDER BY host;")
+            .fetch_all(conn.as_mut())
+            .await?
+            .into_iter()
+            .map(|r| r.host)
+            .collect();
+        Ok(res)
+    }
+
+    async fn set_tags(&self, host: &str, tags: Vec<String>) -> Result<(), AppError> {
+        let patch = HostPatch {
+            tags: Some(tags),
+            ..Default::default()
+        };
+        self.update_host(host, patch).await.map(|_| ())
+    }
+
+    async fn hosts_by_tag(&self, tag: &str) -> Result<Vec<String>, AppError> {
+        let mut conn = self.acquire().await?;
+        let res = sqlx::query!(
+            "SELECT host FROM host_tags WHERE tag = ? ORDER BY host;",
+            tag
+        )
+        .fetch_all(conn.as_mut())
+        .await?
+        .into_iter()
+        .map(|r| r.host)
+        .collect();
+        Ok(res)
+    }
+
+    async fn remove_hosts_by_tag(&self, tag: &str) -> Result<Vec<String>, AppError> {
+        let mut tx = self.begin_hosts_write().await?;
+        let removed: Vec<String> = sqlx::query!(
+            r#"
+SELECT w.host FROM white_list w JOIN host_tags t ON t.host = w.host
+    WHERE t.tag = ? AND w.pinned = 0 ORDER BY w.host;"#,
+            tag
+        )
+        .fetch_all(tx.as_mut())
+        .await?
+        .into_iter()
+        .map(|r| r.host)
+        .collect();
+        for host in removed.iter() {
+            let entry = fetch_entry(tx.as_mut(), host).await?;
+            trash_entry(tx.as_mut(), &entry).await?;
+        }
+        tx.commit().await?;
+        if !removed.is_empty() {
+            self.changes.send(ChangeEvent::Hosts);
+        }
+        Ok(removed)
+    }
+
+    async fn list_tags(&self) -> Result<Vec<TagInfo>, AppError> {
+        let mut conn = self.acquire().await?;
+        let res = sqlx::query!(
+            r#"SELECT tag, COUNT(*) AS "hosts!: i64" FROM host_tags GROUP BY tag ORDER BY tag;"#
+        )
+        .fetch_all(conn.as_mut())
+        .await?
+        .into_iter()
+        .map(|r| TagInfo {
+            name: r.tag,
+            hosts: r.hosts as usize,
+        })
+        .collect();
+        Ok(res)
+    }
+
+    async fn rename_tag(&self, tag: &str, to: &str) -> Result<(), AppError> {
+        let mut tx = self.begin_hosts_write().await?;
+        let tagged = sqlx::query!("SELECT COUNT(*) AS hosts FROM host_tags WHERE tag = ?", tag)
+            .fetch_one(tx.as_mut())
+            .await?;
+        if tagged.hosts == 0 {
+            Err(AppError::NotFound)?
+        }
+        if tag != to {
+            sqlx::query!(
+                r#"
+INSERT INTO host_tags(host, tag) SELECT host, ? FROM host_tags WHERE tag = ?
+    ON CONFLICT DO NOTHING"#,
+                to,
+                tag
+            )
+            .execute(tx.as_mut())
+            .await?;
+            sqlx::query!("DELETE FROM host_tags WHERE tag = ?", tag)
+                .execute(tx.as_mut())
+                .await?;
+            // Profiles listing the tag would otherwise lose its hosts
+            for profile in fetch_profiles(tx.as_mut()).await? {
+                let Some(tags) = renamed_tag(&profile.tags, tag, to) else {
+                    continue;
+                };
+                let tags = tags.join(",");
+                sqlx::query!(
+                    "UPDATE profiles SET tags = ? WHERE name = ?;",
+                    tags,
+                    profile.name
+                )
+                .execute(tx.as_mut())
+                .await?;
+            }
+        }
+        tx.commit().await?;
+        self.changes.send(ChangeEvent::Hosts);
+        Ok(())
+    }
+
+    async fn delete_tag(&self, tag: &str) -> Result<(), AppError> {
+        let mut tx = self.begin_hosts_write().await?;
+        let res = sqlx::query!("DELETE FROM host_tags WHERE tag = ?", tag)
+            .execute(tx.as_mut())
+            .await?;
+        if res.rows_affected() == 0 {
+            Err(AppError::NotFound)?
+        }
+        tx.commit().await?;
+        self.changes.send(ChangeEvent::Hosts);
+        Ok(())
+    }
+
+    async fn create_snapshot(&self, name: &str) -> Result<SnapshotInfo, AppError> {
+        let mut tx = self.pool.begin().await?;
+        let entries = fetch_entries(tx.as_mut()).await?;
+        let hosts = serde_json::to_string(&entries).map_err(|e| AppError::Other(e.to_string()))?;
+        let created_at = unix_now();
+        sqlx::query!(
+            "INSERT INTO snapshots(name, created_at, hosts) VALUES (?, ?, ?)",
+            name,
+            created_at,
+            hosts
+        )
+        .execute(tx.as_mut())
+        .await?;
+        tx.commit().await?;
+        Ok(SnapshotInfo {
+            name: name.to_string(),
+            created_at,
+            hosts: entries.len(),
+        })
+    }
+
+    async fn list_snapshots(&self) -> Result<Vec<SnapshotInfo>, AppError> {
+        let mut conn = self.acquire().await?;
+        let res = sqlx::query!(
+            r#"
+SELECT name, created_at, json_array_length(hosts) as "hosts!: i64"
+    FROM snapshots ORDER BY name;"#
+        )
+        .fetch_all(conn.as_mut())
+        .await?
+        .into_iter()
+        .map(|r| SnapshotInfo {
+            name: r.name,
+            created_at: r.created_at,
+            hosts: r.hosts as usize,
+        })
+        .collect();
+        Ok(res)
+    }
+
+    async fn restore_snapshot(&self, name: &str) -> Result<HostsDiff, AppError> {
+        let mut tx = self.begin_hosts_write().await?;
+        let snapshot = sqlx::query!("SELECT hosts FROM snapshots WHERE name = ?;", name)
+            .fetch_one(tx.as_mut())
+            .await?;
+        let entries: Vec<HostEntry> =
+            serde_json::from_str(&snapshot.hosts).map_err(|e| AppError::Other(e.to_string()))?;
+        let current: Vec<String> = sqlx::query!("SELECT host FROM white_list ORDER BY host;")
+            .fetch_all(tx.as_mut())
+            .await?
+            .into_iter()
+            .map(|r| r.host)
+            .collect();
+        let wanted: Vec<String> = entries.iter().map(|e| e.host.clone()).collect();
+
+        replace_entries(tx.as_mut(), &entries).await?;
+        tx.commit().await?;
+        self.changes.send(ChangeEvent::Hosts);
+        Ok(HostsDiff::between(&current, &wanted))
+    }
+
+    async fn delete_snapshot(&self, name: &str) -> Result<(), AppError> {
+        let mut conn = self.acquire().await?;
+        let res = sqlx::query!("DELETE FROM snapshots WHERE name = ?", name)
+            .execute(conn.as_mut())
+            .await?;
+        if res.rows_affected() == 0 {
+            Err(AppError::NotFound)?
+        }
+        Ok(())
+    }
+
+    async fn set_profile(&self, profile: Profile) -> Result<(), AppError> {
+        let mut conn = self.acquire().await?;
+        let tags = profile.tags.join(",");
+        sqlx::query!(
+            r#"
+INSERT INTO profiles(name, proxy, tags) VALUES (?, ?, ?)
+    ON CONFLICT(name) DO UPDATE SET proxy=excluded.proxy, tags=excluded.tags"#,
+            profile.name,
+            profile.proxy,
+            tags
+        )
+        .execute(conn.as_mut())
+        .await?;
+        self.changes.send(ChangeEvent::Config);
+        Ok(())
+    }
+
+    async fn get_profile(&self, name: &str) -> Result<Profile, AppError> {
+        let mut conn = self.acquire().await?;
+        let res = sqlx::query!(
+            "SELECT name, proxy, tags FROM profiles WHERE name = ?;",
+            name
+        )
+        .fetch_one(conn.as_mut())
+        .await?;
+        Ok(Profile {
+            name: res.name,
+            proxy: res.proxy,
+            tags: split_tags(&res.tags),
+        })
+    }
+
+    async fn list_profiles(&self) -> Result<Vec<Profile>, AppError> {
+        let mut conn = self.acquire().await?;
+        fetch_profiles(conn.as_mut()).await
+    }
+
+    async fn remove_profile(&self, name: &str) -> Result<(), AppError> {
+        let mut conn = self.acquire().await?;
+        let res = sqlx::query!("DELETE FROM profiles WHERE name = ?", name)
+            .execute(conn.as_mut())
+            .await?;
+        if res.rows_affected() == 0 {
+            Err(AppError::NotFound)?
+        }
+        self.changes.send(ChangeEvent::Config);
+        Ok(())
+    }
+
+    async fn set_group(&self, group: ProxyGroup) -> Result<(), AppError> {
+        let mut conn = self.acquire().await?;
+        let schedule = group.schedule.map(|s| s.to_string());
+        sqlx::query!(
+            r#"
+INSERT INTO proxy_groups(name, proxy, schedule) VALUES (?, ?, ?)
+    ON CONFLICT(name) DO UPDATE SET proxy=excluded.proxy, schedule=excluded.schedule"#,
+            group.name,
+            group.proxy,
+            schedule
+        )
+        .execute(conn.as_mut())
+        .await?;
+        self.changes.send(ChangeEvent::Config);
+        Ok(())
+    }
+
+    async fn list_groups(&self) -> Result<Vec<ProxyGroup>, AppError> {
+        let mut conn = self.acquire().await?;
+        fetch_groups(conn.as_mut()).await
+    }
+
+    async fn remove_group(&self, name: &str) -> Result<(), AppError> {
+        let mut tx = self.begin_hosts_write().await?;
+        let res = sqlx::query!("DELETE FROM proxy_groups WHERE name = ?", name)
+            .execute(tx.as_mut())
+            .await?;
+        if res.rows_affected() == 0 {
+            Err(AppError::NotFound)?
+        }
+        sqlx::query!(
+            "UPDATE white_list SET proxy_group = NULL WHERE proxy_group = ?",
+            name
+        )
+        .execute(tx.as_mut())
+        .await?;
+        tx.commit().await?;
+        self.changes.send(ChangeEvent::Config);
+        Ok(())
+    }
+
+    async fn list_ip_ranges(&self) -> Result<Vec<String>, AppError> {
+        let mut conn = self.acquire().await?;
+        fetch_ip_ranges(conn.as_mut()).await
+    }
+
+    async fn set_ip_ranges(&self, ranges: Vec<String>) -> Result<(), AppError> {
+        let mut tx = self.pool.begin().await?;
+        replace_ip_ranges(tx.as_mut(), &ranges).await?;
+        tx.commit().await?;
+        self.changes.send(ChangeEvent::Config);
+        Ok(())
+    }
+
+    async fn set_network(&self, network: NetworkProfile) -> Result<(), AppError> {
+        let mut conn = self.acquire().await?;
+        sqlx::query!(
+            r#"
+INSERT INTO networks(name, network, proxy) VALUES (?, ?, ?)
+    ON CONFLICT(name) DO UPDATE SET network=excluded.network, proxy=excluded.proxy"#,
+            network.name,
+            network.network,
+            network.proxy
+        )
+        .execute(conn.as_mut())
+        .await?;
+        self.changes.send(ChangeEvent::Config);
+        Ok(())
+    }
+
+    async fn list_networks(&self) -> Result<Vec<NetworkProfile>, AppError> {
+        let mut conn = self.acquire().await?;
+        fetch_networks(conn.as_mut()).await
+    }
+
+    async fn remove_network(&self, name: &str) -> Result<(), AppError> {
+        let mut conn = self.acquire().await?;
+        let res = sqlx::query!("DELETE FROM networks WHERE name = ?", name)
+            .execute(conn.as_mut())
+            .await?;
+        if res.rows_affected() == 0 {
+            Err(AppError::NotFound)?
+        }
+        self.changes.send(ChangeEvent::Config);
+        Ok(())
+    }
+
+    async fn hosts_version(&self) -> Result<i64, AppError> {
+        let mut conn = self.acquire().await?;
+        let res = sqlx::query!("SELECT value FROM conf WHERE key = 'hosts_version';")
+            .fetch_optional(conn.as_mut())
+            .await?;
+        Ok(res.and_then(|r| r.value.parse().ok()).unwrap_or_default())
+    }
+
+    async fn config_version(&self) -> Result<i64, AppError> {
+        let mut conn = self.acquire().await?;
+        let res = sqlx::query!("SELECT value FROM conf WHERE key = 'config_version';")
+            .fetch_optional(conn.as_mut())
+            .await?;
+        Ok(res.and_then(|r| r.value.parse().ok()).unwrap_or_default())
+    }
+
+    async fn get_proxy(&self) -> Result<Option<String>, AppError> {
+        let mut conn = self.acquire().await?;
+        let res = sqlx::query!("SELECT value FROM conf WHERE key = 'proxy';")
+            .fetch_optional(conn.as_mut())
+            .await?;
+        Ok(res.map(|r| r.value))
+    }
+
+    async fn set_proxy(&self, proxy: &str) -> Result<(), AppError> {
+        let mut conn = self.acquire().await?;
+        sqlx::query!(
+            r#"
+INSERT INTO conf(key, value) VALUES ('proxy', ?)
+    ON CONFLICT(key) DO UPDATE SET value=excluded.value"#,
+            proxy
+        )
+        .execute(conn.as_mut())
+        .await?;
+        self.changes.send(ChangeEvent::Config);
+        Ok(())
+    }
+
+    async fn get_mode(&self) -> Result<PacMode, AppError> {
+        let mut conn = self.acquire().await?;
+        fetch_mode(conn.as_mut()).await
+    }
+
+    async fn set_mode(&self, mode: PacMode) -> Result<(), AppError> {
+        let mut conn = self.acquire().await?;
+        store_mode(conn.as_mut(), mode).await?;
+        self.changes.send(ChangeEvent::Config);
+        Ok(())
+    }
+
+    async fn get_bypass_private(&self) -> Result<bool, AppError> {
+        let mut conn = self.acquire().await?;
+        fetch_bypass_private(conn.as_mut()).await
+    }
+
+    async fn set_bypass_private(&self, enabled: bool) -> Result<(), AppError> {
+        let mut conn = self.acquire().await?;
+        store_bypass_private(conn.as_mut(), enabled).await?;
+        self.changes.send(ChangeEvent::Config);
+        Ok(())
+    }
+
+    async fn list_upstreams(&self) -> Result<Vec<Upstream>, AppError> {
+        let mut conn = self.acquire().await?;
+        fetch_upstreams(conn.as_mut()).await
+    }
+
+    async fn set_upstreams(&self, upstreams: Vec<Upstream>) -> Result<(), AppError> {
+        let mut tx = self.pool.begin().await?;
+        replace_upstreams(tx.as_mut(), &upstreams).await?;
+        tx.commit().await?;
+        self.changes.send(ChangeEvent::Config);
+        Ok(())
+    }
+
+    async fn list_exclusions(&self) -> Result<Vec<String>, AppError> {
+        let mut conn = self.acquire().await?;
+        fetch_exclusions(conn.as_mut()).await
+    }
+
+    async fn add_exclusions(&self, hosts: Vec<String>) -> Result<Vec<String>, AppError> {
+        let mut tx = self.begin_hosts_write().await?;
+        let added = insert_exclusions(tx.as_mut(), &hosts).await?;
+        tx.commit().await?;
+        if !added.is_empty() {
+            self.changes.send(ChangeEvent::Hosts);
+        }
+        Ok(added)
+    }
+
+    async fn remove_exclusion(&self, host: &str) -> Result<(), AppError> {
+        let mut tx = self.begin_hosts_write().await?;
+        let res = sqlx::query!("DELETE FROM exclusions WHERE host = ?", host)
+            .execute(tx.as_mut())
+            .await?;
+        if res.rows_affected() == 0 {
+            Err(AppError::NotFound)?
+        }
+        tx.commit().await?;
+        self.changes.send(ChangeEvent::Hosts);
+        Ok(())
+    }
+
+    async fn list_blocklist(&self) -> Result<Vec<BlocklistEntry>, AppError> {
+        let mut conn = self.acquire().await?;
+        fetch_blocklist(conn.as_mut()).await
+    }
+
+    async fn set_blocklisted(&self, entry: BlocklistEntry) -> Result<(), AppError> {
+        let mut tx = self.begin_hosts_write().await?;
+        let entry = BlocklistEntry {
+            created_at: unix_now(),
+            ..entry
+        };
+        upsert_blocklisted(tx.as_mut(), &entry).await?;
+        tx.commit().await?;
+        self.changes.send(ChangeEvent::Hosts);
+        Ok(())
+    }
+
+    async fn remove_blocklisted(&self, host: &str) -> Result<(), AppError> {
+        let mut tx = self.begin_hosts_write().await?;
+        let res = sqlx::query!("DELETE FROM blocklist WHERE host = ?", host)
+            .execute(tx.as_mut())
+            .await?;
+        if res.rows_affected() == 0 {
+            Err(AppError::NotFound)?
+        }
+        tx.commit().await?;
+        self.changes.send(ChangeEvent::Hosts);
+        Ok(())
+    }
+
+    async fn record_client_fetches(&self, fetches: Vec<ClientFetches>) -> Result<(), AppError> {
+        let mut tx = self.pool.begin().await?;
+        for f in fetches.iter() {
+            let profile = f.profile.as_deref().unwrap_or_default();
+            sqlx::query!(
+                r#"
+INSERT INTO client_fetches(day, client, profile, fetches) VALUES (?, ?, ?, ?)
+    ON CONFLICT(day, client, profile) DO UPDATE SET fetches=fetches + excluded.fetches"#,
+                f.day,
+                f.client,
+                profile,
+                f.fetches
+            )
+            .execute(tx.as_mut())
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn client_fetches(&self, since: i64) -> Result<Vec<ClientFetches>, AppError> {
+        let mut conn = self.acquire().await?;
+        let res = sqlx::query!(
+            r#"
+SELECT day, client, profile, fetches FROM client_fetches
+    WHERE day >= ?
+    ORDER BY day, client, profile"#,
+            since
+        )
+        .fetch_all(conn.as_mut())
+        .await?;
+        Ok(res
+            .into_iter()
+            .map(|r| ClientFetches {
+                day: r.day,
+                client: r.client,
+                profile: (!r.profile.is_empty()).then_some(r.profile),
+                fetches: r.fetches,
+            })
+            .collect())
+    }
+
+    async fn prune_client_fetches(&self, before: i64) -> Result<u64, AppError> {
+        let mut conn = self.acquire().await?;
+        let res = sqlx::query!("DELETE FROM client_fetches WHERE day < ?", before)
+            .execute(conn.as_mut())
+            .await?;
+        Ok(res.rows_affected())
+    }
+
+    async fn record_audit(&self, entry: AuditEntry) -> Result<(), AppError> {
+        let mut conn = self.acquire().await?;
+        let payload = entry.payload.to_string();
+        sqlx::query!(
+            "INSERT INTO audit_log(at, actor, ip, action, payload) VALUES (?, ?, ?, ?, ?)",
+            entry.at,
+            entry.actor,
+            entry.ip,
+            entry.action,
+            payload
+        )
+        .execute(conn.as_mut())
+        .await?;
+        Ok(())
+    }
+
+    async fn audit_log(&self, since: i64, limit: u32) -> Result<Vec<AuditEntry>, AppError> {
+        let mut conn = self.acquire().await?;
+        let res = sqlx::query!(
+            r#"
+SELECT at, actor, ip, action, payload FROM audit_log
+    WHERE at >= ?
+    ORDER BY id DESC
+    LIMIT ?"#,
+            since,
+            limit
+        )
+        .fetch_all(conn.as_mut())
+        .await?;
+        res.into_iter()
+            .map(|r| {
+                Ok(AuditEntry {
+                    at: r.at,
+                    actor: r.actor,
+                    ip: r.ip,
+                    action: r.action,
+                    payload: serde_json::from_str(&r.payload)
+                        .map_err(|e| AppError::Other(e.to_string()))?,
+                })
+            })
+            .collect()
+    }
+
+    async fn prune_audit(&self, before: i64) -> Result<u64, AppError> {
+        let mut conn = self.acquire().await?;
+        let res = sqlx::query!("DELETE FROM audit_log WHERE at < ?", before)
+            .execute(conn.as_mut())
+            .await?;
+        Ok(res.rows_affected())
+    }
+
+    async fn try_lease(&self, name: &str, owner: &str, ttl: Duration) -> Result<bool, AppError> {
+        let mut conn = self.acquire().await?;
+        let key = format!("lease:{}", name);
+        let now = unix_now();
+        let value = serde_json::to_string(&json!({
+            "owner": owner,
+            "expires_at": now + ttl.as_secs() as i64,
+        }))
+        .map_err(|e| AppError::Other(e.to_string()))?;
+        let res = sqlx::query!(
+            r#"
+INSERT INTO conf(key, value) VALUES (?, ?)
+    ON CONFLICT(key) DO UPDATE SET value=excluded.value
+    WHERE json_extract(conf.value, '$.owner') = ?
+        OR json_extract(conf.value, '$.expires_at') <= ?"#,
+            key,
+            value,
+            owner,
+            now
+        )
+        .execute(conn.as_mut())
+        .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    async fn request_regeneration(&self) -> Result<(), AppError> {
+        let mut conn = self.acquire().await?;
+        sqlx::query!(
+            r#"
+INSERT INTO conf(key, value) VALUES ('regeneration_requests', '1')
+    ON CONFLICT(key) DO UPDATE SET value = CAST(CAST(value AS INTEGER) + 1 AS TEXT)"#
+        )
+        .execute(conn.as_mut())
+        .await?;
+        Ok(())
+    }
+
+    async fn regeneration_requests(&self) -> Result<i64, AppError> {
+        let mut conn = self.acquire().await?;
+        let res = sqlx::query!("SELECT value FROM conf WHERE key = 'regeneration_requests';")
+            .fetch_optional(conn.as_mut())
+            .await?;
+        Ok(res.and_then(|r| r.value.parse().ok()).unwrap_or_default())
+    }
+
+    async fn storage_stats(&self) -> Result<StorageStats, AppError> {
+        let mut conn = self.acquire().await?;
+        let counts = sqlx::query!(
+            r#"
+SELECT (SELECT COUNT(*) FROM white_list) as "hosts!: i64",
+    (SELECT COUNT(*) FROM deleted_hosts) as "deleted_hosts!: i64",
+    (SELECT COUNT(*) FROM pac WHERE version IS NOT NULL) as "files!: i64",
+    (SELECT COUNT(*) FROM snapshots) as "snapshots!: i64",
+    (SELECT COUNT(*) FROM audit_log) as "audit_entries!: i64",
+    (SELECT size FROM pac WHERE hash =
+        (SELECT value FROM conf WHERE key = 'latest_pac_file')) as "latest_pac_bytes: i64";"#
+        )
+        .fetch_one(conn.as_mut())
+        .await?;
+        let page_count: i64 = sqlx::query_scalar("PRAGMA page_count;")
+            .fetch_one(conn.as_mut())
+            .await?;
+        let page_size: i64 = sqlx::query_scalar("PRAGMA page_size;")
+            .fetch_one(conn.as_mut())
+            .await?;
+        // Empty for in-memory databases, which have no log
+        let file: String =
+            sqlx::query_scalar("SELECT file FROM pragma_database_list WHERE name = 'main';")
+                .fetch_one(conn.as_mut())
+                .await?;
+        let wal_bytes = match file.as_str() {
+            "" => None,
+            file => Some(match tokio::fs::metadata(format!("{file}-wal")).await {
+                Ok(meta) => meta.len(),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+                Err(e) => Err(AppError::Other(format!("{file}-wal: {e}")))?,
+            }),
+        };
+        Ok(StorageStats {
+            hosts: counts.hosts as u64,
+            deleted_hosts: counts.deleted_hosts as u64,
+            files: counts.files as u64,
+            snapshots: counts.snapshots as u64,
+            audit_entries: counts.audit_entries as u64,
+            latest_pac_bytes: counts.latest_pac_bytes.map(|b| b as u64),
+            db_bytes: Some((page_count * page_size) as u64),
+            wal_bytes,
+        })
+    }
+
+    async fn export_state(&self) -> Result<InstanceState, AppError> {
+        let mut tx = self.pool.begin().await?;
+        let hosts = fetch_entries(tx.as_mut()).await?;
+        let mut snapshots = vec![];
+        for r in sqlx::query!("SELECT name, created_at, hosts FROM snapshots ORDER BY name;")
+            .fetch_all(tx.as_mut())
+            .await?
+        {
+            snapshots.push(Snapshot {
+                name: r.name,
+                created_at: r.created_at,
+                hosts: serde_json::from_str(&r.hosts)
+                    .map_err(|e| AppError::Other(e.to_string()))?,
+            });
+        }
+        let profiles = fetch_profiles(tx.as_mut()).await?;
+        let proxy = sqlx::query!("SELECT value FROM conf WHERE key = 'proxy';")
+            .fetch_optional(tx.as_mut())
+            .await?
+            .map(|r| r.value);
+        let groups = fetch_groups(tx.as_mut()).await?;
+        let upstreams = fetch_upstreams(tx.as_mut()).await?;
+        let mode = fetch_mode(tx.as_mut()).await?;
+        let exclusions = fetch_exclusions(tx.as_mut()).await?;
+        let bypass_private = fetch_bypass_private(tx.as_mut()).await?;
+        let networks = fetch_networks(tx.as_mut()).await?;
+        let ip_ranges = fetch_ip_ranges(tx.as_mut()).await?;
+        let blocklist = fetch_blocklist(tx.as_mut()).await?;
+        tx.commit().await?;
+        Ok(InstanceState {
+            version: STATE_VERSION,
+            hosts,
+            snapshots,
+            profiles,
+            proxy,
+            groups,
+            upstreams,
+            mode,
+            exclusions,
+            bypass_private,
+            networks,
+            ip_ranges,
+            blocklist,
+        })
+    }
+
+    async fn import_state(&self, state: InstanceState) -> Result<(), AppError> {
+        check_state_version(&state)?;
+        let mut tx = self.begin_hosts_write().await?;
+        replace_entries(tx.as_mut(), &state.hosts).await?;
+        sqlx::query!("DELETE FROM snapshots")
+            .execute(tx.as_mut())
+            .await?;
+        for snapshot in state.snapshots.iter() {
+            let hosts = serde_json::to_string(&snapshot.hosts)
+                .map_err(|e| AppError::Other(e.to_string()))?;
+            sqlx::query!(
+                "INSERT INTO snapshots(name, created_at, hosts) VALUES (?, ?, ?)",
+                snapshot.name,
+                snapshot.created_at,
+                hosts
+            )
+            .execute(tx.as_mut())
+            .await?;
+        }
+        sqlx::query!("DELETE FROM profiles")
+            .execute(tx.as_mut())
+            .await?;
+        for profile in state.profiles.iter() {
+            let tags = profile.tags.join(",");
+            sqlx::query!(
+                "INSERT INTO profiles(name, proxy, tags) VALUES (?, ?, ?)",
+                profile.name,
+                profile.proxy,
+                tags
+            )
+            .execute(tx.as_mut())
+            .await?;
+        }
+        sqlx::query!("DELETE FROM proxy_groups")
+            .execute(tx.as_mut())
+            .await?;
+        for group in state.groups.iter() {
+            let schedule = group.schedule.map(|s| s.to_string());
+            sqlx::query!(
+                "INSERT INTO proxy_groups(name, proxy, schedule) VALUES (?, ?, ?)",
+                group.name,
+                group.proxy,
+                schedule
+            )
+            .execute(tx.as_mut())
+            .await?;
+        }
+        replace_upstreams(tx.as_mut(), &state.upstreams).await?;
+        store_mode(tx.as_mut(), state.mode).await?;
+        sqlx::query!("DELETE FROM exclusions")
+            .execute(tx.as_mut())
+            .await?;
+        insert_exclusions(tx.as_mut(), &state.exclusions).await?;
+        store_bypass_private(tx.as_mut(), state.bypass_private).await?;
+        sqlx::query!("DELETE FROM networks")
+            .execute(tx.as_mut())
+            .await?;
+        for network in state.networks.iter() {
+            sqlx::query!(
+                "INSERT INTO networks(name, network, proxy) VALUES (?, ?, ?)",
+                network.name,
+                network.network,
+                network.proxy
+            )
+            .execute(tx.as_mut())
+            .await?;
+        }
+        replace_ip_ranges(tx.as_mut(), &state.ip_ranges).await?;
+        sqlx::query!("DELETE FROM blocklist")
+            .execute(tx.as_mut())
+            .await?;
+        for entry in state.blocklist.iter() {
+            upsert_blocklisted(tx.as_mut(), entry).await?;
+        }
+        match &state.proxy {
+            Some(proxy) => {
+                sqlx::query!(
+                    r#"
+INSERT INTO conf(key, value) VALUES ('proxy', ?)
+    ON CONFLICT(key) DO UPDATE SET value=excluded.value"#,
+                    proxy
+                )
+                .execute(tx.as_mut())
+                .await?;
+            }
+            None => {
+                sqlx::query!("DELETE FROM conf WHERE key = 'proxy'")
+                    .execute(tx.as_mut())
+                    .await?;
+            }
+        }
+        tx.commit().await?;
+        self.changes.send(ChangeEvent::Hosts);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{error::Result, storage::tests::conformance};
+
+    conformance!(SqliteStorage, SqliteStorage::new("sqlite::memory:").await?);
+
+    #[tokio::test]
+    async fn mirrors_files_to_bucket() -> Result<()> {
+        use std::sync::Arc;
+
+        use object_store::{memory::InMemory, path::Path, ObjectStore};
+
+        let store = Arc::new(InMemory::new());
+        let storage = SqliteStorage::new("sqlite::memory:")
+            .await?
+            .with_bucket(PacBucket::new(store.clone(), ""));
+        let first = Pac::generate(vec!["a".to_string()]);
+        let second = Pac::generate(vec!["b".to_string()]);
+        for pac in [&first, &second] {
+            storage.upload_file(pac).await?;
+        }
+        let object = |key: String| {
+            let store = store.clone();
+            async move { store.get(&Path::from(key)).await?.bytes().await }
+        };
+        assert_eq!(object(format!("{}.pac", first.hash)).await?, first.file);
+        assert!(object("latest.pac".to_string()).await.is_err());
+
+        storage.set_latest(&first.hash).await?;
+        assert_eq!(object("latest.pac".to_string()).await?, first.file);
+        storage.set_staged_hash(Some(second.hash.clone())).await?;
+        storage.promote_staged().await?;
+        assert_eq!(object("latest.pac".to_string()).await?, second.file);
+
+        assert_eq!(storage.prune_files(1, i64::MAX).await?, 1);
+        assert!(object(format!("{}.pac", first.hash)).await.is_err());
+        assert_eq!(object(format!("{}.pac", second.hash)).await?, second.file);
+        Ok(())
+    }
+
+    /// In-memory bucket failing every write while `down` is set
+    #[derive(Debug, Default)]
+    struct FlakyStore {
+        inner: object_store::memory::InMemory,
+        down: std::sync::atomic::AtomicBool,
+    }
+
+    impl FlakyStore {
+        fn check(&self) -> object_store::Result<()> {
+            if self.down.load(std::sync::atomic::Ordering::SeqCst) {
+                return Err(object_store::Error::NotImplemented);
+            }
+            Ok(())
+        }
+    }
+
+    impl std::fmt::Display for FlakyStore {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "FlakyStore")
+        }
+    }
+
+    #[async_trait]
+    impl object_store::ObjectStore for FlakyStore {
+        async fn put_opts(
+            &self,
+            location: &object_store::path::Path,
+            payload: object_store::PutPayload,
+            opts: object_store::PutOptions,
+        ) -> object_store::Result<object_store::PutResult> {
+            self.check()?;
+            self.inner.put_opts(location, payload, opts).await
+        }
+
+        async fn put_multipart_opts(
+            &self,
+            location: &object_store::path::Path,
+            opts: object_store::PutMultipartOpts,
+        ) -> object_store::Result<Box<dyn object_store::MultipartUpload>> {
+            self.check()?;
+            self.inner.put_multipart_opts(location, opts).await
+        }
+
+        async fn get_opts(
+            &self,
+            location: &object_store::path::Path,
+            options: object_store::GetOptions,
+        ) -> object_store::Result<object_store::GetResult> {
+            self.inner.get_opts(location, options).await
+        }
+
+        async fn delete(&self, location: &object_store::path::Path) -> object_store::Result<()> {
+            self.check()?;
+            self.inner.delete(location).await
+        }
+
+        fn list(
+            &self,
+            prefix: Option<&object_store::path::Path>,
+        ) -> BoxStream<'_, object_store::Result<object_store::ObjectMeta>> {
+            self.inner.list(prefix)
+        }
+
+        async fn list_with_delimiter(
+            &self,
+            prefix: Option<&object_store::path::Path>,
+        ) -> object_store::Result<object_store::ListResult> {
+            self.inner.list_with_delimiter(prefix).await
+        }
+
+        async fn copy(
+            &self,
+            from: &object_store::path::Path,
+            to: &object_store::path::Path,
+        ) -> object_store::Result<()> {
+            self.check()?;
+            self.inner.copy(from, to).await
+        }
+
+        async fn copy_if_not_exists(
+            &self,
+            from: &object_store::path::Path,
+            to: &object_store::path::Path,
+        ) -> object_store::Result<()> {
+            self.check()?;
+            self.inner.copy_if_not_exists(from, to).await
+        }
+    }
+
+    #[tokio::test]
+    async fn retries_failed_bucket_writes() -> Result<()> {
+        use std::sync::{atomic::Ordering, Arc};
+
+        use object_store::{path::Path, ObjectStore};
+
+        let store = Arc::new(FlakyStore::default());
+        store.down.store(true, Ordering::SeqCst);
+        let storage = SqliteStorage::new("sqlite::memory:")
+            .await?
+            .with_bucket(PacBucket::new(store.clone(), ""));
+        let first = Pac::generate(vec!["a".to_string()]);
+        let second = Pac::generate(vec!["b".to_string()]);
+        // The storage writes succeed while the bucket is down
+        storage.upload_file(&first).await?;
+        storage.set_latest(&first.hash).await?;
+        assert_eq!(storage.get_file(&first.hash).await?, first.file);
+
+        store.down.store(false, Ordering::SeqCst);
+        storage.upload_file(&second).await?;
+        for (name, file) in [
+            (format!("{}.pac", first.hash), &first.file),
+            (format!("{}.pac", second.hash), &second.file),
+            ("latest.pac".to_string(), &first.file),
+        ] {
+            let mirrored = store.get(&Path::from(name)).await?.bytes().await?;
+            assert_eq!(std::str::from_utf8(&mirrored).unwrap(), file);
+        }
+        Ok(())
+    }
+
+    #[cfg(not(feature = "sqlcipher"))]
+    #[tokio::test]
+    async fn refuses_key_without_sqlcipher() {
+        let args = SqliteArgs {
+            db_key: Some("secret".to_string()),
+            ..Default::default()
+        };
+        let err = SqliteStorage::new_with("sqlite::memory:", &args)
+            .await
+            .err()
+            .unwrap();
+        assert!(format!("{err:?}").contains("sqlcipher"), "{err:?}");
+    }
+
+    #[tokio::test]
+    async fn reports_migration_status() -> Result<()> {
+        let storage = SqliteStorage::connect("sqlite::memory:").await?;
+        let status = storage.migration_status().await?;
+        assert!(!status.is_current());
+        assert_eq!(
+            status.pending.len(),
+            storage.pending_migrations().await?.len()
+        );
+
+        storage.migrate().await?;
+        assert_eq!(
+            storage.migration_status().await?,
+            MigrationStatus::default()
         );
+
+        let mut conn = storage.acquire().await?;
+        sqlx::query(
+            "INSERT INTO _sqlx_migrations(version, description, success, checksum, execution_time)
+                VALUES (99990101000000, 'newer', 1, x'00', 0)",
+        )
+        .execute(conn.as_mut())
+        .await?;
+        sqlx::query("UPDATE _sqlx_migrations SET checksum = x'00' WHERE version = 20241011214038")
+            .execute(conn.as_mut())
+            .await?;
+        drop(conn);
+        let status = storage.migration_status().await?;
+        assert!(status.pending.is_empty());
+        assert_eq!(status.unknown, vec![99990101000000]);
+        assert_eq!(status.modified, vec![20241011214038]);
+        assert!(!status.is_current());
         Ok(())
     }
 
     #[tokio::test]
-    async fn remove_existing() -> Result<()> {
+    async fn runs_maintenance() -> Result<()> {
         let storage = SqliteStorage::new("sqlite::memory:").await?;
-        let test = ["a", "aa", "ab"];
-        for s in test.into_iter() {
-            storage.add_host(s).await?;
-        }
-        storage.remove_host("ab").await?;
-        storage.remove_host("aa").await?;
-        let res = storage.all_hosts().await?;
-        assert_eq!(res, vec!["a".to_string()]);
+        storage.add_host("a", HostPatch::default()).await?;
+        storage.maintenance().await?;
+        assert_eq!(storage.all_hosts().await?, vec!["a"]);
         Ok(())
     }
 
     #[tokio::test]
-    async fn fails_to_remove_missing() -> Result<()> {
+    async fn compresses_old_files() -> Result<()> {
         let storage = SqliteStorage::new("sqlite::memory:").await?;
-        let test = vec!["a", "aa"];
-        for s in test.into_iter() {
-            storage.add_host(s).await?;
+        let pac = Pac::generate(vec!["a".to_string()]);
+        storage.upload_file(&pac).await?;
+        let mut conn = storage.acquire().await?;
+        sqlx::query!(
+            "UPDATE pac SET file = ?, file_encoding = 'identity' WHERE hash = ?;",
+            pac.file,
+            pac.hash
+        )
+        .execute(conn.as_mut())
+        .await?;
+        drop(conn);
+        assert_eq!(storage.get_file(&pac.hash).await?, pac.file);
+
+        assert_eq!(storage.compress_files().await?, 1);
+        assert_eq!(storage.compress_files().await?, 0);
+        assert_eq!(storage.get_file(&pac.hash).await?, pac.file);
+        let versions = storage.list_versions(1).await?;
+        assert_eq!(versions[0].size, pac.file.len() as i64);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rotates_backups() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("qpac-backups-{}", std::process::id()));
+        let backups = dir.join("backups");
+        std::fs::create_dir_all(&backups)?;
+        for name in ["qpac-1000000000.db", "qpac-1000000001.db", "notes.txt"] {
+            std::fs::write(backups.join(name), "")?;
         }
-        assert_eq!(storage.remove_host("ab").await, Err(AppError::NotFound));
+        // `VACUUM INTO` of an in memory database never reaches the disk
+        let storage = SqliteStorage::new(&format!("sqlite://{}/qpac.db", dir.display())).await?;
+        storage.add_host("a", HostPatch::default()).await?;
+        // Same second most of the time
+        let first = storage.backup(&backups, 3).await?;
+        let path = storage.backup(&backups, 2).await?;
+        assert_ne!(first, path);
+
+        let mut names: Vec<String> = std::fs::read_dir(&backups)?
+            .map(|e| Ok(e?.file_name().to_string_lossy().into_owned()))
+            .collect::<std::io::Result<_>>()?;
+        names.sort();
+        let copy = SqliteStorage::connect(&format!("sqlite://{}", path.display())).await?;
+        let hosts = copy.all_hosts().await;
+        std::fs::remove_dir_all(&dir)?;
+        assert_eq!(hosts?, vec!["a"]);
+        assert_eq!(names.len(), 3);
+        assert_eq!(names[0], "notes.txt");
+        assert!(!names.contains(&"qpac-1000000001.db".to_string()));
+        assert!(names.contains(&path.file_name().unwrap().to_string_lossy().into_owned()));
+        assert_eq!(backup_order("qpac-1000000000-2.db"), Some((1000000000, 2)));
+        assert_eq!(backup_order("qpac-x.db"), None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reports_pending_migrations() -> Result<()> {
+        let storage = SqliteStorage::connect("sqlite::memory:").await?;
+        let pending = storage.pending_migrations().await?;
+        assert_eq!(pending.len(), migrate!().iter().count() / 2);
+        storage.migrate().await?;
+        assert!(storage.pending_migrations().await?.is_empty());
         Ok(())
     }
 }
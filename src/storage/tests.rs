@@ -0,0 +1,1152 @@
+//! Behavior every [`Storage`] backend has to share, run against a backend with
+//! [`conformance!`]
+
+use std::time::Duration;
+
+use super::*;
+use crate::{
+    error::Result,
+    pac::{self, NetworkProfile, Pac, PacEncodings},
+    rules::{EntryKind, Rule},
+};
+
+/// Adds a `conformance` test module running every check against fresh
+/// instances built by the second argument, `Default` when left out
+macro_rules! conformance {
+    ($storage:ty) => {
+        $crate::storage::tests::conformance!($storage, <$storage>::default());
+    };
+    ($storage:ty, $new:expr) => {
+        $crate::storage::tests::conformance!(
+            @checks $storage, $new;
+            adds_sorted,
+            pages_hosts,
+            searches_hosts,
+            fails_to_add_non_uniq,
+            upserts_hosts,
+            upserts_in_bulk,
+            remove_existing,
+            fails_to_remove_missing,
+            restores_removed,
+            removes_expired,
+            adds_and_removes_in_bulk,
+            pins_hosts,
+            imports_mirror,
+            stores_manifest,
+            numbers_versions,
+            prunes_files,
+            removes_by_tag,
+            renames_and_deletes_tags,
+            renames_profile_tags,
+            updates_host_meta,
+            restores_snapshot,
+            bumps_hosts_version,
+            checks_expected_revision,
+            bumps_config_version,
+            notifies_changes,
+            stores_groups,
+            stores_proxy,
+            stores_mode,
+            stores_upstreams,
+            stores_exclusions,
+            stores_blocklist,
+            records_client_fetches,
+            records_audit_log,
+            reports_stats,
+            stores_profiles,
+            leases_expire,
+            stores_encodings,
+            stores_networks,
+            stores_ip_ranges,
+            stages_files
+        );
+    };
+    (@checks $storage:ty, $new:expr; $($check:ident),* $(,)?) => {
+        mod conformance {
+            use super::*;
+            use $crate::storage::tests;
+
+            $(
+                #[tokio::test]
+                async fn $check() -> $crate::error::Result<()> {
+                    let storage: $storage = $new;
+                    tests::$check(storage).await
+                }
+            )*
+
+            #[tokio::test]
+            async fn exports_and_imports_state() -> $crate::error::Result<()> {
+                let storage: $storage = $new;
+                let copy: $storage = $new;
+                tests::exports_and_imports_state(storage, copy).await
+            }
+        }
+    };
+}
+pub(crate) use conformance;
+
+pub async fn adds_sorted(storage: impl Storage) -> Result<()> {
+    let test = vec![
+        "a".to_string(),
+        "aa".to_string(),
+        "ab".to_string(),
+        "abc".to_string(),
+        "b".to_string(),
+        "bac".to_string(),
+        "sa".to_string(),
+        "z".to_string(),
+    ];
+    for s in test.iter() {
+        storage.add_host(s, HostPatch::default()).await?;
+    }
+    let res = storage.all_hosts().await?;
+    assert_eq!(res, test);
+    Ok(())
+}
+
+pub async fn pages_hosts(storage: impl Storage) -> Result<()> {
+    for s in ["d", "b", "a", "c", "e"] {
+        storage.add_host(s, HostPatch::default()).await?;
+    }
+    storage.set_tags("c", vec!["t".to_string()]).await?;
+    let hosts = |page: Vec<HostEntry>| page.into_iter().map(|e| e.host).collect::<Vec<_>>();
+
+    assert_eq!(hosts(storage.hosts_page(None, 2).await?), vec!["a", "b"]);
+    let page = storage.hosts_page(Some("b"), 2).await?;
+    assert_eq!(page[0].tags, vec!["t"]);
+    assert_eq!(hosts(page), vec!["c", "d"]);
+    assert_eq!(hosts(storage.hosts_page(Some("d"), 2).await?), vec!["e"]);
+    assert!(storage.hosts_page(Some("e"), 2).await?.is_empty());
+    assert!(storage.hosts_page(None, 0).await?.is_empty());
+    Ok(())
+}
+
+pub async fn searches_hosts(storage: impl Storage) -> Result<()> {
+    for s in [
+        "google.com",
+        "mail.google.com",
+        "googleapis.com",
+        "example.com",
+    ] {
+        storage.add_host(s, HostPatch::default()).await?;
+    }
+    assert_eq!(
+        storage.search_hosts("%google%").await?,
+        vec!["google.com", "googleapis.com", "mail.google.com"]
+    );
+    assert_eq!(
+        storage.search_hosts("GOOGLE%").await?,
+        vec!["google.com", "googleapis.com"]
+    );
+    assert_eq!(storage.search_hosts("%.c_m").await?.len(), 4);
+    assert_eq!(
+        storage.search_hosts("example.com").await?,
+        vec!["example.com"]
+    );
+    assert!(storage.search_hosts("example").await?.is_empty());
+    Ok(())
+}
+
+pub async fn fails_to_add_non_uniq(storage: impl Storage) -> Result<()> {
+    let test = vec!["a", "aa"];
+    for s in test.into_iter() {
+        storage.add_host(s, HostPatch::default()).await?;
+    }
+    assert_eq!(
+        storage.add_host("aa", HostPatch::default()).await,
+        Err(AppError::PreconditionFailed(
+            "Host already exists".to_string()
+        ))
+    );
+    Ok(())
+}
+
+pub async fn upserts_hosts(storage: impl Storage) -> Result<()> {
+    let patch = |tag: &str| HostPatch {
+        tags: Some(vec![tag.to_string()]),
+        ..Default::default()
+    };
+    storage.add_host("b", patch("x")).await?;
+    assert_eq!(storage.get_host("b").await?.tags, vec!["x"]);
+    assert!(storage.add_host("b", patch("y")).await.is_err());
+    assert_eq!(storage.get_host("b").await?.tags, vec!["x"]);
+    storage.remove_host("b").await?;
+    assert!(storage.upsert_host("a", patch("x")).await?);
+    storage
+        .update_host(
+            "a",
+            HostPatch {
+                note: Some(Some("kept".to_string())),
+                ..Default::default()
+            },
+        )
+        .await?;
+    assert!(!storage.upsert_host("a", patch("y")).await?);
+    let entry = storage.get_host("a").await?;
+    assert_eq!(entry.tags, vec!["y"]);
+    assert_eq!(entry.note.as_deref(), Some("kept"));
+    assert_eq!(storage.all_hosts().await?, vec!["a"]);
+    Ok(())
+}
+
+pub async fn upserts_in_bulk(storage: impl Storage) -> Result<()> {
+    storage
+        .add_host(
+            "a",
+            HostPatch {
+                note: Some(Some("kept".to_string())),
+                ..Default::default()
+            },
+        )
+        .await?;
+    let patch = HostPatch {
+        tags: Some(vec!["t".to_string()]),
+        kind: Some(EntryKind::Suffix),
+        ..Default::default()
+    };
+    let added = storage
+        .upsert_hosts(
+            vec!["c".to_string(), "a".to_string(), "b".to_string()],
+            patch,
+        )
+        .await?;
+    assert_eq!(added, vec!["b", "c"]);
+    for entry in storage.host_entries().await? {
+        assert_eq!(entry.tags, vec!["t"], "{}", entry.host);
+        assert_eq!(entry.kind, EntryKind::Suffix, "{}", entry.host);
+    }
+    assert_eq!(storage.get_host("a").await?.note.as_deref(), Some("kept"));
+    assert!(storage
+        .upsert_hosts(vec![], HostPatch::default())
+        .await?
+        .is_empty());
+    Ok(())
+}
+
+pub async fn remove_existing(storage: impl Storage) -> Result<()> {
+    let test = ["a", "aa", "ab"];
+    for s in test.into_iter() {
+        storage.add_host(s, HostPatch::default()).await?;
+    }
+    storage.remove_host("ab").await?;
+    storage.remove_host("aa").await?;
+    let res = storage.all_hosts().await?;
+    assert_eq!(res, vec!["a".to_string()]);
+    Ok(())
+}
+
+pub async fn fails_to_remove_missing(storage: impl Storage) -> Result<()> {
+    let test = vec!["a", "aa"];
+    for s in test.into_iter() {
+        storage.add_host(s, HostPatch::default()).await?;
+    }
+    assert_eq!(storage.remove_host("ab").await, Err(AppError::NotFound));
+    Ok(())
+}
+
+pub async fn restores_removed(storage: impl Storage) -> Result<()> {
+    for s in ["a", "b", "c"] {
+        storage.add_host(s, HostPatch::default()).await?;
+    }
+    storage.set_tags("a", vec!["tmp".to_string()]).await?;
+    storage.remove_host("a").await?;
+    storage.remove_hosts(vec!["b".to_string()]).await?;
+    let deleted = storage.deleted_hosts().await?;
+    assert_eq!(
+        deleted
+            .iter()
+            .map(|d| d.entry.host.as_str())
+            .collect::<Vec<_>>(),
+        vec!["a", "b"]
+    );
+    assert_eq!(deleted[0].entry.tags, vec!["tmp"]);
+
+    let version = storage.hosts_version().await?;
+    let restored = storage.restore_host("a").await?;
+    assert_eq!(restored.tags, vec!["tmp"]);
+    assert_eq!(storage.all_hosts().await?, vec!["a", "c"]);
+    assert_eq!(storage.hosts_by_tag("tmp").await?, vec!["a"]);
+    assert_ne!(storage.hosts_version().await?, version);
+    assert_eq!(storage.restore_host("a").await, Err(AppError::NotFound));
+
+    storage.add_host("b", HostPatch::default()).await?;
+    assert!(matches!(
+        storage.restore_host("b").await,
+        Err(AppError::Conflict(_))
+    ));
+    let now = crate::utils::time::unix_now();
+    assert_eq!(storage.purge_deleted(now - 60).await?, 0);
+    assert_eq!(storage.purge_deleted(now + 1).await?, 1);
+    assert!(storage.deleted_hosts().await?.is_empty());
+    Ok(())
+}
+
+pub async fn removes_expired(storage: impl Storage) -> Result<()> {
+    for s in ["a", "b", "c", "d"] {
+        storage.add_host(s, HostPatch::default()).await?;
+    }
+    let expire = |at| HostPatch {
+        expires_at: Some(Some(at)),
+        ..Default::default()
+    };
+    storage.update_host("a", expire(100)).await?;
+    storage.update_host("b", expire(200)).await?;
+    storage.update_host("c", expire(100)).await?;
+    storage.set_pinned("c", true).await?;
+
+    let version = storage.hosts_version().await?;
+    assert!(storage.remove_expired(99).await?.is_empty());
+    assert_eq!(storage.hosts_version().await?, version);
+    assert_eq!(storage.remove_expired(100).await?, vec!["a"]);
+    assert_ne!(storage.hosts_version().await?, version);
+    assert_eq!(storage.remove_expired(300).await?, vec!["b"]);
+    assert_eq!(storage.all_hosts().await?, vec!["c", "d"]);
+    assert_eq!(storage.deleted_hosts().await?.len(), 2);
+    Ok(())
+}
+
+pub async fn reports_stats(storage: impl Storage) -> Result<()> {
+    let stats = storage.storage_stats().await?;
+    assert_eq!(
+        (stats.hosts, stats.files, stats.latest_pac_bytes),
+        (0, 0, None)
+    );
+
+    storage.add_host("a", HostPatch::default()).await?;
+    storage.add_host("b", HostPatch::default()).await?;
+    storage.remove_host("b").await?;
+    storage.create_snapshot("s").await?;
+    let pac = Pac::generate(vec!["a".to_string()]);
+    storage.upload_file(&pac).await?;
+    storage.set_latest(&pac.hash).await?;
+    let stats = storage.storage_stats().await?;
+    assert_eq!(
+        (
+            stats.hosts,
+            stats.deleted_hosts,
+            stats.files,
+            stats.snapshots
+        ),
+        (1, 1, 1, 1)
+    );
+    assert_eq!(stats.latest_pac_bytes, Some(pac.file.len() as u64));
+    Ok(())
+}
+
+pub async fn adds_and_removes_in_bulk(storage: impl Storage) -> Result<()> {
+    storage.add_host("b", HostPatch::default()).await?;
+    let version = storage.hosts_version().await?;
+    let patch = HostPatch {
+        tags: Some(vec!["t".to_string()]),
+        kind: Some(EntryKind::Suffix),
+        ..Default::default()
+    };
+    assert_eq!(
+        storage
+            .add_hosts(
+                vec!["c".to_string(), "b".to_string(), "a".to_string()],
+                patch
+            )
+            .await?,
+        vec!["a", "c"]
+    );
+    assert!(storage.hosts_version().await? > version);
+    assert_eq!(storage.all_hosts().await?, vec!["a", "b", "c"]);
+    // Only the added hosts are patched
+    assert_eq!(storage.hosts_by_tag("t").await?, vec!["a", "c"]);
+    assert_eq!(storage.get_host("b").await?.kind, EntryKind::Exact);
+
+    storage.set_pinned("a", true).await?;
+    assert_eq!(
+        storage
+            .remove_hosts(vec!["c".to_string(), "a".to_string(), "z".to_string()])
+            .await?,
+        vec!["c"]
+    );
+    assert_eq!(storage.all_hosts().await?, vec!["a", "b"]);
+    Ok(())
+}
+
+pub async fn pins_hosts(storage: impl Storage) -> Result<()> {
+    for s in ["a", "b", "c"] {
+        storage.add_host(s, HostPatch::default()).await?;
+    }
+    storage.set_pinned("c", true).await?;
+    storage.set_pinned("a", true).await?;
+    storage.set_pinned("c", false).await?;
+    assert_eq!(storage.pinned_hosts().await?, vec!["a".to_string()]);
+    assert_eq!(storage.set_pinned("z", true).await, Err(AppError::NotFound));
+
+    storage.remove_host("a").await?;
+    storage.add_host("a", HostPatch::default()).await?;
+    assert!(storage.pinned_hosts().await?.is_empty());
+    Ok(())
+}
+
+pub async fn imports_mirror(storage: impl Storage) -> Result<()> {
+    for s in ["a", "b", "c"] {
+        storage.add_host(s, HostPatch::default()).await?;
+    }
+    storage.set_pinned("c", true).await?;
+    let wanted = vec!["b".to_string(), "d".to_string()];
+
+    let diff = storage
+        .import_hosts(wanted.clone(), ImportMode::Mirror, true)
+        .await?;
+    assert_eq!(diff.added, vec!["d".to_string()]);
+    assert_eq!(diff.removed, vec!["a".to_string()]);
+    assert_eq!(storage.all_hosts().await?, vec!["a", "b", "c"]);
+
+    storage
+        .import_hosts(wanted.clone(), ImportMode::Mirror, false)
+        .await?;
+    assert_eq!(storage.all_hosts().await?, vec!["b", "c", "d"]);
+
+    let diff = storage
+        .import_hosts(vec![], ImportMode::Merge, false)
+        .await?;
+    assert!(diff.is_empty());
+    Ok(())
+}
+
+pub async fn stores_manifest(storage: impl Storage) -> Result<()> {
+    let hosts = vec!["a".to_string(), "b".to_string()];
+    let pac = Pac::generate(hosts.clone());
+    storage.upload_file(&pac).await?;
+    assert_eq!(storage.latest_hash().await, Err(AppError::NotFound));
+    storage.set_latest(&pac.hash).await?;
+    assert_eq!(storage.latest_hash().await?, pac.hash);
+    assert_eq!(storage.get_manifest(&pac.hash).await?, hosts);
+    assert_eq!(storage.get_manifest("nope").await, Err(AppError::NotFound));
+    assert_eq!(
+        storage.get_checksum(&pac.hash).await?,
+        pac::checksum(&pac.file)
+    );
+    Ok(())
+}
+
+pub async fn numbers_versions(storage: impl Storage) -> Result<()> {
+    let a = Pac::generate(vec!["a".to_string()]);
+    let b = Pac::generate(vec!["b".to_string()]);
+    storage.upload_file(&a).await?;
+    storage.upload_file(&b).await?;
+    storage.upload_file(&a).await?;
+    assert_eq!(storage.get_version(&a.hash).await?, 1);
+    assert_eq!(storage.get_version(&b.hash).await?, 2);
+    assert_eq!(storage.get_version_hash(2).await?, b.hash);
+    assert_eq!(storage.get_version_hash(3).await, Err(AppError::NotFound));
+    assert_eq!(storage.get_version("nope").await, Err(AppError::NotFound));
+    let latest = storage.list_versions(1).await?;
+    assert!(latest[0].created_at.is_some_and(|t| t > 0));
+    assert_eq!(
+        latest,
+        vec![PacVersion {
+            version: 2,
+            hash: b.hash.clone(),
+            size: b.file.len() as i64,
+            created_at: latest[0].created_at,
+            meta: b.meta.clone(),
+        }]
+    );
+    assert_eq!(storage.list_versions(u32::MAX).await?.len(), 2);
+    Ok(())
+}
+
+pub async fn prunes_files(storage: impl Storage) -> Result<()> {
+    let pacs: Vec<Pac> = ["a", "b", "c", "d"]
+        .iter()
+        .map(|h| Pac::generate(vec![h.to_string()]))
+        .collect();
+    for pac in pacs.iter() {
+        storage.upload_file(pac).await?;
+    }
+    storage.set_latest(&pacs[0].hash).await?;
+    storage.set_staged_hash(Some(pacs[1].hash.clone())).await?;
+
+    // Everything is newer than the cutoff
+    assert_eq!(storage.prune_files(0, 0).await?, 0);
+    assert_eq!(storage.prune_files(0, i64::MAX).await?, 1);
+    assert_eq!(
+        storage.get_file(&pacs[2].hash).await,
+        Err(AppError::NotFound)
+    );
+    assert_eq!(storage.get_version_hash(3).await, Err(AppError::NotFound));
+    let versions: Vec<i64> = storage
+        .list_versions(10)
+        .await?
+        .into_iter()
+        .map(|v| v.version)
+        .collect();
+    assert_eq!(versions, vec![4, 2, 1]);
+
+    storage.upload_file(&pacs[2]).await?;
+    assert_eq!(storage.get_version(&pacs[2].hash).await?, 5);
+    Ok(())
+}
+
+pub async fn removes_by_tag(storage: impl Storage) -> Result<()> {
+    for s in ["a", "b", "c"] {
+        storage.add_host(s, HostPatch::default()).await?;
+    }
+    storage.set_tags("a", vec!["tmp".to_string()]).await?;
+    storage
+        .set_tags("b", vec!["tmp".to_string(), "x".to_string()])
+        .await?;
+    storage.set_pinned("b", true).await?;
+    assert_eq!(
+        storage.set_tags("z", vec!["tmp".to_string()]).await,
+        Err(AppError::NotFound)
+    );
+    assert_eq!(storage.hosts_by_tag("tmp").await?, vec!["a", "b"]);
+
+    assert_eq!(storage.remove_hosts_by_tag("tmp").await?, vec!["a"]);
+    assert_eq!(storage.all_hosts().await?, vec!["b", "c"]);
+    assert_eq!(storage.hosts_by_tag("tmp").await?, vec!["b"]);
+
+    storage.remove_host("b").await?;
+    storage.add_host("b", HostPatch::default()).await?;
+    assert!(storage.hosts_by_tag("x").await?.is_empty());
+    Ok(())
+}
+
+pub async fn renames_and_deletes_tags(storage: impl Storage) -> Result<()> {
+    for s in ["a", "b", "c"] {
+        storage.add_host(s, HostPatch::default()).await?;
+    }
+    storage
+        .set_tags("a", vec!["tmp".to_string(), "work".to_string()])
+        .await?;
+    storage.set_tags("b", vec!["tmp".to_string()]).await?;
+    let tag = |name: &str, hosts| TagInfo {
+        name: name.to_string(),
+        hosts,
+    };
+    assert_eq!(
+        storage.list_tags().await?,
+        vec![tag("tmp", 2), tag("work", 1)]
+    );
+
+    storage.rename_tag("tmp", "work").await?;
+    assert_eq!(storage.list_tags().await?, vec![tag("work", 2)]);
+    assert_eq!(storage.get_host("a").await?.tags, vec!["work"]);
+    storage.rename_tag("work", "work").await?;
+    assert_eq!(
+        storage.rename_tag("tmp", "x").await,
+        Err(AppError::NotFound)
+    );
+
+    storage.delete_tag("work").await?;
+    assert!(storage.list_tags().await?.is_empty());
+    assert_eq!(storage.all_hosts().await?, vec!["a", "b", "c"]);
+    assert_eq!(storage.delete_tag("work").await, Err(AppError::NotFound));
+    Ok(())
+}
+
+pub async fn renames_profile_tags(storage: impl Storage) -> Result<()> {
+    storage.add_host("a", HostPatch::default()).await?;
+    storage.set_tags("a", vec!["tmp".to_string()]).await?;
+    let profile = |name: &str, tags: &[&str]| Profile {
+        name: name.to_string(),
+        proxy: "PROXY 10.0.0.1:3128".to_string(),
+        tags: tags.iter().map(|t| t.to_string()).collect(),
+    };
+    storage
+        .set_profile(profile("office", &["tmp", "work"]))
+        .await?;
+    storage.set_profile(profile("home", &["home"])).await?;
+
+    storage.rename_tag("tmp", "work").await?;
+    assert_eq!(
+        storage.list_profiles().await?,
+        vec![profile("home", &["home"]), profile("office", &["work"])]
+    );
+    storage.rename_tag("work", "corp").await?;
+    assert_eq!(
+        storage.get_profile("office").await?,
+        profile("office", &["corp"])
+    );
+    Ok(())
+}
+
+pub async fn updates_host_meta(storage: impl Storage) -> Result<()> {
+    storage.add_host("a", HostPatch::default()).await?;
+    let created = storage.get_host("a").await?;
+    assert!(created.kind == EntryKind::Exact && created.note.is_none());
+
+    let patch = HostPatch {
+        note: Some(Some("vpn only".to_string())),
+        tags: Some(vec!["x".to_string(), "tmp".to_string()]),
+        expires_at: Some(Some(100)),
+        kind: Some(EntryKind::Suffix),
+        pinned: Some(true),
+        group: None,
+        schedule: None,
+    };
+    let updated = storage.update_host("a", patch).await?;
+    assert_eq!(updated, storage.get_host("a").await?);
+    assert_eq!(updated.note.as_deref(), Some("vpn only"));
+    assert_eq!(updated.tags, vec!["tmp", "x"]);
+    assert_eq!(updated.expires_at, Some(100));
+    assert!(updated.kind == EntryKind::Suffix && updated.pinned);
+    assert_eq!(updated.created_at, created.created_at);
+    assert_eq!(updated.rule(), Rule::Subdomains("a".to_string()));
+
+    let patch = HostPatch {
+        note: Some(None),
+        ..Default::default()
+    };
+    let updated = storage.update_host("a", patch).await?;
+    assert!(updated.note.is_none());
+    assert_eq!(updated.expires_at, Some(100));
+    assert_eq!(storage.host_entries().await?, vec![updated]);
+    assert_eq!(
+        storage.update_host("z", HostPatch::default()).await,
+        Err(AppError::NotFound)
+    );
+    Ok(())
+}
+
+pub async fn restores_snapshot(storage: impl Storage) -> Result<()> {
+    for s in ["a", "b"] {
+        storage.add_host(s, HostPatch::default()).await?;
+    }
+    storage.set_tags("a", vec!["t".to_string()]).await?;
+    storage.set_pinned("b", true).await?;
+    let info = storage.create_snapshot("before").await?;
+    assert_eq!(info.hosts, 2);
+    assert!(matches!(
+        storage.create_snapshot("before").await,
+        Err(AppError::Conflict(_))
+    ));
+    let saved = storage.host_entries().await?;
+
+    storage.remove_host("a").await?;
+    storage.remove_host("b").await?;
+    storage.add_host("c", HostPatch::default()).await?;
+
+    let diff = storage.restore_snapshot("before").await?;
+    assert_eq!(diff.added, vec!["a", "b"]);
+    assert_eq!(diff.removed, vec!["c"]);
+    assert_eq!(storage.host_entries().await?, saved);
+    assert_eq!(storage.list_snapshots().await?, vec![info]);
+
+    storage.delete_snapshot("before").await?;
+    assert_eq!(
+        storage.restore_snapshot("before").await,
+        Err(AppError::NotFound)
+    );
+    Ok(())
+}
+
+pub async fn exports_and_imports_state<S: Storage>(storage: S, copy: S) -> Result<()> {
+    for s in ["a", "b"] {
+        storage.add_host(s, HostPatch::default()).await?;
+    }
+    storage.set_tags("a", vec!["t".to_string()]).await?;
+    storage.create_snapshot("both").await?;
+    storage.set_pinned("b", true).await?;
+    storage
+        .set_profile(Profile {
+            name: "lte".to_string(),
+            proxy: "PROXY 10.0.0.1:3128".to_string(),
+            tags: vec!["t".to_string()],
+        })
+        .await?;
+    storage.set_proxy("PROXY 10.0.0.2:3128").await?;
+    storage.set_mode(PacMode::Blacklist).await?;
+    storage.set_bypass_private(true).await?;
+    storage
+        .set_network(NetworkProfile {
+            name: "home".to_string(),
+            network: "192.168.1.0/24".to_string(),
+            proxy: "DIRECT".to_string(),
+        })
+        .await?;
+    storage
+        .add_exclusions(vec!["intranet.example.com".to_string()])
+        .await?;
+    storage
+        .set_ip_ranges(vec!["203.0.113.0/24".to_string()])
+        .await?;
+    storage
+        .set_blocklisted(BlocklistEntry {
+            host: "ads.example.com".to_string(),
+            kind: EntryKind::Suffix,
+            ..Default::default()
+        })
+        .await?;
+    storage
+        .set_upstreams(vec![Upstream {
+            proxy: "PROXY 10.0.0.4:3128".to_string(),
+            weight: 2,
+        }])
+        .await?;
+    storage
+        .set_group(ProxyGroup {
+            name: "work".to_string(),
+            proxy: "PROXY 10.0.0.3:3128".to_string(),
+            schedule: Some("MON-FRI 9-17".parse()?),
+        })
+        .await?;
+    storage
+        .update_host(
+            "b",
+            HostPatch {
+                group: Some(Some("work".to_string())),
+                schedule: Some(Some("SAT".parse()?)),
+                ..Default::default()
+            },
+        )
+        .await?;
+    let state = storage.export_state().await?;
+    assert_eq!(state.hosts.len(), 2);
+    assert_eq!(state.snapshots[0].hosts.len(), 2);
+
+    copy.add_host("c", HostPatch::default()).await?;
+    copy.import_state(state.clone()).await?;
+    assert_eq!(copy.export_state().await?, state);
+
+    let first = InstanceState {
+        version: 1,
+        hosts: state.hosts.clone(),
+        ..Default::default()
+    };
+    copy.import_state(first).await?;
+    assert_eq!(copy.export_state().await?.hosts, state.hosts);
+
+    let newer = InstanceState {
+        version: STATE_VERSION + 1,
+        ..Default::default()
+    };
+    assert!(matches!(
+        copy.import_state(newer).await,
+        Err(AppError::Validation { .. })
+    ));
+    Ok(())
+}
+
+pub async fn bumps_hosts_version(storage: impl Storage) -> Result<()> {
+    let initial = storage.hosts_version().await?;
+    storage.add_host("a", HostPatch::default()).await?;
+    let added = storage.hosts_version().await?;
+    assert_ne!(added, initial);
+    storage.set_tags("a", vec!["t".to_string()]).await?;
+    let tagged = storage.hosts_version().await?;
+    assert_ne!(tagged, added);
+    storage
+        .upload_file(&Pac::generate(vec!["a".to_string()]))
+        .await?;
+    assert_eq!(storage.hosts_version().await?, tagged);
+    storage.remove_hosts_by_tag("t").await?;
+    assert_ne!(storage.hosts_version().await?, tagged);
+    Ok(())
+}
+
+pub async fn checks_expected_revision(storage: impl Storage) -> Result<()> {
+    let loaded = storage.hosts_version().await?;
+    storage.add_host("a", HostPatch::default()).await?;
+    let current = storage.hosts_version().await?;
+
+    let stale = expect_revision(loaded, storage.add_host("b", HostPatch::default())).await;
+    assert_eq!(
+        stale,
+        Err(AppError::StaleRevision {
+            expected: loaded,
+            current
+        })
+    );
+    assert_eq!(storage.all_hosts().await?, vec!["a"]);
+    let stale = expect_revision(loaded, storage.add_exclusions(vec!["b".to_string()])).await;
+    assert!(matches!(stale, Err(AppError::StaleRevision { .. })));
+    assert!(storage.list_exclusions().await?.is_empty());
+
+    // Only the first write checks, the ones after it build on it
+    expect_revision(current, async {
+        storage.add_host("b", HostPatch::default()).await?;
+        storage.set_tags("b", vec!["t".to_string()]).await
+    })
+    .await?;
+    assert_eq!(storage.all_hosts().await?, vec!["a", "b"]);
+    // Writes outside of it aren't checked at all
+    storage.remove_host("a").await?;
+    Ok(())
+}
+
+pub async fn bumps_config_version(storage: impl Storage) -> Result<()> {
+    let initial = storage.config_version().await?;
+    storage.add_host("a", HostPatch::default()).await?;
+    assert_eq!(storage.config_version().await?, initial);
+    storage.set_proxy("PROXY 10.0.0.1:3128").await?;
+    let proxied = storage.config_version().await?;
+    assert_ne!(proxied, initial);
+    storage.set_mode(PacMode::Blacklist).await?;
+    let mode = storage.config_version().await?;
+    assert_ne!(mode, proxied);
+    storage
+        .set_profile(Profile {
+            name: "office".to_string(),
+            proxy: "PROXY 10.0.0.2:3128".to_string(),
+            tags: vec![],
+        })
+        .await?;
+    let profiled = storage.config_version().await?;
+    assert_ne!(profiled, mode);
+    storage
+        .set_ip_ranges(vec!["10.0.0.0/8".to_string()])
+        .await?;
+    assert_ne!(storage.config_version().await?, profiled);
+    Ok(())
+}
+
+pub async fn notifies_changes(storage: impl Storage) -> Result<()> {
+    use tokio_stream::StreamExt;
+
+    let mut changes = storage.watch();
+    storage.add_host("a", HostPatch::default()).await?;
+    storage.set_proxy("PROXY 10.0.0.1:3128").await?;
+    storage.add_exclusions(vec![]).await?;
+    storage
+        .upload_file(&Pac::generate(vec!["a".to_string()]))
+        .await?;
+    storage.remove_host("a").await?;
+
+    let events: Vec<ChangeEvent> = (&mut changes).take(3).collect().await;
+    assert_eq!(
+        events,
+        vec![ChangeEvent::Hosts, ChangeEvent::Config, ChangeEvent::Hosts]
+    );
+    let rest = tokio::time::timeout(Duration::from_millis(50), changes.next()).await;
+    assert!(rest.is_err());
+    Ok(())
+}
+
+pub async fn stores_groups(storage: impl Storage) -> Result<()> {
+    let mut group = ProxyGroup {
+        name: "work".to_string(),
+        proxy: "PROXY 10.0.0.1:3128".to_string(),
+        schedule: None,
+    };
+    storage.set_group(group.clone()).await?;
+    group.proxy = "PROXY 10.0.0.2:3128".to_string();
+    group.schedule = Some("SAT-SUN 22-6 GMT".parse()?);
+    storage.set_group(group.clone()).await?;
+    assert_eq!(storage.list_groups().await?, vec![group]);
+
+    storage.add_host("a", HostPatch::default()).await?;
+    let schedule = "MON-FRI 9-17".parse()?;
+    let patch = HostPatch {
+        group: Some(Some("work".to_string())),
+        schedule: Some(Some(schedule)),
+        ..Default::default()
+    };
+    let entry = storage.update_host("a", patch).await?;
+    assert_eq!(entry.group.as_deref(), Some("work"));
+    assert_eq!(entry.schedule, Some(schedule));
+    assert_eq!(storage.get_host("a").await?.schedule, Some(schedule));
+    let patch = HostPatch {
+        schedule: Some(None),
+        ..Default::default()
+    };
+    assert_eq!(storage.update_host("a", patch).await?.schedule, None);
+
+    storage.remove_group("work").await?;
+    assert_eq!(storage.get_host("a").await?.group, None);
+    assert_eq!(storage.remove_group("work").await, Err(AppError::NotFound));
+    Ok(())
+}
+
+pub async fn stores_proxy(storage: impl Storage) -> Result<()> {
+    assert_eq!(storage.get_proxy().await?, None);
+    storage.set_proxy("PROXY 10.0.0.1:3128").await?;
+    storage.set_proxy("SOCKS5 10.0.0.1:1080").await?;
+    assert_eq!(
+        storage.get_proxy().await?.as_deref(),
+        Some("SOCKS5 10.0.0.1:1080")
+    );
+    Ok(())
+}
+
+pub async fn stores_mode(storage: impl Storage) -> Result<()> {
+    assert_eq!(storage.get_mode().await?, PacMode::Whitelist);
+    storage.set_mode(PacMode::Blacklist).await?;
+    assert_eq!(storage.get_mode().await?, PacMode::Blacklist);
+
+    assert!(!storage.get_bypass_private().await?);
+    storage.set_bypass_private(true).await?;
+    assert!(storage.get_bypass_private().await?);
+    Ok(())
+}
+
+pub async fn stores_upstreams(storage: impl Storage) -> Result<()> {
+    assert!(storage.list_upstreams().await?.is_empty());
+    let upstreams = vec![
+        Upstream {
+            proxy: "PROXY 10.0.0.2:3128".to_string(),
+            weight: 3,
+        },
+        Upstream {
+            proxy: "PROXY 10.0.0.1:3128".to_string(),
+            weight: 1,
+        },
+    ];
+    storage.set_upstreams(upstreams.clone()).await?;
+    assert_eq!(storage.list_upstreams().await?, upstreams);
+    storage.set_upstreams(vec![]).await?;
+    assert!(storage.list_upstreams().await?.is_empty());
+    Ok(())
+}
+
+pub async fn stores_exclusions(storage: impl Storage) -> Result<()> {
+    let version = storage.hosts_version().await?;
+    let added = storage
+        .add_exclusions(vec!["b.com".to_string(), "a.com".to_string()])
+        .await?;
+    assert_eq!(added, vec!["a.com", "b.com"]);
+    assert_ne!(storage.hosts_version().await?, version);
+    let added = storage
+        .add_exclusions(vec!["b.com".to_string(), "c.com".to_string()])
+        .await?;
+    assert_eq!(added, vec!["c.com"]);
+    assert_eq!(
+        storage.list_exclusions().await?,
+        vec!["a.com", "b.com", "c.com"]
+    );
+
+    storage.remove_exclusion("b.com").await?;
+    assert!(matches!(
+        storage.remove_exclusion("b.com").await,
+        Err(AppError::NotFound)
+    ));
+    assert_eq!(storage.list_exclusions().await?, vec!["a.com", "c.com"]);
+    Ok(())
+}
+
+pub async fn stores_blocklist(storage: impl Storage) -> Result<()> {
+    let version = storage.hosts_version().await?;
+    let entry = |host: &str, action| BlocklistEntry {
+        host: host.to_string(),
+        action,
+        ..Default::default()
+    };
+    storage
+        .set_blocklisted(entry("b.com", BlocklistAction::Block))
+        .await?;
+    storage
+        .set_blocklisted(entry("a.com", BlocklistAction::Direct))
+        .await?;
+    assert_ne!(storage.hosts_version().await?, version);
+    let created_at = storage.list_blocklist().await?[1].created_at;
+    assert!(created_at > 0);
+
+    storage
+        .set_blocklisted(BlocklistEntry {
+            kind: EntryKind::Suffix,
+            ..entry("b.com", BlocklistAction::Direct)
+        })
+        .await?;
+    let blocklist = storage.list_blocklist().await?;
+    assert_eq!(
+        blocklist
+            .iter()
+            .map(|e| e.host.as_str())
+            .collect::<Vec<_>>(),
+        vec!["a.com", "b.com"]
+    );
+    assert_eq!(blocklist[1].action, BlocklistAction::Direct);
+    assert_eq!(blocklist[1].rule(), Rule::Subdomains("b.com".to_string()));
+    assert_eq!(blocklist[1].created_at, created_at);
+
+    storage.remove_blocklisted("a.com").await?;
+    assert!(matches!(
+        storage.remove_blocklisted("a.com").await,
+        Err(AppError::NotFound)
+    ));
+    assert_eq!(storage.list_blocklist().await?.len(), 1);
+    Ok(())
+}
+
+pub async fn records_client_fetches(storage: impl Storage) -> Result<()> {
+    let fetches = |day, profile: Option<&str>, fetches| ClientFetches {
+        day,
+        client: "10.0.0.0/24".to_string(),
+        profile: profile.map(str::to_string),
+        fetches,
+    };
+    storage
+        .record_client_fetches(vec![fetches(0, None, 2), fetches(86400, Some("lte"), 1)])
+        .await?;
+    storage
+        .record_client_fetches(vec![fetches(86400, Some("lte"), 3)])
+        .await?;
+    assert_eq!(
+        storage.client_fetches(0).await?,
+        vec![fetches(0, None, 2), fetches(86400, Some("lte"), 4)]
+    );
+    assert_eq!(storage.client_fetches(86400).await?.len(), 1);
+
+    assert_eq!(storage.prune_client_fetches(86400).await?, 1);
+    assert_eq!(
+        storage.client_fetches(0).await?,
+        vec![fetches(86400, Some("lte"), 4)]
+    );
+    Ok(())
+}
+
+pub async fn records_audit_log(storage: impl Storage) -> Result<()> {
+    let entry = |at, action: &str, payload| AuditEntry {
+        at,
+        actor: "token".to_string(),
+        ip: Some("127.0.0.1".to_string()),
+        action: action.to_string(),
+        payload,
+    };
+    let add = entry(10, "POST /add", serde_json::json!({ "hosts": ["a.com"] }));
+    let remove = entry(20, "POST /remove", serde_json::Value::Null);
+    storage.record_audit(add.clone()).await?;
+    storage.record_audit(remove.clone()).await?;
+    storage
+        .record_audit(AuditEntry {
+            actor: "server".to_string(),
+            ip: None,
+            ..entry(20, "regenerate", serde_json::json!({ "hash": "abc" }))
+        })
+        .await?;
+
+    let log = storage.audit_log(0, 10).await?;
+    assert_eq!(log.len(), 3);
+    assert_eq!(log[0].action, "regenerate");
+    assert_eq!(log[0].ip, None);
+    assert_eq!(log[1..], [remove.clone(), add]);
+    assert_eq!(storage.audit_log(20, 10).await?.len(), 2);
+    assert_eq!(storage.audit_log(0, 2).await?[1], remove);
+
+    assert_eq!(storage.prune_audit(20).await?, 1);
+    assert_eq!(storage.audit_log(0, 10).await?.len(), 2);
+    assert_eq!(storage.prune_audit(20).await?, 0);
+    Ok(())
+}
+
+pub async fn stores_profiles(storage: impl Storage) -> Result<()> {
+    let mut profile = Profile {
+        name: "lte".to_string(),
+        proxy: "PROXY 10.0.0.1:3128".to_string(),
+        tags: Vec::new(),
+    };
+    storage.set_profile(profile.clone()).await?;
+    profile.proxy = "SOCKS5 10.0.0.1:1080".to_string();
+    profile.tags = vec!["video".to_string(), "work".to_string()];
+    storage.set_profile(profile.clone()).await?;
+    assert_eq!(storage.get_profile("lte").await?, profile);
+    assert_eq!(storage.list_profiles().await?, vec![profile]);
+
+    storage.remove_profile("lte").await?;
+    assert_eq!(storage.get_profile("lte").await, Err(AppError::NotFound));
+    assert_eq!(storage.remove_profile("lte").await, Err(AppError::NotFound));
+    Ok(())
+}
+
+pub async fn leases_expire(storage: impl Storage) -> Result<()> {
+    let minute = Duration::from_secs(60);
+    assert!(storage.try_lease("regen", "a", minute).await?);
+    assert!(!storage.try_lease("regen", "b", minute).await?);
+    assert!(storage.try_lease("regen", "a", Duration::ZERO).await?);
+    assert!(storage.try_lease("regen", "b", minute).await?);
+    assert!(!storage.try_lease("regen", "a", minute).await?);
+
+    assert_eq!(storage.regeneration_requests().await?, 0);
+    storage.request_regeneration().await?;
+    storage.request_regeneration().await?;
+    assert_eq!(storage.regeneration_requests().await?, 2);
+    Ok(())
+}
+
+pub async fn stores_encodings(storage: impl Storage) -> Result<()> {
+    let pac = Pac::generate(vec!["a".to_string()]);
+    let encodings = PacEncodings::compress(&pac.file);
+    assert_eq!(
+        storage.upload_encodings(&pac.hash, &encodings).await,
+        Err(AppError::NotFound)
+    );
+
+    storage.upload_file(&pac).await?;
+    assert_eq!(
+        storage.get_encodings(&pac.hash).await?,
+        PacEncodings::default()
+    );
+    let gzip_only = PacEncodings {
+        br: None,
+        ..encodings.clone()
+    };
+    storage.upload_encodings(&pac.hash, &gzip_only).await?;
+    assert_eq!(storage.get_encodings(&pac.hash).await?, gzip_only);
+    storage.upload_encodings(&pac.hash, &encodings).await?;
+    assert_eq!(storage.get_encodings(&pac.hash).await?, encodings);
+    Ok(())
+}
+
+pub async fn stores_networks(storage: impl Storage) -> Result<()> {
+    let network = |name: &str, network: &str| NetworkProfile {
+        name: name.to_string(),
+        network: network.to_string(),
+        proxy: "DIRECT".to_string(),
+    };
+    storage.set_network(network("vpn", "10.8.0.0/24")).await?;
+    storage
+        .set_network(network("home", "192.168.1.0/24"))
+        .await?;
+    let mut vpn = network("vpn", "10.9.0.0/16");
+    vpn.proxy = "PROXY 10.9.0.1:3128".to_string();
+    storage.set_network(vpn.clone()).await?;
+    assert_eq!(
+        storage.list_networks().await?,
+        vec![network("home", "192.168.1.0/24"), vpn]
+    );
+
+    storage.remove_network("home").await?;
+    assert_eq!(storage.list_networks().await?.len(), 1);
+    assert_eq!(
+        storage.remove_network("home").await,
+        Err(AppError::NotFound)
+    );
+    Ok(())
+}
+
+pub async fn stores_ip_ranges(storage: impl Storage) -> Result<()> {
+    assert!(storage.list_ip_ranges().await?.is_empty());
+    let ranges = |ranges: &[&str]| ranges.iter().map(|r| r.to_string()).collect::<Vec<_>>();
+    storage
+        .set_ip_ranges(ranges(&["203.0.113.0/24", "10.0.0.0/8", "10.0.0.0/8"]))
+        .await?;
+    assert_eq!(
+        storage.list_ip_ranges().await?,
+        ranges(&["10.0.0.0/8", "203.0.113.0/24"])
+    );
+    storage.set_ip_ranges(vec![]).await?;
+    assert!(storage.list_ip_ranges().await?.is_empty());
+    Ok(())
+}
+
+pub async fn stages_files(storage: impl Storage) -> Result<()> {
+    assert_eq!(storage.staged_hash().await?, None);
+    assert_eq!(storage.promote_staged().await, Err(AppError::NotFound));
+    let published = Pac::generate(vec!["a".to_string()]);
+    let staged = Pac::generate(vec!["a".to_string(), "b".to_string()]);
+    storage.upload_file(&published).await?;
+    storage.set_latest(&published.hash).await?;
+    storage.upload_file(&staged).await?;
+    storage.set_staged_hash(Some(staged.hash.clone())).await?;
+    assert_eq!(storage.staged_hash().await?, Some(staged.hash.clone()));
+    assert_eq!(storage.latest_hash().await?, published.hash);
+
+    assert_eq!(storage.promote_staged().await?, staged.hash);
+    assert_eq!(storage.latest_hash().await?, staged.hash);
+    assert_eq!(storage.staged_hash().await?, None);
+
+    storage
+        .set_staged_hash(Some(published.hash.clone()))
+        .await?;
+    storage.set_staged_hash(None).await?;
+    assert_eq!(storage.staged_hash().await?, None);
+    assert_eq!(storage.latest_hash().await?, staged.hash);
+    Ok(())
+}
@@ -1,14 +1,43 @@
-use axum::{body::Body, http::Request, response::Response};
+use axum::{
+    body::{Body, HttpBody as _},
+    http::{HeaderMap, Request},
+    response::Response,
+};
 use std::time::Duration;
 use tracing::Span;
 
+/// W3C trace context header set by a traced proxy or client in front
+const TRACEPARENT: &str = "traceparent";
+
 pub(crate) fn trace_layer_make_span_with(request: &Request<Body>) -> Span {
-    tracing::error_span!("request",
+    let span = tracing::error_span!("request",
         uri = %request.uri(),
         method = %request.method(),
+        trace_id = tracing::field::Empty,
         status = tracing::field::Empty,
-        latency = tracing::field::Empty,
-    )
+        latency_ms = tracing::field::Empty,
+        bytes = tracing::field::Empty,
+    );
+    if let Some(trace_id) = trace_id(request.headers()) {
+        span.record("trace_id", trace_id);
+    }
+    span
+}
+
+/// Trace the request belongs to from its `traceparent`, e.g.
+/// `00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01`
+pub(crate) fn trace_id(headers: &HeaderMap) -> Option<&str> {
+    let value = headers.get(TRACEPARENT)?.to_str().ok()?;
+    let mut parts = value.trim().split('-');
+    let (version, trace_id, parent_id) = (parts.next()?, parts.next()?, parts.next()?);
+    let hex = |s: &str, len: usize| s.len() == len && s.bytes().all(|b| b.is_ascii_hexdigit());
+    // All zeros is an invalid id, `ff` an invalid version
+    let valid = hex(version, 2)
+        && version != "ff"
+        && hex(trace_id, 32)
+        && trace_id.bytes().any(|b| b != b'0')
+        && hex(parent_id, 16);
+    valid.then_some(trace_id)
 }
 
 pub(crate) fn trace_layer_on_request(_request: &Request<Body>, _span: &Span) {
@@ -16,10 +45,41 @@ pub(crate) fn trace_layer_on_request(_request: &Request<Body>, _span: &Span) {
 }
 
 pub(crate) fn trace_layer_on_response(response: &Response<Body>, latency: Duration, span: &Span) {
-    span.record(
-        "latency",
-        tracing::field::display(format!("{}μs", latency.as_micros())),
-    );
-    span.record("status", tracing::field::display(response.status()));
+    span.record("latency_ms", latency.as_secs_f64() * 1000.0);
+    span.record("status", response.status().as_u16());
+    // Streamed bodies, e.g. compressed ones, have no known size
+    if let Some(bytes) = response.body().size_hint().exact() {
+        span.record("bytes", bytes);
+    }
     tracing::trace!("Responded");
 }
+
+#[cfg(test)]
+mod test {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    #[test]
+    fn reads_trace_id_from_traceparent() {
+        let trace_id_of = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(TRACEPARENT, HeaderValue::from_str(value).unwrap());
+            trace_id(&headers).map(str::to_string)
+        };
+        assert_eq!(
+            trace_id_of("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01").as_deref(),
+            Some("4bf92f3577b34da6a3ce929d0e0e4736")
+        );
+        for invalid in [
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e473-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736",
+            "garbage",
+        ] {
+            assert_eq!(trace_id_of(invalid), None, "{invalid}");
+        }
+        assert_eq!(trace_id(&HeaderMap::new()), None);
+    }
+}
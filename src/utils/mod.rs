@@ -1 +1,3 @@
 pub mod color_eyre;
+pub mod time;
+pub mod token;
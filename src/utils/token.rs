@@ -0,0 +1,41 @@
+use argon2::{
+    password_hash::SaltString, Algorithm, Argon2, ParamsBuilder, PasswordHasher, Version,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use ring::rand::{SecureRandom, SystemRandom};
+use tracing::{debug, trace};
+
+/// Argon2 PHC string of `token` for `--token`
+pub fn hash(token: &[u8]) -> String {
+    let mut params = ParamsBuilder::new();
+    params.m_cost(65540).t_cost(3).p_cost(4);
+    debug!("Generating with params {:?}", &params);
+
+    let argon = Argon2::new(
+        Algorithm::Argon2id,
+        Version::V0x13,
+        params.build().expect("Should build params"),
+    );
+    trace!("Argon2 {:?}", argon);
+
+    let salt = SaltString::encode_b64(&random_bytes()).unwrap();
+    trace!("Generated salt");
+
+    argon
+        .hash_password(token, &salt)
+        .expect("Hashed password")
+        .to_string()
+}
+
+/// Random plain token for clients
+pub fn generate() -> String {
+    URL_SAFE_NO_PAD.encode(random_bytes())
+}
+
+fn random_bytes() -> [u8; 32] {
+    let mut buf = [0; 32];
+    SystemRandom::new()
+        .fill(&mut buf)
+        .expect("Error generating random values");
+    buf
+}
@@ -0,0 +1,145 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use axum::{
+    body::{to_bytes, Body},
+    extract::{ConnectInfo, Request, State},
+    http::{Response, StatusCode},
+    middleware::Next,
+    response::IntoResponse,
+};
+use serde_json::{json, Value};
+use tracing::error;
+
+use super::ServerState;
+use crate::{storage::AuditEntry, utils::time::unix_now};
+
+/// Bodies are buffered to be logged or checked, larger ones are refused like
+/// axum's default body limit does
+pub const MAX_BODY_SIZE: usize = 2 * 1024 * 1024;
+
+/// Larger bodies are logged as their size and digest
+const MAX_LOGGED_BODY_SIZE: usize = 4 * 1024;
+
+/// Whole state documents, logged as their size and digest whatever their size
+const DIGESTED_PATHS: [&str; 2] = ["/state", "/import"];
+
+/// How an admin request was authenticated and with which admin token, e.g.
+/// `root` or `tenant:<name>`, attached to the request by
+/// [`super::auth::AdminAuth`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Actor {
+    Token(String),
+    /// Exchanged for the token on `/login`
+    Session(String),
+}
+
+impl Actor {
+    /// Logged as e.g. `token:root` or `session:tenant:team`
+    pub fn label(&self) -> String {
+        match self {
+            Self::Token(id) => format!("token:{id}"),
+            Self::Session(id) => format!("session:{id}"),
+        }
+    }
+}
+
+/// Logs successful admin mutations with the caller and request body, runs
+/// inside the auth layer so rejected requests never get here
+pub async fn record(
+    State(server_state): State<Arc<ServerState>>,
+    request: Request<Body>,
+    next: Next,
+) -> Response<Body> {
+    if request.method().is_safe() {
+        return next.run(request).await;
+    }
+    let actor = request
+        .extensions()
+        .get::<Actor>()
+        .map_or_else(|| "anonymous".to_string(), Actor::label);
+    let ip = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip().to_canonical().to_string());
+    let path = request.uri().path();
+    // Nested routers, such as the tenant ones, see their own paths
+    let digested = DIGESTED_PATHS.iter().any(|p| path.ends_with(p));
+    let action = match request.uri().path_and_query() {
+        Some(path) => format!("{} {path}", request.method()),
+        None => format!("{} {}", request.method(), request.uri().path()),
+    };
+
+    let (parts, body) = request.into_parts();
+    let Ok(body) = to_bytes(body, MAX_BODY_SIZE).await else {
+        return StatusCode::PAYLOAD_TOO_LARGE.into_response();
+    };
+    let payload = summarize(&body, digested);
+    let res = next.run(Request::from_parts(parts, Body::from(body))).await;
+    if res.status().is_success() {
+        let entry = AuditEntry {
+            at: unix_now(),
+            actor,
+            ip,
+            action,
+            payload,
+        };
+        // The operation went through already, a missing entry doesn't undo it
+        if let Err(e) = server_state.storage.record_audit(entry).await {
+            error!("Error recording audit entry: {e}");
+        }
+    }
+    res
+}
+
+/// Request body as logged, large or whole state bodies only by size and
+/// blake3 digest
+fn summarize(body: &[u8], digested: bool) -> Value {
+    if body.is_empty() {
+        return Value::Null;
+    }
+    if digested || body.len() > MAX_LOGGED_BODY_SIZE {
+        return json!({
+            "bytes": body.len(),
+            "blake3": blake3::hash(body).to_hex().to_string(),
+        });
+    }
+    serde_json::from_slice(body)
+        .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(body).into_owned()))
+}
+
+/// Logs a file generated by the server itself
+pub async fn record_regeneration(server_state: &ServerState, hash: &str, staged: bool) {
+    let entry = AuditEntry {
+        at: unix_now(),
+        actor: "server".to_string(),
+        ip: None,
+        action: "regenerate".to_string(),
+        payload: json!({ "hash": hash, "staged": staged }),
+    };
+    if let Err(e) = server_state.storage.record_audit(entry).await {
+        error!("Error recording audit entry: {e}");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn summarizes_bodies() {
+        assert_eq!(summarize(b"", false), Value::Null);
+        assert_eq!(
+            summarize(br#"{"host": "a.com"}"#, false),
+            json!({ "host": "a.com" })
+        );
+        assert_eq!(summarize(b"a.com", false), json!("a.com"));
+
+        let digest = json!({
+            "bytes": 2,
+            "blake3": blake3::hash(b"{}").to_hex().to_string(),
+        });
+        assert_eq!(summarize(b"{}", true), digest);
+        let large = vec![b'a'; MAX_LOGGED_BODY_SIZE + 1];
+        assert_eq!(summarize(&large, false)["bytes"], MAX_LOGGED_BODY_SIZE + 1);
+    }
+}
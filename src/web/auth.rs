@@ -1,17 +1,94 @@
+use std::sync::Arc;
+
 use argon2::{Argon2, PasswordHash, PasswordVerifier};
 use axum::{
     body::Body,
     http::{Response, StatusCode},
     response::IntoResponse,
 };
-use ring::constant_time::verify_slices_are_equal;
+use subtle::ConstantTimeEq;
 use tower_http::validate_request::{ValidateRequest, ValidateRequestHeaderLayer};
 use tracing::info;
 
+use super::{audit::Actor, session::SessionStore};
 use crate::error::AppError;
 
-pub fn use_auth_layer(token: String) -> ValidateRequestHeaderLayer<AuthTokenValidator> {
-    ValidateRequestHeaderLayer::custom(AuthTokenValidator::new(token))
+pub fn use_auth_layer(auth: AdminAuth) -> ValidateRequestHeaderLayer<AdminAuth> {
+    ValidateRequestHeaderLayer::custom(auth)
+}
+
+/// Bearer token for scripts, session cookie with a CSRF header for browsers
+#[derive(Clone)]
+pub struct AdminAuth {
+    /// Any of them is accepted with the id it's audited as, tenants take
+    /// their own and the admin token
+    tokens: Vec<(String, AuthTokenValidator)>,
+    sessions: Arc<SessionStore>,
+}
+
+impl AdminAuth {
+    /// Only the admin token, audited as `root`
+    pub fn new(token: String) -> Self {
+        Self::with_tokens(vec![("root".to_string(), token)])
+    }
+
+    /// Tokens by the id they're audited as
+    pub fn with_tokens(tokens: Vec<(String, String)>) -> Self {
+        Self {
+            tokens: tokens
+                .into_iter()
+                .map(|(id, token)| (id, AuthTokenValidator::new(token)))
+                .collect(),
+            sessions: Arc::default(),
+        }
+    }
+
+    /// Id of the token `raw_token` matches
+    pub fn verify(&self, raw_token: &str) -> Result<Option<&str>, AppError> {
+        for (id, token) in self.tokens.iter() {
+            if token.verify(raw_token)? {
+                return Ok(Some(id));
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn sessions(&self) -> &SessionStore {
+        &self.sessions
+    }
+}
+
+impl std::fmt::Debug for AdminAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AdminAuth")
+            .field("sessions", &self.sessions)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<B> ValidateRequest<B> for AdminAuth {
+    type ResponseBody = Body;
+
+    fn validate(
+        &mut self,
+        request: &mut axum::http::Request<B>,
+    ) -> std::result::Result<(), Response<Self::ResponseBody>> {
+        if !request.headers().contains_key("Authorization") {
+            if let Some(id) = self.sessions.authorize(request.headers(), request.method()) {
+                request.extensions_mut().insert(Actor::Session(id));
+                return Ok(());
+            }
+        }
+        let mut res = Err(response_unathorized("Unathorized"));
+        for (id, token) in self.tokens.iter_mut() {
+            res = token.validate(request).map(|()| id.clone());
+            if res.is_ok() {
+                break;
+            }
+        }
+        request.extensions_mut().insert(Actor::Token(res?));
+        Ok(())
+    }
 }
 
 #[derive(Clone)]
@@ -29,6 +106,14 @@ impl AuthTokenValidator {
             AuthTokenValidator::Simple(SimpleAuthTokenValidator::new(token.as_bytes().to_vec()))
         }
     }
+
+    /// Checks a plain token, as sent on `/login`
+    fn verify(&self, raw_token: &str) -> Result<bool, AppError> {
+        match self {
+            Self::Simple(v) => v.verify(raw_token),
+            Self::Argon2(v) => v.verify(raw_token),
+        }
+    }
 }
 
 impl<B> ValidateRequest<B> for AuthTokenValidator {
@@ -54,6 +139,10 @@ impl SimpleAuthTokenValidator {
     pub fn new(token: Vec<u8>) -> Self {
         Self { token }
     }
+
+    fn verify(&self, raw_token: &str) -> Result<bool, AppError> {
+        Ok(raw_token.as_bytes().ct_eq(&self.token).into())
+    }
 }
 
 impl<B> ValidateRequest<B> for SimpleAuthTokenValidator {
@@ -63,10 +152,12 @@ impl<B> ValidateRequest<B> for SimpleAuthTokenValidator {
         &mut self,
         request: &mut axum::http::Request<B>,
     ) -> std::result::Result<(), Response<Self::ResponseBody>> {
-        let raw_token = extract_token(request)?;
-
-        verify_slices_are_equal(raw_token.as_bytes(), &self.token)
-            .map_err(|_| response_unathorized("Unathorized"))
+        let raw_token = extract_token(request).map_err(response_unathorized)?;
+        match self.verify(raw_token) {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(response_unathorized("Unathorized")),
+            Err(e) => Err(e.into_response()),
+        }
     }
 }
 
@@ -79,6 +170,15 @@ impl Argon2AuthTokenValidator {
     pub fn new(token: String) -> Self {
         Self { token }
     }
+
+    fn verify(&self, raw_token: &str) -> Result<bool, AppError> {
+        let Ok(hash) = PasswordHash::new(&self.token) else {
+            return Err(AppError::Other("Server error".to_string()));
+        };
+        let argon = Argon2::default();
+
+        Ok(argon.verify_password(raw_token.as_bytes(), &hash).is_ok())
+    }
 }
 
 impl<B> ValidateRequest<B> for Argon2AuthTokenValidator {
@@ -88,33 +188,28 @@ impl<B> ValidateRequest<B> for Argon2AuthTokenValidator {
         &mut self,
         request: &mut axum::http::Request<B>,
     ) -> std::result::Result<(), Response<Self::ResponseBody>> {
-        let Ok(hash) = PasswordHash::new(&self.token) else {
-            return Err(AppError::Other("Server error".to_string()).into_response());
-        };
-        let raw_token = extract_token(request)?;
-        let argon = Argon2::default();
-
-        argon
-            .verify_password(raw_token.as_bytes(), &hash)
-            .map_err(|_| response_unathorized("Unathorized"))
+        let raw_token = extract_token(request).map_err(response_unathorized)?;
+        match self.verify(raw_token) {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(response_unathorized("Unathorized")),
+            Err(e) => Err(e.into_response()),
+        }
     }
 }
 
-fn extract_token<B>(
-    request: &mut axum::http::Request<B>,
-) -> std::result::Result<String, Response<Body>> {
+/// Bearer token of the request, the error is the message to respond with
+fn extract_token<B>(request: &axum::http::Request<B>) -> std::result::Result<&str, &'static str> {
     let Some(auth_header) = request.headers().get("Authorization") else {
-        return Err(response_unathorized("Missing auth token"));
+        return Err("Missing auth token");
     };
     let Ok(full_token_str) = auth_header.to_str() else {
-        return Err(response_unathorized("Bad token"));
+        return Err("Bad token");
     };
 
     full_token_str
         .trim()
         .strip_prefix("Bearer ")
-        .map(String::from)
-        .ok_or_else(|| response_unathorized("Token should be Bearer"))
+        .ok_or("Token should be Bearer")
 }
 
 fn response_unathorized(msg: impl Into<String>) -> Response<Body> {
@@ -0,0 +1,353 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use axum::body::Bytes;
+use tokio::sync::RwLock;
+
+use crate::{
+    pac::{Pac, PacEncodings},
+    storage::Profile,
+};
+
+/// Last known latest PAC with its precompressed bodies, served on the hot
+/// path and when storage is unavailable
+#[derive(Debug, Default)]
+pub struct LatestPacCache {
+    pac: RwLock<Option<Arc<PrimedPac>>>,
+}
+
+impl LatestPacCache {
+    pub async fn get(&self) -> Option<Arc<Pac>> {
+        self.get_primed().await.map(|p| p.pac.clone())
+    }
+
+    pub async fn get_by_hash(&self, hash: &str) -> Option<Arc<Pac>> {
+        self.get().await.filter(|p| p.hash == hash)
+    }
+
+    pub async fn get_primed(&self) -> Option<Arc<PrimedPac>> {
+        self.pac.read().await.clone()
+    }
+
+    pub async fn get_primed_by_hash(&self, hash: &str) -> Option<Arc<PrimedPac>> {
+        self.get_primed().await.filter(|p| p.pac.hash == hash)
+    }
+
+    /// Compresses `pac` off the runtime and caches it, already cached hashes
+    /// are kept as is
+    pub async fn set(&self, pac: Arc<Pac>) -> Arc<PrimedPac> {
+        if let Some(primed) = self.get_primed_by_hash(&pac.hash).await {
+            return primed;
+        }
+        self.set_primed(PrimedPac::compress(pac).await).await
+    }
+
+    /// Caches a pac primed elsewhere, e.g. from stored encodings
+    pub async fn set_primed(&self, primed: PrimedPac) -> Arc<PrimedPac> {
+        if let Some(primed) = self.get_primed_by_hash(&primed.pac.hash).await {
+            return primed;
+        }
+        let primed = Arc::new(primed);
+        *self.pac.write().await = Some(primed.clone());
+        primed
+    }
+}
+
+/// PAC alongside its gzip and brotli encoded bodies
+#[derive(Debug)]
+pub struct PrimedPac {
+    pub pac: Arc<Pac>,
+    gzip: Option<Bytes>,
+    br: Option<Bytes>,
+}
+
+impl PrimedPac {
+    pub fn new(pac: Arc<Pac>, encodings: PacEncodings) -> Self {
+        Self {
+            pac,
+            gzip: encodings.gzip.map(Bytes::from),
+            br: encodings.br.map(Bytes::from),
+        }
+    }
+
+    /// Compresses off the runtime, served uncompressed when that fails
+    pub async fn compress(pac: Arc<Pac>) -> Self {
+        let file = pac.clone();
+        match tokio::task::spawn_blocking(move || PacEncodings::compress(&file.file)).await {
+            Ok(encodings) => Self::new(pac, encodings),
+            Err(e) => {
+                tracing::error!("Error compressing pac: {e}");
+                Self::new(pac, PacEncodings::default())
+            }
+        }
+    }
+
+    /// Bodies to store along with the file
+    pub fn encodings(&self) -> PacEncodings {
+        PacEncodings {
+            gzip: self.gzip.as_ref().map(|b| b.to_vec()),
+            br: self.br.as_ref().map(|b| b.to_vec()),
+        }
+    }
+
+    /// Body for the `Accept-Encoding` header value with its content encoding,
+    /// brotli is preferred over gzip
+    pub fn body(&self, accept_encoding: &str) -> (Option<&'static str>, Bytes) {
+        let accepts = |name: &str| {
+            accept_encoding.split(',').any(|item| {
+                let mut parts = item.split(';').map(str::trim);
+                parts.next() == Some(name)
+                    && parts
+                        .filter_map(|p| p.strip_prefix("q="))
+                        .all(|q| q.parse::<f32>().is_ok_and(|q| q > 0.0))
+            })
+        };
+        match (&self.br, &self.gzip) {
+            (Some(br), _) if accepts("br") => (Some("br"), br.clone()),
+            (_, Some(gzip)) if accepts("gzip") => (Some("gzip"), gzip.clone()),
+            _ => (None, Bytes::from(self.pac.file.clone())),
+        }
+    }
+}
+
+/// Profile variants of the latest PAC, keyed by profile name
+#[derive(Debug, Default)]
+pub struct ProfilePacCache {
+    pacs: RwLock<HashMap<String, ProfilePac>>,
+}
+
+#[derive(Debug)]
+struct ProfilePac {
+    base_hash: String,
+    profile: Profile,
+    /// Hosts version the entries were read at
+    hosts_version: i64,
+    pac: Arc<PrimedPac>,
+}
+
+impl ProfilePacCache {
+    /// Variant of `base_hash` generated with the current proxy and tags of
+    /// `profile` from the hosts at `hosts_version`
+    pub async fn get(
+        &self,
+        profile: &Profile,
+        base_hash: &str,
+        hosts_version: i64,
+    ) -> Option<Arc<PrimedPac>> {
+        self.pacs
+            .read()
+            .await
+            .get(&profile.name)
+            .filter(|p| {
+                p.base_hash == base_hash
+                    && p.profile == *profile
+                    && p.hosts_version == hosts_version
+            })
+            .map(|p| p.pac.clone())
+    }
+
+    pub async fn set(
+        &self,
+        profile: &Profile,
+        base_hash: &str,
+        hosts_version: i64,
+        pac: Arc<PrimedPac>,
+    ) {
+        let entry = ProfilePac {
+            base_hash: base_hash.to_string(),
+            profile: profile.clone(),
+            hosts_version,
+            pac,
+        };
+        self.pacs.write().await.insert(profile.name.clone(), entry);
+    }
+
+    /// Drops every variant, settings they were generated with changed
+    pub async fn clear(&self) {
+        self.pacs.write().await.clear();
+    }
+}
+
+/// Serialized `/list` body, valid until the next host change
+#[derive(Debug, Default)]
+pub struct ListCache {
+    seq: AtomicU64,
+    list: RwLock<Option<CachedList>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct CachedList {
+    seq: u64,
+    /// The quoted revision, so it can go back as `If-Match` on mutations
+    pub etag: String,
+    /// Storage hosts version the body was loaded at
+    pub revision: i64,
+    pub body: Bytes,
+}
+
+impl ListCache {
+    /// Current change sequence, read it before loading the list
+    pub fn seq(&self) -> u64 {
+        self.seq.load(Ordering::Acquire)
+    }
+
+    /// Bumps the change sequence, dropping the cached body
+    pub fn invalidate(&self) {
+        self.seq.fetch_add(1, Ordering::AcqRel);
+    }
+
+    pub async fn get(&self) -> Option<CachedList> {
+        let seq = self.seq();
+        self.list.read().await.clone().filter(|l| l.seq == seq)
+    }
+
+    /// Caches `body` loaded at change sequence `seq` and hosts version
+    /// `revision`
+    pub async fn set(&self, seq: u64, revision: i64, body: String) -> CachedList {
+        let list = CachedList {
+            seq,
+            etag: format!("\"{revision}\""),
+            revision,
+            body: Bytes::from(body),
+        };
+        *self.list.write().await = Some(list.clone());
+        list
+    }
+}
+
+/// Recently missed hashes, keeps path scanners off the database
+#[derive(Debug)]
+pub struct NegativeCache {
+    capacity: usize,
+    ttl: Duration,
+    misses: Mutex<NegativeEntries>,
+}
+
+#[derive(Debug, Default)]
+struct NegativeEntries {
+    expires: HashMap<String, Instant>,
+    /// Insertion order, oldest first
+    order: VecDeque<String>,
+}
+
+impl NegativeCache {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            capacity,
+            ttl,
+            misses: Mutex::default(),
+        }
+    }
+
+    pub fn contains(&self, hash: &str) -> bool {
+        let misses = self.misses.lock().expect("Poisoned negative cache");
+        misses
+            .expires
+            .get(hash)
+            .is_some_and(|at| *at > Instant::now())
+    }
+
+    pub fn insert(&self, hash: &str) {
+        let mut misses = self.misses.lock().expect("Poisoned negative cache");
+        let expires = Instant::now() + self.ttl;
+        if misses.expires.insert(hash.to_string(), expires).is_some() {
+            misses.order.retain(|h| h != hash);
+        }
+        misses.order.push_back(hash.to_string());
+        while misses.order.len() > self.capacity {
+            if let Some(oldest) = misses.order.pop_front() {
+                misses.expires.remove(&oldest);
+            }
+        }
+    }
+
+    /// Drops `hash` once a file is stored under it
+    pub fn remove(&self, hash: &str) {
+        let mut misses = self.misses.lock().expect("Poisoned negative cache");
+        if misses.expires.remove(hash).is_some() {
+            misses.order.retain(|h| h != hash);
+        }
+    }
+
+    /// Forgets every miss, files may have been stored by someone else
+    pub fn clear(&self) {
+        let mut misses = self.misses.lock().expect("Poisoned negative cache");
+        misses.expires.clear();
+        misses.order.clear();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn primes_latest() {
+        let cache = LatestPacCache::default();
+        let pac = Arc::new(Pac::generate(vec!["example.com".to_string()]));
+        let primed = cache.set(pac.clone()).await;
+        assert!(Arc::ptr_eq(&primed, &cache.set(pac.clone()).await));
+        assert_eq!(primed.encodings(), PacEncodings::compress(&pac.file));
+
+        let (encoding, body) = primed.body("gzip, deflate, br");
+        assert_eq!(encoding, Some("br"));
+        let mut file = String::new();
+        brotli::Decompressor::new(body.as_ref(), 4096)
+            .read_to_string(&mut file)
+            .unwrap();
+        assert_eq!(file, pac.file);
+
+        let (encoding, body) = primed.body("br;q=0, gzip");
+        assert_eq!(encoding, Some("gzip"));
+        let mut file = String::new();
+        flate2::read::GzDecoder::new(body.as_ref())
+            .read_to_string(&mut file)
+            .unwrap();
+        assert_eq!(file, pac.file);
+
+        assert_eq!(primed.body("identity").0, None);
+    }
+
+    #[test]
+    fn evicts_misses() {
+        let cache = NegativeCache::new(2, Duration::from_secs(60));
+        for hash in ["a", "b", "c"] {
+            cache.insert(hash);
+        }
+        assert!(!cache.contains("a"));
+        assert!(cache.contains("b") && cache.contains("c"));
+
+        cache.remove("b");
+        assert!(!cache.contains("b"));
+        cache.clear();
+        assert!(!cache.contains("c"));
+
+        let expired = NegativeCache::new(2, Duration::ZERO);
+        expired.insert("a");
+        assert!(!expired.contains("a"));
+    }
+
+    #[tokio::test]
+    async fn invalidates_list() {
+        let cache = ListCache::default();
+        assert!(cache.get().await.is_none());
+
+        let list = cache.set(cache.seq(), 1, "[]".to_string()).await;
+        assert_eq!(list.etag, "\"1\"");
+        assert_eq!(cache.get().await.map(|l| l.etag), Some(list.etag.clone()));
+
+        cache.invalidate();
+        assert!(cache.get().await.is_none());
+        let stale = cache.seq() - 1;
+        cache.set(stale, 1, "[\"a\"]".to_string()).await;
+        assert!(cache.get().await.is_none());
+    }
+}
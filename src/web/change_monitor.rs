@@ -0,0 +1,125 @@
+use std::{
+    collections::VecDeque,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use serde_json::json;
+use tracing::{error, warn};
+
+use crate::{http_client::HttpClient, instrument::metrics::HOST_CHANGE_ALERTS};
+
+/// Counts host changes in a sliding window to flag bursts of mutations
+#[derive(Debug)]
+pub struct ChangeMonitor {
+    threshold: usize,
+    window: Duration,
+    state: Mutex<MonitorState>,
+    webhook: Option<(HttpClient, String)>,
+}
+
+#[derive(Debug, Default)]
+struct MonitorState {
+    events: VecDeque<(Instant, usize)>,
+    total: usize,
+    alerted_at: Option<Instant>,
+}
+
+impl ChangeMonitor {
+    pub fn new(threshold: usize, window: Duration) -> Self {
+        Self {
+            threshold,
+            window,
+            state: Mutex::default(),
+            webhook: None,
+        }
+    }
+
+    pub fn with_webhook(mut self, client: HttpClient, url: String) -> Self {
+        self.webhook = Some((client, url));
+        self
+    }
+
+    /// Records `changed` hosts and raises an alert when over the threshold
+    pub fn observe(&self, changed: usize) {
+        let Some(total) = self.record(changed) else {
+            return;
+        };
+        let window = self.window.as_secs();
+        warn!("{total} hosts changed within {window}s, check for a leaked token or runaway automation");
+        metrics::counter!(HOST_CHANGE_ALERTS).increment(1);
+
+        if let Some((client, url)) = self.webhook.clone() {
+            tokio::spawn(async move {
+                let body = json!({
+                    "text": format!("qpac: {total} hosts changed within {window}s"),
+                    "changed": total,
+                    "window_secs": window,
+                });
+                let res = client.send(|c| c.post(&url).json(&body)).await;
+                if let Err(e) = res.and_then(|r| r.error_for_status()) {
+                    error!("Error sending change alert webhook: {e}");
+                }
+            });
+        }
+    }
+
+    /// Returns changes within the window once it goes over the threshold,
+    /// at most once per window
+    fn record(&self, changed: usize) -> Option<usize> {
+        self.record_at(Instant::now(), changed)
+    }
+
+    fn record_at(&self, now: Instant, changed: usize) -> Option<usize> {
+        let mut state = self.state.lock().expect("Change monitor lock poisoned");
+        while let Some(&(at, n)) = state.events.front() {
+            if now.duration_since(at) <= self.window {
+                break;
+            }
+            state.events.pop_front();
+            state.total -= n;
+        }
+        state.events.push_back((now, changed));
+        state.total += changed;
+
+        if state.total <= self.threshold {
+            return None;
+        }
+        if let Some(at) = state.alerted_at {
+            if now.duration_since(at) <= self.window {
+                return None;
+            }
+        }
+        state.alerted_at = Some(now);
+        Some(state.total)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn alerts_once_per_window() {
+        let monitor = ChangeMonitor::new(5, Duration::from_secs(60));
+        let start = Instant::now();
+        assert_eq!(monitor.record_at(start, 3), None);
+        assert_eq!(
+            monitor.record_at(start + Duration::from_secs(1), 3),
+            Some(6)
+        );
+        assert_eq!(monitor.record_at(start + Duration::from_secs(2), 10), None);
+        assert_eq!(
+            monitor.record_at(start + Duration::from_secs(70), 10),
+            Some(10)
+        );
+    }
+
+    #[test]
+    fn forgets_changes_outside_window() {
+        let monitor = ChangeMonitor::new(5, Duration::from_secs(60));
+        let start = Instant::now();
+        assert_eq!(monitor.record_at(start, 5), None);
+        assert_eq!(monitor.record_at(start + Duration::from_secs(61), 5), None);
+    }
+}
@@ -0,0 +1,112 @@
+use std::sync::Arc;
+
+use reqwest::Url;
+use serde::Serialize;
+
+use crate::{
+    error::AppError,
+    pac::{
+        eval::{EvalEnv, Evaluator},
+        Pac,
+    },
+};
+
+/// Decision of a pac for one url
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Decision {
+    pub url: String,
+    /// What `FindProxyForURL` gets as its host argument
+    pub host: String,
+    pub hash: String,
+    pub proxy: String,
+    /// Whether the first entry of the chain is `DIRECT`
+    pub direct: bool,
+}
+
+/// Url and host a browser would pass for `raw`, a bare host counts as https
+pub fn target(raw: &str) -> Result<(String, String), AppError> {
+    let raw = raw.trim();
+    let url = Url::parse(raw)
+        .ok()
+        .filter(|u| u.has_host())
+        .or_else(|| Url::parse(&format!("https://{raw}")).ok())
+        .filter(|u| u.has_host())
+        .ok_or_else(|| AppError::Validation {
+            field: "url".to_string(),
+            message: "expected a url or a host".to_string(),
+        })?;
+    let host = url
+        .host_str()
+        .unwrap_or_default()
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_string();
+    Ok((url.to_string(), host))
+}
+
+/// Runs the file on a blocking thread, it's evaluated from scratch every time
+pub async fn evaluate(
+    pac: Arc<Pac>,
+    url: String,
+    host: String,
+    env: EvalEnv,
+) -> Result<Decision, AppError> {
+    tokio::task::spawn_blocking(move || {
+        let proxy = Evaluator::new(&pac.file, &env)
+            .and_then(|mut eval| eval.find_proxy(&url, &host))
+            .map_err(|e| AppError::Other(e.to_string()))?;
+        let direct = proxy
+            .split(';')
+            .map(str::trim)
+            .find(|p| !p.is_empty())
+            .is_some_and(|p| p.eq_ignore_ascii_case("DIRECT"));
+        Ok(Decision {
+            url,
+            host,
+            hash: pac.hash.clone(),
+            proxy,
+            direct,
+        })
+    })
+    .await
+    .map_err(|e| AppError::Other(e.to_string()))?
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pac::DEFAULT_PROXY;
+
+    #[test]
+    fn parses_targets() {
+        let target = |raw| target(raw).map(|(_, host)| host);
+        assert_eq!(
+            target("https://Example.com/a?b"),
+            Ok("example.com".to_string())
+        );
+        assert_eq!(target(" example.com "), Ok("example.com".to_string()));
+        assert_eq!(target("example.com:8080/x"), Ok("example.com".to_string()));
+        assert_eq!(
+            target("http://münchen.de"),
+            Ok("xn--mnchen-3ya.de".to_string())
+        );
+        assert_eq!(target("http://[::1]:80/"), Ok("::1".to_string()));
+        assert!(matches!(target(""), Err(AppError::Validation { .. })));
+        assert!(matches!(target("a b"), Err(AppError::Validation { .. })));
+    }
+
+    #[tokio::test]
+    async fn evaluates_decisions() -> Result<(), AppError> {
+        let pac = Arc::new(Pac::generate(vec!["example.com".to_string()]));
+        let (url, host) = target("example.com")?;
+        let decision = evaluate(pac.clone(), url, host, EvalEnv::default()).await?;
+        assert_eq!(decision.url, "https://example.com/");
+        assert_eq!(decision.proxy, DEFAULT_PROXY);
+        assert!(!decision.direct);
+        assert_eq!(decision.hash, pac.hash);
+
+        let (url, host) = target("other.com")?;
+        assert!(evaluate(pac, url, host, EvalEnv::default()).await?.direct);
+        Ok(())
+    }
+}
@@ -0,0 +1,50 @@
+use std::time::Duration;
+
+use axum::http::HeaderMap;
+
+use crate::error::AppError;
+
+/// Milliseconds the caller is still waiting for the response
+pub const DEADLINE_HEADER: &str = "x-request-deadline-ms";
+
+/// Time budget asked for by the caller, capped at `max`
+pub fn requested(headers: &HeaderMap, max: Duration) -> Result<Option<Duration>, AppError> {
+    let Some(value) = headers.get(DEADLINE_HEADER) else {
+        return Ok(None);
+    };
+    let ms: u64 = value
+        .to_str()
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+        .ok_or_else(|| AppError::Validation {
+            field: DEADLINE_HEADER.to_string(),
+            message: "expected milliseconds".to_string(),
+        })?;
+    Ok(Some(Duration::from_millis(ms).min(max)))
+}
+
+#[cfg(test)]
+mod test {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    #[test]
+    fn caps_requested_deadline() {
+        let max = Duration::from_secs(30);
+        let mut headers = HeaderMap::new();
+        assert!(matches!(requested(&headers, max), Ok(None)));
+
+        headers.insert(DEADLINE_HEADER, HeaderValue::from_static("1500"));
+        assert!(
+            matches!(requested(&headers, max), Ok(Some(d)) if d == Duration::from_millis(1500))
+        );
+        headers.insert(DEADLINE_HEADER, HeaderValue::from_static("3600000"));
+        assert!(matches!(requested(&headers, max), Ok(Some(d)) if d == max));
+        headers.insert(DEADLINE_HEADER, HeaderValue::from_static("soon"));
+        assert!(matches!(
+            requested(&headers, max),
+            Err(AppError::Validation { .. })
+        ));
+    }
+}
@@ -0,0 +1,166 @@
+use std::collections::BTreeMap;
+
+use crate::{
+    args::GenerateArgs,
+    error::AppError,
+    storage::{HostEntry, HostPatch, HostsDiff, Storage},
+};
+
+use super::{pac_from_entries, PacConfig};
+
+/// In-memory copy of the host list mutations are replayed on, mirrors the
+/// storage semantics without committing anything
+#[derive(Debug)]
+pub struct DryRun {
+    entries: BTreeMap<String, HostEntry>,
+    config: PacConfig,
+}
+
+impl DryRun {
+    pub async fn load(storage: &dyn Storage, generate: GenerateArgs) -> Result<Self, AppError> {
+        let entries = storage
+            .host_entries()
+            .await?
+            .into_iter()
+            .map(|e| (e.host.clone(), e))
+            .collect();
+        let config = PacConfig::load(storage, generate).await?;
+        Ok(Self { entries, config })
+    }
+
+    /// Only the tags and kind of `patch` are replayed, the rest doesn't
+    /// change what `/add` generates
+    pub fn add(&mut self, host: String, patch: &HostPatch) -> Result<(), AppError> {
+        if self.entries.contains_key(&host) {
+            return Err(AppError::PreconditionFailed(
+                "Host already exists".to_string(),
+            ));
+        }
+        self.upsert(host, patch);
+        Ok(())
+    }
+
+    /// Like [`DryRun::add`], a listed host gets the tags and kind `patch` sets
+    /// instead
+    pub fn upsert(&mut self, host: String, patch: &HostPatch) {
+        let entry = self.entries.entry(host.clone()).or_insert(HostEntry {
+            host,
+            ..Default::default()
+        });
+        if let Some(tags) = &patch.tags {
+            entry.tags = tags.clone();
+        }
+        if let Some(kind) = patch.kind {
+            entry.kind = kind;
+        }
+    }
+
+    pub fn remove(&mut self, host: &str) -> Result<(), AppError> {
+        self.entries
+            .remove(host)
+            .map(|_| ())
+            .ok_or(AppError::NotFound)
+    }
+
+    pub fn set_pinned(&mut self, host: &str, pinned: bool) -> Result<(), AppError> {
+        let entry = self.entries.get_mut(host).ok_or(AppError::NotFound)?;
+        entry.pinned = pinned;
+        Ok(())
+    }
+
+    pub fn remove_hosts_by_tag(&mut self, tag: &str) -> Vec<String> {
+        let removed: Vec<String> = self
+            .entries
+            .values()
+            .filter(|e| !e.pinned && e.tags.iter().any(|t| t == tag))
+            .map(|e| e.host.clone())
+            .collect();
+        for host in removed.iter() {
+            self.entries.remove(host);
+        }
+        removed
+    }
+
+    pub fn apply_diff(&mut self, diff: &HostsDiff) {
+        for host in diff.removed.iter() {
+            self.entries.remove(host);
+        }
+        for host in diff.added.iter() {
+            // Already present hosts never end up in a diff
+            let _ = self.add(host.clone(), &HostPatch::default());
+        }
+    }
+
+    /// Hash the latest pac would have after the replayed changes
+    pub fn hash(&self) -> String {
+        pac_from_entries(self.entries.values(), &self.config).hash
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        error::Result,
+        pac::{JsTarget, MatchStrategy},
+        rules::EntryKind,
+        storage::{memory_storage::MemoryStorage, ProxyGroup},
+    };
+
+    #[tokio::test]
+    async fn predicts_hash() -> Result<()> {
+        let storage = MemoryStorage::default();
+        for s in ["a", "b"] {
+            storage.add_host(s, HostPatch::default()).await?;
+        }
+        storage.set_tags("b", vec!["t".to_string()]).await?;
+        storage.set_proxy("PROXY 10.0.0.1:3128").await?;
+        storage
+            .set_group(ProxyGroup {
+                name: "work".to_string(),
+                proxy: "PROXY 10.0.0.2:3128".to_string(),
+                schedule: None,
+            })
+            .await?;
+        let patch = HostPatch {
+            group: Some(Some("work".to_string())),
+            ..Default::default()
+        };
+        storage.update_host("a", patch).await?;
+        let generate = GenerateArgs {
+            match_strategy: MatchStrategy::Trie,
+            ipv6: true,
+            resolve_ip_ranges: true,
+            js_target: JsTarget::Es2015,
+        };
+        let config = PacConfig::load(&storage, generate).await?;
+
+        let mut dry = DryRun::load(&storage, generate).await?;
+        assert_eq!(
+            dry.hash(),
+            pac_from_entries(&storage.host_entries().await?, &config).hash
+        );
+        let suffix = HostPatch {
+            kind: Some(EntryKind::Suffix),
+            ..Default::default()
+        };
+        assert!(dry.add("a".to_string(), &HostPatch::default()).is_err());
+        assert_eq!(dry.remove("z"), Err(AppError::NotFound));
+        dry.add("c".to_string(), &suffix)?;
+        assert_eq!(dry.remove_hosts_by_tag("t"), vec!["b"]);
+        dry.upsert("a".to_string(), &suffix);
+        // Upserting without tags or kind keeps them
+        dry.upsert("c".to_string(), &HostPatch::default());
+
+        storage.add_host("c", HostPatch::default()).await?;
+        storage.update_host("c", suffix.clone()).await?;
+        storage.remove_hosts_by_tag("t").await?;
+        storage.upsert_host("a", suffix).await?;
+        storage.upsert_host("c", HostPatch::default()).await?;
+        assert_eq!(
+            dry.hash(),
+            pac_from_entries(&storage.host_entries().await?, &config).hash
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,426 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    net::IpAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use axum::{extract::ConnectInfo, Router};
+use hyper::{body::Incoming, Request};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo, TokioTimer},
+    server::conn::auto,
+};
+use socket2::{SockRef, TcpKeepalive};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, watch},
+    time::Instant,
+};
+use tower::Service;
+use tracing::{debug, error, info, trace, warn};
+
+use crate::{args::ServeArgs, error::Result, instrument::metrics::CONNECTIONS_REJECTED};
+
+/// Binds `--bind` or adopts the socket passed with `--bind-fd`
+pub async fn bind(args: &ServeArgs) -> Result<TcpListener> {
+    let listener = match args.bind_fd {
+        Some(fd) => from_fd(fd)?,
+        None => TcpListener::bind(args.bind).await?,
+    };
+    tracing::info!("Listening on {}", listener.local_addr()?);
+    Ok(listener)
+}
+
+#[cfg(unix)]
+fn from_fd(fd: i32) -> Result<TcpListener> {
+    use std::os::fd::FromRawFd;
+
+    tracing::debug!("Using pre-opened socket fd {fd}");
+    // SAFETY: the supervisor hands the bound socket over to this process
+    let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+    listener.set_nonblocking(true)?;
+    Ok(TcpListener::from_std(listener)?)
+}
+
+#[cfg(not(unix))]
+fn from_fd(_fd: i32) -> Result<TcpListener> {
+    Err(color_eyre::eyre::eyre!("--bind-fd is only supported on unix").into())
+}
+
+/// Per connection socket and protocol settings
+#[derive(Debug, Clone)]
+pub struct ConnOptions {
+    pub tcp_nodelay: bool,
+    pub tcp_keepalive: Option<Duration>,
+    pub tcp_keepalive_interval: Option<Duration>,
+    /// Idle connections are closed after it, `Some(ZERO)` disables HTTP/1
+    /// keep-alive
+    pub http1_keepalive_timeout: Option<Duration>,
+    pub http1_header_read_timeout: Option<Duration>,
+    pub max_connections_per_ip: Option<usize>,
+}
+
+impl From<&ServeArgs> for ConnOptions {
+    fn from(args: &ServeArgs) -> Self {
+        Self {
+            tcp_nodelay: args.tcp_nodelay,
+            tcp_keepalive: args.tcp_keepalive.map(Duration::from_secs),
+            tcp_keepalive_interval: args.tcp_keepalive_interval.map(Duration::from_secs),
+            http1_keepalive_timeout: args.http1_keepalive_timeout.map(Duration::from_secs),
+            http1_header_read_timeout: args.http1_header_read_timeout.map(Duration::from_secs),
+            max_connections_per_ip: args.max_connections_per_ip.map(|n| n as usize),
+        }
+    }
+}
+
+/// Open connection counts per client IP
+#[derive(Debug, Clone)]
+struct ConnLimiter {
+    max: usize,
+    open: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+/// Held for the lifetime of a connection
+#[derive(Debug)]
+struct ConnPermit {
+    ip: IpAddr,
+    open: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl ConnLimiter {
+    fn new(max: usize) -> Self {
+        Self {
+            max,
+            open: Arc::default(),
+        }
+    }
+
+    fn acquire(&self, ip: IpAddr) -> Option<ConnPermit> {
+        // Mapped IPv4 addresses count towards the IPv4 client
+        let ip = ip.to_canonical();
+        let mut open = self.open.lock().expect("Poisoned connection counts");
+        let count = open.entry(ip).or_default();
+        if *count >= self.max {
+            return None;
+        }
+        *count += 1;
+        Some(ConnPermit {
+            ip,
+            open: self.open.clone(),
+        })
+    }
+}
+
+impl Drop for ConnPermit {
+    fn drop(&mut self) {
+        let mut open = self.open.lock().expect("Poisoned connection counts");
+        if let Some(count) = open.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// Time open connections get to finish after shutdown was requested
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Resolves on ctrl-c or SIGTERM
+pub async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("Error listening for ctrl-c: {e}");
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut s) => {
+                s.recv().await;
+            }
+            Err(e) => {
+                error!("Error listening for SIGTERM: {e}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+    info!("Shutting down");
+}
+
+/// Accept loop serving `app` until `shutdown` resolves, open connections are
+/// then drained. Handlers can extract `ConnectInfo<SocketAddr>`
+pub async fn serve(
+    listener: TcpListener,
+    app: Router,
+    opts: ConnOptions,
+    shutdown: impl Future<Output = ()>,
+) -> Result<()> {
+    let mut builder = auto::Builder::new(TokioExecutor::new());
+    if opts.http1_keepalive_timeout == Some(Duration::ZERO) {
+        builder.http1().keep_alive(false);
+    }
+    if let Some(timeout) = opts.http1_header_read_timeout {
+        builder
+            .http1()
+            .timer(TokioTimer::new())
+            .header_read_timeout(timeout);
+    }
+    let idle_timeout = opts
+        .http1_keepalive_timeout
+        .filter(|timeout| !timeout.is_zero());
+
+    let limiter = opts.max_connections_per_ip.map(ConnLimiter::new);
+    let (shutdown_tx, shutdown_rx) = watch::channel(());
+    // Every connection holds a sender, the channel closes once all are done
+    let (open_tx, mut open_rx) = mpsc::channel::<()>(1);
+    tokio::pin!(shutdown);
+    loop {
+        let (stream, remote) = tokio::select! {
+            res = listener.accept() => match res {
+                Ok(v) => v,
+                Err(e) => {
+                    // Mostly EMFILE, back off instead of spinning
+                    error!("Error accepting connection: {e}");
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            },
+            _ = &mut shutdown => break,
+        };
+        trace!("Accepted {remote}");
+        let permit = match &limiter {
+            Some(limiter) => match limiter.acquire(remote.ip()) {
+                Some(permit) => Some(permit),
+                None => {
+                    debug!("Too many connections from {}, closing", remote.ip());
+                    metrics::counter!(CONNECTIONS_REJECTED).increment(1);
+                    continue;
+                }
+            },
+            None => None,
+        };
+        if let Err(e) = configure_stream(&stream, &opts) {
+            debug!("Error configuring socket for {remote}: {e}");
+        }
+
+        let tower_service = app.clone();
+        let hyper_service = hyper::service::service_fn(move |mut request: Request<Incoming>| {
+            request.extensions_mut().insert(ConnectInfo(remote));
+            tower_service.clone().call(request)
+        });
+        let builder = builder.clone();
+        let mut shutdown_rx = shutdown_rx.clone();
+        let open_tx = open_tx.clone();
+        tokio::spawn(async move {
+            let stream = IdleStream::new(stream);
+            let last_active = stream.last_active.clone();
+            let conn = builder.serve_connection_with_upgrades(TokioIo::new(stream), hyper_service);
+            tokio::pin!(conn);
+            let res = loop {
+                let idle_deadline = idle_timeout.map(|timeout| last_active.get() + timeout);
+                tokio::select! {
+                    res = conn.as_mut() => break res,
+                    _ = shutdown_rx.changed() => {
+                        conn.as_mut().graceful_shutdown();
+                        break conn.await;
+                    }
+                    // Woken early when the connection was used in between
+                    _ = sleep_until(idle_deadline) => {
+                        if idle_timeout.is_some_and(|t| last_active.get().elapsed() >= t) {
+                            trace!("Closing idle connection {remote}");
+                            conn.as_mut().graceful_shutdown();
+                            break conn.await;
+                        }
+                    }
+                }
+            };
+            if let Err(e) = res {
+                trace!("Connection {remote} closed with error: {e}");
+            }
+            drop((open_tx, permit));
+        });
+    }
+
+    drop(listener);
+    debug!("Draining connections");
+    let _ = shutdown_tx.send(());
+    drop(open_tx);
+    if tokio::time::timeout(DRAIN_TIMEOUT, open_rx.recv())
+        .await
+        .is_err()
+    {
+        warn!("Connections still open after {DRAIN_TIMEOUT:?}, closing");
+    }
+    Ok(())
+}
+
+/// Pending forever without a deadline
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// When a connection last read or wrote
+#[derive(Debug, Clone)]
+struct LastActive(Arc<Mutex<Instant>>);
+
+impl LastActive {
+    fn get(&self) -> Instant {
+        *self.0.lock().expect("Poisoned last activity")
+    }
+
+    fn touch(&self) {
+        *self.0.lock().expect("Poisoned last activity") = Instant::now();
+    }
+}
+
+/// Stream noting its activity, the keep-alive timeout counts from it
+struct IdleStream {
+    inner: TcpStream,
+    last_active: LastActive,
+}
+
+impl IdleStream {
+    fn new(inner: TcpStream) -> Self {
+        Self {
+            inner,
+            last_active: LastActive(Arc::new(Mutex::new(Instant::now()))),
+        }
+    }
+}
+
+impl AsyncRead for IdleStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if buf.filled().len() > filled {
+            self.last_active.touch();
+        }
+        res
+    }
+}
+
+impl AsyncWrite for IdleStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let res = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if matches!(res, Poll::Ready(Ok(n)) if n > 0) {
+            self.last_active.touch();
+        }
+        res
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let res = Pin::new(&mut self.inner).poll_write_vectored(cx, bufs);
+        if matches!(res, Poll::Ready(Ok(n)) if n > 0) {
+            self.last_active.touch();
+        }
+        res
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+fn configure_stream(stream: &TcpStream, opts: &ConnOptions) -> std::io::Result<()> {
+    stream.set_nodelay(opts.tcp_nodelay)?;
+    if let Some(time) = opts.tcp_keepalive {
+        let mut keepalive = TcpKeepalive::new().with_time(time);
+        if let Some(interval) = opts.tcp_keepalive_interval {
+            keepalive = keepalive.with_interval(interval);
+        }
+        SockRef::from(stream).set_tcp_keepalive(&keepalive)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn limits_connections_per_ip() {
+        let limiter = ConnLimiter::new(2);
+        let a: IpAddr = "10.0.0.1".parse().unwrap();
+        let mapped: IpAddr = "::ffff:10.0.0.1".parse().unwrap();
+        let b: IpAddr = "10.0.0.2".parse().unwrap();
+
+        let first = limiter.acquire(a).unwrap();
+        let _second = limiter.acquire(mapped).unwrap();
+        assert!(limiter.acquire(a).is_none());
+        assert!(limiter.acquire(b).is_some());
+
+        drop(first);
+        assert!(limiter.acquire(a).is_some());
+    }
+
+    #[tokio::test]
+    async fn closes_idle_connections() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new().route("/", axum::routing::get(|| async { "ok" }));
+        let opts = ConnOptions {
+            tcp_nodelay: false,
+            tcp_keepalive: None,
+            tcp_keepalive_interval: None,
+            http1_keepalive_timeout: Some(Duration::from_millis(200)),
+            http1_header_read_timeout: Some(Duration::from_secs(30)),
+            max_connections_per_ip: None,
+        };
+        tokio::spawn(serve(listener, app, opts, std::future::pending()));
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: qpac\r\n\r\n")
+            .await
+            .unwrap();
+        let mut buf = [0; 1024];
+        let n = client.read(&mut buf).await.unwrap();
+        assert!(buf[..n].starts_with(b"HTTP/1.1 200"));
+
+        // Closed by the keep-alive timeout well before the header one
+        let read = tokio::time::timeout(Duration::from_secs(5), client.read(&mut buf)).await;
+        assert_eq!(read.unwrap().unwrap(), 0);
+    }
+}
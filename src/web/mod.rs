@@ -1,201 +1,3699 @@
-use std::{fmt::Debug, net::SocketAddr, sync::Arc, time::Duration};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fmt::Debug,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
 
 use axum::{
-    extract::{Path, State},
-    http::{header, Response, StatusCode},
+    body::{Body, Bytes},
+    extract::{ConnectInfo, Path, Query, Request, State},
+    http::{header, HeaderMap, Response, StatusCode},
+    middleware::{self, Next},
     response::IntoResponse,
-    routing::{get, post},
+    routing::{delete, get, patch, post, put},
     Json, Router,
 };
 use debounced::debounced;
-use serde::Deserialize;
+use futures::stream::BoxStream;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
 use serde_json::json;
 use tokio::sync::mpsc::{self, Receiver, Sender};
 use tokio_stream::{wrappers::ReceiverStream, StreamExt};
 use tower_http::{compression::CompressionLayer, trace::TraceLayer};
-use tracing::{debug, error, info, span, trace, Level};
+use tracing::{debug, error, info, span, trace, warn, Level};
 
+use self::{
+    auth::AdminAuth,
+    cache::{LatestPacCache, ListCache, NegativeCache, PrimedPac, ProfilePacCache},
+    change_monitor::ChangeMonitor,
+    dry_run::DryRun,
+    listener::ConnOptions,
+    stats::{ClientStats, ServerStats},
+};
 use crate::{
+    args::{GenerateArgs, PostgresArgs, ServeArgs, SqliteArgs},
+    client::Client,
     error::{AppError, Result},
-    pac::Pac,
-    storage::{sqlite_storage::SqliteStorage, Storage},
+    host,
+    http_client::HttpClient,
+    init,
+    instrument::{
+        self,
+        metrics::{
+            CONTENT_VERIFICATION_FAILURES, OVERSIZED_PACS, PAC_SIZE_BYTES, STORAGE_BYTES,
+            STORAGE_ROWS,
+        },
+    },
+    metrics_layer,
+    pac::{
+        self, eval::EvalEnv, JsTarget, MatchStrategy, NetworkProfile, Pac, PacEncodings, PacGroup,
+        PacMode, PacOptions, Upstream,
+    },
+    rules::{EntryKind, Rule, RuleSet},
+    schedule::Schedule,
+    storage::{
+        self, bucket::PacBucket, file_storage::FileStorage, memory_storage::MemoryStorage,
+        postgres_storage::PostgresStorage, sqlite_storage::SqliteStorage, BlocklistAction,
+        BlocklistEntry, ChangeEvent, HostEntry, HostPatch, ImportMode, InstanceState, Profile,
+        ProxyGroup, Storage, StorageKind,
+    },
     trace_layer,
+    utils::time::unix_now,
 };
 
+mod audit;
 mod auth;
+mod cache;
+mod change_monitor;
+mod check;
+mod deadline;
+mod dry_run;
+mod listener;
+mod replication;
+pub(crate) mod revision;
+mod session;
+mod stats;
+mod verify;
 
 #[derive(Debug)]
-struct ServerState<S>
-where
-    S: Storage,
-{
-    storage: Arc<S>,
+struct ServerState {
+    storage: Arc<dyn Storage>,
+    /// Regenerations not caused by a storage change, such as requests of
+    /// other instances
     update_tx: Sender<()>,
+    latest: LatestPacCache,
+    list: ListCache,
+    profiles: ProfilePacCache,
+    missing: NegativeCache,
+    change_monitor: Option<ChangeMonitor>,
+    verify_content: bool,
+    poll_interval: u64,
+    /// Lease ttl when the database is shared with other instances
+    regeneration_lease: Option<Duration>,
+    instance_id: String,
+    http_client: HttpClient,
+    stats: Arc<ServerStats>,
+    /// Set with `--client-stats`
+    client_stats: Option<ClientStats>,
+    client_stats_retention: u32,
+    /// Set with `--token`, also holds the browser sessions
+    auth: Option<AdminAuth>,
+    secure_cookies: bool,
+    /// Cap of the deadline clients ask for
+    max_request_deadline: Duration,
+    generate: GenerateArgs,
+    /// Set with `--max-pac-size`
+    max_pac_size: Option<usize>,
+    refuse_oversized_pac: bool,
 }
 
-impl<S: Storage + Debug> ServerState<S> {
-    fn new(storage: S, update_tx: Sender<()>) -> Self {
+/// Bounds of the unknown hash cache in front of `/:hash`
+const MISSING_CACHE_CAPACITY: usize = 1024;
+const MISSING_CACHE_TTL: Duration = Duration::from_secs(60);
+/// How often counted client fetches are written to storage
+const CLIENT_STATS_FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+/// How often stored files past retention are deleted
+const PAC_RETENTION_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// How often removed hosts past retention are forgotten
+const DELETED_PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// How often audit log entries past retention are dropped
+const AUDIT_PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// How often hosts past their `expires_at` are removed
+const HOST_EXPIRY_INTERVAL: Duration = Duration::from_secs(60);
+/// How often storage size gauges are refreshed
+const STORAGE_STATS_INTERVAL: Duration = Duration::from_secs(60);
+
+impl ServerState {
+    fn new(
+        storage: Arc<dyn Storage>,
+        update_tx: Sender<()>,
+        auth: Option<AdminAuth>,
+        args: &ServeArgs,
+        http_client: HttpClient,
+    ) -> Self {
         Self {
-            storage: Arc::new(storage),
+            storage,
             update_tx,
+            latest: LatestPacCache::default(),
+            list: ListCache::default(),
+            profiles: ProfilePacCache::default(),
+            missing: NegativeCache::new(MISSING_CACHE_CAPACITY, MISSING_CACHE_TTL),
+            change_monitor: args.change_alert_threshold.map(|threshold| {
+                let monitor =
+                    ChangeMonitor::new(threshold, Duration::from_secs(args.change_alert_window));
+                match &args.change_alert_webhook {
+                    Some(url) => monitor.with_webhook(http_client.clone(), url.clone()),
+                    None => monitor,
+                }
+            }),
+            verify_content: args.verify_content,
+            poll_interval: args.poll_interval,
+            regeneration_lease: args.regeneration_lease.map(Duration::from_secs),
+            instance_id: instance_id(),
+            http_client,
+            stats: Arc::default(),
+            client_stats: args.client_stats.then(ClientStats::default),
+            client_stats_retention: args.client_stats_retention,
+            auth,
+            secure_cookies: args.secure_cookies,
+            max_request_deadline: Duration::from_millis(args.max_request_deadline),
+            generate: args.generate,
+            max_pac_size: args.max_pac_size,
+            refuse_oversized_pac: args.refuse_oversized_pac,
         }
     }
 }
 
 pub async fn run_web_server(
-    bind: SocketAddr,
-    token: Option<String>,
-    database: Option<String>,
+    args: ServeArgs,
+    sqlite: SqliteArgs,
+    postgres: PostgresArgs,
+    http_client: HttpClient,
 ) -> Result<()> {
     tracing::debug!("Starting web server");
 
-    let (update_tx, rx) = mpsc::channel(1);
+    check_storage_args(&args)?;
+    if args.storage == StorageKind::Sqlite && args.backup_dir.is_some() && args.database.is_none() {
+        return Err(
+            color_eyre::eyre::eyre!("--backup-dir needs a --database file to back up").into(),
+        );
+    }
+    let bucket = match args.storage {
+        StorageKind::Sqlite => PacBucket::from_args(&args.bucket)?,
+        StorageKind::Memory | StorageKind::Postgres | StorageKind::File => None,
+    };
+
+    let mut tenants = Vec::with_capacity(args.tenants.len());
+    for tenant in args.tenants.iter() {
+        let name = normalize_name("tenant", &tenant.name)?;
+        if tenants.iter().any(|(n, _)| *n == name) {
+            return Err(color_eyre::eyre::eyre!("Tenant {name} is listed twice").into());
+        }
+        let storage: Arc<dyn Storage> = match (args.storage, &tenant.value) {
+            (StorageKind::Sqlite, Some(url)) => {
+                let mut storage = connect_sqlite(url, &sqlite, args.no_auto_migrate).await?;
+                if let Some(bucket) = &bucket {
+                    storage = storage.with_bucket(bucket.for_tenant(&name));
+                }
+                let storage = Arc::new(storage);
+                run_sqlite_tasks(
+                    &storage,
+                    &args,
+                    args.backup_dir.as_ref().map(|dir| dir.join(&name)),
+                );
+                storage
+            }
+            (StorageKind::Postgres, Some(url)) => {
+                Arc::new(connect_postgres(url, &postgres, args.no_auto_migrate).await?)
+            }
+            (StorageKind::File, Some(path)) => Arc::new(FileStorage::open(path).await?),
+            (StorageKind::Memory, None) => Arc::new(MemoryStorage::default()),
+            (StorageKind::Memory, Some(_)) => {
+                return Err(color_eyre::eyre::eyre!(
+                    "Tenant {name} has a database, which memory storage doesn't take"
+                )
+                .into());
+            }
+            (kind, None) => {
+                let example = match kind {
+                    StorageKind::Postgres => "postgres://...",
+                    StorageKind::File => "<state file>",
+                    _ => "sqlite://...",
+                };
+                return Err(color_eyre::eyre::eyre!(
+                    "Tenant {name} needs a database, pass --tenant {name}={example}"
+                )
+                .into());
+            }
+        };
+        tenants.push((name, storage));
+    }
+    for token in args.tenant_tokens.iter() {
+        let name = normalize_name("tenant", &token.name)?;
+        if !tenants.iter().any(|(n, _)| *n == name) {
+            return Err(color_eyre::eyre::eyre!("Token of unknown tenant {name}").into());
+        }
+        if token.value.is_none() {
+            return Err(color_eyre::eyre::eyre!(
+                "Tenant token needs a value, pass --tenant-token {name}=<token>"
+            )
+            .into());
+        }
+    }
+
+    match (args.storage, &args.postgres_url, &args.state_file) {
+        (StorageKind::Sqlite, _, _) => {
+            let storage = Arc::new(open_sqlite(&args, &sqlite, bucket).await?);
+            run_sqlite_tasks(&storage, &args, args.backup_dir.clone());
+            serve(storage, tenants, args, http_client).await
+        }
+        (StorageKind::Postgres, Some(url), _) => {
+            let storage = connect_postgres(url, &postgres, args.no_auto_migrate).await?;
+            serve(Arc::new(storage), tenants, args, http_client).await
+        }
+        (StorageKind::File, _, Some(path)) => {
+            let storage = FileStorage::open(path).await?;
+            info!("Keeping state in {}", storage.path().display());
+            serve(Arc::new(storage), tenants, args, http_client).await
+        }
+        (StorageKind::Memory, _, _) => {
+            warn!("Serving from memory storage, everything is lost on shutdown");
+            serve(
+                Arc::new(MemoryStorage::default()),
+                tenants,
+                args,
+                http_client,
+            )
+            .await
+        }
+        (kind, _, _) => unreachable!("{} without its options", kind.as_str()),
+    }
+}
+
+/// Refuses options that don't apply to `--storage`, and a backend missing its
+/// connection option
+fn check_storage_args(args: &ServeArgs) -> Result<()> {
+    use StorageKind::{File, Postgres, Sqlite};
+
+    let only: [(&str, bool, &[StorageKind]); 7] = [
+        ("--database", args.database.is_some(), &[Sqlite]),
+        ("--postgres-url", args.postgres_url.is_some(), &[Postgres]),
+        ("--state-file", args.state_file.is_some(), &[File]),
+        (
+            "--no-auto-migrate",
+            args.no_auto_migrate,
+            &[Sqlite, Postgres],
+        ),
+        (
+            "--maintenance-interval",
+            args.maintenance_interval.is_some(),
+            &[Sqlite],
+        ),
+        ("--pac-bucket", args.bucket.pac_bucket.is_some(), &[Sqlite]),
+        ("--backup-dir", args.backup_dir.is_some(), &[Sqlite]),
+    ];
+    if let Some((flag, _, kinds)) = only
+        .iter()
+        .find(|(_, set, kinds)| *set && !kinds.contains(&args.storage))
+    {
+        let kinds: Vec<&str> = kinds.iter().map(StorageKind::as_str).collect();
+        return Err(color_eyre::eyre::eyre!(
+            "{flag} only applies to --storage {}",
+            kinds.join(" and ")
+        )
+        .into());
+    }
+    let missing = match args.storage {
+        Postgres if args.postgres_url.is_none() => Some("--postgres-url"),
+        File if args.state_file.is_none() => Some("--state-file"),
+        _ => None,
+    };
+    match missing {
+        Some(flag) => {
+            Err(color_eyre::eyre::eyre!("--storage {} needs {flag}", args.storage.as_str()).into())
+        }
+        None => Ok(()),
+    }
+}
 
-    let storage = match database {
-        Some(url) => SqliteStorage::new(&url).await?,
-        None => SqliteStorage::new("sqlite::memory:").await?,
+/// Sqlite storage of `--database`, in memory without one
+async fn open_sqlite(
+    args: &ServeArgs,
+    sqlite: &SqliteArgs,
+    bucket: Option<PacBucket>,
+) -> Result<SqliteStorage> {
+    let storage = match &args.database {
+        Some(url) => connect_sqlite(url, sqlite, args.no_auto_migrate).await?,
+        None => SqliteStorage::new_with("sqlite::memory:", sqlite).await?,
     };
-    let server_state = Arc::new(ServerState::new(storage, update_tx));
+    Ok(match bucket {
+        Some(bucket) => {
+            info!("Mirroring generated files to the pac bucket");
+            storage.with_bucket(bucket)
+        }
+        None => storage,
+    })
+}
+
+/// Starts the maintenance and backups `args` ask for, tenants are backed up
+/// to their own directory under `--backup-dir`
+fn run_sqlite_tasks(storage: &Arc<SqliteStorage>, args: &ServeArgs, backup_dir: Option<PathBuf>) {
+    if let Some(interval) = args.maintenance_interval {
+        tokio::spawn(maintain_storage(
+            storage.clone(),
+            Duration::from_secs(interval),
+        ));
+    }
+    if let Some(dir) = backup_dir {
+        tokio::spawn(back_up_storage(
+            storage.clone(),
+            dir,
+            args.backup_keep,
+            Duration::from_secs(args.backup_interval),
+        ));
+    }
+}
+
+/// Applies pending migrations unless `no_auto_migrate` refuses them
+async fn connect_sqlite(
+    url: &str,
+    sqlite: &SqliteArgs,
+    no_auto_migrate: bool,
+) -> Result<SqliteStorage> {
+    if !no_auto_migrate {
+        return SqliteStorage::new_with(url, sqlite).await;
+    }
+    let storage = SqliteStorage::connect_with(url, sqlite).await?;
+    let pending = storage.pending_migrations().await?;
+    if !pending.is_empty() {
+        let versions: Vec<String> = pending.iter().map(|(v, _)| v.to_string()).collect();
+        return Err(color_eyre::eyre::eyre!(
+            "Database has pending migrations ({}), run `qpac migrate` first",
+            versions.join(", ")
+        )
+        .into());
+    }
+    Ok(storage)
+}
+
+/// Applies pending migrations unless `no_auto_migrate` refuses them
+async fn connect_postgres(
+    url: &str,
+    postgres: &PostgresArgs,
+    no_auto_migrate: bool,
+) -> Result<PostgresStorage> {
+    if !no_auto_migrate {
+        return PostgresStorage::new_with(url, postgres).await;
+    }
+    let storage = PostgresStorage::connect_with(url, postgres).await?;
+    let pending = storage.pending_migrations().await?;
+    if !pending.is_empty() {
+        let versions: Vec<String> = pending.iter().map(|(v, _)| v.to_string()).collect();
+        return Err(color_eyre::eyre::eyre!(
+            "Database has pending migrations ({}), run `qpac migrate` first",
+            versions.join(", ")
+        )
+        .into());
+    }
+    Ok(storage)
+}
 
-    tokio::spawn(subscribe_pac(server_state.storage.clone(), rx));
+/// Serves `storage` at the root and every tenant under `/t/<name>`
+async fn serve(
+    storage: Arc<dyn Storage>,
+    tenants: Vec<(String, Arc<dyn Storage>)>,
+    args: ServeArgs,
+    http_client: HttpClient,
+) -> Result<()> {
+    let server_state = start(
+        storage,
+        args.token.clone().map(AdminAuth::new),
+        &args,
+        http_client.clone(),
+    )
+    .await?;
+    if server_state.auth.is_none() {
+        if args.allow_unauthenticated {
+            warn!("Auth token is missing, mutation endpoints are open to anyone");
+        } else {
+            info!("Auth token is missing, only serving on loopback");
+        }
+    }
+
+    if let Some(path) = &args.seed_file {
+        seed_hosts(&server_state, path).await?;
+    }
+    if let Some(url) = &args.replicate_from {
+        let client = Client::new(http_client.clone(), url, args.replicate_token.clone())
+            .map_err(|e| color_eyre::eyre::eyre!("--replicate-from: {e}"))?;
+        info!("Replicating from {url}");
+        tokio::spawn(replication::pull_every(
+            server_state.clone(),
+            client,
+            Duration::from_secs(args.replicate_interval),
+        ));
+    }
+
+    let mut app = routes(server_state.clone());
+    let mut states = vec![server_state];
+    // The root list is recorded with an empty tenant label
+    let mut tenant_names = vec![String::new()];
+    for (name, storage) in tenants {
+        let state = start(
+            storage,
+            tenant_auth(&args, &name),
+            &args,
+            http_client.clone(),
+        )
+        .await?;
+        info!("Serving tenant {name} under /t/{name}");
+        app = app.nest(&format!("/t/{name}"), routes(state.clone()));
+        states.push(state);
+        tenant_names.push(name);
+    }
+    if args.metrics {
+        let handle = instrument::metrics::setup()?;
+        for (tenant, state) in tenant_names.iter().zip(states.iter()) {
+            tokio::spawn(record_storage_stats_every(
+                state.storage.clone(),
+                tenant.clone(),
+                STORAGE_STATS_INTERVAL,
+            ));
+        }
+        app = app.route(
+            "/metrics",
+            get(move |headers: HeaderMap| async move { render_metrics(&handle, &headers) }),
+        );
+    }
 
     let trace_layer = TraceLayer::new_for_http()
         .make_span_with(trace_layer::trace_layer_make_span_with)
         .on_request(trace_layer::trace_layer_on_request)
         .on_response(trace_layer::trace_layer_on_response);
+    let mut app = app.fallback(fallback);
+    if args.metrics {
+        app = app.layer(middleware::from_fn(metrics_layer::track_metrics));
+    }
+    let app = app.layer(trace_layer);
+
+    let listener = listener::bind(&args).await?;
+    let addr = listener.local_addr()?;
+    if args.token.is_none()
+        && !args.allow_unauthenticated
+        && !addr.ip().to_canonical().is_loopback()
+    {
+        return Err(color_eyre::eyre::eyre!(
+            "Refusing to serve without a token on {addr}, set --token or pass --allow-unauthenticated"
+        )
+        .into());
+    }
+    listener::serve(
+        listener,
+        app,
+        ConnOptions::from(&args),
+        listener::shutdown_signal(),
+    )
+    .await?;
+    for state in states.iter() {
+        shutdown_report(state).await;
+    }
+
+    Ok(())
+}
+
+/// Auth of tenant `name`, its own tokens audited as `tenant:<name>` and
+/// `--token` as `root`
+fn tenant_auth(args: &ServeArgs, name: &str) -> Option<AdminAuth> {
+    let tokens: Vec<(String, String)> = args
+        .token
+        .iter()
+        .map(|t| ("root".to_string(), t.clone()))
+        .chain(
+            args.tenant_tokens
+                .iter()
+                .filter(|t| t.name.trim().eq_ignore_ascii_case(name))
+                .filter_map(|t| Some((format!("tenant:{name}"), t.value.clone()?))),
+        )
+        .collect();
+    (!tokens.is_empty()).then(|| AdminAuth::with_tokens(tokens))
+}
+
+/// Imports the hosts of `path` into an empty list, the storage change
+/// generates the initial pac
+async fn seed_hosts(server_state: &ServerState, path: &std::path::Path) -> Result<()> {
+    if !server_state.storage.all_hosts().await?.is_empty() {
+        debug!("Hosts are listed already, not seeding");
+        return Ok(());
+    }
+    let (hosts, errors) = init::parse_hosts(&std::fs::read_to_string(path)?);
+    for error in errors.iter() {
+        warn!("Skipping seed {error}");
+    }
+    let diff = server_state
+        .storage
+        .import_hosts(hosts, ImportMode::Merge, false)
+        .await?;
+    notify_update(server_state, diff.added.len());
+    info!("Seeded {} hosts from {}", diff.added.len(), path.display());
+    Ok(())
+}
+
+/// State of one host list with its regeneration and cleanup tasks running
+async fn start(
+    storage: Arc<dyn Storage>,
+    auth: Option<AdminAuth>,
+    args: &ServeArgs,
+    http_client: HttpClient,
+) -> Result<Arc<ServerState>> {
+    // Subscribed before the startup changes below so they regenerate as well
+    let changes = storage.watch();
+    let (update_tx, rx) = mpsc::channel(1);
+
+    if !args.proxy.is_empty() {
+        let proxy = normalize_proxy(&args.proxy.join("; "))?;
+        if default_proxy(storage.as_ref()).await? != proxy {
+            info!("Proxy changed to {proxy}, regenerating");
+            storage.set_proxy(&proxy).await?;
+        }
+    }
+    if let Some(mode) = args.mode {
+        if storage.get_mode().await? != mode {
+            info!("Mode changed to {}, regenerating", mode.as_str());
+            storage.set_mode(mode).await?;
+        }
+    }
+    if let Some(enabled) = args.bypass_private {
+        if storage.get_bypass_private().await? != enabled {
+            info!("Private network bypass set to {enabled}, regenerating");
+            storage.set_bypass_private(enabled).await?;
+        }
+    }
+    let server_state = Arc::new(ServerState::new(
+        storage,
+        update_tx,
+        auth,
+        args,
+        http_client,
+    ));
+    if let Ok(pac) = server_state.storage.get_file_latest().await {
+        let primed = prime_stored(server_state.storage.as_ref(), Arc::new(pac)).await;
+        server_state.latest.set_primed(primed).await;
+    }
+
+    tokio::spawn(subscribe_pac(server_state.clone(), changes, rx));
+    if let Some(ttl) = server_state.regeneration_lease {
+        tokio::spawn(hold_regeneration_lease(server_state.clone(), ttl));
+    }
+    if let Some(changes) = server_state.storage.watch_shared().await? {
+        tokio::spawn(follow_shared_changes(server_state.clone(), changes));
+    }
+    if let Some(interval) = args.change_poll_interval {
+        tokio::spawn(watch_changes(
+            server_state.clone(),
+            Duration::from_secs(interval),
+        ));
+    }
+    if server_state.client_stats.is_some() {
+        tokio::spawn(flush_client_stats_every(
+            server_state.clone(),
+            CLIENT_STATS_FLUSH_INTERVAL,
+        ));
+    }
+    if args.pac_retention_keep.is_some() || args.pac_retention_days.is_some() {
+        tokio::spawn(prune_files_every(
+            server_state.storage.clone(),
+            args.pac_retention_keep,
+            args.pac_retention_days,
+            PAC_RETENTION_INTERVAL,
+        ));
+    }
+    tokio::spawn(purge_deleted_every(
+        server_state.storage.clone(),
+        args.deleted_retention,
+        DELETED_PURGE_INTERVAL,
+    ));
+    tokio::spawn(prune_audit_every(
+        server_state.storage.clone(),
+        args.audit_retention,
+        AUDIT_PRUNE_INTERVAL,
+    ));
+    tokio::spawn(expire_hosts_every(
+        server_state.clone(),
+        HOST_EXPIRY_INTERVAL,
+    ));
+    Ok(server_state)
+}
+
+/// Public and admin endpoints of one host list
+fn routes(server_state: Arc<ServerState>) -> Router {
     let compression = CompressionLayer::new();
 
-    let public = Router::new()
+    let mut public = Router::new()
         .route("/list", get(get_list))
+        .route("/pinned", get(get_pinned))
+        .route("/poll-hint", get(get_poll_hint))
+        .route("/versions", get(get_versions))
+        .route("/versions/:id/hosts", get(get_version_hosts))
+        .route("/tags", get(get_tags))
+        .route("/tags/:tag/hosts", get(get_tag_hosts))
+        .route("/api/v1/hosts", get(get_hosts_page))
+        .route("/search", get(search_hosts))
+        .route("/api/v1/hosts/:host", get(get_host))
+        .route("/snapshots", get(get_snapshots))
+        .route("/profiles", get(get_profiles))
+        .route("/proxy", get(get_proxy))
+        .route("/mode", get(get_mode))
+        .route("/groups", get(get_groups))
+        .route("/networks", get(get_networks))
+        .route("/ip-ranges", get(get_ip_ranges))
+        .route("/upstreams", get(get_upstreams))
+        .route("/exclusions", get(get_exclusions))
+        .route("/blocklist", get(get_blocklist))
+        .route("/bypass-private", get(get_bypass_private))
+        .route("/check", get(check_url))
+        .route("/preview", get(get_preview))
         .route("/", get(get_latest_pac))
         .route("/:hash", get(get_pac))
         .layer(compression);
 
     let mut admin = Router::new()
         .route("/add", post(add_to_list))
-        .route("/remove", post(remove_from_list));
-    if let Some(t) = token {
-        admin = admin.route_layer(auth::use_auth_layer(t));
-    } else {
-        info!("Auth token is missing, running unsafe");
+        .route("/remove", post(remove_from_list))
+        .route("/pin", post(pin_hosts))
+        .route("/unpin", post(unpin_hosts))
+        .route("/deleted", get(get_deleted))
+        .route("/deleted/:host/restore", post(restore_host))
+        .route("/import", post(import_hosts))
+        .route("/tags/:tag", patch(rename_tag).delete(delete_tag))
+        .route("/tags/:tag/hosts", delete(remove_tag_hosts))
+        .route("/api/v1/hosts/:host", patch(update_host))
+        .route("/snapshots", post(create_snapshot))
+        .route("/snapshots/:name/restore", post(restore_snapshot))
+        .route("/snapshots/:name", delete(delete_snapshot))
+        .route("/profiles/:name", put(set_profile).delete(remove_profile))
+        .route("/proxy", put(set_proxy))
+        .route("/versions/:id/rollback", post(rollback_version))
+        .route("/preview", post(start_preview))
+        .route("/preview/promote", post(promote_preview))
+        .route("/mode", put(set_mode))
+        .route("/upstreams", put(set_upstreams))
+        .route("/groups/:name", put(set_group).delete(remove_group))
+        .route("/networks/:name", put(set_network).delete(remove_network))
+        .route("/ip-ranges", put(set_ip_ranges))
+        .route("/exclusions", post(add_exclusions))
+        .route("/bypass-private", put(set_bypass_private))
+        .route("/exclusions/:host", delete(remove_exclusion))
+        .route(
+            "/blocklist/:host",
+            put(set_blocklisted).delete(remove_blocklisted),
+        )
+        .route("/stats/clients", get(get_client_stats))
+        .route("/stats/storage", get(get_storage_stats))
+        .route("/audit", get(get_audit_log))
+        .route("/state", get(get_state).put(put_state))
+        .route_layer(middleware::from_fn_with_state(
+            server_state.clone(),
+            enforce_revision,
+        ))
+        .route_layer(middleware::from_fn_with_state(
+            server_state.clone(),
+            audit::record,
+        ));
+    if let Some(auth) = server_state.auth.clone() {
+        admin = admin.route_layer(auth::use_auth_layer(auth));
+        public = public
+            .route("/login", post(login))
+            .route("/logout", post(logout))
+            .route("/session", get(get_session));
     }
 
-    let app = Router::new()
+    Router::new()
         .merge(public)
         .merge(admin)
-        .fallback(fallback)
-        .layer(trace_layer)
-        .with_state(server_state);
+        .layer(middleware::from_fn_with_state(
+            server_state.clone(),
+            enforce_deadline,
+        ))
+        .layer(middleware::from_fn_with_state(
+            server_state.stats.clone(),
+            count_request,
+        ))
+        .with_state(server_state)
+}
 
-    let listener = tokio::net::TcpListener::bind(bind).await.unwrap();
-    tracing::info!("Listening on {}", bind);
-    axum::serve(listener, app)
-        .await
-        .expect("Should start web server");
+async fn count_request(
+    State(stats): State<Arc<ServerStats>>,
+    request: Request<Body>,
+    next: Next,
+) -> Response<Body> {
+    stats.request();
+    next.run(request).await
+}
 
-    Ok(())
+/// Drops reads once the caller's `X-Request-Deadline-Ms` has passed.
+/// Mutations are only refused when it's over before they start, dropping
+/// one mid-way could leave it committed without an audit entry or the
+/// regeneration it asked for
+async fn enforce_deadline(
+    State(server_state): State<Arc<ServerState>>,
+    request: Request<Body>,
+    next: Next,
+) -> Result<Response<Body>, AppError> {
+    let Some(budget) = deadline::requested(request.headers(), server_state.max_request_deadline)?
+    else {
+        return Ok(next.run(request).await);
+    };
+    if budget.is_zero() {
+        return Err(AppError::Timeout("Request deadline exceeded".to_string()));
+    }
+    if !request.method().is_safe() {
+        return Ok(next.run(request).await);
+    }
+    match tokio::time::timeout(budget, next.run(request)).await {
+        Ok(res) => Ok(res),
+        Err(_) => {
+            debug!("Request deadline of {budget:?} exceeded");
+            Err(AppError::Timeout("Request deadline exceeded".to_string()))
+        }
+    }
+}
+
+/// Rejects mutations based on an outdated list revision, see
+/// [`revision::expected`]. Storage compares it in the transaction of the
+/// first host list write, so a change from another request or instance can't
+/// slip in between
+async fn enforce_revision(
+    State(server_state): State<Arc<ServerState>>,
+    request: Request<Body>,
+    next: Next,
+) -> Result<Response<Body>, AppError> {
+    if request.method().is_safe() {
+        return Ok(next.run(request).await);
+    }
+    let (parts, body) = request.into_parts();
+    let Ok(body) = axum::body::to_bytes(body, audit::MAX_BODY_SIZE).await else {
+        return Ok(StatusCode::PAYLOAD_TOO_LARGE.into_response());
+    };
+    let request = Request::from_parts(parts, Body::from(body.clone()));
+    let Some(expected) = revision::expected(request.headers(), &body)? else {
+        return Ok(next.run(request).await);
+    };
+    let mut res = storage::expect_revision(expected, next.run(request)).await;
+    if res.status().is_success() {
+        if let Ok(revision) = server_state.storage.hosts_version().await {
+            res.headers_mut()
+                .insert(revision::REVISION_HEADER, revision.into());
+        }
+    }
+    Ok(res)
+}
+
+/// Flushes host changes still waiting for regeneration and logs a summary
+async fn shutdown_report(server_state: &ServerState) {
+    let stats = &server_state.stats;
+    let pending = stats.pending();
+    if pending > 0 {
+        debug!("Flushing {pending} pending host changes");
+        regenerate(server_state).await;
+    }
+    flush_client_stats(server_state).await;
+    let last_hash = server_state.latest.get().await.map(|p| p.hash.clone());
+    info!(
+        uptime_secs = stats.uptime().as_secs(),
+        requests = stats.requests(),
+        regenerations = stats.regenerations(),
+        last_hash,
+        flushed = pending,
+        "Shutdown report"
+    );
+}
+
+#[derive(Debug, Deserialize)]
+struct PacQuery {
+    profile: Option<String>,
 }
 
 #[tracing::instrument(skip_all, err(level = Level::DEBUG))]
 async fn get_latest_pac(
-    server_state: State<Arc<ServerState<impl Storage>>>,
-) -> Result<Response<String>, AppError> {
-    let pac = server_state.storage.get_file_latest().await?;
-    Response::builder()
+    Query(query): Query<PacQuery>,
+    headers: HeaderMap,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    server_state: State<Arc<ServerState>>,
+) -> Result<Response<Body>, AppError> {
+    let primed = latest_pac(&server_state).await?;
+    let (hash, encoding, body) = match query.profile {
+        Some(name) => {
+            let name = normalize_name("profile", &name)?;
+            let profiled = profile_pac(&server_state, &name, &primed.pac).await?;
+            record_fetch(&server_state, connect_info, Some(&name));
+            let (encoding, body) = profiled.body(accept_encoding(&headers));
+            (profiled.pac.hash.clone(), encoding, body)
+        }
+        None => {
+            record_fetch(&server_state, connect_info, None);
+            let (encoding, body) = primed.body(accept_encoding(&headers));
+            (primed.pac.hash.clone(), encoding, body)
+        }
+    };
+    let res = Response::builder()
         .header(header::CONTENT_TYPE, "text/javascript")
-        .header(
-            header::LOCATION,
-            format!("/{}", urlencoding::encode(&pac.hash)),
-        )
-        .body(pac.file)
+        .header(header::LOCATION, format!("/{}", urlencoding::encode(&hash)));
+    encoded_response(res, encoding, body)
+}
+
+#[derive(Debug, Deserialize)]
+struct CheckQuery {
+    url: String,
+    profile: Option<String>,
+    /// What `myIpAddress()` returns, picks the network profile
+    my_ip: Option<IpAddr>,
+}
+
+/// Runs the latest pac against a url the way a browser would
+#[tracing::instrument(skip(server_state), err(level = Level::DEBUG))]
+async fn check_url(
+    Query(query): Query<CheckQuery>,
+    server_state: State<Arc<ServerState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let (url, host) = check::target(&query.url)?;
+    let primed = latest_pac(&server_state).await?;
+    let pac = match query.profile {
+        Some(name) => {
+            let name = normalize_name("profile", &name)?;
+            profile_pac(&server_state, &name, &primed.pac)
+                .await?
+                .pac
+                .clone()
+        }
+        None => primed.pac.clone(),
+    };
+    let mut env = EvalEnv::default();
+    if let Some(my_ip) = query.my_ip {
+        env.my_ip_address = my_ip;
+    }
+    check::evaluate(pac, url, host, env).await.map(Json)
+}
+
+/// Responds with a body that may already be encoded, compression layer leaves
+/// such responses alone
+fn encoded_response(
+    mut res: axum::http::response::Builder,
+    encoding: Option<&str>,
+    body: Bytes,
+) -> Result<Response<Body>, AppError> {
+    res = res.header(header::VARY, "accept-encoding");
+    if let Some(encoding) = encoding {
+        res = res.header(header::CONTENT_ENCODING, encoding);
+    }
+    res.body(Body::from(body))
         .map_err(|e| AppError::Other(e.to_string()))
 }
 
+/// Latest pac from the primed cache, loaded and primed when the cache is behind
+async fn latest_pac(server_state: &ServerState) -> Result<Arc<PrimedPac>, AppError> {
+    let loaded = match server_state.storage.latest_hash().await {
+        Ok(hash) => match server_state.latest.get_primed_by_hash(&hash).await {
+            Some(primed) => return Ok(primed),
+            None => server_state.storage.get_file_latest().await,
+        },
+        Err(e) => Err(e),
+    };
+    match loaded {
+        Ok(pac) => {
+            let primed = prime_stored(server_state.storage.as_ref(), Arc::new(pac)).await;
+            Ok(server_state.latest.set_primed(primed).await)
+        }
+        Err(e) => {
+            let pac = cached_on_outage(&server_state.latest, e, None).await?;
+            Ok(server_state.latest.set(pac).await)
+        }
+    }
+}
+
+/// Primes a stored file with its stored encodings. Missing ones, e.g. of files
+/// stored before encodings were, are compressed and stored for the next time
+async fn prime_stored(storage: &dyn Storage, pac: Arc<Pac>) -> PrimedPac {
+    let stored = match storage.get_encodings(&pac.hash).await {
+        Ok(stored) => stored,
+        Err(e) => {
+            warn!("Error loading encodings of {}: {e}", pac.hash);
+            PacEncodings::default()
+        }
+    };
+    if stored.is_complete() {
+        return PrimedPac::new(pac, stored);
+    }
+    let primed = PrimedPac::compress(pac).await;
+    if let Err(e) = storage
+        .upload_encodings(&primed.pac.hash, &primed.encodings())
+        .await
+    {
+        warn!("Error storing encodings of {}: {e}", primed.pac.hash);
+    }
+    primed
+}
+
+fn accept_encoding(headers: &HeaderMap) -> &str {
+    headers
+        .get(header::ACCEPT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default()
+}
+
 #[tracing::instrument(skip_all, err(level = Level::DEBUG))]
 async fn get_pac(
     Path(hash): Path<String>,
-    server_state: State<Arc<ServerState<impl Storage>>>,
-) -> Result<Response<String>, AppError> {
-    let file = server_state.storage.get_file(hash).await?;
-    Response::builder()
+    headers: HeaderMap,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    server_state: State<Arc<ServerState>>,
+) -> Result<Response<Body>, AppError> {
+    let res = Response::builder()
         .header(header::CONTENT_TYPE, "text/javascript")
-        .body(file)
-        .map_err(|e| AppError::Other(e.to_string()))
+        // Content under a hash never changes
+        .header(header::CACHE_CONTROL, "public, max-age=31536000, immutable");
+    if !server_state.verify_content {
+        if let Some(primed) = server_state.latest.get_primed_by_hash(&hash).await {
+            record_fetch(&server_state, connect_info, None);
+            let (encoding, body) = primed.body(accept_encoding(&headers));
+            return encoded_response(res, encoding, body);
+        }
+    }
+    if server_state.missing.contains(&hash) {
+        return Err(AppError::NotFound);
+    }
+    let primed = match server_state.storage.get_file(&hash).await {
+        Ok(file) => {
+            if server_state.verify_content {
+                verify_content(server_state.storage.as_ref(), &hash, &file).await?;
+            }
+            let pac = Arc::new(Pac::new(file, hash));
+            Arc::new(prime_stored(server_state.storage.as_ref(), pac).await)
+        }
+        Err(AppError::NotFound) => {
+            server_state.missing.insert(&hash);
+            return Err(AppError::NotFound);
+        }
+        Err(e) => {
+            let pac = cached_on_outage(&server_state.latest, e, Some(&hash)).await?;
+            server_state.latest.set(pac).await
+        }
+    };
+    record_fetch(&server_state, connect_info, None);
+    let (encoding, body) = primed.body(accept_encoding(&headers));
+    encoded_response(res, encoding, body)
+}
+
+/// Counts a pac fetch when client stats are enabled, `/:hash` fetches are
+/// counted without a profile
+fn record_fetch(
+    server_state: &ServerState,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    profile: Option<&str>,
+) {
+    if let (Some(stats), Some(ConnectInfo(addr))) = (&server_state.client_stats, connect_info) {
+        stats.record(addr.ip(), profile);
+    }
+}
+
+/// Writes counted client fetches to storage and drops the ones past retention
+async fn flush_client_stats(server_state: &ServerState) {
+    let Some(stats) = &server_state.client_stats else {
+        return;
+    };
+    let fetches = stats.take();
+    if !fetches.is_empty() {
+        if let Err(e) = server_state
+            .storage
+            .record_client_fetches(fetches.clone())
+            .await
+        {
+            error!("Error storing client stats: {e}");
+            stats.restore(fetches);
+            return;
+        }
+    }
+    let retention = i64::from(server_state.client_stats_retention) * 24 * 60 * 60;
+    let before = stats::day_start(unix_now()) - retention;
+    match server_state.storage.prune_client_fetches(before).await {
+        Ok(0) => {}
+        Ok(n) => debug!("Pruned {n} client stats past retention"),
+        Err(e) => error!("Error pruning client stats: {e}"),
+    }
+}
+
+async fn flush_client_stats_every(server_state: Arc<ServerState>, every: Duration) {
+    let mut interval = tokio::time::interval(every);
+    loop {
+        interval.tick().await;
+        flush_client_stats(&server_state).await;
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ClientStatsQuery {
+    /// Including today
+    days: Option<u32>,
+}
+
+/// Totals of one day of client stats
+#[derive(Debug, Default, Serialize)]
+struct ClientStatsDay {
+    day: i64,
+    /// Distinct client networks
+    clients: usize,
+    fetches: i64,
+}
+
+/// Row counts and sizes for capacity monitoring
+#[tracing::instrument(skip(server_state), err(level = Level::DEBUG))]
+async fn get_storage_stats(
+    server_state: State<Arc<ServerState>>,
+) -> Result<impl IntoResponse, AppError> {
+    Ok(Json(server_state.storage.storage_stats().await?))
+}
+
+/// Pac fetches per day by client network and profile, `days` defaults to 30
+#[tracing::instrument(skip(server_state), err(level = Level::DEBUG))]
+async fn get_client_stats(
+    Query(query): Query<ClientStatsQuery>,
+    server_state: State<Arc<ServerState>>,
+) -> Result<impl IntoResponse, AppError> {
+    if server_state.client_stats.is_none() {
+        return Err(AppError::PreconditionFailed(
+            "client stats are disabled, start with --client-stats".to_string(),
+        ));
+    }
+    let days = query.days.unwrap_or(30).max(1);
+    flush_client_stats(&server_state).await;
+    let since = stats::day_start(unix_now()) - i64::from(days - 1) * 24 * 60 * 60;
+    let fetches = server_state.storage.client_fetches(since).await?;
+
+    let mut totals: BTreeMap<i64, (HashSet<&str>, i64)> = BTreeMap::new();
+    for f in fetches.iter() {
+        let (clients, count) = totals.entry(f.day).or_default();
+        clients.insert(&f.client);
+        *count += f.fetches;
+    }
+    let totals: Vec<ClientStatsDay> = totals
+        .into_iter()
+        .map(|(day, (clients, fetches))| ClientStatsDay {
+            day,
+            clients: clients.len(),
+            fetches,
+        })
+        .collect();
+    Ok(Json(json!({ "days": totals, "clients": fetches })))
+}
+
+#[derive(Debug, Deserialize)]
+struct AuditLogQuery {
+    /// Unix time
+    since: Option<i64>,
+    limit: Option<u32>,
+}
+
+const DEFAULT_AUDIT_LOG_LIMIT: u32 = 100;
+const MAX_AUDIT_LOG_LIMIT: u32 = 1000;
+
+/// Admin operations newest first
+#[tracing::instrument(skip(server_state), err(level = Level::DEBUG))]
+async fn get_audit_log(
+    Query(query): Query<AuditLogQuery>,
+    server_state: State<Arc<ServerState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let limit = query.limit.unwrap_or(DEFAULT_AUDIT_LOG_LIMIT);
+    if limit == 0 || limit > MAX_AUDIT_LOG_LIMIT {
+        return Err(AppError::Validation {
+            field: "limit".to_string(),
+            message: format!("expected 1 to {MAX_AUDIT_LOG_LIMIT}"),
+        });
+    }
+    let entries = server_state
+        .storage
+        .audit_log(query.since.unwrap_or_default(), limit)
+        .await?;
+    Ok(Json(json!({ "entries": entries })))
+}
+
+/// Latest pac regenerated with the profile's proxy chain in place of the
+/// default proxy and its upstreams, stored so that `/:hash` serves it as well.
+/// Everything else is generated like the default file, a profile with tags
+/// lists only the hosts bearing one of them
+async fn profile_pac(
+    server_state: &ServerState,
+    name: &str,
+    base: &Pac,
+) -> Result<Arc<PrimedPac>, AppError> {
+    let profile = server_state.storage.get_profile(name).await?;
+    // Tags and staged changes come without a new base file
+    let hosts_version = server_state.storage.hosts_version().await?;
+    if let Some(pac) = server_state
+        .profiles
+        .get(&profile, &base.hash, hosts_version)
+        .await
+    {
+        return Ok(pac);
+    }
+    let mut entries = server_state.storage.host_entries().await?;
+    if !profile.tags.is_empty() {
+        entries.retain(|e| e.tags.iter().any(|t| profile.tags.contains(t)));
+    }
+    let mut config = PacConfig::load(server_state.storage.as_ref(), server_state.generate).await?;
+    config.proxy = profile.proxy.clone();
+    config.upstreams.clear();
+    let pac = pac_from_entries(&entries, &config);
+    if !size_allowed(server_state, &pac, &format!("Pac of profile {name}")) {
+        return Err(AppError::Other(format!(
+            "Pac of profile {name} is over the size limit"
+        )));
+    }
+    server_state.storage.upload_file(&pac).await?;
+    let primed = Arc::new(PrimedPac::compress(Arc::new(pac)).await);
+    server_state
+        .storage
+        .upload_encodings(&primed.pac.hash, &primed.encodings())
+        .await?;
+    server_state.missing.remove(&primed.pac.hash);
+    server_state
+        .profiles
+        .set(&profile, &base.hash, hosts_version, primed.clone())
+        .await;
+    Ok(primed)
+}
+
+/// Checks `pac` against `--max-pac-size`, false when it's over and oversized
+/// files are refused. `what` names the file in the logs
+fn size_allowed(server_state: &ServerState, pac: &Pac, what: &str) -> bool {
+    let size = pac.file.len();
+    let Some(max) = server_state.max_pac_size.filter(|max| size > *max) else {
+        return true;
+    };
+    metrics::counter!(OVERSIZED_PACS).increment(1);
+    if server_state.refuse_oversized_pac {
+        error!("{what} is {size} bytes, over the {max} byte limit, not publishing it");
+        return false;
+    }
+    warn!("{what} is {size} bytes, over the {max} byte limit");
+    true
+}
+
+async fn verify_content(storage: &dyn Storage, hash: &str, file: &str) -> Result<(), AppError> {
+    let expected = match storage.get_checksum(hash).await {
+        Ok(v) => v,
+        Err(AppError::NotFound) => {
+            debug!("No checksum stored for {hash}, skipping verification");
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    };
+    if pac::checksum(file) != expected {
+        error!("Stored file {hash} doesn't match its checksum, database may be corrupted");
+        metrics::counter!(CONTENT_VERIFICATION_FAILURES).increment(1);
+        return Err(AppError::Other("Content verification failed".to_string()));
+    }
+    Ok(())
+}
+
+/// Recommended polling interval, lets operators slow down clients centrally
+#[tracing::instrument(skip_all, err(level = Level::DEBUG))]
+async fn get_poll_hint(
+    server_state: State<Arc<ServerState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let hash = match server_state.latest.get().await {
+        Some(pac) => Some(pac.hash.clone()),
+        None => match server_state.storage.get_file_latest().await {
+            Ok(pac) => Some(pac.hash),
+            Err(AppError::NotFound) => None,
+            Err(e) => return Err(e),
+        },
+    };
+    Ok(Json(json!({
+        "interval_secs": server_state.poll_interval,
+        "hash": hash,
+    })))
 }
 
+/// Served from memory until the next change, `If-None-Match` is honored.
+/// The ETag is the list revision, mutations take it back as `If-Match`
 #[tracing::instrument(skip_all, err(level = Level::DEBUG))]
 async fn get_list(
-    server_state: State<Arc<ServerState<impl Storage>>>,
+    server_state: State<Arc<ServerState>>,
+    headers: HeaderMap,
 ) -> Result<impl IntoResponse, AppError> {
-    server_state.storage.all_hosts().await.map(Json)
+    let list = match server_state.list.get().await {
+        Some(list) => list,
+        None => {
+            let seq = server_state.list.seq();
+            // Read first, a change in between only makes it look outdated
+            let revision = server_state.storage.hosts_version().await?;
+            let hosts = server_state.storage.all_hosts().await?;
+            let body = serde_json::to_string(&hosts).map_err(|e| AppError::Other(e.to_string()))?;
+            server_state.list.set(seq, revision, body).await
+        }
+    };
+    let not_modified = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| {
+            v.split(',')
+                .map(|t| t.trim().trim_start_matches("W/"))
+                .any(|t| t == list.etag || t == "*")
+        });
+    if not_modified {
+        return Ok((
+            StatusCode::NOT_MODIFIED,
+            [
+                (header::ETAG, list.etag),
+                (revision::REVISION_HEADER, list.revision.to_string()),
+            ],
+        )
+            .into_response());
+    }
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/json".to_string()),
+            (header::ETAG, list.etag),
+            (revision::REVISION_HEADER, list.revision.to_string()),
+        ],
+        list.body,
+    )
+        .into_response())
+}
+
+/// `v12` style label of a stored file, easier to pass around than its hash
+fn version_label(version: i64) -> String {
+    format!("v{version}")
+}
+
+/// Hash of a `v12` label, anything else is taken as a hash
+async fn resolve_version(storage: &dyn Storage, id: &str) -> Result<String, AppError> {
+    match id.strip_prefix('v').and_then(|v| v.parse().ok()) {
+        Some(version) => storage.get_version_hash(version).await,
+        None => Ok(id.to_string()),
+    }
 }
 
 #[derive(Debug, Deserialize)]
-struct HostProps {
-    host: String,
+struct VersionsQuery {
+    limit: Option<u32>,
+}
+
+const DEFAULT_VERSIONS_LIMIT: u32 = 50;
+
+/// Stored files newest first, profile variants included
+#[tracing::instrument(skip(server_state), err(level = Level::DEBUG))]
+async fn get_versions(
+    Query(query): Query<VersionsQuery>,
+    server_state: State<Arc<ServerState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let limit = query.limit.unwrap_or(DEFAULT_VERSIONS_LIMIT);
+    let versions = server_state.storage.list_versions(limit).await?;
+    let latest = match server_state.storage.latest_hash().await {
+        Ok(hash) => Some(hash),
+        Err(AppError::NotFound) => None,
+        Err(e) => return Err(e),
+    };
+    let staged = server_state.storage.staged_hash().await?;
+    let versions: Vec<_> = versions
+        .into_iter()
+        .map(|v| {
+            json!({
+                "version": version_label(v.version),
+                "latest": latest.as_ref() == Some(&v.hash),
+                "staged": staged.as_ref() == Some(&v.hash),
+                "hash": v.hash,
+                "size": v.size,
+                "created_at": v.created_at,
+                "meta": v.meta,
+            })
+        })
+        .collect();
+    Ok(Json(versions))
 }
 
+/// Accepts a hash or a `v12` label
+#[tracing::instrument(skip(server_state), err(level = Level::DEBUG))]
+async fn get_version_hosts(
+    Path(id): Path<String>,
+    server_state: State<Arc<ServerState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let hash = resolve_version(server_state.storage.as_ref(), &id).await?;
+    server_state.storage.get_manifest(&hash).await.map(Json)
+}
+
+/// Serves an older file as the latest until the next regeneration, accepts a
+/// hash or a `v12` label
 #[tracing::instrument(skip(server_state), ret(level = Level::TRACE))]
-async fn add_to_list(
-    server_state: State<Arc<ServerState<impl Storage>>>,
-    Json(props): Json<HostProps>,
+async fn rollback_version(
+    Path(id): Path<String>,
+    server_state: State<Arc<ServerState>>,
 ) -> Result<impl IntoResponse, AppError> {
-    server_state.storage.add_host(props.host).await?;
-    server_state
-        .update_tx
-        .send(())
-        .await
-        .map_err(|e| AppError::Other(e.to_string()))?;
-    Ok(Json(json!({ "success": true })))
+    let storage = server_state.storage.as_ref();
+    let hash = resolve_version(storage, &id).await?;
+    let version = version_label(storage.get_version(&hash).await?);
+    storage.set_latest(&hash).await?;
+    latest_pac(&server_state).await?;
+    info!("Rolled back to {version} {hash}");
+    Ok(Json(
+        json!({ "success": true, "version": version, "hash": hash }),
+    ))
 }
 
+/// Starts staging from the latest file, regenerations then only replace the
+/// candidate served at `/preview` until it is promoted
 #[tracing::instrument(skip(server_state), ret(level = Level::TRACE))]
-async fn remove_from_list(
-    server_state: State<Arc<ServerState<impl Storage>>>,
-    Json(props): Json<HostProps>,
+async fn start_preview(
+    server_state: State<Arc<ServerState>>,
 ) -> Result<impl IntoResponse, AppError> {
-    server_state.storage.remove_host(props.host).await?;
-    server_state
-        .update_tx
-        .send(())
-        .await
-        .map_err(|e| AppError::Other(e.to_string()))?;
-    Ok(Json(json!({ "success": true })))
+    let storage = server_state.storage.as_ref();
+    let hash = match storage.staged_hash().await? {
+        Some(hash) => hash,
+        None => {
+            let hash = storage.latest_hash().await?;
+            storage.set_staged_hash(Some(hash.clone())).await?;
+            info!("Staging changes on top of {hash}");
+            hash
+        }
+    };
+    Ok(Json(json!({ "success": true, "hash": hash })))
 }
 
-#[tracing::instrument]
-async fn fallback() -> (StatusCode, &'static str) {
-    (StatusCode::NOT_FOUND, "Not Found")
+/// Candidate file while staging, clients aren't pointed at it
+#[tracing::instrument(skip_all, err(level = Level::DEBUG))]
+async fn get_preview(
+    headers: HeaderMap,
+    server_state: State<Arc<ServerState>>,
+) -> Result<Response<Body>, AppError> {
+    let storage = server_state.storage.as_ref();
+    let hash = storage.staged_hash().await?.ok_or(AppError::NotFound)?;
+    let file = storage.get_file(&hash).await?;
+    let primed = prime_stored(storage, Arc::new(Pac::new(file, hash))).await;
+    let res = Response::builder()
+        .header(header::CONTENT_TYPE, "text/javascript")
+        .header(header::CACHE_CONTROL, "no-store")
+        .header(
+            header::LOCATION,
+            format!("/{}", urlencoding::encode(&primed.pac.hash)),
+        );
+    let (encoding, body) = primed.body(accept_encoding(&headers));
+    encoded_response(res, encoding, body)
 }
 
-#[tracing::instrument(skip_all, err(Debug))]
-async fn subscribe_pac(storage: Arc<impl Storage>, rx: Receiver<()>) -> Result<()> {
-    let mut deb = debounced(ReceiverStream::new(rx), Duration::from_millis(150));
-    while deb.next().await.is_some() {
-        let s = span!(Level::TRACE, "update_tx");
-        let _se = s.enter();
-        debug!("recv");
-        let hosts = match storage.all_hosts().await {
-            Ok(v) => v,
+/// Publishes the candidate and stops staging, later changes publish directly
+#[tracing::instrument(skip(server_state), ret(level = Level::TRACE))]
+async fn promote_preview(
+    server_state: State<Arc<ServerState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let storage = server_state.storage.as_ref();
+    let hash = storage.promote_staged().await?;
+    let version = version_label(storage.get_version(&hash).await?);
+    latest_pac(&server_state).await?;
+    info!("Promoted {version} {hash}");
+    Ok(Json(
+        json!({ "success": true, "version": version, "hash": hash }),
+    ))
+}
+
+/// Either a single `host` or a batch of `hosts`, both may be combined
+#[derive(Debug, Deserialize)]
+struct HostProps {
+    host: Option<String>,
+    hosts: Option<Vec<String>>,
+    /// Assigned to added hosts, upserted ones keep theirs without it
+    tags: Option<Vec<String>>,
+    /// Whether added hosts match just themselves or their domain tree,
+    /// `exact` unless given. Upserted ones keep theirs without it
+    kind: Option<EntryKind>,
+    /// Unix time added hosts are removed at
+    expires_at: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+struct HostResult {
+    host: String,
+    success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    warning: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum HostOp {
+    Add,
+    Remove,
+    Pin,
+    Unpin,
+}
+
+impl HostOp {
+    /// `patch` holds the tags, kind and expiry of added hosts
+    async fn apply(
+        self,
+        storage: &dyn Storage,
+        host: &str,
+        patch: &HostPatch,
+        add: AddOptions,
+    ) -> Result<(), AppError> {
+        if let HostOp::Remove = self {
+            return remove_host(storage, host).await;
+        }
+        let host = host::normalize(host)?;
+        match self {
+            HostOp::Add if add.upsert => {
+                storage.upsert_host(&host, patch.clone()).await.map(|_| ())
+            }
+            HostOp::Add => storage.add_host(&host, patch.clone()).await,
+            HostOp::Remove => unreachable!("removed above"),
+            HostOp::Pin => storage.set_pinned(&host, true).await,
+            HostOp::Unpin => storage.set_pinned(&host, false).await,
+        }
+    }
+
+    fn apply_dry(
+        self,
+        dry_run: &mut DryRun,
+        host: &str,
+        patch: &HostPatch,
+        add: AddOptions,
+    ) -> Result<(), AppError> {
+        if let HostOp::Remove = self {
+            return match host::normalize(host) {
+                Ok(normalized) => match dry_run.remove(&normalized) {
+                    Err(AppError::NotFound) if normalized != host => dry_run.remove(host),
+                    res => res,
+                },
+                Err(e) => dry_run.remove(host).map_err(|_| AppError::from(e)),
+            };
+        }
+        let host = host::normalize(host)?;
+        match self {
+            HostOp::Add if add.upsert => {
+                dry_run.upsert(host, patch);
+                Ok(())
+            }
+            HostOp::Add => dry_run.add(host, patch),
+            HostOp::Remove => unreachable!("removed above"),
+            HostOp::Pin => dry_run.set_pinned(&host, true),
+            HostOp::Unpin => dry_run.set_pinned(&host, false),
+        }
+    }
+
+    fn changes_pac(self) -> bool {
+        matches!(self, HostOp::Add | HostOp::Remove)
+    }
+}
+
+/// Removes `host` normalized. Rows stored before hosts were normalized are
+/// matched exactly when the normalized one isn't found or `host` no longer
+/// passes
+async fn remove_host(storage: &dyn Storage, host: &str) -> Result<(), AppError> {
+    match host::normalize(host) {
+        Ok(normalized) => match storage.remove_host(&normalized).await {
+            Err(AppError::NotFound) if normalized != host => storage.remove_host(host).await,
+            res => res,
+        },
+        Err(e) => match storage.remove_host(host).await {
+            Err(AppError::NotFound) => Err(e.into()),
+            res => res,
+        },
+    }
+}
+
+#[tracing::instrument(skip(server_state), ret(level = Level::TRACE))]
+async fn add_to_list(
+    server_state: State<Arc<ServerState>>,
+    Query(query): Query<AddQuery>,
+    Json(props): Json<HostProps>,
+) -> Result<impl IntoResponse, AppError> {
+    let verify = match (query.verify, query.verify_connect) {
+        (_, true) => Verify::Connect,
+        (true, false) => Verify::Resolve,
+        (false, false) => Verify::Off,
+    };
+    let opts = AddOptions {
+        verify,
+        allow_secret_like: query.allow_secret_like,
+        kind: props.kind,
+        upsert: query.upsert,
+    };
+    apply_host_props(&server_state, props, HostOp::Add, query.dry_run, opts).await
+}
+
+#[tracing::instrument(skip(server_state), ret(level = Level::TRACE))]
+async fn remove_from_list(
+    server_state: State<Arc<ServerState>>,
+    Query(query): Query<DryRunQuery>,
+    Json(props): Json<HostProps>,
+) -> Result<impl IntoResponse, AppError> {
+    apply_host_props(
+        &server_state,
+        props,
+        HostOp::Remove,
+        query.dry_run,
+        AddOptions::default(),
+    )
+    .await
+}
+
+#[tracing::instrument(skip(server_state), ret(level = Level::TRACE))]
+async fn pin_hosts(
+    server_state: State<Arc<ServerState>>,
+    Query(query): Query<DryRunQuery>,
+    Json(props): Json<HostProps>,
+) -> Result<impl IntoResponse, AppError> {
+    apply_host_props(
+        &server_state,
+        props,
+        HostOp::Pin,
+        query.dry_run,
+        AddOptions::default(),
+    )
+    .await
+}
+
+#[tracing::instrument(skip(server_state), ret(level = Level::TRACE))]
+async fn unpin_hosts(
+    server_state: State<Arc<ServerState>>,
+    Query(query): Query<DryRunQuery>,
+    Json(props): Json<HostProps>,
+) -> Result<impl IntoResponse, AppError> {
+    apply_host_props(
+        &server_state,
+        props,
+        HostOp::Unpin,
+        query.dry_run,
+        AddOptions::default(),
+    )
+    .await
+}
+
+#[tracing::instrument(skip_all, err(level = Level::DEBUG))]
+async fn get_pinned(server_state: State<Arc<ServerState>>) -> Result<impl IntoResponse, AppError> {
+    server_state.storage.pinned_hosts().await.map(Json)
+}
+
+/// Removed hosts that can still be restored
+#[tracing::instrument(skip_all, err(level = Level::DEBUG))]
+async fn get_deleted(server_state: State<Arc<ServerState>>) -> Result<impl IntoResponse, AppError> {
+    server_state.storage.deleted_hosts().await.map(Json)
+}
+
+/// Lists a removed host again with its note, tags and other metadata
+#[tracing::instrument(skip(server_state), err(level = Level::DEBUG))]
+async fn restore_host(
+    Path(host): Path<String>,
+    server_state: State<Arc<ServerState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let host = host::normalize(&host)?;
+    let entry = server_state.storage.restore_host(&host).await?;
+    notify_update(&server_state, 1);
+    Ok(Json(entry))
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct DryRunQuery {
+    /// Validates and reports the predicted hash without committing
+    #[serde(default)]
+    dry_run: bool,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct AddQuery {
+    #[serde(default)]
+    dry_run: bool,
+    /// Warns about hosts that don't resolve
+    #[serde(default)]
+    verify: bool,
+    /// Warns about hosts not answering on port 443 through the outbound proxy,
+    /// implies `verify`
+    #[serde(default)]
+    verify_connect: bool,
+    /// Accepts hosts that look like they carry credentials or tokens
+    #[serde(default)]
+    allow_secret_like: bool,
+    /// Updates the given tags and kind of listed hosts instead of failing on
+    /// them
+    #[serde(default)]
+    upsert: bool,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct AddOptions {
+    verify: Verify,
+    allow_secret_like: bool,
+    kind: Option<EntryKind>,
+    upsert: bool,
+}
+
+impl AddOptions {
+    fn check(self, op: HostOp, host: &str) -> Result<(), AppError> {
+        match op {
+            HostOp::Add => {
+                check_secret_like(host, self.allow_secret_like)?;
+                check_kind(&host::normalize(host)?, self.kind.unwrap_or_default())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+fn check_kind(host: &str, kind: EntryKind) -> Result<(), AppError> {
+    kind.rule(host)
+        .map(|_| ())
+        .map_err(|message| AppError::Validation {
+            field: "kind".to_string(),
+            message,
+        })
+}
+
+/// Rejects inputs that would persist a secret into every generated pac
+fn check_secret_like(host: &str, allowed: bool) -> Result<(), AppError> {
+    match host::secret_like(host) {
+        Some(reason) if !allowed => Err(AppError::Validation {
+            field: "host".to_string(),
+            message: format!("{reason}, pass allow_secret_like=true to add it anyway"),
+        }),
+        _ => Ok(()),
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+enum Verify {
+    #[default]
+    Off,
+    Resolve,
+    Connect,
+}
+
+impl Verify {
+    async fn probe(self, server_state: &ServerState, host: &str) -> Option<String> {
+        let client = match self {
+            Verify::Off => return None,
+            Verify::Resolve => None,
+            Verify::Connect => Some(&server_state.http_client),
+        };
+        let host = host::normalize(host).ok()?;
+        if host::regex_pattern(&host).is_some() {
+            return None;
+        }
+        // Wildcards can't be resolved, the domain they cover is probed instead
+        let host = host::wildcard_domain(&host).unwrap_or(&host);
+        verify::probe(host, client).await
+    }
+}
+
+/// Single `host` requests fail as a whole, batches report a status per item
+/// and skip pinned hosts on removal. Verification only warns, hosts are
+/// still accepted
+async fn apply_host_props(
+    server_state: &ServerState,
+    props: HostProps,
+    op: HostOp,
+    dry_run: bool,
+    add: AddOptions,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let tags = match props.tags {
+        Some(tags) => Some(
+            tags.iter()
+                .map(|t| normalize_tag(t))
+                .collect::<Result<Vec<_>, _>>()?,
+        ),
+        None => None,
+    };
+    let expires_at = match (op, props.expires_at) {
+        (HostOp::Add, Some(at)) if at <= unix_now() => {
+            return Err(AppError::Validation {
+                field: "expires_at".to_string(),
+                message: "value is in the past".to_string(),
+            });
+        }
+        (HostOp::Add, at) => at,
+        _ => None,
+    };
+    let patch = HostPatch {
+        tags,
+        kind: add.kind,
+        expires_at: expires_at.map(Some),
+        ..Default::default()
+    };
+    let mut dry = match dry_run {
+        true => Some(DryRun::load(server_state.storage.as_ref(), server_state.generate).await?),
+        false => None,
+    };
+    let Some(batch) = props.hosts else {
+        let Some(host) = props.host else {
+            return Err(AppError::Validation {
+                field: "host".to_string(),
+                message: "either host or hosts is required".to_string(),
+            });
+        };
+        add.check(op, &host)?;
+        let warning = add.verify.probe(server_state, &host).await;
+        let mut res = match &mut dry {
+            Some(dry) => {
+                op.apply_dry(dry, &host, &patch, add)?;
+                json!({
+                    "success": true,
+                    "dry_run": true,
+                    "hash": dry.hash(),
+                })
+            }
+            None => {
+                op.apply(server_state.storage.as_ref(), &host, &patch, add)
+                    .await?;
+                if op.changes_pac() {
+                    notify_update(server_state, 1);
+                }
+                json!({ "success": true })
+            }
+        };
+        if let Some(warning) = warning {
+            res["warning"] = json!(warning);
+        }
+        return Ok(Json(res));
+    };
+
+    let pinned: HashSet<String> = match op {
+        HostOp::Remove => server_state
+            .storage
+            .pinned_hosts()
+            .await?
+            .into_iter()
+            .collect(),
+        _ => HashSet::new(),
+    };
+    let hosts: Vec<String> = props.host.into_iter().chain(batch).collect();
+    let outcomes = match (&mut dry, op) {
+        (None, HostOp::Add | HostOp::Remove) => {
+            apply_bulk(
+                server_state.storage.as_ref(),
+                op,
+                &hosts,
+                &patch,
+                add,
+                &pinned,
+            )
+            .await?
+        }
+        _ => {
+            let mut outcomes = Vec::with_capacity(hosts.len());
+            for host in hosts.iter() {
+                outcomes.push(match host::normalize(host) {
+                    Ok(h) if pinned.contains(&h) => {
+                        Err(AppError::PreconditionFailed("Host is pinned".to_string()))
+                    }
+                    _ => match (add.check(op, host), &mut dry) {
+                        (Err(e), _) => Err(e),
+                        (Ok(()), Some(dry)) => op.apply_dry(dry, host, &patch, add),
+                        (Ok(()), None) => {
+                            op.apply(server_state.storage.as_ref(), host, &patch, add)
+                                .await
+                        }
+                    },
+                });
+            }
+            outcomes
+        }
+    };
+    let mut results = Vec::with_capacity(hosts.len());
+    for (host, res) in hosts.into_iter().zip(outcomes) {
+        let warning = match res {
+            Ok(_) => add.verify.probe(server_state, &host).await,
+            Err(_) => None,
+        };
+        results.push(HostResult {
+            host,
+            success: res.is_ok(),
+            error: res.err().map(|e| e.to_string()),
+            warning,
+        });
+    }
+    let success = results.iter().all(|r| r.success);
+    if let Some(dry) = dry {
+        return Ok(Json(json!({
+            "success": success,
+            "results": results,
+            "dry_run": true,
+            "hash": dry.hash(),
+        })));
+    }
+    let changed = results.iter().filter(|r| r.success).count();
+    if op.changes_pac() && changed > 0 {
+        notify_update(server_state, changed);
+    }
+    Ok(Json(json!({
+        "success": success,
+        "results": results,
+    })))
+}
+
+/// Adds or removes the valid hosts of a batch with a single storage call
+/// instead of a round trip per host, returns the outcome of every host
+async fn apply_bulk(
+    storage: &dyn Storage,
+    op: HostOp,
+    hosts: &[String],
+    patch: &HostPatch,
+    add: AddOptions,
+    pinned: &HashSet<String>,
+) -> Result<Vec<Result<(), AppError>>, AppError> {
+    let normalized: Vec<Result<String, AppError>> = hosts
+        .iter()
+        .map(|host| {
+            let normalized = host::normalize(host);
+            if normalized.as_ref().is_ok_and(|h| pinned.contains(h)) {
+                return Err(AppError::PreconditionFailed("Host is pinned".to_string()));
+            }
+            add.check(op, host)?;
+            Ok(normalized?)
+        })
+        .collect();
+    let valid: Vec<String> = normalized.iter().flatten().cloned().collect();
+    let mut done: HashSet<String> = match op {
+        HostOp::Add if add.upsert => {
+            storage.upsert_hosts(valid.clone(), patch.clone()).await?;
+            valid
+        }
+        HostOp::Add => storage.add_hosts(valid, patch.clone()).await?,
+        _ => storage.remove_hosts(valid).await?,
+    }
+    .into_iter()
+    .collect();
+    // Rows stored before hosts were normalized only match exactly
+    let mut legacy = HashSet::new();
+    if let HostOp::Remove = op {
+        for (raw, host) in hosts.iter().zip(normalized.iter()) {
+            let missing = match host {
+                Ok(h) => h != raw && !done.contains(h),
+                Err(e) => matches!(e, AppError::InvalidHost(_)),
+            };
+            if missing && !pinned.contains(raw) && storage.remove_host(raw).await.is_ok() {
+                legacy.insert(raw.as_str());
+            }
+        }
+    }
+    Ok(hosts
+        .iter()
+        .zip(normalized)
+        .map(|(raw, host)| {
+            if legacy.contains(raw.as_str()) {
+                return Ok(());
+            }
+            match done.remove(&host?) {
+                true => Ok(()),
+                false if matches!(op, HostOp::Add) => Err(AppError::PreconditionFailed(
+                    "Host already exists".to_string(),
+                )),
+                false => Err(AppError::NotFound),
+            }
+        })
+        .collect())
+}
+
+const MAX_NAME_LEN: usize = 64;
+
+/// Trims and lowercases a tag, snapshot or profile name, allowed are ascii
+/// alphanumerics, `-`, `_`, `.` and `:`
+fn normalize_name(field: &str, value: &str) -> Result<String, AppError> {
+    let value = value.trim().to_lowercase();
+    let invalid = |message: &str| AppError::Validation {
+        field: field.to_string(),
+        message: message.to_string(),
+    };
+    if value.is_empty() {
+        return Err(invalid("value is empty"));
+    }
+    if value.len() > MAX_NAME_LEN {
+        return Err(invalid(&format!(
+            "value is longer than {MAX_NAME_LEN} bytes"
+        )));
+    }
+    if !value
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':'))
+    {
+        return Err(invalid("value contains invalid characters"));
+    }
+    Ok(value)
+}
+
+fn normalize_tag(tag: &str) -> Result<String, AppError> {
+    normalize_name("tags", tag)
+}
+
+#[tracing::instrument(skip_all, err(level = Level::DEBUG))]
+async fn get_tags(server_state: State<Arc<ServerState>>) -> Result<impl IntoResponse, AppError> {
+    server_state.storage.list_tags().await.map(Json)
+}
+
+#[derive(Debug, Deserialize)]
+struct RenameTagProps {
+    name: String,
+}
+
+/// Renames a tag on every host bearing it and in the profiles listing it
+#[tracing::instrument(skip(server_state), ret(level = Level::TRACE))]
+async fn rename_tag(
+    Path(tag): Path<String>,
+    server_state: State<Arc<ServerState>>,
+    Json(props): Json<RenameTagProps>,
+) -> Result<impl IntoResponse, AppError> {
+    let tag = normalize_tag(&tag)?;
+    let name = normalize_tag(&props.name)?;
+    server_state.storage.rename_tag(&tag, &name).await?;
+    Ok(Json(json!({ "success": true })))
+}
+
+/// Takes a tag off every host, unlike `DELETE /tags/:tag/hosts` the hosts stay
+#[tracing::instrument(skip(server_state), ret(level = Level::TRACE))]
+async fn delete_tag(
+    Path(tag): Path<String>,
+    server_state: State<Arc<ServerState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let tag = normalize_tag(&tag)?;
+    server_state.storage.delete_tag(&tag).await?;
+    Ok(Json(json!({ "success": true })))
+}
+
+#[tracing::instrument(skip(server_state), err(level = Level::DEBUG))]
+async fn get_tag_hosts(
+    Path(tag): Path<String>,
+    server_state: State<Arc<ServerState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let tag = normalize_tag(&tag)?;
+    server_state.storage.hosts_by_tag(&tag).await.map(Json)
+}
+
+/// Removes all non-pinned hosts bearing a tag with a single regeneration
+#[tracing::instrument(skip(server_state), ret(level = Level::TRACE))]
+async fn remove_tag_hosts(
+    Path(tag): Path<String>,
+    server_state: State<Arc<ServerState>>,
+    Query(query): Query<DryRunQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    let tag = normalize_tag(&tag)?;
+    if query.dry_run {
+        let mut dry = DryRun::load(server_state.storage.as_ref(), server_state.generate).await?;
+        let removed = dry.remove_hosts_by_tag(&tag);
+        return Ok(Json(json!({
+            "success": true,
+            "removed": removed,
+            "dry_run": true,
+            "hash": dry.hash(),
+        })));
+    }
+    let removed = server_state.storage.remove_hosts_by_tag(&tag).await?;
+    if !removed.is_empty() {
+        notify_update(&server_state, removed.len());
+    }
+    Ok(Json(json!({
+        "success": true,
+        "removed": removed,
+    })))
+}
+
+#[derive(Debug, Deserialize)]
+struct SearchQuery {
+    q: String,
+}
+
+const MAX_SEARCH_LEN: usize = 255;
+
+/// Hosts matching a `LIKE` pattern such as `%google%`
+#[tracing::instrument(skip(server_state), err(level = Level::DEBUG))]
+async fn search_hosts(
+    Query(query): Query<SearchQuery>,
+    server_state: State<Arc<ServerState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let pattern = query.q.trim();
+    if pattern.is_empty() || pattern.len() > MAX_SEARCH_LEN {
+        return Err(AppError::Validation {
+            field: "q".to_string(),
+            message: format!("expected 1 to {MAX_SEARCH_LEN} bytes"),
+        });
+    }
+    server_state.storage.search_hosts(pattern).await.map(Json)
+}
+
+#[derive(Debug, Deserialize)]
+struct HostsPageQuery {
+    /// Last host of the previous page
+    after: Option<String>,
+    limit: Option<u32>,
+}
+
+const DEFAULT_HOSTS_PAGE_LIMIT: u32 = 100;
+const MAX_HOSTS_PAGE_LIMIT: u32 = 1000;
+
+/// Hosts with metadata a page at a time, `next` is the `after` of the
+/// following page and `null` on the last one
+#[tracing::instrument(skip(server_state), err(level = Level::DEBUG))]
+async fn get_hosts_page(
+    Query(query): Query<HostsPageQuery>,
+    server_state: State<Arc<ServerState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let limit = query.limit.unwrap_or(DEFAULT_HOSTS_PAGE_LIMIT);
+    if limit == 0 || limit > MAX_HOSTS_PAGE_LIMIT {
+        return Err(AppError::Validation {
+            field: "limit".to_string(),
+            message: format!("expected 1 to {MAX_HOSTS_PAGE_LIMIT}"),
+        });
+    }
+    let hosts = server_state
+        .storage
+        .hosts_page(query.after.as_deref(), limit)
+        .await?;
+    let next = if hosts.len() == limit as usize {
+        hosts.last().map(|e| e.host.clone())
+    } else {
+        None
+    };
+    Ok(Json(json!({ "hosts": hosts, "next": next })))
+}
+
+#[tracing::instrument(skip(server_state), err(level = Level::DEBUG))]
+async fn get_host(
+    Path(host): Path<String>,
+    server_state: State<Arc<ServerState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let host = host::normalize(&host)?;
+    server_state.storage.get_host(&host).await.map(Json)
+}
+
+/// Updates metadata in place, regenerates only when the pac would change
+#[tracing::instrument(skip(server_state), ret(level = Level::TRACE))]
+async fn update_host(
+    Path(host): Path<String>,
+    server_state: State<Arc<ServerState>>,
+    Json(mut patch): Json<HostPatch>,
+) -> Result<impl IntoResponse, AppError> {
+    let host = host::normalize(&host)?;
+    if let Some(tags) = patch.tags.take() {
+        patch.tags = Some(
+            tags.iter()
+                .map(|t| normalize_tag(t))
+                .collect::<Result<Vec<_>, _>>()?,
+        );
+    }
+    if let Some(Some(note)) = &patch.note {
+        let note = note.trim();
+        patch.note = Some((!note.is_empty()).then(|| note.to_string()));
+    }
+    if let Some(Some(group)) = &patch.group {
+        let group = normalize_name("group", group)?;
+        let groups = server_state.storage.list_groups().await?;
+        if !groups.iter().any(|g| g.name == group) {
+            return Err(AppError::Validation {
+                field: "group".to_string(),
+                message: format!("group {group} doesn't exist"),
+            });
+        }
+        patch.group = Some(Some(group));
+    }
+
+    if let Some(kind) = patch.kind {
+        check_kind(&host, kind)?;
+    }
+
+    let before = server_state.storage.get_host(&host).await?;
+    let after = server_state.storage.update_host(&host, patch).await?;
+    if before.rule() != after.rule() || before.group != after.group {
+        notify_update(&server_state, 1);
+    }
+    Ok(Json(after))
+}
+
+#[tracing::instrument(skip_all, err(level = Level::DEBUG))]
+async fn get_snapshots(
+    server_state: State<Arc<ServerState>>,
+) -> Result<impl IntoResponse, AppError> {
+    server_state.storage.list_snapshots().await.map(Json)
+}
+
+#[derive(Debug, Deserialize)]
+struct SnapshotProps {
+    name: String,
+}
+
+#[tracing::instrument(skip(server_state), ret(level = Level::TRACE))]
+async fn create_snapshot(
+    server_state: State<Arc<ServerState>>,
+    Json(props): Json<SnapshotProps>,
+) -> Result<impl IntoResponse, AppError> {
+    let name = normalize_name("name", &props.name)?;
+    server_state.storage.create_snapshot(&name).await.map(Json)
+}
+
+/// Hosts and settings, what replicas started with `--replicate-from` pull
+#[tracing::instrument(skip(server_state), err(level = Level::DEBUG))]
+async fn get_state(server_state: State<Arc<ServerState>>) -> Result<impl IntoResponse, AppError> {
+    Ok(Json(server_state.storage.export_state().await?))
+}
+
+/// Replaces hosts and settings with a state pushed by the primary
+#[tracing::instrument(skip(server_state, state), err(level = Level::DEBUG))]
+async fn put_state(
+    server_state: State<Arc<ServerState>>,
+    Json(state): Json<InstanceState>,
+) -> Result<impl IntoResponse, AppError> {
+    let changed = replication::apply(&server_state, state).await?;
+    Ok(Json(json!({ "success": true, "changed": changed })))
+}
+
+/// Restores the editable list, unlike pac versions this includes metadata
+#[tracing::instrument(skip(server_state), ret(level = Level::TRACE))]
+async fn restore_snapshot(
+    Path(name): Path<String>,
+    server_state: State<Arc<ServerState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let name = normalize_name("name", &name)?;
+    let diff = server_state.storage.restore_snapshot(&name).await?;
+    // Metadata may change the pac even when the host set doesn't
+    notify_update(&server_state, diff.added.len() + diff.removed.len());
+    Ok(Json(json!({
+        "success": true,
+        "added": diff.added,
+        "removed": diff.removed,
+    })))
+}
+
+#[tracing::instrument(skip(server_state), ret(level = Level::TRACE))]
+async fn delete_snapshot(
+    Path(name): Path<String>,
+    server_state: State<Arc<ServerState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let name = normalize_name("name", &name)?;
+    server_state.storage.delete_snapshot(&name).await?;
+    Ok(Json(json!({ "success": true })))
+}
+
+#[tracing::instrument(skip_all, err(level = Level::DEBUG))]
+async fn get_profiles(
+    server_state: State<Arc<ServerState>>,
+) -> Result<impl IntoResponse, AppError> {
+    server_state.storage.list_profiles().await.map(Json)
+}
+
+const MAX_PROXY_LEN: usize = 1024;
+
+/// Validates and normalizes a proxy chain, e.g. `PROXY 10.0.0.1:3128; DIRECT`
+fn normalize_proxy(proxy: &str) -> Result<String, AppError> {
+    let invalid = |message| AppError::Validation {
+        field: "proxy".to_string(),
+        message,
+    };
+    if proxy.len() > MAX_PROXY_LEN {
+        return Err(invalid(format!(
+            "proxy must be at most {MAX_PROXY_LEN} bytes long"
+        )));
+    }
+    pac::normalize_chain(proxy).map_err(invalid)
+}
+
+#[derive(Debug, Deserialize)]
+struct ProfileProps {
+    proxy: String,
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ProxyProps {
+    proxy: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct GroupProps {
+    proxy: String,
+    /// Route the hosts of the group only at these times, e.g. `MON-FRI 9-17`
+    #[serde(default)]
+    schedule: Option<Schedule>,
+}
+
+#[derive(Debug, Deserialize)]
+struct NetworkProps {
+    /// IPv4 network in CIDR notation the client's address is checked against
+    network: String,
+    proxy: String,
+}
+
+/// Proxy chain of the default pac
+async fn default_proxy(storage: &dyn Storage) -> Result<String, AppError> {
+    Ok(storage
+        .get_proxy()
+        .await?
+        .unwrap_or_else(|| pac::DEFAULT_PROXY.to_string()))
+}
+
+#[tracing::instrument(skip_all, err(level = Level::DEBUG))]
+async fn get_proxy(server_state: State<Arc<ServerState>>) -> Result<impl IntoResponse, AppError> {
+    let proxy = default_proxy(server_state.storage.as_ref()).await?;
+    Ok(Json(json!({ "proxy": proxy })))
+}
+
+#[tracing::instrument(skip(server_state), ret(level = Level::TRACE))]
+async fn set_proxy(
+    server_state: State<Arc<ServerState>>,
+    Json(props): Json<ProxyProps>,
+) -> Result<impl IntoResponse, AppError> {
+    let proxy = normalize_proxy(&props.proxy)?;
+    if default_proxy(server_state.storage.as_ref()).await? != proxy {
+        server_state.storage.set_proxy(&proxy).await?;
+        notify_update(&server_state, 0);
+    }
+    Ok(Json(json!({ "success": true })))
+}
+
+#[derive(Debug, Deserialize)]
+struct ModeProps {
+    mode: PacMode,
+}
+
+#[tracing::instrument(skip_all, err(level = Level::DEBUG))]
+async fn get_mode(server_state: State<Arc<ServerState>>) -> Result<impl IntoResponse, AppError> {
+    let mode = server_state.storage.get_mode().await?;
+    Ok(Json(json!({ "mode": mode })))
+}
+
+/// Switches between proxying only the listed hosts and everything but them
+#[tracing::instrument(skip(server_state), ret(level = Level::TRACE))]
+async fn set_mode(
+    server_state: State<Arc<ServerState>>,
+    Json(props): Json<ModeProps>,
+) -> Result<impl IntoResponse, AppError> {
+    if server_state.storage.get_mode().await? != props.mode {
+        server_state.storage.set_mode(props.mode).await?;
+        notify_update(&server_state, 0);
+    }
+    Ok(Json(json!({ "success": true })))
+}
+
+const MAX_UPSTREAMS: usize = 64;
+const MAX_UPSTREAM_WEIGHT: u32 = 1000;
+
+#[derive(Debug, Deserialize)]
+struct UpstreamsProps {
+    upstreams: Vec<Upstream>,
+}
+
+#[tracing::instrument(skip_all, err(level = Level::DEBUG))]
+async fn get_upstreams(
+    server_state: State<Arc<ServerState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let upstreams = server_state.storage.list_upstreams().await?;
+    Ok(Json(json!({ "upstreams": upstreams })))
+}
+
+/// Replaces the upstreams the default hosts are spread over, an empty list
+/// sends them all through the default proxy again
+#[tracing::instrument(skip(server_state), ret(level = Level::TRACE))]
+async fn set_upstreams(
+    server_state: State<Arc<ServerState>>,
+    Json(props): Json<UpstreamsProps>,
+) -> Result<impl IntoResponse, AppError> {
+    if props.upstreams.len() > MAX_UPSTREAMS {
+        return Err(AppError::Validation {
+            field: "upstreams".to_string(),
+            message: format!("at most {MAX_UPSTREAMS} upstreams are allowed"),
+        });
+    }
+    let mut upstreams = Vec::with_capacity(props.upstreams.len());
+    for upstream in props.upstreams {
+        if !(1..=MAX_UPSTREAM_WEIGHT).contains(&upstream.weight) {
+            return Err(AppError::Validation {
+                field: "weight".to_string(),
+                message: format!("weight must be 1 to {MAX_UPSTREAM_WEIGHT}"),
+            });
+        }
+        upstreams.push(Upstream {
+            proxy: normalize_proxy(&upstream.proxy)?,
+            weight: upstream.weight,
+        });
+    }
+    if server_state.storage.list_upstreams().await? != upstreams {
+        server_state.storage.set_upstreams(upstreams).await?;
+        notify_update(&server_state, 0);
+    }
+    Ok(Json(json!({ "success": true })))
+}
+
+const MAX_IP_RANGES: usize = 4096;
+
+#[derive(Debug, Deserialize)]
+struct IpRangesProps {
+    ip_ranges: Vec<String>,
+}
+
+#[tracing::instrument(skip_all, err(level = Level::DEBUG))]
+async fn get_ip_ranges(
+    server_state: State<Arc<ServerState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let ip_ranges = server_state.storage.list_ip_ranges().await?;
+    Ok(Json(json!({
+        "ip_ranges": ip_ranges,
+        "resolve": server_state.generate.resolve_ip_ranges,
+    })))
+}
+
+/// Replaces the networks hosts resolving into count as listed, they only make
+/// it into the pac with `--resolve-ip-ranges`
+#[tracing::instrument(skip(server_state), ret(level = Level::TRACE))]
+async fn set_ip_ranges(
+    server_state: State<Arc<ServerState>>,
+    Json(props): Json<IpRangesProps>,
+) -> Result<impl IntoResponse, AppError> {
+    if props.ip_ranges.len() > MAX_IP_RANGES {
+        return Err(AppError::Validation {
+            field: "ip_ranges".to_string(),
+            message: format!("at most {MAX_IP_RANGES} ranges are allowed"),
+        });
+    }
+    let mut ip_ranges = props
+        .ip_ranges
+        .iter()
+        .map(|r| pac::normalize_network(r))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|message| AppError::Validation {
+            field: "ip_ranges".to_string(),
+            message,
+        })?;
+    ip_ranges.sort();
+    ip_ranges.dedup();
+    if server_state.storage.list_ip_ranges().await? != ip_ranges {
+        server_state.storage.set_ip_ranges(ip_ranges).await?;
+        if server_state.generate.resolve_ip_ranges {
+            notify_update(&server_state, 0);
+        }
+    }
+    let mut res = json!({ "success": true });
+    if !server_state.generate.resolve_ip_ranges {
+        res["warning"] = json!("ranges are stored but ignored until --resolve-ip-ranges is set");
+    }
+    Ok(Json(res))
+}
+
+#[tracing::instrument(skip(server_state), ret(level = Level::TRACE))]
+async fn set_profile(
+    Path(name): Path<String>,
+    server_state: State<Arc<ServerState>>,
+    Json(props): Json<ProfileProps>,
+) -> Result<impl IntoResponse, AppError> {
+    let name = normalize_name("name", &name)?;
+    let proxy = normalize_proxy(&props.proxy)?;
+    let tags: BTreeSet<String> = props
+        .tags
+        .iter()
+        .map(|t| normalize_tag(t))
+        .collect::<Result<_, _>>()?;
+    server_state
+        .storage
+        .set_profile(Profile {
+            name,
+            proxy,
+            tags: tags.into_iter().collect(),
+        })
+        .await?;
+    Ok(Json(json!({ "success": true })))
+}
+
+#[tracing::instrument(skip(server_state), ret(level = Level::TRACE))]
+async fn remove_profile(
+    Path(name): Path<String>,
+    server_state: State<Arc<ServerState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let name = normalize_name("name", &name)?;
+    server_state.storage.remove_profile(&name).await?;
+    Ok(Json(json!({ "success": true })))
+}
+
+#[tracing::instrument(skip_all, err(level = Level::DEBUG))]
+async fn get_groups(server_state: State<Arc<ServerState>>) -> Result<impl IntoResponse, AppError> {
+    server_state.storage.list_groups().await.map(Json)
+}
+
+#[tracing::instrument(skip(server_state), ret(level = Level::TRACE))]
+async fn set_group(
+    Path(name): Path<String>,
+    server_state: State<Arc<ServerState>>,
+    Json(props): Json<GroupProps>,
+) -> Result<impl IntoResponse, AppError> {
+    let name = normalize_name("name", &name)?;
+    let proxy = normalize_proxy(&props.proxy)?;
+    let schedule = props.schedule;
+    server_state
+        .storage
+        .set_group(ProxyGroup {
+            name,
+            proxy,
+            schedule,
+        })
+        .await?;
+    notify_update(&server_state, 0);
+    Ok(Json(json!({ "success": true })))
+}
+
+#[tracing::instrument(skip(server_state), ret(level = Level::TRACE))]
+async fn remove_group(
+    Path(name): Path<String>,
+    server_state: State<Arc<ServerState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let name = normalize_name("name", &name)?;
+    server_state.storage.remove_group(&name).await?;
+    notify_update(&server_state, 0);
+    Ok(Json(json!({ "success": true })))
+}
+
+#[tracing::instrument(skip_all, err(level = Level::DEBUG))]
+async fn get_networks(
+    server_state: State<Arc<ServerState>>,
+) -> Result<impl IntoResponse, AppError> {
+    server_state.storage.list_networks().await.map(Json)
+}
+
+#[tracing::instrument(skip(server_state), ret(level = Level::TRACE))]
+async fn set_network(
+    Path(name): Path<String>,
+    server_state: State<Arc<ServerState>>,
+    Json(props): Json<NetworkProps>,
+) -> Result<impl IntoResponse, AppError> {
+    let name = normalize_name("name", &name)?;
+    let network =
+        pac::normalize_network(&props.network).map_err(|message| AppError::Validation {
+            field: "network".to_string(),
+            message,
+        })?;
+    let proxy = normalize_proxy(&props.proxy)?;
+    server_state
+        .storage
+        .set_network(NetworkProfile {
+            name,
+            network,
+            proxy,
+        })
+        .await?;
+    notify_update(&server_state, 0);
+    Ok(Json(json!({ "success": true })))
+}
+
+#[tracing::instrument(skip(server_state), ret(level = Level::TRACE))]
+async fn remove_network(
+    Path(name): Path<String>,
+    server_state: State<Arc<ServerState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let name = normalize_name("name", &name)?;
+    server_state.storage.remove_network(&name).await?;
+    notify_update(&server_state, 0);
+    Ok(Json(json!({ "success": true })))
+}
+
+#[derive(Debug, Deserialize)]
+struct BypassPrivateProps {
+    bypass_private: bool,
+}
+
+#[tracing::instrument(skip_all, err(level = Level::DEBUG))]
+async fn get_bypass_private(
+    server_state: State<Arc<ServerState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let enabled = server_state.storage.get_bypass_private().await?;
+    Ok(Json(json!({ "bypass_private": enabled })))
+}
+
+/// Toggles sending plain host names and private network addresses DIRECT
+#[tracing::instrument(skip(server_state), ret(level = Level::TRACE))]
+async fn set_bypass_private(
+    server_state: State<Arc<ServerState>>,
+    Json(props): Json<BypassPrivateProps>,
+) -> Result<impl IntoResponse, AppError> {
+    if server_state.storage.get_bypass_private().await? != props.bypass_private {
+        server_state
+            .storage
+            .set_bypass_private(props.bypass_private)
+            .await?;
+        notify_update(&server_state, 0);
+    }
+    Ok(Json(json!({ "success": true })))
+}
+
+#[tracing::instrument(skip_all, err(level = Level::DEBUG))]
+async fn get_exclusions(
+    server_state: State<Arc<ServerState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let exclusions = server_state.storage.list_exclusions().await?;
+    Ok(Json(json!({ "exclusions": exclusions })))
+}
+
+#[derive(Debug, Deserialize)]
+struct ExclusionProps {
+    hosts: Vec<String>,
+}
+
+/// Hosts sent DIRECT even when a wildcard, group or the blacklist mode would
+/// proxy them
+#[tracing::instrument(skip(server_state), ret(level = Level::TRACE))]
+async fn add_exclusions(
+    server_state: State<Arc<ServerState>>,
+    Json(props): Json<ExclusionProps>,
+) -> Result<impl IntoResponse, AppError> {
+    let hosts = props
+        .hosts
+        .iter()
+        .map(|h| host::normalize(h))
+        .collect::<Result<Vec<_>, _>>()?;
+    let added = server_state.storage.add_exclusions(hosts).await?;
+    if !added.is_empty() {
+        notify_update(&server_state, added.len());
+    }
+    Ok(Json(json!({ "success": true, "added": added })))
+}
+
+#[tracing::instrument(skip(server_state), ret(level = Level::TRACE))]
+async fn remove_exclusion(
+    Path(host): Path<String>,
+    server_state: State<Arc<ServerState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let host = host::normalize(&host)?;
+    server_state.storage.remove_exclusion(&host).await?;
+    notify_update(&server_state, 1);
+    Ok(Json(json!({ "success": true })))
+}
+
+#[tracing::instrument(skip_all, err(level = Level::DEBUG))]
+async fn get_blocklist(
+    server_state: State<Arc<ServerState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let blocklist = server_state.storage.list_blocklist().await?;
+    Ok(Json(json!({ "blocklist": blocklist })))
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct BlocklistProps {
+    #[serde(default)]
+    kind: EntryKind,
+    #[serde(default)]
+    action: BlocklistAction,
+}
+
+/// Blocks a host or sends it DIRECT before exclusions, groups and the list
+/// are checked, replaces an existing entry of the host
+#[tracing::instrument(skip(server_state), ret(level = Level::TRACE))]
+async fn set_blocklisted(
+    Path(host): Path<String>,
+    server_state: State<Arc<ServerState>>,
+    Json(props): Json<BlocklistProps>,
+) -> Result<impl IntoResponse, AppError> {
+    let host = host::normalize(&host)?;
+    check_kind(&host, props.kind)?;
+    let entry = BlocklistEntry {
+        host,
+        kind: props.kind,
+        action: props.action,
+        ..Default::default()
+    };
+    server_state.storage.set_blocklisted(entry).await?;
+    notify_update(&server_state, 1);
+    Ok(Json(json!({ "success": true })))
+}
+
+#[tracing::instrument(skip(server_state), ret(level = Level::TRACE))]
+async fn remove_blocklisted(
+    Path(host): Path<String>,
+    server_state: State<Arc<ServerState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let host = host::normalize(&host)?;
+    server_state.storage.remove_blocklisted(&host).await?;
+    notify_update(&server_state, 1);
+    Ok(Json(json!({ "success": true })))
+}
+
+#[derive(Debug, Deserialize)]
+struct ImportQuery {
+    #[serde(default)]
+    mode: ImportMode,
+    #[serde(default)]
+    dry_run: bool,
+    #[serde(default)]
+    allow_secret_like: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct ImportProps {
+    hosts: Vec<String>,
+}
+
+#[tracing::instrument(skip(server_state, props), ret(level = Level::TRACE))]
+async fn import_hosts(
+    server_state: State<Arc<ServerState>>,
+    Query(query): Query<ImportQuery>,
+    Json(props): Json<ImportProps>,
+) -> Result<impl IntoResponse, AppError> {
+    let hosts = props
+        .hosts
+        .iter()
+        .map(|h| {
+            check_secret_like(h, query.allow_secret_like)?;
+            Ok(host::normalize(h)?)
+        })
+        .collect::<Result<Vec<_>, AppError>>()?;
+    let diff = server_state
+        .storage
+        .import_hosts(hosts, query.mode, query.dry_run)
+        .await?;
+    if query.dry_run {
+        let mut dry = DryRun::load(server_state.storage.as_ref(), server_state.generate).await?;
+        dry.apply_diff(&diff);
+        return Ok(Json(json!({
+            "dry_run": true,
+            "added": diff.added,
+            "removed": diff.removed,
+            "hash": dry.hash(),
+        })));
+    }
+    if !diff.is_empty() {
+        notify_update(&server_state, diff.added.len() + diff.removed.len());
+    }
+    Ok(Json(json!({
+        "dry_run": false,
+        "added": diff.added,
+        "removed": diff.removed,
+    })))
+}
+
+/// Counts `changed` hosts towards the next regeneration, which storage
+/// schedules itself through [`Storage::watch`]
+fn notify_update(server_state: &ServerState, changed: usize) {
+    server_state.list.invalidate();
+    server_state.stats.changed(changed);
+    if let Some(monitor) = &server_state.change_monitor {
+        monitor.observe(changed);
+    }
+}
+
+/// Falls back to the cached PAC when storage is down, other errors pass through
+async fn cached_on_outage(
+    cache: &LatestPacCache,
+    err: AppError,
+    hash: Option<&str>,
+) -> Result<Arc<Pac>, AppError> {
+    if !matches!(err, AppError::Unavailable(_) | AppError::Timeout(_)) {
+        return Err(err);
+    }
+    let cached = match hash {
+        Some(hash) => cache.get_by_hash(hash).await,
+        None => cache.get().await,
+    };
+    match cached {
+        Some(pac) => {
+            info!("Storage unavailable, serving cached pac: {err}");
+            Ok(pac)
+        }
+        None => Err(err),
+    }
+}
+
+#[derive(Deserialize)]
+struct LoginProps {
+    token: String,
+}
+
+/// Exchanges the admin token for a session cookie, mutations made with the
+/// cookie have to echo `csrf_token` in the `X-CSRF-Token` header
+#[tracing::instrument(skip_all)]
+async fn login(
+    server_state: State<Arc<ServerState>>,
+    Json(props): Json<LoginProps>,
+) -> Result<Response<Body>, Response<Body>> {
+    let Some(auth) = &server_state.auth else {
+        return Err(fallback().await.into_response());
+    };
+    let Some(token) = auth
+        .verify(&props.token)
+        .map_err(IntoResponse::into_response)?
+    else {
+        return Err((StatusCode::UNAUTHORIZED, "Unathorized").into_response());
+    };
+    let sessions = auth.sessions();
+    let (id, csrf) = sessions.create(token);
+    let cookie = session::cookie(&id, sessions.ttl(), server_state.secure_cookies);
+    Ok((
+        [(header::SET_COOKIE, cookie)],
+        Json(json!({
+            "success": true,
+            "csrf_token": csrf,
+            "expires_in": sessions.ttl().as_secs(),
+        })),
+    )
+        .into_response())
+}
+
+/// CSRF token of the current session, lets a reloaded page pick it up again
+#[tracing::instrument(skip_all)]
+async fn get_session(headers: HeaderMap, server_state: State<Arc<ServerState>>) -> Response<Body> {
+    let csrf = server_state
+        .auth
+        .as_ref()
+        .and_then(|auth| session::session_id(&headers).and_then(|id| auth.sessions().csrf(id)));
+    match csrf {
+        Some(csrf) => Json(json!({ "csrf_token": csrf })).into_response(),
+        None => (StatusCode::UNAUTHORIZED, "Unathorized").into_response(),
+    }
+}
+
+#[tracing::instrument(skip_all)]
+async fn logout(headers: HeaderMap, server_state: State<Arc<ServerState>>) -> impl IntoResponse {
+    if let (Some(auth), Some(id)) = (&server_state.auth, session::session_id(&headers)) {
+        auth.sessions().remove(id);
+    }
+    (
+        [(
+            header::SET_COOKIE,
+            session::clear_cookie(server_state.secure_cookies),
+        )],
+        Json(json!({ "success": true })),
+    )
+}
+
+/// OpenMetrics with exemplars for scrapers asking for it, the prometheus text
+/// format otherwise
+fn render_metrics(
+    handle: &metrics_exporter_prometheus::PrometheusHandle,
+    headers: &HeaderMap,
+) -> Response<Body> {
+    let openmetrics = headers
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.contains("application/openmetrics-text"));
+    if !openmetrics {
+        return handle.render().into_response();
+    }
+    (
+        [(
+            header::CONTENT_TYPE,
+            instrument::metrics::OPENMETRICS_CONTENT_TYPE,
+        )],
+        instrument::metrics::render_openmetrics(handle),
+    )
+        .into_response()
+}
+
+#[tracing::instrument]
+async fn fallback() -> (StatusCode, &'static str) {
+    (StatusCode::NOT_FOUND, "Not Found")
+}
+
+#[tracing::instrument(skip(storage))]
+async fn maintain_storage(storage: Arc<SqliteStorage>, every: Duration) {
+    let mut interval = tokio::time::interval(every);
+    // First tick completes immediately, skip maintenance on startup
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        if let Err(e) = storage.maintenance().await {
+            error!("Database maintenance failed: {e}");
+        }
+    }
+}
+
+/// Backs the database up on startup and then every `every`
+#[tracing::instrument(skip(storage))]
+async fn back_up_storage(storage: Arc<SqliteStorage>, dir: PathBuf, keep: u32, every: Duration) {
+    let mut interval = tokio::time::interval(every);
+    loop {
+        interval.tick().await;
+        match storage.backup(&dir, keep).await {
+            Ok(path) => info!("Backed up the database to {}", path.display()),
+            Err(e) => error!("Database backup failed: {e}"),
+        }
+    }
+}
+
+/// Deletes stored files kept neither by count nor by age, `None` keeps no
+/// files by that criterion
+#[tracing::instrument(skip(storage))]
+async fn prune_files_every(
+    storage: Arc<dyn Storage>,
+    keep: Option<u32>,
+    days: Option<u32>,
+    every: Duration,
+) {
+    let mut interval = tokio::time::interval(every);
+    loop {
+        interval.tick().await;
+        let before = match days {
+            Some(days) => unix_now() - i64::from(days) * 24 * 60 * 60,
+            None => i64::MAX,
+        };
+        match storage.prune_files(keep.unwrap_or(0), before).await {
+            Ok(0) => {}
+            Ok(n) => info!("Deleted {n} stored files past retention"),
+            Err(e) => error!("Error deleting stored files past retention: {e}"),
+        }
+    }
+}
+
+#[tracing::instrument(skip(storage))]
+async fn purge_deleted_every(storage: Arc<dyn Storage>, days: u32, every: Duration) {
+    let mut interval = tokio::time::interval(every);
+    loop {
+        interval.tick().await;
+        let before = unix_now() - i64::from(days) * 24 * 60 * 60;
+        match storage.purge_deleted(before).await {
+            Ok(0) => {}
+            Ok(n) => debug!("Forgot {n} removed hosts past retention"),
+            Err(e) => error!("Error forgetting removed hosts: {e}"),
+        }
+    }
+}
+
+#[tracing::instrument(skip(storage))]
+async fn prune_audit_every(storage: Arc<dyn Storage>, days: u32, every: Duration) {
+    let mut interval = tokio::time::interval(every);
+    loop {
+        interval.tick().await;
+        let before = unix_now() - i64::from(days) * 24 * 60 * 60;
+        match storage.prune_audit(before).await {
+            Ok(0) => {}
+            Ok(n) => debug!("Dropped {n} audit log entries past retention"),
+            Err(e) => error!("Error dropping audit log entries: {e}"),
+        }
+    }
+}
+
+#[tracing::instrument(skip(storage))]
+async fn record_storage_stats_every(storage: Arc<dyn Storage>, tenant: String, every: Duration) {
+    let mut interval = tokio::time::interval(every);
+    loop {
+        interval.tick().await;
+        let stats = match storage.storage_stats().await {
+            Ok(stats) => stats,
             Err(e) => {
-                error!("Error fetching hosts: {}", e);
+                error!("Error reading storage stats: {e}");
                 continue;
             }
         };
-        trace!("generate");
-        let pac = Pac::generate(hosts);
+        for (table, rows) in [
+            ("hosts", stats.hosts),
+            ("deleted_hosts", stats.deleted_hosts),
+            ("files", stats.files),
+            ("snapshots", stats.snapshots),
+            ("audit_log", stats.audit_entries),
+        ] {
+            metrics::gauge!(STORAGE_ROWS, "table" => table, "tenant" => tenant.clone())
+                .set(rows as f64);
+        }
+        for (part, bytes) in [
+            ("database", stats.db_bytes),
+            ("wal", stats.wal_bytes),
+            ("latest_pac", stats.latest_pac_bytes),
+        ] {
+            if let Some(bytes) = bytes {
+                metrics::gauge!(STORAGE_BYTES, "part" => part, "tenant" => tenant.clone())
+                    .set(bytes as f64);
+            }
+        }
+    }
+}
 
-        trace!("upload");
-        if let Err(e) = storage.upload_file(&pac).await {
-            error!("Error saving file {}", e);
-            continue;
+/// Removes temporary hosts once they expire
+#[tracing::instrument(skip(server_state))]
+async fn expire_hosts_every(server_state: Arc<ServerState>, every: Duration) {
+    let mut interval = tokio::time::interval(every);
+    loop {
+        interval.tick().await;
+        match server_state.storage.remove_expired(unix_now()).await {
+            Ok(hosts) if hosts.is_empty() => {}
+            Ok(hosts) => {
+                info!("Removed {} expired hosts", hosts.len());
+                notify_update(&server_state, hosts.len());
+            }
+            Err(e) => error!("Error removing expired hosts: {e}"),
+        }
+    }
+}
+
+/// Picks up host and settings changes made by other instances, sqlite has
+/// no change notifications across processes so the storage versions are
+/// polled
+#[tracing::instrument(skip(server_state))]
+async fn watch_changes(server_state: Arc<ServerState>, every: Duration) {
+    let mut interval = tokio::time::interval(every);
+    let mut seen = None;
+    loop {
+        interval.tick().await;
+        let versions = async {
+            let hosts = server_state.storage.hosts_version().await?;
+            let config = server_state.storage.config_version().await?;
+            Ok::<_, AppError>((hosts, config))
+        };
+        let (hosts, config) = match versions.await {
+            Ok(v) => v,
+            Err(e) => {
+                error!("Error polling storage versions: {e}");
+                continue;
+            }
         };
+        if let Some((seen_hosts, seen_config)) = seen {
+            if (seen_hosts, seen_config) != (hosts, config) {
+                refresh_caches(&server_state, seen_hosts != hosts).await;
+            }
+        }
+        seen = Some((hosts, config));
+    }
+}
+
+/// Picks up the changes the storage pushes, see [`Storage::watch_shared`]
+#[tracing::instrument(skip_all)]
+async fn follow_shared_changes(
+    server_state: Arc<ServerState>,
+    mut changes: BoxStream<'static, ChangeEvent>,
+) {
+    while let Some(event) = changes.next().await {
+        refresh_caches(&server_state, event == ChangeEvent::Hosts).await;
+    }
+}
+
+/// Drops the caches a change of the storage made stale, the list only when
+/// `hosts` changed
+async fn refresh_caches(server_state: &ServerState, hosts: bool) {
+    if hosts {
+        debug!("Hosts changed, refreshing the list");
+        server_state.list.invalidate();
+    }
+    debug!("Storage changed, refreshing caches");
+    server_state.missing.clear();
+    server_state.profiles.clear().await;
+    if let Err(e) = latest_pac(server_state).await {
+        debug!("Latest pac is not available yet: {e}");
+    }
+}
+
+/// Settings the default pac is generated with besides the hosts
+#[derive(Debug, Clone)]
+struct PacConfig {
+    proxy: String,
+    groups: Vec<ProxyGroup>,
+    upstreams: Vec<Upstream>,
+    mode: PacMode,
+    /// Patterns of the exclusion list and direct blocklist entries
+    exclusions: Vec<String>,
+    /// Patterns of blocked blocklist entries
+    blocklist: Vec<String>,
+    bypass_private: bool,
+    matching: MatchStrategy,
+    ipv6: bool,
+    networks: Vec<NetworkProfile>,
+    /// Empty unless resolving them is allowed
+    ip_ranges: Vec<String>,
+    js_target: JsTarget,
+}
+
+impl PacConfig {
+    /// `generate` comes from the command line, the rest from storage
+    async fn load(storage: &dyn Storage, generate: GenerateArgs) -> Result<Self, AppError> {
+        Ok(Self {
+            proxy: default_proxy(storage).await?,
+            groups: storage.list_groups().await?,
+            upstreams: storage.list_upstreams().await?,
+            mode: storage.get_mode().await?,
+            exclusions: exclusion_patterns(storage).await?,
+            blocklist: blocklist_patterns(storage).await?,
+            bypass_private: storage.get_bypass_private().await?,
+            matching: generate.match_strategy,
+            ipv6: generate.ipv6,
+            networks: storage.list_networks().await?,
+            ip_ranges: match generate.resolve_ip_ranges {
+                true => storage.list_ip_ranges().await?,
+                false => vec![],
+            },
+            js_target: generate.js_target,
+        })
+    }
+}
+
+/// Patterns of the exclusions and of blocklist entries sent DIRECT
+async fn exclusion_patterns(storage: &dyn Storage) -> Result<Vec<String>, AppError> {
+    let hosts = storage.list_exclusions().await?;
+    let direct = blocklist_rules(storage, BlocklistAction::Direct).await?;
+    Ok(RuleSet::new(hosts.iter().map(|h| Rule::from_host(h)).chain(direct)).into_patterns())
+}
+
+/// Patterns of blocklist entries that are blocked
+async fn blocklist_patterns(storage: &dyn Storage) -> Result<Vec<String>, AppError> {
+    let blocklist = blocklist_rules(storage, BlocklistAction::Block).await?;
+    Ok(RuleSet::new(blocklist).into_patterns())
+}
+
+async fn blocklist_rules(
+    storage: &dyn Storage,
+    action: BlocklistAction,
+) -> Result<Vec<Rule>, AppError> {
+    Ok(storage
+        .list_blocklist()
+        .await?
+        .iter()
+        .filter(|e| e.action == action)
+        .map(|e| e.rule())
+        .collect())
+}
+
+/// Generates the default pac from host entries, hosts of unknown groups use
+/// the default proxy. Hosts with a schedule of their own are checked first,
+/// through their group's proxy or like listed ones
+fn pac_from_entries<'a>(
+    entries: impl IntoIterator<Item = &'a HostEntry>,
+    config: &PacConfig,
+) -> Pac {
+    let mut default = vec![];
+    let mut grouped: HashMap<&str, Vec<Rule>> = config
+        .groups
+        .iter()
+        .map(|g| (g.name.as_str(), vec![]))
+        .collect();
+    let mut scheduled: BTreeMap<(Option<&str>, Schedule), Vec<Rule>> = BTreeMap::new();
+    for entry in entries {
+        let group = entry.group.as_deref().filter(|g| grouped.contains_key(g));
+        match (entry.schedule, group) {
+            (Some(schedule), group) => scheduled
+                .entry((group, schedule))
+                .or_default()
+                .push(entry.rule()),
+            (None, Some(group)) => grouped.entry(group).or_default().push(entry.rule()),
+            (None, None) => default.push(entry.rule()),
+        }
+    }
+    let proxy_of = |name: &str| {
+        config
+            .groups
+            .iter()
+            .find(|g| g.name == name)
+            .map(|g| g.proxy.clone())
+    };
+    let scheduled = scheduled
+        .into_iter()
+        .map(|((group, schedule), rules)| PacGroup {
+            proxy: group.and_then(proxy_of),
+            hosts: RuleSet::new(rules).into_patterns(),
+            schedule: Some(schedule),
+        });
+    let groups: Vec<PacGroup> = scheduled
+        .chain(config.groups.iter().filter_map(|g| {
+            let rules = grouped.remove(g.name.as_str())?;
+            (!rules.is_empty()).then(|| PacGroup {
+                proxy: Some(g.proxy.clone()),
+                hosts: RuleSet::new(rules).into_patterns(),
+                schedule: g.schedule,
+            })
+        }))
+        .collect();
+    Pac::generate_with_options(
+        RuleSet::new(default).into_patterns(),
+        &PacOptions {
+            proxy: &config.proxy,
+            groups: &groups,
+            upstreams: &config.upstreams,
+            mode: config.mode,
+            exclusions: &config.exclusions,
+            blocklist: &config.blocklist,
+            bypass_private: config.bypass_private,
+            matching: config.matching,
+            ipv6: config.ipv6,
+            networks: &config.networks,
+            ip_ranges: &config.ip_ranges,
+            js_target: config.js_target,
+        },
+    )
+}
+
+const REGENERATION_LEASE: &str = "regeneration";
+
+/// Random id telling instances sharing a database apart
+fn instance_id() -> String {
+    let mut buf = [0; 8];
+    SystemRandom::new()
+        .fill(&mut buf)
+        .expect("Error generating random values");
+    buf.iter().map(|b| format!("{b:02x}")).collect()
+}
 
-        trace!("set latest {}", &pac.hash);
-        if let Err(e) = storage.set_latest(pac.hash).await {
-            error!("Error setting latest {}", e);
+/// Renews the lease, the holder picks up regeneration requests of other
+/// instances while the rest refresh their latest pac cache
+#[tracing::instrument(skip(server_state))]
+async fn hold_regeneration_lease(server_state: Arc<ServerState>, ttl: Duration) {
+    let storage = &server_state.storage;
+    let mut interval = tokio::time::interval(ttl / 3);
+    let mut seen_requests = None;
+    loop {
+        interval.tick().await;
+        let held = match storage
+            .try_lease(REGENERATION_LEASE, &server_state.instance_id, ttl)
+            .await
+        {
+            Ok(v) => v,
+            Err(e) => {
+                error!("Error renewing regeneration lease: {e}");
+                continue;
+            }
+        };
+        if !held {
+            seen_requests = None;
+            if latest_pac(&server_state).await.is_err() {
+                debug!("Latest pac is not available yet");
+            }
             continue;
+        }
+        let requests = match storage.regeneration_requests().await {
+            Ok(v) => v,
+            Err(e) => {
+                error!("Error reading regeneration requests: {e}");
+                continue;
+            }
         };
+        // Newly acquired leases regenerate once to cover requests made meanwhile
+        if seen_requests != Some(requests) {
+            debug!("Regeneration requested by another instance");
+            seen_requests = Some(requests);
+            let _ = server_state.update_tx.try_send(());
+        }
+    }
+}
+
+#[tracing::instrument(skip_all, err(Debug))]
+async fn subscribe_pac(
+    server_state: Arc<ServerState>,
+    changes: BoxStream<'static, ChangeEvent>,
+    rx: Receiver<()>,
+) -> Result<()> {
+    let state = server_state.clone();
+    // Another writer may have stored a file under a hash that missed before
+    let updates = changes
+        .map(move |_| state.missing.clear())
+        .merge(ReceiverStream::new(rx));
+    let mut deb = debounced(updates, Duration::from_millis(150));
+    while deb.next().await.is_some() {
+        let s = span!(Level::TRACE, "update_tx");
+        let _se = s.enter();
+        debug!("recv");
+        regenerate(&server_state).await;
     }
     Ok(())
 }
+
+/// Generates, stores and primes the latest pac, or hands the work over to
+/// the lease holder. Errors are logged
+async fn regenerate(server_state: &ServerState) {
+    let storage = &server_state.storage;
+    if let Some(ttl) = server_state.regeneration_lease {
+        match storage
+            .try_lease(REGENERATION_LEASE, &server_state.instance_id, ttl)
+            .await
+        {
+            Ok(true) => {}
+            Ok(false) => {
+                debug!("Regeneration lease is held elsewhere, handing over");
+                server_state.stats.take_pending();
+                if let Err(e) = storage.request_regeneration().await {
+                    error!("Error requesting regeneration: {e}");
+                }
+                return;
+            }
+            Err(e) => {
+                error!("Error taking regeneration lease: {e}");
+                return;
+            }
+        }
+    }
+    let pending = server_state.stats.take_pending();
+    let loaded = match storage.host_entries().await {
+        Ok(entries) => PacConfig::load(storage.as_ref(), server_state.generate)
+            .await
+            .map(|config| (entries, config)),
+        Err(e) => Err(e),
+    };
+    let (entries, config) = match loaded {
+        Ok(v) => v,
+        Err(e) => {
+            error!("Error fetching hosts: {}", e);
+            server_state.stats.changed(pending as usize);
+            return;
+        }
+    };
+    trace!("generate");
+    let pac = pac_from_entries(&entries, &config);
+    metrics::gauge!(PAC_SIZE_BYTES).set(pac.file.len() as f64);
+    if !size_allowed(server_state, &pac, "Generated pac") {
+        server_state.stats.changed(pending as usize);
+        return;
+    }
+
+    trace!("upload");
+    if let Err(e) = storage.upload_file(&pac).await {
+        error!("Error saving file {}", e);
+        server_state.stats.changed(pending as usize);
+        return;
+    };
+    server_state.missing.remove(&pac.hash);
+
+    match storage.staged_hash().await {
+        Ok(None) => {}
+        Ok(Some(_)) => {
+            stage(server_state, pac, pending).await;
+            return;
+        }
+        Err(e) => {
+            error!("Error reading staged file {}", e);
+            server_state.stats.changed(pending as usize);
+            return;
+        }
+    }
+
+    // Primed before the pointer moves so the first request after the
+    // change is served from the cache
+    trace!("prime");
+    let primed = server_state.latest.set(Arc::new(pac)).await;
+    if let Err(e) = storage
+        .upload_encodings(&primed.pac.hash, &primed.encodings())
+        .await
+    {
+        // Compressed again when loaded from storage
+        warn!("Error storing encodings of {}: {e}", primed.pac.hash);
+    }
+
+    trace!("set latest {}", &primed.pac.hash);
+    if let Err(e) = storage.set_latest(&primed.pac.hash).await {
+        error!("Error setting latest {}", e);
+        server_state.stats.changed(pending as usize);
+        return;
+    };
+    server_state.stats.regenerated();
+    audit::record_regeneration(server_state, &primed.pac.hash, false).await;
+    match storage.get_version(&primed.pac.hash).await {
+        Ok(version) => info!("Generated {} {}", version_label(version), primed.pac.hash),
+        Err(e) => error!("Error reading version of {}: {e}", primed.pac.hash),
+    }
+}
+
+/// Makes an uploaded `pac` the candidate instead of the latest file, it stays
+/// out of the latest cache
+async fn stage(server_state: &ServerState, pac: Pac, pending: u64) {
+    let storage = &server_state.storage;
+    let primed = PrimedPac::compress(Arc::new(pac)).await;
+    if let Err(e) = storage
+        .upload_encodings(&primed.pac.hash, &primed.encodings())
+        .await
+    {
+        warn!("Error storing encodings of {}: {e}", primed.pac.hash);
+    }
+    let hash = primed.pac.hash.clone();
+    if let Err(e) = storage.set_staged_hash(Some(hash.clone())).await {
+        error!("Error setting staged {}", e);
+        server_state.stats.changed(pending as usize);
+        return;
+    }
+    server_state.stats.regenerated();
+    audit::record_regeneration(server_state, &hash, true).await;
+    info!("Staged {hash}");
+}
+
+#[cfg(test)]
+mod test {
+    use clap::Parser;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::args::{Args, Command};
+
+    fn serve_args(flags: &[&str]) -> (ServeArgs, HttpClient) {
+        let args = Args::try_parse_from(["qpac", "serve"].iter().chain(flags)).unwrap();
+        let Command::Serve(serve) = args.command else {
+            unreachable!("parsed serve")
+        };
+        (*serve, HttpClient::new(&args.http_client).unwrap())
+    }
+
+    /// State of `qpac serve` with `flags` without any background task
+    fn test_state(
+        storage: Arc<dyn Storage>,
+        auth: Option<AdminAuth>,
+        flags: &[&str],
+    ) -> Arc<ServerState> {
+        let (serve, http_client) = serve_args(flags);
+        let (update_tx, _) = mpsc::channel(1);
+        Arc::new(ServerState::new(
+            storage,
+            update_tx,
+            auth,
+            &serve,
+            http_client,
+        ))
+    }
+
+    async fn json_body(res: Response<Body>) -> Result<serde_json::Value> {
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX).await?;
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    #[tokio::test]
+    async fn profile_lists_tagged_suffix_entries() -> Result<()> {
+        let storage = Arc::new(MemoryStorage::default());
+        let patch = HostPatch {
+            tags: Some(vec!["work".to_string()]),
+            kind: Some(EntryKind::Suffix),
+            ..Default::default()
+        };
+        storage.upsert_host("example.com", patch).await?;
+        storage.add_host("other.com", HostPatch::default()).await?;
+        storage
+            .set_profile(Profile {
+                name: "w".to_string(),
+                proxy: "PROXY 10.0.0.9:3128".to_string(),
+                tags: vec!["work".to_string()],
+            })
+            .await?;
+        let state = test_state(storage, None, &[]);
+        regenerate(&state).await;
+        let app = routes(state);
+
+        for (host, direct) in [
+            ("example.com", false),
+            ("a.example.com", false),
+            ("other.com", true),
+        ] {
+            let req = Request::get(format!("/check?url={host}&profile=w")).body(Body::empty())?;
+            let res = app.clone().oneshot(req).await?;
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(json_body(res).await?["direct"], direct, "{host}");
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn profile_generated_like_default() -> Result<()> {
+        let storage = Arc::new(MemoryStorage::default());
+        storage.set_proxy("PROXY 10.0.0.1:3128").await?;
+        storage.add_host("a.com", HostPatch::default()).await?;
+        storage.add_host("b.com", HostPatch::default()).await?;
+        let patch = HostPatch {
+            schedule: Some(Some("MON-FRI 9-17".parse()?)),
+            ..Default::default()
+        };
+        storage.update_host("b.com", patch).await?;
+        storage
+            .set_network(NetworkProfile {
+                name: "home".to_string(),
+                network: "192.168.1.0/24".to_string(),
+                proxy: "DIRECT".to_string(),
+            })
+            .await?;
+        storage
+            .set_profile(Profile {
+                name: "w".to_string(),
+                proxy: "PROXY 10.0.0.9:3128".to_string(),
+                tags: vec![],
+            })
+            .await?;
+        let state = test_state(storage, None, &[]);
+        regenerate(&state).await;
+
+        let base = latest_pac(&state).await?;
+        let profiled = profile_pac(&state, "w", &base.pac).await?;
+        // Past the header with its hash
+        let body = |file: &str| file[file.find("var ").unwrap()..].to_string();
+        assert_eq!(
+            body(&profiled.pac.file),
+            body(&base.pac.file).replace(
+                r#"var __PROXY__ = "PROXY 10.0.0.1:3128";"#,
+                r#"var __PROXY__ = "PROXY 10.0.0.9:3128";"#
+            )
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn upsert_keeps_fields_not_given() -> Result<()> {
+        let storage = Arc::new(MemoryStorage::default());
+        let app = routes(test_state(storage.clone(), None, &[]));
+        for body in [
+            r#"{"host": "example.com", "tags": ["work"], "kind": "suffix"}"#,
+            r#"{"host": "example.com"}"#,
+            r#"{"hosts": ["example.com"]}"#,
+        ] {
+            let req = Request::post("/add?upsert=true")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body))?;
+            let res = app.clone().oneshot(req).await?;
+            assert_eq!(res.status(), StatusCode::OK, "{body}");
+        }
+        let entry = storage.get_host("example.com").await?;
+        assert_eq!(entry.tags, vec!["work"]);
+        assert_eq!(entry.kind, EntryKind::Suffix);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn adds_hosts_with_metadata() -> Result<()> {
+        let storage = Arc::new(MemoryStorage::default());
+        let app = routes(test_state(storage.clone(), None, &[]));
+        let at = unix_now() + 3600;
+        for body in [
+            json!({"host": "a.com", "tags": ["t"], "kind": "suffix", "expires_at": at}),
+            json!({"hosts": ["b.com", "c.com"], "tags": ["t"], "kind": "suffix", "expires_at": at}),
+        ] {
+            let req = Request::post("/add")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string()))?;
+            let res = app.clone().oneshot(req).await?;
+            assert_eq!(json_body(res).await?["success"], true, "{body}");
+        }
+        for entry in storage.host_entries().await? {
+            assert_eq!(entry.tags, vec!["t"]);
+            assert_eq!(entry.kind, EntryKind::Suffix);
+            assert_eq!(entry.expires_at, Some(at));
+        }
+        assert_eq!(storage.all_hosts().await?.len(), 3);
+        Ok(())
+    }
+
+    fn post_json(uri: &str, body: serde_json::Value) -> Request<Body> {
+        Request::post(uri)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn rejects_stale_revision() -> Result<()> {
+        let storage = Arc::new(MemoryStorage::default());
+        let app = routes(test_state(storage.clone(), None, &[]));
+        let res = app
+            .clone()
+            .oneshot(Request::get("/list").body(Body::empty())?)
+            .await?;
+        let etag = res.headers()[header::ETAG].clone();
+        storage.add_host("a.com", HostPatch::default()).await?;
+        let current = storage.hosts_version().await?;
+
+        let mut req = post_json("/add", json!({"host": "b.com"}));
+        req.headers_mut().insert(header::IF_MATCH, etag);
+        let res = app.clone().oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::PRECONDITION_FAILED);
+        assert_eq!(
+            res.headers()[revision::REVISION_HEADER],
+            current.to_string().as_str()
+        );
+        assert!(storage.get_host("b.com").await.is_err());
+
+        let mut req = post_json("/add", json!({"host": "b.com"}));
+        req.headers_mut()
+            .insert(header::IF_MATCH, format!("\"{current}\"").parse()?);
+        let res = app.oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers()[revision::REVISION_HEADER],
+            storage.hosts_version().await?.to_string().as_str()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn session_mutations_need_csrf_token() -> Result<()> {
+        let storage = Arc::new(MemoryStorage::default());
+        let auth = AdminAuth::new("secret".to_string());
+        let app = routes(test_state(storage.clone(), Some(auth), &[]));
+        let res = app
+            .clone()
+            .oneshot(post_json("/login", json!({"token": "secret"})))
+            .await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let cookie = res.headers()[header::SET_COOKIE].to_str()?.to_string();
+        let cookie = cookie.split(';').next().unwrap().to_string();
+        let csrf = json_body(res).await?["csrf_token"]
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        let mut req = post_json("/add", json!({"host": "a.com"}));
+        req.headers_mut().insert(header::COOKIE, cookie.parse()?);
+        let res = app.clone().oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        assert!(storage.all_hosts().await?.is_empty());
+
+        let mut req = post_json("/add", json!({"host": "a.com"}));
+        req.headers_mut().insert(header::COOKIE, cookie.parse()?);
+        req.headers_mut()
+            .insert(session::CSRF_HEADER, csrf.parse()?);
+        let res = app.oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(storage.all_hosts().await?, vec!["a.com"]);
+        let audit = storage.audit_log(0, 10).await?;
+        assert_eq!(audit[0].actor, "session:root");
+        Ok(())
+    }
+
+    #[test]
+    fn checks_storage_args() {
+        for (flags, error) in [
+            (&["--storage", "memory"][..], None),
+            (
+                &["--storage", "postgres"],
+                Some("--storage postgres needs --postgres-url"),
+            ),
+            (
+                &["--storage", "file"],
+                Some("--storage file needs --state-file"),
+            ),
+            (
+                &["--storage", "memory", "--no-auto-migrate"],
+                Some("--no-auto-migrate only applies to --storage sqlite and postgres"),
+            ),
+            (
+                &[
+                    "--storage",
+                    "file",
+                    "--state-file",
+                    "qpac.json",
+                    "--database",
+                    "sqlite://a.db",
+                ],
+                Some("--database only applies to --storage sqlite"),
+            ),
+            (
+                &[
+                    "--storage",
+                    "postgres",
+                    "--postgres-url",
+                    "postgres://db/qpac",
+                    "--no-auto-migrate",
+                ],
+                None,
+            ),
+        ] {
+            let (args, _) = serve_args(flags);
+            let res = check_storage_args(&args).map_err(|e| format!("{e:?}"));
+            match error {
+                Some(error) => assert!(res.unwrap_err().starts_with(error), "{flags:?}"),
+                None => assert!(res.is_ok(), "{flags:?}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn tenant_token_only_manages_its_tenant() -> Result<()> {
+        let (args, _) = serve_args(&[
+            "--token",
+            "root",
+            "--tenant",
+            "team",
+            "--tenant-token",
+            "team=team-token",
+        ]);
+        let root = Arc::new(MemoryStorage::default());
+        let team = Arc::new(MemoryStorage::default());
+        let auth = args.token.clone().map(AdminAuth::new);
+        let app = routes(test_state(root.clone(), auth, &[])).nest(
+            "/t/team",
+            routes(test_state(team.clone(), tenant_auth(&args, "team"), &[])),
+        );
+
+        for (uri, token, status) in [
+            ("/add", "team-token", StatusCode::UNAUTHORIZED),
+            ("/t/team/add", "team-token", StatusCode::OK),
+            ("/t/team/add", "root", StatusCode::OK),
+        ] {
+            let mut req = post_json(uri, json!({"host": token}));
+            req.headers_mut()
+                .insert(header::AUTHORIZATION, format!("Bearer {token}").parse()?);
+            let res = app.clone().oneshot(req).await?;
+            assert_eq!(res.status(), status, "{uri} with {token}");
+        }
+        assert!(root.all_hosts().await?.is_empty());
+        assert_eq!(team.all_hosts().await?.len(), 2);
+        let mut actors: Vec<String> = team
+            .audit_log(0, 10)
+            .await?
+            .into_iter()
+            .map(|e| e.actor)
+            .collect();
+        actors.sort();
+        assert_eq!(actors, vec!["token:root", "token:tenant:team"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dry_run_predicts_without_changes() -> Result<()> {
+        let storage = Arc::new(MemoryStorage::default());
+        let patch = HostPatch {
+            tags: Some(vec!["t".to_string()]),
+            ..Default::default()
+        };
+        storage.add_host("a.com", patch).await?;
+        let state = test_state(storage.clone(), None, &[]);
+        regenerate(&state).await;
+        let app = routes(state.clone());
+
+        let res = app
+            .clone()
+            .oneshot(post_json("/add?dry_run=true", json!({"host": "b.com"})))
+            .await?;
+        let dry = json_body(res).await?;
+        assert_eq!(dry["dry_run"], true);
+        assert_eq!(storage.all_hosts().await?, vec!["a.com"]);
+
+        let req = Request::delete("/tags/t/hosts?dry_run=true").body(Body::empty())?;
+        let removed = json_body(app.clone().oneshot(req).await?).await?;
+        assert_eq!(removed["removed"], json!(["a.com"]));
+        assert_eq!(storage.all_hosts().await?, vec!["a.com"]);
+
+        let res = app
+            .oneshot(post_json("/add", json!({"host": "b.com"})))
+            .await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        regenerate(&state).await;
+        assert_eq!(dry["hash"], storage.latest_hash().await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn lists_deleted_hosts_to_admins_only() -> Result<()> {
+        let storage = Arc::new(MemoryStorage::default());
+        storage.add_host("a.com", HostPatch::default()).await?;
+        storage.remove_host("a.com").await?;
+        let auth = AdminAuth::new("secret".to_string());
+        let app = routes(test_state(storage, Some(auth), &[]));
+
+        let req = Request::get("/deleted").body(Body::empty())?;
+        let res = app.clone().oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let req = Request::get("/deleted")
+            .header(header::AUTHORIZATION, "Bearer secret")
+            .body(Body::empty())?;
+        let res = app.oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(json_body(res).await?[0]["host"], "a.com");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn deadline_never_cuts_mutations_short() -> Result<()> {
+        let storage = Arc::new(MemoryStorage::default());
+        let app = routes(test_state(storage.clone(), None, &[]));
+        let hosts: Vec<String> = (0..2000).map(|i| format!("h{i}.com")).collect();
+        for (budget, status, listed) in [
+            ("0", StatusCode::SERVICE_UNAVAILABLE, 0),
+            ("1", StatusCode::OK, hosts.len()),
+        ] {
+            let mut req = post_json("/add", json!({ "hosts": hosts }));
+            req.headers_mut()
+                .insert(deadline::DEADLINE_HEADER, budget.parse()?);
+            let res = app.clone().oneshot(req).await?;
+            assert_eq!(res.status(), status, "{budget}");
+            assert_eq!(storage.all_hosts().await?.len(), listed, "{budget}");
+        }
+        Ok(())
+    }
+}
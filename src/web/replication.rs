@@ -0,0 +1,50 @@
+use std::{collections::HashSet, sync::Arc, time::Duration};
+
+use tracing::{error, info};
+
+use super::{notify_update, ServerState};
+use crate::{client::Client, error::AppError, storage::InstanceState};
+
+/// Imports the state of the primary behind `client` whenever it differs from
+/// the one imported last
+#[tracing::instrument(skip_all)]
+pub async fn pull_every(server_state: Arc<ServerState>, client: Client, every: Duration) {
+    let mut interval = tokio::time::interval(every);
+    let mut applied: Option<InstanceState> = None;
+    loop {
+        interval.tick().await;
+        let state = match client.state().await {
+            Ok(state) => state,
+            Err(e) => {
+                error!("Error fetching the primary state: {e}");
+                continue;
+            }
+        };
+        if applied.as_ref() == Some(&state) {
+            continue;
+        }
+        match apply(&server_state, state.clone()).await {
+            Ok(changed) => {
+                info!("Replicated the primary, {changed} hosts changed");
+                applied = Some(state);
+            }
+            Err(e) => error!("Error importing the primary state: {e}"),
+        }
+    }
+}
+
+/// Replaces hosts and settings with `state`, returns how many hosts were
+/// added or removed. The storage change regenerates the pac
+pub async fn apply(server_state: &ServerState, state: InstanceState) -> Result<usize, AppError> {
+    let before: HashSet<String> = server_state
+        .storage
+        .all_hosts()
+        .await?
+        .into_iter()
+        .collect();
+    let after: HashSet<String> = state.hosts.iter().map(|e| e.host.clone()).collect();
+    server_state.storage.import_state(state).await?;
+    let changed = before.symmetric_difference(&after).count();
+    notify_update(server_state, changed);
+    Ok(changed)
+}
@@ -0,0 +1,66 @@
+use axum::http::{header, HeaderMap, HeaderName};
+
+use crate::error::AppError;
+
+/// Revision of the host list `/list` was loaded at, see
+/// [`crate::storage::Storage::hosts_version`]
+pub const REVISION_HEADER: HeaderName = HeaderName::from_static("x-list-revision");
+
+/// Revision a mutation was based on, from `If-Match` or the `revision` field
+/// of a JSON body. `If-Match: *` doesn't check anything
+pub fn expected(headers: &HeaderMap, body: &[u8]) -> Result<Option<i64>, AppError> {
+    if let Some(value) = headers.get(header::IF_MATCH) {
+        let value = value.to_str().unwrap_or_default().trim();
+        if value == "*" {
+            return Ok(None);
+        }
+        let revision = value
+            .trim_start_matches("W/")
+            .trim_matches('"')
+            .parse()
+            .map_err(|_| AppError::Validation {
+                field: header::IF_MATCH.to_string(),
+                message: "expected a list revision".to_string(),
+            })?;
+        return Ok(Some(revision));
+    }
+    let Ok(serde_json::Value::Object(body)) = serde_json::from_slice(body) else {
+        return Ok(None);
+    };
+    match body.get("revision") {
+        None | Some(serde_json::Value::Null) => Ok(None),
+        Some(revision) => revision
+            .as_i64()
+            .map(Some)
+            .ok_or_else(|| AppError::Validation {
+                field: "revision".to_string(),
+                message: "expected a list revision".to_string(),
+            }),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    #[test]
+    fn reads_header_before_body() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(expected(&headers, b"").unwrap(), None);
+        assert_eq!(
+            expected(&headers, br#"{"host": "a", "revision": 4}"#).unwrap(),
+            Some(4)
+        );
+        assert_eq!(expected(&headers, br#"{"host": "a"}"#).unwrap(), None);
+        assert!(expected(&headers, br#"{"revision": "4"}"#).is_err());
+
+        headers.insert(header::IF_MATCH, HeaderValue::from_static("\"7\""));
+        assert_eq!(expected(&headers, br#"{"revision": 4}"#).unwrap(), Some(7));
+        headers.insert(header::IF_MATCH, HeaderValue::from_static("*"));
+        assert_eq!(expected(&headers, br#"{"revision": 4}"#).unwrap(), None);
+        headers.insert(header::IF_MATCH, HeaderValue::from_static("abc"));
+        assert!(expected(&headers, b"").is_err());
+    }
+}
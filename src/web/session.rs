@@ -0,0 +1,169 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use axum::http::{header, HeaderMap, Method};
+use subtle::ConstantTimeEq;
+
+use crate::utils;
+
+pub const SESSION_COOKIE: &str = "qpac_session";
+/// Mutations made with a session cookie carry the session's CSRF token here
+pub const CSRF_HEADER: &str = "x-csrf-token";
+const SESSION_TTL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug)]
+struct Session {
+    csrf: String,
+    /// Id of the admin token the session was exchanged for
+    token: String,
+    expires: Instant,
+}
+
+/// Browser sessions exchanged for the admin token on `/login`, kept in memory
+/// so a restart logs everyone out
+#[derive(Debug)]
+pub struct SessionStore {
+    ttl: Duration,
+    sessions: Mutex<HashMap<String, Session>>,
+}
+
+impl Default for SessionStore {
+    fn default() -> Self {
+        Self::new(SESSION_TTL)
+    }
+}
+
+impl SessionStore {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            sessions: Mutex::default(),
+        }
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// Starts a session for the admin token `token`, returns its id and CSRF
+    /// token
+    pub fn create(&self, token: &str) -> (String, String) {
+        let id = utils::token::generate();
+        let csrf = utils::token::generate();
+        let now = Instant::now();
+        let mut sessions = self.sessions.lock().expect("Poisoned sessions");
+        sessions.retain(|_, s| s.expires > now);
+        sessions.insert(
+            id.clone(),
+            Session {
+                csrf: csrf.clone(),
+                token: token.to_string(),
+                expires: now + self.ttl,
+            },
+        );
+        (id, csrf)
+    }
+
+    /// CSRF token of a live session
+    pub fn csrf(&self, id: &str) -> Option<String> {
+        self.live(id).map(|(csrf, _)| csrf)
+    }
+
+    /// CSRF token and admin token id of a live session
+    fn live(&self, id: &str) -> Option<(String, String)> {
+        let sessions = self.sessions.lock().expect("Poisoned sessions");
+        sessions
+            .get(id)
+            .filter(|s| s.expires > Instant::now())
+            .map(|s| (s.csrf.clone(), s.token.clone()))
+    }
+
+    pub fn remove(&self, id: &str) {
+        self.sessions.lock().expect("Poisoned sessions").remove(id);
+    }
+
+    /// Admin token id of the live session cookie the request carries,
+    /// anything but reads also needs the session's CSRF token
+    pub fn authorize(&self, headers: &HeaderMap, method: &Method) -> Option<String> {
+        let (csrf, token) = session_id(headers).and_then(|id| self.live(id))?;
+        if matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS) {
+            return Some(token);
+        }
+        headers
+            .get(CSRF_HEADER)
+            .is_some_and(|v| v.as_bytes().ct_eq(csrf.as_bytes()).into())
+            .then_some(token)
+    }
+}
+
+/// Session id from the `Cookie` header
+pub fn session_id(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .find_map(|c| {
+            let (name, value) = c.trim().split_once('=')?;
+            (name == SESSION_COOKIE).then_some(value)
+        })
+}
+
+/// `Set-Cookie` value, the cookie is out of reach of scripts and isn't sent
+/// along with cross-site requests
+pub fn cookie(id: &str, ttl: Duration, secure: bool) -> String {
+    let secure = if secure { "; Secure" } else { "" };
+    format!(
+        "{SESSION_COOKIE}={id}; Path=/; Max-Age={}; HttpOnly; SameSite=Strict{secure}",
+        ttl.as_secs()
+    )
+}
+
+pub fn clear_cookie(secure: bool) -> String {
+    cookie("", Duration::ZERO, secure)
+}
+
+#[cfg(test)]
+mod test {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    #[test]
+    fn authorizes_sessions() {
+        let store = SessionStore::default();
+        let (id, csrf) = store.create("root");
+        let mut headers = HeaderMap::new();
+        assert_eq!(store.authorize(&headers, &Method::GET), None);
+
+        let cookie = format!("theme=dark; {SESSION_COOKIE}={id}");
+        headers.insert(header::COOKIE, HeaderValue::from_str(&cookie).unwrap());
+        assert_eq!(
+            store.authorize(&headers, &Method::GET).as_deref(),
+            Some("root")
+        );
+        assert_eq!(store.authorize(&headers, &Method::POST), None);
+
+        headers.insert(CSRF_HEADER, HeaderValue::from_static("nope"));
+        assert_eq!(store.authorize(&headers, &Method::POST), None);
+        headers.insert(CSRF_HEADER, HeaderValue::from_str(&csrf).unwrap());
+        assert_eq!(
+            store.authorize(&headers, &Method::POST).as_deref(),
+            Some("root")
+        );
+
+        store.remove(&id);
+        assert_eq!(store.authorize(&headers, &Method::GET), None);
+    }
+
+    #[test]
+    fn expires_sessions() {
+        let store = SessionStore::new(Duration::ZERO);
+        let (id, _) = store.create("root");
+        assert_eq!(store.csrf(&id), None);
+        assert!(clear_cookie(true).contains("Max-Age=0; HttpOnly; SameSite=Strict; Secure"));
+    }
+}
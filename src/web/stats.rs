@@ -0,0 +1,170 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{
+    storage::{ClientFetches, ClientKey},
+    utils::time::unix_now,
+};
+
+/// Process lifetime counters summarized in the shutdown report
+#[derive(Debug)]
+pub struct ServerStats {
+    started: Instant,
+    requests: AtomicU64,
+    regenerations: AtomicU64,
+    /// Host changes not picked up by a regeneration yet
+    pending: AtomicU64,
+}
+
+impl Default for ServerStats {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            requests: AtomicU64::new(0),
+            regenerations: AtomicU64::new(0),
+            pending: AtomicU64::new(0),
+        }
+    }
+}
+
+impl ServerStats {
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    pub fn request(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn requests(&self) -> u64 {
+        self.requests.load(Ordering::Relaxed)
+    }
+
+    pub fn changed(&self, changed: usize) {
+        self.pending.fetch_add(changed as u64, Ordering::AcqRel);
+    }
+
+    /// Takes the pending changes a regeneration is about to cover
+    pub fn take_pending(&self) -> u64 {
+        self.pending.swap(0, Ordering::AcqRel)
+    }
+
+    pub fn pending(&self) -> u64 {
+        self.pending.load(Ordering::Acquire)
+    }
+
+    pub fn regenerated(&self) {
+        self.regenerations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn regenerations(&self) -> u64 {
+        self.regenerations.load(Ordering::Relaxed)
+    }
+}
+
+const DAY_SECS: i64 = 24 * 60 * 60;
+
+/// Unix time of the UTC midnight starting the day of `unix`
+pub fn day_start(unix: i64) -> i64 {
+    unix - unix.rem_euclid(DAY_SECS)
+}
+
+/// Network a client is counted under, the address itself is never kept.
+/// IPv4 clients are grouped by /24 and IPv6 ones by /48
+pub fn client_network(ip: IpAddr) -> String {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            format!("{a}.{b}.{c}.0/24")
+        }
+        IpAddr::V6(ip) => {
+            let [a, b, c, ..] = ip.segments();
+            format!("{a:x}:{b:x}:{c:x}::/48")
+        }
+    }
+}
+
+/// PAC fetches by day, client network and profile counted in memory until
+/// they are flushed to storage
+#[derive(Debug, Default)]
+pub struct ClientStats {
+    counts: Mutex<HashMap<ClientKey, i64>>,
+}
+
+impl ClientStats {
+    pub fn record(&self, ip: IpAddr, profile: Option<&str>) {
+        let key = (
+            day_start(unix_now()),
+            client_network(ip),
+            profile.map(str::to_string),
+        );
+        *self
+            .counts
+            .lock()
+            .expect("Poisoned client stats")
+            .entry(key)
+            .or_default() += 1;
+    }
+
+    /// Takes the counts a flush is about to store
+    pub fn take(&self) -> Vec<ClientFetches> {
+        let counts = std::mem::take(&mut *self.counts.lock().expect("Poisoned client stats"));
+        counts
+            .into_iter()
+            .map(|((day, client, profile), fetches)| ClientFetches {
+                day,
+                client,
+                profile,
+                fetches,
+            })
+            .collect()
+    }
+
+    /// Puts back counts of a failed flush
+    pub fn restore(&self, fetches: Vec<ClientFetches>) {
+        let mut counts = self.counts.lock().expect("Poisoned client stats");
+        for f in fetches {
+            *counts.entry((f.day, f.client, f.profile)).or_default() += f.fetches;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn counts_client_networks() {
+        assert_eq!(day_start(DAY_SECS + 5), DAY_SECS);
+        assert_eq!(client_network("10.1.2.3".parse().unwrap()), "10.1.2.0/24");
+        assert_eq!(
+            client_network("::ffff:10.1.2.3".parse().unwrap()),
+            "10.1.2.0/24"
+        );
+        assert_eq!(
+            client_network("2001:db8:1:2::1".parse().unwrap()),
+            "2001:db8:1::/48"
+        );
+
+        let stats = ClientStats::default();
+        stats.record("10.1.2.3".parse().unwrap(), None);
+        stats.record("10.1.2.4".parse().unwrap(), None);
+        stats.record("10.1.2.4".parse().unwrap(), Some("lte"));
+        let mut fetches = stats.take();
+        fetches.sort_by(|a, b| a.profile.cmp(&b.profile));
+        assert_eq!(fetches.len(), 2);
+        assert_eq!(fetches[0].fetches, 2);
+        assert_eq!(fetches[1].profile.as_deref(), Some("lte"));
+        assert!(stats.take().is_empty());
+
+        stats.restore(fetches);
+        assert_eq!(stats.take().len(), 2);
+    }
+}
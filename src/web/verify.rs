@@ -0,0 +1,44 @@
+use std::time::Duration;
+
+use tokio::{net::lookup_host, time::timeout};
+
+use crate::http_client::HttpClient;
+
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Probes `host` before it's added, returning a warning when it doesn't
+/// resolve or, with `client`, when port 443 doesn't answer through the
+/// outbound proxy
+pub async fn probe(host: &str, client: Option<&HttpClient>) -> Option<String> {
+    let lookup = timeout(LOOKUP_TIMEOUT, lookup_host((host, 443)))
+        .await
+        .map(|r| r.map(|mut addrs| addrs.next().is_some()));
+    match lookup {
+        Ok(Ok(true)) => {}
+        Ok(Ok(false)) => return Some("host has no addresses".to_string()),
+        Ok(Err(e)) => return Some(format!("host doesn't resolve: {e}")),
+        Err(_) => return Some("host lookup timed out".to_string()),
+    }
+    let client = client?;
+    // Any http answer will do, only connection failures are reported
+    match client
+        .client()
+        .head(format!("https://{host}/"))
+        .send()
+        .await
+    {
+        Err(e) if e.is_connect() || e.is_timeout() => Some(format!("port 443 doesn't answer: {e}")),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn probes_resolution() {
+        assert_eq!(probe("localhost", None).await, None);
+        assert!(probe("qpac.invalid", None).await.is_some());
+    }
+}